@@ -0,0 +1,149 @@
+//! Benchmarks of the `Kcb` hot paths: parsing input with large windows,
+//! flushing with thousands of segments in flight and fragmenting sends.
+#[macro_use]
+extern crate criterion;
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use criterion::{BatchSize, Criterion, Throughput};
+use kcp::Kcb;
+
+const CONV: u32 = 0x11223344;
+const WND: i32 = 4096;
+
+#[derive(Clone)]
+struct Pipe {
+    packets: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe { packets: Rc::new(RefCell::new(VecDeque::new())) }
+    }
+
+    fn drain(&self) -> Vec<Vec<u8>> {
+        self.packets.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets.borrow_mut().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Sink;
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a sender whose whole window of `segments` full size segments is in
+/// flight, its peer having advertised a window large enough for them
+fn in_flight(segments: usize) -> (Kcb<Pipe>, Pipe) {
+    let out = Pipe::new();
+    let mut sender = Kcb::new(CONV, out.clone());
+    sender.wndsize(WND, WND);
+    sender.nodelay(1, 10, 2, true);
+
+    let peer_out = Pipe::new();
+    let mut peer = Kcb::new(CONV, peer_out.clone());
+    peer.wndsize(WND, WND);
+    peer.ask_window();
+    peer.update(0);
+    for pkt in peer_out.drain() {
+        sender.input(&pkt).unwrap();
+    }
+
+    let msg = vec![0x5a; 1376];
+    for _ in 0..segments {
+        sender.send(&msg).unwrap();
+    }
+    sender.update(0);
+    (sender, out)
+}
+
+fn input(c: &mut Criterion) {
+    let (_, out) = in_flight(WND as usize);
+    let packets = out.drain();
+    let bytes = packets.iter().map(|pkt| pkt.len() as u64).sum();
+
+    let mut group = c.benchmark_group("input");
+    group.throughput(Throughput::Bytes(bytes));
+    group.bench_function("full window", |b| {
+        b.iter_batched(
+            || {
+                let mut kcb = Kcb::new(CONV, Sink);
+                kcb.wndsize(WND, WND);
+                kcb
+            },
+            |mut kcb| for pkt in &packets {
+                kcb.input(pkt).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("full window reversed", |b| {
+        b.iter_batched(
+            || {
+                let mut kcb = Kcb::new(CONV, Sink);
+                kcb.wndsize(WND, WND);
+                kcb
+            },
+            |mut kcb| for pkt in packets.iter().rev() {
+                kcb.input(pkt).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn flush(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flush");
+    for &segments in &[256, 1024, 4096] {
+        let (mut sender, out) = in_flight(segments);
+        out.drain();
+        group.bench_function(format!("{} in flight", segments), |b| {
+            b.iter(|| {
+                sender.flush();
+                out.drain()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("send");
+    for &len in &[1024, 16 * 1024, 128 * 1024] {
+        let msg = vec![0x5a; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(format!("{} bytes", len), |b| {
+            b.iter_batched(
+                || Kcb::new(CONV, Sink),
+                |mut kcb| kcb.send(&msg).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, input, flush, send);
+criterion_main!(benches);
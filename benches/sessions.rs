@@ -0,0 +1,90 @@
+//! Benchmarks of the listener's session maps at 100k+ sessions against a
+//! plain `HashMap`: filling them, which grows the tables as a flood of new
+//! peers would, and looking sessions up as every received datagram does,
+//! by address or by the id the address resolved to.
+#[macro_use]
+extern crate criterion;
+extern crate kcp;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use criterion::{BatchSize, Criterion, Throughput};
+use kcp::{SessionMap, SharedSessionMap};
+
+const SESSIONS: usize = 100_000;
+
+fn peers() -> Vec<SocketAddr> {
+    (0..SESSIONS as u32)
+        .map(|i| {
+            let ip = Ipv4Addr::from(0x0a00_0000 | (i >> 8));
+            SocketAddr::new(IpAddr::V4(ip), 1024 + (i & 0xff) as u16)
+        })
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let peers = peers();
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(SESSIONS as u64));
+    group.bench_function("session map", |b| {
+        b.iter_batched(
+            SessionMap::new,
+            |mut map| for (i, peer) in peers.iter().enumerate() {
+                map.insert(*peer, i);
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("shared session map", |b| {
+        b.iter_batched(
+            SharedSessionMap::new,
+            |map| for (i, peer) in peers.iter().enumerate() {
+                map.insert(*peer, Arc::new(Mutex::new(i)));
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("hash map", |b| {
+        b.iter_batched(
+            HashMap::new,
+            |mut map| for (i, peer) in peers.iter().enumerate() {
+                map.insert(*peer, i);
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn lookup(c: &mut Criterion) {
+    let peers = peers();
+    let mut map = SessionMap::new();
+    let shared = SharedSessionMap::new();
+    let mut plain = HashMap::new();
+    for (i, peer) in peers.iter().enumerate() {
+        map.insert(*peer, i);
+        shared.insert(*peer, Arc::new(Mutex::new(i)));
+        plain.insert(*peer, i);
+    }
+    let mut group = c.benchmark_group("lookup");
+    group.throughput(Throughput::Elements(SESSIONS as u64));
+    group.bench_function("session map", |b| {
+        b.iter(|| peers.iter().filter(|peer| map.contains_key(peer)).count())
+    });
+    let ids: Vec<_> = peers.iter().map(|peer| map.id(peer).unwrap()).collect();
+    group.bench_function("session id", |b| {
+        b.iter(|| ids.iter().filter(|id| map.by_id(**id).is_some()).count())
+    });
+    group.bench_function("shared session map", |b| {
+        b.iter(|| peers.iter().filter(|peer| shared.get(peer).is_some()).count())
+    });
+    group.bench_function("hash map", |b| {
+        b.iter(|| peers.iter().filter(|peer| plain.contains_key(peer)).count())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, insert, lookup);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+fn main() {
+    #[cfg(feature = "ikcp-conformance")]
+    ikcp::build();
+}
+
+#[cfg(feature = "ikcp-conformance")]
+mod ikcp {
+    extern crate cc;
+
+    use std::env;
+    use std::path::PathBuf;
+
+    pub fn build() {
+        println!("cargo:rerun-if-env-changed=IKCP_DIR");
+        let dir = env::var("IKCP_DIR").expect("IKCP_DIR must point at a checkout of ikcp.c");
+        let dir = PathBuf::from(dir);
+        println!("cargo:rerun-if-changed={}", dir.join("ikcp.c").display());
+        cc::Build::new()
+            .file(dir.join("ikcp.c"))
+            .include(&dir)
+            .compile("ikcp");
+    }
+}
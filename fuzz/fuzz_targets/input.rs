@@ -0,0 +1,56 @@
+//! Arbitrary datagrams fed to `Kcb::input`, interleaved with updates and
+//! reads. The input is a sequence of length prefixed datagrams.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate kcp;
+
+use std::io::{self, Write};
+
+use kcp::Kcb;
+
+const CONV: u32 = 0x11223344;
+
+struct Sink;
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut kcb = Kcb::new(CONV, Sink);
+    kcb.nodelay(1, 10, 2, true);
+    let mut buf = [0; 1 << 16];
+    let mut now = 0;
+    let mut data = data;
+
+    while !data.is_empty() {
+        let len = data[0] as usize * 8 + 1;
+        let len = len.min(data.len() - 1);
+        let mut pkt = data[1..1 + len].to_vec();
+        data = &data[1 + len..];
+
+        // most datagrams get past the conv check, to reach the parser
+        if pkt.len() >= 4 && pkt[0] & 0x80 == 0 {
+            pkt[..4].copy_from_slice(&[0x44, 0x33, 0x22, 0x11]);
+        }
+        kcb.input(&pkt).ok();
+        if let Err(e) = kcb.verify_invariants() {
+            panic!("after input: {}", e);
+        }
+
+        now += 10;
+        kcb.update(now);
+        // 0 once a close frame came in
+        while kcb.recv(&mut buf).map_or(false, |n| n > 0) {}
+        if let Err(e) = kcb.verify_invariants() {
+            panic!("after recv: {}", e);
+        }
+    }
+});
@@ -0,0 +1,123 @@
+//! Arbitrary interleavings of send, recv, time and packet delivery, loss
+//! and reordering between two control blocks. Ordered messages have to
+//! come out in the order they were sent.
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use kcp::Kcb;
+
+#[derive(Clone)]
+struct Pipe {
+    packets: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets.borrow_mut().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct End {
+    kcb: Kcb<Pipe>,
+    out: Pipe,
+    sent: u32,
+    received: u32,
+}
+
+impl End {
+    fn new() -> End {
+        let out = Pipe { packets: Rc::new(RefCell::new(VecDeque::new())) };
+        let mut kcb = Kcb::new(0x11223344, out.clone());
+        kcb.nodelay(1, 10, 2, true);
+        End {
+            kcb: kcb,
+            out: out,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// ordered messages carry their index, unordered ones a marker
+    fn send(&mut self, len: usize, unordered: bool) {
+        let mut msg = vec![0xff; len.max(5)];
+        if unordered {
+            self.kcb.send_unordered(&msg).ok();
+        } else {
+            msg[0] = 0;
+            msg[1..5].copy_from_slice(&index(self.sent));
+            if self.kcb.send(&msg).is_ok() {
+                self.sent += 1;
+            }
+        }
+    }
+
+    fn recv(&mut self) {
+        let mut buf = [0; 1 << 16];
+        while let Ok(n) = self.kcb.recv(&mut buf) {
+            assert!(n >= 5);
+            if buf[0] == 0 {
+                assert_eq!(&buf[1..5], &index(self.received), "ordered message out of order");
+                self.received += 1;
+            }
+        }
+    }
+
+    fn verify(&self) {
+        if let Err(e) = self.kcb.verify_invariants() {
+            panic!("{}", e);
+        }
+    }
+}
+
+fn index(i: u32) -> [u8; 4] {
+    [i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8]
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut ends = [End::new(), End::new()];
+    let mut now = 0;
+
+    for op in data.chunks(2) {
+        let arg = *op.get(1).unwrap_or(&0) as usize;
+        let side = (op[0] & 1) as usize;
+        match op[0] >> 1 {
+            0..=15 => ends[side].send(arg * 24, false),
+            16..=23 => ends[side].send(arg * 24, true),
+            24..=63 => {
+                now += arg as u32;
+                ends[0].kcb.update(now);
+                ends[1].kcb.update(now);
+            }
+            64..=95 => {
+                // deliver the packet `arg` places back in the queue,
+                // reordering the path
+                let pkt = {
+                    let mut packets = ends[side].out.packets.borrow_mut();
+                    let i = arg % (packets.len() + 1);
+                    packets.remove(i)
+                };
+                if let Some(pkt) = pkt {
+                    ends[1 - side].kcb.input(&pkt).ok();
+                }
+            }
+            96..=103 => {
+                ends[side].out.packets.borrow_mut().pop_front();
+            }
+            _ => ends[side].recv(),
+        }
+        ends[0].verify();
+        ends[1].verify();
+    }
+});
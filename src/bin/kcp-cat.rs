@@ -0,0 +1,214 @@
+//! netcat over KCP: pipes stdin to a `KcpStream` and the stream to stdout.
+//!
+//! Connect to a server:
+//!
+//!     kcp-cat 127.0.0.1:8080
+//!
+//! or wait for a single session on an address:
+//!
+//!     kcp-cat -l 127.0.0.1:8080
+//!
+//! Started by systemd socket activation, `-l` takes the inherited socket
+//! instead and the address is not bound.
+//!
+//! The session is tuned with `--mode default|normal|fast` (the presets of
+//! the `make test` runs), `--wnd N`, `--mtu N`, `--interval MS` and
+//! `--stream`, on top of a `--config FILE` in TOML and `KCP_*` environment
+//! variables (see `KcpConfig::load`). Both ends should agree on them.
+
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+
+use futures::future::{self, Loop};
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+use kcp::{KcpConfig, KcpListener, KcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncRead;
+use tokio_io::io::{read, write_all};
+
+const USAGE: &'static str = "usage: kcp-cat [-l] [--config FILE] [--mode default|normal|fast] \
+                             [--wnd N] [--mtu N] [--interval MS] [--stream] <addr:port>";
+
+struct Options {
+    listen: bool,
+    addr: SocketAddr,
+    config: KcpConfig,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut listen = false;
+    let mut addr = None;
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let mut config = load_config(&args)?;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-l" | "--listen" => listen = true,
+            "--config" => {
+                value(&mut args, &arg)?;
+            }
+            "--stream" => config.stream = true,
+            "--mode" => {
+                let mode = value(&mut args, &arg)?;
+                let preset = KcpConfig::preset(&mode).ok_or(format!("unknown mode {}", mode))?;
+                config.nodelay = preset.nodelay;
+                config.resend = preset.resend;
+                config.nc = preset.nc;
+            }
+            "--wnd" => {
+                let wnd = number(&mut args, &arg)?;
+                config.snd_wnd = wnd;
+                config.rcv_wnd = wnd;
+            }
+            "--mtu" => config.mtu = number(&mut args, &arg)?,
+            "--interval" => config.interval = number(&mut args, &arg)?,
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if addr.is_none() && !arg.starts_with('-') => {
+                let parsed = arg.parse().map_err(|e| format!("{}: {}", arg, e))?;
+                addr = Some(parsed);
+            }
+            _ => return Err(format!("unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+    match addr {
+        Some(addr) => Ok(Options {
+            listen: listen,
+            addr: addr,
+            config: config,
+        }),
+        None => Err(USAGE.to_owned()),
+    }
+}
+
+/// the file of `--config` and the environment, the other flags override them
+fn load_config(args: &[String]) -> Result<KcpConfig, String> {
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Some(args.get(i + 1).ok_or("--config needs a value")?),
+        None => None,
+    };
+    KcpConfig::load(path.map(Path::new)).map_err(|e| format!("config: {}", e))
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn number<T: FromStr, I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<T, String> {
+    let v = value(args, flag)?;
+    v.parse().map_err(|_| format!("{} takes a number, not {}", flag, v))
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let stream: Box<Future<Item = KcpStream, Error = io::Error>> = if options.listen {
+        let listener = match inherited_listener(&options.config, &handle) {
+            Some(listener) => listener,
+            None => KcpListener::bind_with_config(&options.addr, options.config, &handle).unwrap(),
+        };
+        let spawner = handle.clone();
+        Box::new(
+            listener
+                .incoming()
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(accepted, rest)| {
+                    // only the incoming sessions read the listener socket,
+                    // they go on feeding ours and turn away later peers
+                    spawner.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+                    match accepted {
+                        Some((stream, addr)) => {
+                            eprintln!("session from {}", addr);
+                            Ok(stream)
+                        }
+                        None => Err(io::Error::new(io::ErrorKind::Other, "listener closed")),
+                    }
+                }),
+        )
+    } else {
+        let config = options.config;
+        Box::new(KcpStream::connect(&options.addr, &handle).and_then(move |stream| {
+            stream.configure(&config).map(|()| stream)
+        }))
+    };
+
+    // stdin only blocks, read it on a thread of its own
+    let (stdin_tx, stdin_rx) = mpsc::channel(0);
+    thread::spawn(|| read_stdin(stdin_tx));
+    let stdin_rx = stdin_rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "stdin"));
+
+    let session = stream.and_then(|stream| {
+        let (reader, writer) = stream.split();
+        let send = stdin_rx
+            .fold(writer, |writer, buf| write_all(writer, buf).map(|(w, _)| w))
+            .map(|mut writer| {
+                // end of input closes the session, the peer reads EOF
+                let _ = tokio_io::AsyncWrite::shutdown(&mut writer);
+            });
+        let recv = future::loop_fn((reader, vec![0; 64 * 1024]), |(reader, buf)| {
+            read(reader, buf).and_then(|(reader, buf, n)| {
+                if n == 0 {
+                    return Ok(Loop::Break(()));
+                }
+                let mut stdout = io::stdout();
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+                Ok(Loop::Continue((reader, buf)))
+            })
+        });
+        // done once the peer closed, input may still be pending on a tty
+        let send = send.and_then(|_| future::empty());
+        recv.select(send).map(|_| ()).map_err(|(e, _)| e)
+    });
+
+    if let Err(e) = core.run(session) {
+        eprintln!("kcp-cat: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn inherited_listener(config: &KcpConfig, handle: &Handle) -> Option<KcpListener> {
+    KcpListener::from_systemd(config.clone(), handle).unwrap_or_else(|e| {
+        eprintln!("kcp-cat: {}", e);
+        process::exit(1);
+    })
+}
+
+#[cfg(not(unix))]
+fn inherited_listener(_: &KcpConfig, _: &Handle) -> Option<KcpListener> {
+    None
+}
+
+fn read_stdin(mut tx: mpsc::Sender<Vec<u8>>) {
+    let mut stdin = io::stdin();
+    loop {
+        let mut buf = vec![0; 16 * 1024];
+        let n = match stdin.read(&mut buf) {
+            Err(_) | Ok(0) => break,
+            Ok(n) => n,
+        };
+        buf.truncate(n);
+        tx = match tx.send(buf).wait() {
+            Ok(tx) => tx,
+            Err(_) => break,
+        };
+    }
+}
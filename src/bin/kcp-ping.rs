@@ -0,0 +1,290 @@
+//! ping over KCP: measures round trips of small probes through a session,
+//! to compare tuning profiles on a real link.
+//!
+//! Run the echo side somewhere:
+//!
+//!     kcp-ping -l 0.0.0.0:9000
+//!
+//! and probe it, once per profile:
+//!
+//!     kcp-ping --mode default,normal,fast -c 200 -i 20 host:9000
+//!
+//! Sessions start from a `--config FILE` in TOML and `KCP_*` environment
+//! variables (see `KcpConfig::load`), the modes only pick the nodelay,
+//! resend and congestion control settings.
+//!
+//! Every probe carries its sequence number and send time and is echoed
+//! back. The report gives the rtt distribution, the probes not answered
+//! within `-w` millisec after the last one was sent, and how often kcp had
+//! to retransmit to get them through.
+
+extern crate bytes;
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use bytes::{ByteOrder, LittleEndian};
+use futures::future::{self, Either, Loop};
+use futures::{Async, Future, Stream};
+use kcp::{KcpConfig, KcpListener, KcpStats, KcpStream};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use tokio_io::io::{read, write_all};
+
+const USAGE: &'static str = "usage: kcp-ping [--config FILE] -l <addr:port>\n       \
+                             kcp-ping [--config FILE] [--mode NAME[,NAME..]] [-c COUNT] \
+                             [-i MS] [-s SIZE] [-w MS] <addr:port>";
+
+const PROBE_HEADER: usize = 12; // sequence number and send time in microsec
+
+struct Options {
+    listen: bool,
+    addr: SocketAddr,
+    config: KcpConfig,
+    modes: Vec<String>,
+    count: u32,
+    interval: u64,
+    size: usize,
+    wait: u64,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let mut options = Options {
+        listen: false,
+        addr: "0.0.0.0:0".parse().unwrap(),
+        config: load_config(&args)?,
+        modes: vec!["fast".to_owned()],
+        count: 100,
+        interval: 100,
+        size: 64,
+        wait: 3_000,
+    };
+    let mut addr = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-l" | "--listen" => options.listen = true,
+            "--config" => {
+                value(&mut args, &arg)?;
+            }
+            "--mode" => {
+                let modes = value(&mut args, &arg)?;
+                options.modes = modes.split(',').map(|m| m.to_owned()).collect();
+                if let Some(m) = options.modes.iter().find(|m| KcpConfig::preset(m).is_none()) {
+                    return Err(format!("unknown mode {}", m));
+                }
+            }
+            "-c" => options.count = number(&mut args, &arg)?,
+            "-i" => options.interval = number(&mut args, &arg)?,
+            "-s" => options.size = number(&mut args, &arg)?,
+            "-w" => options.wait = number(&mut args, &arg)?,
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if addr.is_none() && !arg.starts_with('-') => {
+                let parsed = arg.parse().map_err(|e| format!("{}: {}", arg, e))?;
+                addr = Some(parsed);
+            }
+            _ => return Err(format!("unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+    options.addr = addr.ok_or(USAGE.to_owned())?;
+    if options.size < PROBE_HEADER {
+        return Err(format!("probes take at least {} bytes", PROBE_HEADER));
+    }
+    Ok(options)
+}
+
+/// the file of `--config` and the environment
+fn load_config(args: &[String]) -> Result<KcpConfig, String> {
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Some(args.get(i + 1).ok_or("--config needs a value")?),
+        None => None,
+    };
+    KcpConfig::load(path.map(Path::new)).map_err(|e| format!("config: {}", e))
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn number<T: FromStr, I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<T, String> {
+    let v = value(args, flag)?;
+    v.parse().map_err(|_| format!("{} takes a number, not {}", flag, v))
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let result = if options.listen {
+        core.run(echo(&options.addr, &options.config, &handle))
+    } else {
+        options.modes.iter().fold(Ok(()), |result, mode| {
+            result.and_then(|_| {
+                let preset = KcpConfig::preset(mode).unwrap();
+                let config = KcpConfig {
+                    nodelay: preset.nodelay,
+                    resend: preset.resend,
+                    nc: preset.nc,
+                    ..options.config.clone()
+                };
+                let (rtts, stats) = core.run(ping(&options, config, &handle))?;
+                report(mode, &options, &rtts, &stats);
+                Ok(())
+            })
+        })
+    };
+    if let Err(e) = result {
+        eprintln!("kcp-ping: {}", e);
+        process::exit(1);
+    }
+}
+
+/// send every message of every session back
+fn echo(
+    addr: &SocketAddr,
+    config: &KcpConfig,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = io::Error>> {
+    let listener = match KcpListener::bind_with_config(addr, config.clone(), handle) {
+        Ok(listener) => listener,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let handle = handle.clone();
+    Box::new(listener.incoming().for_each(move |(stream, peer)| {
+        eprintln!("session from {}", peer);
+        let session = future::loop_fn((stream, vec![0; 64 * 1024]), |(stream, buf)| {
+            read(stream, buf).and_then(|(stream, mut buf, n)| {
+                if n == 0 {
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+                buf.truncate(n);
+                Either::B(write_all(stream, buf).map(|(stream, mut buf)| {
+                    buf.resize(64 * 1024, 0);
+                    Loop::Continue((stream, buf))
+                }))
+            })
+        });
+        handle.spawn(session.map_err(|e| eprintln!("session failed: {}", e)));
+        Ok(())
+    }))
+}
+
+/// probe `options.addr` with a session tuned by `config`, returns the rtt
+/// of each probe in microsec, `None` for the unanswered ones
+fn ping(
+    options: &Options,
+    config: KcpConfig,
+    handle: &Handle,
+) -> Box<Future<Item = (Vec<Option<u64>>, KcpStats), Error = io::Error>> {
+    let count = options.count;
+    let size = options.size;
+    let interval = Duration::from_millis(options.interval);
+    let wait = interval * count + Duration::from_millis(options.wait);
+    let handle = handle.clone();
+    Box::new(KcpStream::connect(&options.addr, &handle).and_then(move |stream| {
+        stream.configure(&config)?;
+        let stream = Rc::new(RefCell::new(stream));
+        let rtts = Rc::new(RefCell::new(vec![None; count as usize]));
+        let start = Instant::now();
+
+        let sender = stream.clone();
+        let mut seq = 0;
+        let send = Interval::new(interval, &handle)?.take(count as u64).for_each(move |_| {
+            let mut probe = vec![0; size];
+            LittleEndian::write_u32(&mut probe[..4], seq);
+            LittleEndian::write_u64(&mut probe[4..12], micros(start.elapsed()));
+            seq += 1;
+            // a probe refused by a full backlog is lost like any other
+            let _ = sender.borrow_mut().write(&probe);
+            Ok(())
+        });
+
+        let receiver = stream.clone();
+        let answers = rtts.clone();
+        let mut answered = 0;
+        let mut buf = vec![0; 64 * 1024];
+        let recv = future::poll_fn(move || loop {
+            if answered == count {
+                return Ok(Async::Ready(()));
+            }
+            let n = match receiver.borrow_mut().read(&mut buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed")),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(e),
+            };
+            if n < PROBE_HEADER {
+                continue;
+            }
+            let seq = LittleEndian::read_u32(&buf[..4]) as usize;
+            let sent = LittleEndian::read_u64(&buf[4..12]);
+            let mut answers = answers.borrow_mut();
+            if seq < answers.len() && answers[seq].is_none() {
+                answers[seq] = Some(micros(start.elapsed()).saturating_sub(sent));
+                answered += 1;
+            }
+        });
+
+        let deadline = Timeout::new(wait, &handle)?;
+        let done = send
+            .join(recv.select(deadline).map(|_| ()).map_err(|(e, _)| e))
+            .map(move |_| {
+                let stats = stream.borrow().stats();
+                let rtts = rtts.borrow().clone();
+                (rtts, stats)
+            });
+        Ok(done)
+    }).flatten())
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1_000
+}
+
+fn report(mode: &str, options: &Options, rtts: &[Option<u64>], stats: &KcpStats) {
+    let mut answered: Vec<u64> = rtts.iter().filter_map(|rtt| *rtt).collect();
+    answered.sort();
+    let lost = rtts.len() - answered.len();
+    println!("--- {} ping statistics, mode {} ---", options.addr, mode);
+    println!(
+        "{} probes sent, {} answered, {:.1}% lost, {} retransmits",
+        rtts.len(),
+        answered.len(),
+        100.0 * lost as f64 / cmp::max(rtts.len(), 1) as f64,
+        stats.retransmits
+    );
+    if answered.is_empty() {
+        return;
+    }
+    let ms = |us: u64| us as f64 / 1000.0;
+    let at = |q: usize| answered[(answered.len() - 1) * q / 100];
+    let avg = answered.iter().sum::<u64>() / answered.len() as u64;
+    println!(
+        "rtt min/avg/p50/p90/p99/max = {:.3}/{:.3}/{:.3}/{:.3}/{:.3}/{:.3} ms",
+        ms(answered[0]),
+        ms(avg),
+        ms(at(50)),
+        ms(at(90)),
+        ms(at(99)),
+        ms(answered[answered.len() - 1])
+    );
+    println!("srtt {} ms, rto {} ms", stats.srtt, stats.rto);
+}
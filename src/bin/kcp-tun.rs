@@ -0,0 +1,324 @@
+//! TCP over KCP: every connection taken on one end of the tunnel is
+//! carried over a session of its own to the other end, and connected on
+//! to a target there.
+//!
+//! The server end listens for sessions and connects each to the target:
+//!
+//!     kcp-tun -l 0.0.0.0:4000 127.0.0.1:22
+//!
+//! the client end takes connections on a local address:
+//!
+//!     kcp-tun 127.0.0.1:2222 203.0.113.7:4000
+//!
+//! `--status 127.0.0.1:9100` serves the sessions of either end on a local
+//! HTTP endpoint as JSON, each with its rtt, retransmits, throughput and
+//! state, along with the config:
+//!
+//!     curl http://127.0.0.1:9100/
+//!
+//! Sessions always run in stream mode. They are tuned with `--mode
+//! default|normal|fast`, `--wnd N`, `--mtu N` and `--interval MS`, on top
+//! of a `--config FILE` in TOML and `KCP_*` environment variables (see
+//! `KcpConfig::load`). Both ends should agree on them.
+
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+
+use futures::future;
+use futures::{Future, Poll, Stream};
+use kcp::{KcpConfig, KcpListener, KcpStream};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{copy, read, shutdown, write_all};
+
+const USAGE: &'static str = "usage: kcp-tun [-l] [--config FILE] [--mode default|normal|fast] \
+                             [--wnd N] [--mtu N] [--interval MS] [--status addr:port] \
+                             <from addr:port> <to addr:port>";
+
+struct Options {
+    // `from` is where sessions come in and `to` the TCP target with `-l`,
+    // otherwise `from` takes TCP connections and `to` is the server end
+    listen: bool,
+    from: SocketAddr,
+    to: SocketAddr,
+    config: KcpConfig,
+    status: Option<SocketAddr>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut listen = false;
+    let mut addrs = Vec::new();
+    let mut status = None;
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let mut config = load_config(&args)?;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-l" | "--listen" => listen = true,
+            "--config" => {
+                value(&mut args, &arg)?;
+            }
+            "--mode" => {
+                let mode = value(&mut args, &arg)?;
+                let preset = KcpConfig::preset(&mode).ok_or(format!("unknown mode {}", mode))?;
+                config.nodelay = preset.nodelay;
+                config.resend = preset.resend;
+                config.nc = preset.nc;
+            }
+            "--wnd" => {
+                let wnd = number(&mut args, &arg)?;
+                config.snd_wnd = wnd;
+                config.rcv_wnd = wnd;
+            }
+            "--mtu" => config.mtu = number(&mut args, &arg)?,
+            "--interval" => config.interval = number(&mut args, &arg)?,
+            "--status" => {
+                let v = value(&mut args, &arg)?;
+                status = Some(v.parse().map_err(|e| format!("{}: {}", v, e))?);
+            }
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if addrs.len() < 2 && !arg.starts_with('-') => {
+                let parsed = arg.parse().map_err(|e| format!("{}: {}", arg, e))?;
+                addrs.push(parsed);
+            }
+            _ => return Err(format!("unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+    // a byte stream either way, whatever sizes the reads and writes have
+    config.stream = true;
+    if addrs.len() != 2 {
+        return Err(USAGE.to_owned());
+    }
+    Ok(Options {
+        listen: listen,
+        from: addrs[0],
+        to: addrs[1],
+        config: config,
+        status: status,
+    })
+}
+
+/// the file of `--config` and the environment, the other flags override them
+fn load_config(args: &[String]) -> Result<KcpConfig, String> {
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => Some(args.get(i + 1).ok_or("--config needs a value")?),
+        None => None,
+    };
+    KcpConfig::load(path.map(Path::new)).map_err(|e| format!("config: {}", e))
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn number<T: FromStr, I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<T, String> {
+    let v = value(args, flag)?;
+    v.parse().map_err(|_| format!("{} takes a number, not {}", flag, v))
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let sessions = Sessions::default();
+    if let Some(addr) = options.status {
+        if let Err(e) = serve_status(&addr, sessions.clone(), options.config.clone(), &handle) {
+            eprintln!("kcp-tun: status: {}", e);
+            process::exit(1);
+        }
+    }
+    let tunnel = if options.listen {
+        server_end(&options, sessions, &handle)
+    } else {
+        client_end(&options, sessions, &handle)
+    };
+    if let Err(e) = core.run(tunnel) {
+        eprintln!("kcp-tun: {}", e);
+        process::exit(1);
+    }
+}
+
+/// take sessions on `from` and connect each to the TCP target `to`
+fn server_end(
+    options: &Options,
+    sessions: Sessions,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = io::Error>> {
+    let listener = match KcpListener::bind_with_config(&options.from, options.config.clone(), handle) {
+        Ok(listener) => listener,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let target = options.to;
+    let handle = handle.clone();
+    Box::new(listener.incoming().for_each(move |(stream, peer)| {
+        let stream = Rc::new(RefCell::new(stream));
+        sessions.add(peer, &stream);
+        let tunnel = TcpStream::connect(&target, &handle).and_then(|tcp| pipe(stream, tcp));
+        handle.spawn(tunnel.map_err(move |e| eprintln!("kcp-tun: {}: {}", peer, e)));
+        Ok(())
+    }))
+}
+
+/// take TCP connections on `from` and carry each to the server end `to`
+fn client_end(
+    options: &Options,
+    sessions: Sessions,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = io::Error>> {
+    let listener = match TcpListener::bind(&options.from, handle) {
+        Ok(listener) => listener,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let server = options.to;
+    let config = options.config.clone();
+    let handle = handle.clone();
+    Box::new(listener.incoming().for_each(move |(tcp, addr)| {
+        let sessions = sessions.clone();
+        let config = config.clone();
+        let tunnel = KcpStream::connect(&server, &handle)
+            .and_then(move |stream| stream.configure(&config).map(|()| stream))
+            .and_then(move |stream| {
+                let stream = Rc::new(RefCell::new(stream));
+                sessions.add(server, &stream);
+                pipe(stream, tcp)
+            });
+        handle.spawn(tunnel.map_err(move |e| eprintln!("kcp-tun: {}: {}", addr, e)));
+        Ok(())
+    }))
+}
+
+/// copy both ways between a session and a TCP connection, each direction
+/// closing the other end for writing once it is done
+fn pipe(stream: Rc<RefCell<KcpStream>>, tcp: TcpStream) -> Box<Future<Item = (), Error = io::Error>> {
+    let (tcp_reader, tcp_writer) = tcp.split();
+    let up = copy(tcp_reader, Shared(stream.clone())).and_then(|(_, _, writer)| shutdown(writer));
+    let down = copy(Shared(stream), tcp_writer).and_then(|(_, _, writer)| shutdown(writer));
+    Box::new(up.join(down).map(|_| ()))
+}
+
+/// the sessions of the tunnel, each listed for as long as it is piped
+#[derive(Clone, Default)]
+struct Sessions(Rc<RefCell<Vec<(SocketAddr, Weak<RefCell<KcpStream>>)>>>);
+
+impl Sessions {
+    fn add(&self, peer: SocketAddr, stream: &Rc<RefCell<KcpStream>>) {
+        let mut sessions = self.0.borrow_mut();
+        sessions.retain(|&(_, ref stream)| stream.upgrade().is_some());
+        sessions.push((peer, Rc::downgrade(stream)));
+    }
+}
+
+/// a session, read and written by both directions of its pipe and looked
+/// at by the status endpoint
+struct Shared(Rc<RefCell<KcpStream>>);
+
+impl Read for Shared {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl AsyncRead for Shared {}
+
+impl AsyncWrite for Shared {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.0.borrow_mut().shutdown()
+    }
+}
+
+/// answer every HTTP request on `addr` with the state of the sessions
+fn serve_status(addr: &SocketAddr, sessions: Sessions, config: KcpConfig, handle: &Handle) -> io::Result<()> {
+    let listener = TcpListener::bind(addr, handle)?;
+    eprintln!("status on http://{}/", listener.local_addr()?);
+    let handle2 = handle.clone();
+    let server = listener.incoming().for_each(move |(socket, _)| {
+        let body = status_json(&sessions, &config);
+        let response = format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        // whatever was asked for, the request is not looked at
+        let reply = read(socket, vec![0; 4096])
+            .and_then(move |(socket, _, _)| write_all(socket, response.into_bytes()))
+            .then(|_| Ok(()));
+        handle2.spawn(reply);
+        Ok(())
+    });
+    handle.spawn(server.map_err(|e| eprintln!("kcp-tun: status: {}", e)));
+    Ok(())
+}
+
+fn status_json(sessions: &Sessions, config: &KcpConfig) -> String {
+    let sessions = sessions.0.borrow();
+    let sessions = sessions
+        .iter()
+        .filter_map(|&(peer, ref stream)| {
+            stream.upgrade().map(|stream| session_json(&stream.borrow(), &peer))
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"sessions\":[{}],\
+         \"config\":{{\"mtu\":{},\"snd_wnd\":{},\"rcv_wnd\":{},\"interval\":{},\
+         \"nodelay\":{},\"resend\":{},\"nc\":{},\"stream\":{}}}}}\n",
+        sessions.join(","),
+        config.mtu,
+        config.snd_wnd,
+        config.rcv_wnd,
+        config.interval,
+        config.nodelay,
+        config.resend,
+        config.nc,
+        config.stream,
+    )
+}
+
+fn session_json(stream: &KcpStream, peer: &SocketAddr) -> String {
+    let stats = stream.stats();
+    format!(
+        "{{\"peer\":\"{}\",\"conv\":{},\"state\":\"{:?}\",\
+         \"srtt_ms\":{},\"rto_ms\":{},\"cwnd\":{},\"retransmits\":{},\
+         \"duplicates\":{},\"delivered_bytes\":{},\"delivery_rate\":{},\
+         \"bandwidth\":{},\"waitsnd\":{}}}",
+        peer,
+        stream.conv(),
+        stream.state(),
+        stats.srtt,
+        stats.rto,
+        stats.cwnd,
+        stats.retransmits,
+        stats.duplicates,
+        stats.delivered,
+        stats.delivery_rate,
+        stats.bandwidth,
+        stream.waitsnd(),
+    )
+}
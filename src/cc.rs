@@ -0,0 +1,393 @@
+//! Congestion controllers, see `Kcb::set_congestion`. The block runs slow
+//! start itself, caps the window at what the peer advertises and leaves
+//! the rest to its controller: growth once past ssthresh and how far the
+//! window backs off when segments go missing. Controllers steering by
+//! delay or bandwidth also get every rtt and delivery rate sample, and
+//! may end slow start early by lowering ssthresh to cwnd.
+
+use std::cmp;
+use std::collections::VecDeque;
+
+const THRESH_MIN: u32 = 2;
+const CUBIC_C: f64 = 0.4; // scaling constant of the cubic function
+const CUBIC_BETA: f64 = 0.7; // window kept on a loss
+const LEDBAT_TARGET: u32 = 100; // queueing delay aimed for in millisec
+const LEDBAT_BASE_MINUTES: usize = 10; // minutes the base delay is the least rtt of
+const BBR_BW_SAMPLES: usize = 10; // delivery rate samples in the max filter
+const BBR_MIN_RTT_WINDOW: u32 = 10_000; // millisec a min rtt sample is trusted
+const BBR_FULL_BW_ROUNDS: u32 = 3; // samples without 25% growth ending startup
+const BBR_CWND_GAIN: u32 = 2; // bdps in flight
+const BBR_CWND_MIN: u32 = 4;
+// pacing gains cycled through once per min rtt, probing for more
+// bandwidth, then draining the queue the probe built, in percent
+const BBR_PACING_GAIN: [u64; 8] = [125, 75, 100, 100, 100, 100, 100, 100];
+
+/// The window a controller steers, in segments of `mss` bytes. `incr` is
+/// the same window in bytes, for controllers growing it by fractions of a
+/// segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub incr: u32,
+    pub mss: u32,
+}
+
+impl Window {
+    /// set `cwnd` and `incr` to match
+    pub fn set_cwnd(&mut self, cwnd: u32) {
+        self.cwnd = cwnd;
+        self.incr = cwnd * self.mss;
+    }
+}
+
+/// Congestion avoidance and loss response of a `Kcb`.
+pub trait CongestionControl {
+    /// `acked` more segments acknowledged at `now` millisec while past
+    /// slow start, with `srtt` the smoothed rtt in millisec
+    fn on_ack(&mut self, window: &mut Window, acked: u32, now: u32, srtt: u32);
+
+    /// a segment was resent on duplicate acks, `inflight` segments are
+    /// unacknowledged and `resent` acks triggered it
+    fn on_fast_resend(&mut self, window: &mut Window, inflight: u32, resent: u32);
+
+    /// a segment timed out, `cwnd` being the window the flush used
+    fn on_timeout(&mut self, window: &mut Window, cwnd: u32);
+
+    /// an rtt of `rtt` millisec was measured at `now`, in slow start too
+    fn on_rtt(&mut self, _window: &mut Window, _rtt: u32, _now: u32) {}
+
+    /// the peer took `rate` bytes per second over the last smoothed rtt,
+    /// sampled at `now`
+    fn on_delivery(&mut self, _window: &mut Window, _rate: u64, _now: u32) {}
+
+    /// bytes per second to spread the segments of a flush over, `None`
+    /// sends everything the window allows at once
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The scheme of the original KCP: about one segment more per rtt past
+/// slow start, half the flight on a fast resend and a single segment
+/// after a timeout. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Classic;
+
+impl CongestionControl for Classic {
+    fn on_ack(&mut self, window: &mut Window, _acked: u32, _now: u32, _srtt: u32) {
+        let mss = window.mss;
+        if window.incr < mss {
+            window.incr = mss;
+        }
+        window.incr += (mss * mss) / window.incr + (mss / 16);
+        if (window.cwnd + 1) * mss <= window.incr {
+            window.cwnd += 1;
+        }
+    }
+
+    fn on_fast_resend(&mut self, window: &mut Window, inflight: u32, resent: u32) {
+        window.ssthresh = cmp::max(inflight / 2, THRESH_MIN);
+        let cwnd = window.ssthresh + resent;
+        window.set_cwnd(cwnd);
+    }
+
+    fn on_timeout(&mut self, window: &mut Window, cwnd: u32) {
+        window.ssthresh = cmp::max(cwnd / 2, THRESH_MIN);
+        window.set_cwnd(1);
+    }
+}
+
+/// CUBIC (RFC 8312): past a loss the window grows along a cubic function
+/// of the time since, flat around the window the loss happened at and
+/// fast away from it, and never slower than TCP Reno would. Shares a
+/// bottleneck with TCP flows about fairly.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cubic {
+    // window before the last reduction
+    w_max: f64,
+    // start of the current growth epoch in millisec, `None` before the
+    // first ack after a reduction
+    epoch: Option<u32>,
+    // time to grow back to `origin` in seconds
+    k: f64,
+    origin: f64,
+    // window Reno would have reached in the epoch
+    w_est: f64,
+    // growth owed in fractions of a segment
+    pending: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Cubic {
+        Cubic::default()
+    }
+
+    fn reduce(&mut self, cwnd: u32) -> u32 {
+        let cwnd = cwnd as f64;
+        // fast convergence, release bandwidth to newer flows
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.epoch = None;
+        cmp::max((cwnd * CUBIC_BETA) as u32, THRESH_MIN)
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(&mut self, window: &mut Window, acked: u32, now: u32, srtt: u32) {
+        let cwnd = window.cwnd as f64;
+        let start = match self.epoch {
+            Some(start) => start,
+            None => {
+                self.epoch = Some(now);
+                self.origin = self.w_max.max(cwnd);
+                self.k = ((self.origin - cwnd) / CUBIC_C).cbrt();
+                self.w_est = cwnd;
+                now
+            }
+        };
+        let t = (now.wrapping_sub(start) + srtt) as f64 / 1000.0;
+        let mut target = self.origin + CUBIC_C * (t - self.k).powi(3);
+
+        // never below the window of an equally aggressive Reno flow
+        let alpha = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+        self.w_est += alpha * acked as f64 / cwnd;
+        if self.w_est > target {
+            target = self.w_est;
+        }
+
+        if target > cwnd {
+            // at most half a segment more per segment acknowledged
+            self.pending += ((target - cwnd) / cwnd).min(0.5) * acked as f64;
+        }
+        if self.pending >= 1.0 {
+            let grow = self.pending as u32;
+            self.pending -= grow as f64;
+            let cwnd = window.cwnd + grow;
+            window.set_cwnd(cwnd);
+        }
+    }
+
+    fn on_fast_resend(&mut self, window: &mut Window, _inflight: u32, _resent: u32) {
+        window.ssthresh = self.reduce(window.cwnd);
+        let cwnd = window.ssthresh;
+        window.set_cwnd(cwnd);
+    }
+
+    fn on_timeout(&mut self, window: &mut Window, _cwnd: u32) {
+        window.ssthresh = self.reduce(window.cwnd);
+        self.w_est = 0.0;
+        window.set_cwnd(1);
+    }
+}
+
+/// LEDBAT (RFC 6817) on rtts instead of one-way delays: the window grows
+/// while the queueing delay, the rtt over the least one of the last ten
+/// minutes, stays below the target and shrinks as it rises above, before
+/// any segment is lost. Yields to TCP and to latency-sensitive traffic
+/// on a shared bottleneck, for bulk transfers in the background.
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    target: u32,
+    // least rtt of each of the last minutes, the oldest first, with the
+    // start of the minute
+    base: VecDeque<(u32, u32)>,
+    rtt: u32,
+    // growth owed in fractions of a segment, negative to shrink
+    pending: f64,
+}
+
+impl Ledbat {
+    pub fn new() -> Ledbat {
+        Ledbat::with_target(LEDBAT_TARGET)
+    }
+
+    /// aim for `target` millisec of queueing delay instead of 100
+    pub fn with_target(target: u32) -> Ledbat {
+        Ledbat {
+            target: cmp::max(target, 1),
+            base: VecDeque::with_capacity(LEDBAT_BASE_MINUTES),
+            rtt: 0,
+            pending: 0.0,
+        }
+    }
+
+    /// the rtt over the base delay in millisec
+    pub fn queueing_delay(&self) -> u32 {
+        let base = self.base.iter().map(|&(_, rtt)| rtt).min().unwrap_or(self.rtt);
+        self.rtt.saturating_sub(base)
+    }
+}
+
+impl Default for Ledbat {
+    fn default() -> Ledbat {
+        Ledbat::new()
+    }
+}
+
+impl CongestionControl for Ledbat {
+    fn on_ack(&mut self, window: &mut Window, acked: u32, _now: u32, _srtt: u32) {
+        let queueing = self.queueing_delay() as f64;
+        let target = self.target as f64;
+        let off_target = ((target - queueing) / target).max(-1.0);
+        self.pending += off_target * acked as f64 / window.cwnd as f64;
+        if self.pending >= 1.0 {
+            let grow = self.pending as u32;
+            self.pending -= grow as f64;
+            let cwnd = window.cwnd + grow;
+            window.set_cwnd(cwnd);
+        } else if self.pending <= -1.0 {
+            let shrink = -self.pending as u32;
+            self.pending += shrink as f64;
+            let cwnd = cmp::max(window.cwnd.saturating_sub(shrink), THRESH_MIN);
+            window.ssthresh = cwnd;
+            window.set_cwnd(cwnd);
+        }
+    }
+
+    fn on_fast_resend(&mut self, window: &mut Window, _inflight: u32, _resent: u32) {
+        let cwnd = cmp::max(window.cwnd / 2, THRESH_MIN);
+        window.ssthresh = cwnd;
+        window.set_cwnd(cwnd);
+    }
+
+    fn on_timeout(&mut self, window: &mut Window, cwnd: u32) {
+        window.ssthresh = cmp::max(cwnd / 2, THRESH_MIN);
+        window.set_cwnd(1);
+    }
+
+    fn on_rtt(&mut self, window: &mut Window, rtt: u32, now: u32) {
+        self.rtt = rtt;
+        match self.base.back_mut() {
+            Some(&mut (start, ref mut least)) if now.wrapping_sub(start) < 60_000 => {
+                *least = cmp::min(*least, rtt);
+            }
+            _ => {
+                if self.base.len() == LEDBAT_BASE_MINUTES {
+                    self.base.pop_front();
+                }
+                self.base.push_back((now, rtt));
+            }
+        }
+        // the queue is building, no need to wait for a loss
+        if window.cwnd < window.ssthresh && self.queueing_delay() > self.target * 3 / 4 {
+            window.ssthresh = cmp::max(window.cwnd, THRESH_MIN);
+        }
+    }
+}
+
+/// Modeled on BBR: estimates the bottleneck bandwidth as the max recent
+/// delivery rate and the propagation delay as the least rtt of the last
+/// ten seconds, keeps two of their product in flight and paces segments
+/// out at about the bandwidth. Slow start ends once the bandwidth stops
+/// growing rather than on a loss, and neither losses nor timeouts
+/// collapse the window, for links with random loss.
+#[derive(Debug, Clone, Default)]
+pub struct Bbr {
+    samples: VecDeque<u64>,
+    // bytes per second
+    bw: u64,
+    min_rtt: u32,
+    min_rtt_at: u32,
+    // startup: the bandwidth startup last grew to and the samples since
+    full_bw: u64,
+    full_bw_count: u32,
+    filled: bool,
+    cycle: usize,
+    cycle_at: u32,
+}
+
+impl Bbr {
+    pub fn new() -> Bbr {
+        Bbr::default()
+    }
+
+    /// estimated bottleneck bandwidth in bytes per second
+    pub fn bandwidth(&self) -> u64 {
+        self.bw
+    }
+
+    /// the least rtt of the last ten seconds in millisec, 0 before any
+    pub fn min_rtt(&self) -> u32 {
+        self.min_rtt
+    }
+
+    /// whether startup is over
+    pub fn filled(&self) -> bool {
+        self.filled
+    }
+
+    /// two bandwidth-delay products in segments of `mss`
+    fn target(&self, mss: u32) -> u32 {
+        let bdp = self.bw * self.min_rtt as u64 / 1000 / cmp::max(mss, 1) as u64;
+        cmp::max(BBR_CWND_GAIN * bdp as u32, BBR_CWND_MIN)
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn on_ack(&mut self, window: &mut Window, acked: u32, now: u32, srtt: u32) {
+        // nothing measured yet to size the window by
+        if self.bw == 0 || self.min_rtt == 0 {
+            return Classic.on_ack(window, acked, now, srtt);
+        }
+        let cwnd = cmp::min(window.cwnd + acked, self.target(window.mss));
+        window.set_cwnd(cwnd);
+    }
+
+    fn on_fast_resend(&mut self, window: &mut Window, _inflight: u32, _resent: u32) {
+        // loss is not taken for congestion, only startup ends
+        window.ssthresh = cmp::max(window.cwnd, THRESH_MIN);
+    }
+
+    fn on_timeout(&mut self, window: &mut Window, _cwnd: u32) {
+        // keep a single bdp in flight until acks come back
+        let cwnd = cmp::min(window.cwnd, cmp::max(self.target(window.mss) / BBR_CWND_GAIN, BBR_CWND_MIN));
+        window.ssthresh = cwnd;
+        window.set_cwnd(cwnd);
+    }
+
+    fn on_rtt(&mut self, _window: &mut Window, rtt: u32, now: u32) {
+        if self.min_rtt == 0 || rtt <= self.min_rtt || now.wrapping_sub(self.min_rtt_at) > BBR_MIN_RTT_WINDOW {
+            self.min_rtt = cmp::max(rtt, 1);
+            self.min_rtt_at = now;
+        }
+    }
+
+    fn on_delivery(&mut self, window: &mut Window, rate: u64, now: u32) {
+        if self.samples.len() == BBR_BW_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rate);
+        self.bw = self.samples.iter().cloned().max().unwrap_or(0);
+
+        if !self.filled {
+            if self.bw >= self.full_bw * 5 / 4 {
+                self.full_bw = self.bw;
+                self.full_bw_count = 0;
+            } else {
+                self.full_bw_count += 1;
+            }
+            if self.full_bw_count >= BBR_FULL_BW_ROUNDS {
+                self.filled = true;
+                self.cycle_at = now;
+                let cwnd = cmp::min(window.cwnd, self.target(window.mss));
+                window.ssthresh = cmp::max(cwnd, THRESH_MIN);
+                window.set_cwnd(cwnd);
+            }
+        } else if now.wrapping_sub(self.cycle_at) >= self.min_rtt {
+            self.cycle = (self.cycle + 1) % BBR_PACING_GAIN.len();
+            self.cycle_at = now;
+        }
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        if self.filled && self.bw > 0 {
+            Some(self.bw * BBR_PACING_GAIN[self.cycle] / 100)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,500 @@
+//! Session parameters applied to every `Kcb` an endpoint creates, and the
+//! offer exchanged to agree on them, see `KcpStream::connect_negotiated`.
+
+use std::cmp;
+#[cfg(feature = "config-file")]
+use std::env;
+#[cfg(feature = "config-file")]
+use std::fmt;
+#[cfg(feature = "config-file")]
+use std::fs::File;
+#[cfg(feature = "config-file")]
+use std::io::{self, Read};
+use std::io::Write;
+#[cfg(feature = "config-file")]
+use std::path::Path;
+
+use bytes::{ByteOrder, LittleEndian};
+use rand;
+#[cfg(feature = "config-file")]
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+#[cfg(feature = "config-file")]
+use serde::ser::{Serialize, SerializeMap, Serializer};
+#[cfg(feature = "config-file")]
+use toml;
+
+use cc::{Bbr, Classic, Cubic, Ledbat};
+use rng::XorShift;
+use {Kcb, OutputErrorPolicy, ParseMode, SlowStart};
+
+/// The congestion controllers of `cc` by name, see `Kcb::set_congestion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "config-file", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "config-file", serde(rename_all = "kebab-case"))]
+pub enum Congestion {
+    /// `cc::Classic`, the scheme of the original KCP
+    Classic,
+    /// `cc::Cubic`, about fair to TCP flows on a shared bottleneck
+    Cubic,
+    /// `cc::Ledbat`, backs off as the queueing delay rises, for bulk
+    /// transfers that should not get in the way of anything else
+    Ledbat,
+    /// `cc::Bbr`, sized and paced by the measured bandwidth and rtt,
+    /// for links with random loss
+    Bbr,
+}
+
+/// How often an idle session sends something to keep the mappings of NATs
+/// and stateful firewalls on its path alive. Pick the preset for the worst
+/// box expected between the two ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keepalive {
+    /// every 15 secs, carrier-grade NATs of mobile networks drop idle UDP
+    /// mappings after as little as 30 secs
+    Mobile,
+    /// every 25 secs, the common 30 secs UDP timeout of home routers
+    HomeRouter,
+    /// every 60 secs, cloud NAT gateways and firewalls with timeouts of
+    /// minutes
+    Datacenter,
+    /// every given number of millisec
+    Every(u32),
+}
+
+impl Keepalive {
+    /// the interval in millisec
+    pub fn interval(&self) -> u32 {
+        match *self {
+            Keepalive::Mobile => 15_000,
+            Keepalive::HomeRouter => 25_000,
+            Keepalive::Datacenter => 60_000,
+            Keepalive::Every(ms) => ms,
+        }
+    }
+}
+
+/// The presets by name and `Every` as `{ every = ms }`. By hand, the TOML
+/// serializer takes no newtype variants and writes `Every` as a table of
+/// its own, which the derived impl would refuse to read back.
+#[cfg(feature = "config-file")]
+impl Serialize for Keepalive {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Keepalive::Mobile => serializer.serialize_str("mobile"),
+            Keepalive::HomeRouter => serializer.serialize_str("home-router"),
+            Keepalive::Datacenter => serializer.serialize_str("datacenter"),
+            Keepalive::Every(ms) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("every", &ms)?;
+                map.end()
+            }
+        }
+    }
+}
+
+#[cfg(feature = "config-file")]
+impl<'de> Deserialize<'de> for Keepalive {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Keepalive, D::Error> {
+        deserializer.deserialize_any(KeepaliveVisitor)
+    }
+}
+
+#[cfg(feature = "config-file")]
+struct KeepaliveVisitor;
+
+#[cfg(feature = "config-file")]
+impl<'de> Visitor<'de> for KeepaliveVisitor {
+    type Value = Keepalive;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"mobile\", \"home-router\", \"datacenter\" or { every = millisec }")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Keepalive, E> {
+        match name {
+            "mobile" => Ok(Keepalive::Mobile),
+            "home-router" => Ok(Keepalive::HomeRouter),
+            "datacenter" => Ok(Keepalive::Datacenter),
+            _ => Err(E::unknown_variant(name, &["mobile", "home-router", "datacenter", "every"])),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keepalive, A::Error> {
+        match map.next_entry::<String, u32>()? {
+            Some((ref key, ms)) if key == "every" => {
+                if map.next_key::<String>()?.is_some() {
+                    return Err(de::Error::custom("more than `every` in keepalive"));
+                }
+                Ok(Keepalive::Every(ms))
+            }
+            Some((key, _)) => Err(de::Error::unknown_field(&key, &["every"])),
+            None => Err(de::Error::missing_field("every")),
+        }
+    }
+}
+
+/// Tuning of new sessions, see `KcpListener::bind_with_config`. The
+/// defaults are what every session used before it could be configured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "config-file", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "config-file", serde(default))]
+pub struct KcpConfig {
+    /// send window in segments
+    pub snd_wnd: i32,
+    /// receive window in segments
+    pub rcv_wnd: i32,
+    /// see `Kcb::nodelay`
+    pub nodelay: i32,
+    /// update interval in millisec
+    pub interval: i32,
+    /// duplicate acks triggering a fast resend, 0 disables it
+    pub resend: i32,
+    /// disable congestion control
+    pub nc: bool,
+    /// see `Kcb::set_cwnd_validation`
+    pub cwnd_validation: bool,
+    /// see `Kcb::set_slow_start`
+    pub slow_start: SlowStart,
+    /// controller past slow start, see `Kcb::set_congestion`
+    pub congestion: Congestion,
+    /// see `Kcb::set_stream`
+    pub stream: bool,
+    pub mtu: usize,
+    /// see `Kcb::set_ack_ranges`
+    pub ack_ranges: bool,
+    /// see `Kcb::set_mtu_downshift`
+    pub mtu_downshift: bool,
+    /// see `Kcb::set_compact_headers`
+    pub compact_headers: bool,
+    /// millisec acks wait for data to ride along, see `Kcb::set_ack_delay`
+    pub ack_delay: u32,
+    /// least millisec between flushes triggered by acks, none by default,
+    /// see `Kcb::set_ack_flush`
+    pub ack_flush: Option<u32>,
+    /// most data segments a flush emits, see `Kcb::set_burst_limit`
+    pub burst_limit: u32,
+    /// jitter buffer depth in millisec, see `Kcb::set_playout_delay`
+    pub playout_delay: u32,
+    /// lower bound of the retransmission timeout in millisec, `None` for
+    /// the one picked by `nodelay`
+    pub min_rto: Option<u32>,
+    /// first wait before probing a zero window in millisec, see
+    /// `Kcb::set_probe_timers`
+    pub probe_init: u32,
+    /// upper bound of the window probe backoff in millisec
+    pub probe_limit: u32,
+    /// see `Kcb::set_dead_link`
+    pub dead_link: u32,
+    /// see `Kcb::set_parse_mode`
+    pub parse_mode: ParseMode,
+    /// largest message accepted from the peer in bytes, see
+    /// `Kcb::set_max_message`
+    #[cfg_attr(feature = "config-file", serde(skip_serializing_if = "unlimited"))]
+    pub max_message: usize,
+    /// most fragments of a message accepted from the peer
+    pub max_fragments: u8,
+    /// most messages held ahead of a gap, see `Kcb::set_reassembly_limits`
+    #[cfg_attr(feature = "config-file", serde(skip_serializing_if = "unlimited"))]
+    pub reassembly_chains: usize,
+    /// most bytes held ahead of a gap
+    #[cfg_attr(feature = "config-file", serde(skip_serializing_if = "unlimited"))]
+    pub reassembly_bytes: usize,
+    /// payload bytes the queues of a session may hold, see
+    /// `Kcb::set_memory_budget`
+    #[cfg_attr(feature = "config-file", serde(skip_serializing_if = "unlimited"))]
+    pub memory_budget: usize,
+    /// number segments from a random sn, both ends must agree, see
+    /// `Kcb::set_initial_sn`
+    pub random_isn: bool,
+    /// Derive the random initial sn from this seed and the conv instead
+    /// of the thread rng, for runs reproducible bit for bit, see
+    /// `sim::Simulation::seeded_pair`. For simulations and tests only:
+    /// anyone who knows the seed can predict every initial sn, which is
+    /// all `random_isn` guards against. Never read from or written to a
+    /// config file for that reason.
+    #[cfg_attr(feature = "config-file", serde(skip))]
+    pub seed: Option<u64>,
+    /// segments waiting to be sent before writes to a `KcpStream` block,
+    /// see `Kcb::set_send_backlog`
+    pub send_backlog: usize,
+    /// window updates sent while idle, see `Keepalive`
+    pub keepalive: Option<Keepalive>,
+    /// see `Kcb::set_output_error_policy`
+    pub output_error: OutputErrorPolicy,
+    /// data and parity shards of forward error correction, none by
+    /// default, both ends must agree, see `fec`. Only sessions of the
+    /// `tokio` module support it, the others refuse such a config
+    #[cfg(feature = "fec")]
+    pub fec: Option<(usize, usize)>,
+}
+
+impl Default for KcpConfig {
+    fn default() -> KcpConfig {
+        KcpConfig {
+            snd_wnd: 128,
+            rcv_wnd: 128,
+            nodelay: 0,
+            interval: 10,
+            resend: 0,
+            nc: true,
+            cwnd_validation: false,
+            slow_start: SlowStart::Standard,
+            congestion: Congestion::Classic,
+            stream: false,
+            mtu: 1400,
+            ack_ranges: false,
+            mtu_downshift: false,
+            compact_headers: false,
+            ack_delay: 0,
+            ack_flush: None,
+            burst_limit: u32::MAX,
+            playout_delay: 0,
+            min_rto: None,
+            probe_init: 7_000,
+            probe_limit: 120_000,
+            dead_link: 20,
+            parse_mode: ParseMode::Strict,
+            max_message: usize::MAX,
+            max_fragments: 255,
+            reassembly_chains: usize::MAX,
+            reassembly_bytes: usize::MAX,
+            memory_budget: usize::MAX,
+            random_isn: false,
+            seed: None,
+            send_backlog: 1024,
+            keepalive: None,
+            output_error: OutputErrorPolicy::Drop,
+            #[cfg(feature = "fec")]
+            fec: None,
+        }
+    }
+}
+
+impl KcpConfig {
+    /// The tuning profiles of the `make test` runs by name: "default" with
+    /// congestion control and no fast resend, "normal" without congestion
+    /// control, "fast" also without the rto backoff and resending after
+    /// two duplicate acks. `None` for any other name.
+    pub fn preset(name: &str) -> Option<KcpConfig> {
+        let (nodelay, resend, nc) = match name {
+            "default" => (0, 0, false),
+            "normal" => (0, 0, true),
+            "fast" => (1, 2, true),
+            _ => return None,
+        };
+        Some(KcpConfig {
+            nodelay: nodelay,
+            resend: resend,
+            nc: nc,
+            ..KcpConfig::default()
+        })
+    }
+
+    /// configure `kcb` before any data flows
+    pub fn apply<W: Write>(&self, kcb: &mut Kcb<W>) {
+        kcb.wndsize(self.snd_wnd, self.rcv_wnd);
+        kcb.nodelay(self.nodelay, self.interval, self.resend, self.nc);
+        kcb.set_cwnd_validation(self.cwnd_validation);
+        kcb.set_slow_start(self.slow_start);
+        match self.congestion {
+            Congestion::Classic => kcb.set_congestion(Classic),
+            Congestion::Cubic => kcb.set_congestion(Cubic::new()),
+            Congestion::Ledbat => kcb.set_congestion(Ledbat::new()),
+            Congestion::Bbr => kcb.set_congestion(Bbr::new()),
+        }
+        kcb.set_stream(self.stream);
+        kcb.setmtu(self.mtu);
+        kcb.set_ack_ranges(self.ack_ranges);
+        kcb.set_mtu_downshift(self.mtu_downshift);
+        kcb.set_compact_headers(self.compact_headers);
+        kcb.set_ack_delay(self.ack_delay);
+        kcb.set_ack_flush(self.ack_flush);
+        kcb.set_burst_limit(self.burst_limit);
+        kcb.set_playout_delay(self.playout_delay);
+        if let Some(rto) = self.min_rto {
+            kcb.set_min_rto(rto);
+        }
+        kcb.set_probe_timers(self.probe_init, self.probe_limit);
+        kcb.set_dead_link(self.dead_link);
+        kcb.set_parse_mode(self.parse_mode);
+        kcb.set_max_message(self.max_message, self.max_fragments);
+        kcb.set_reassembly_limits(self.reassembly_chains, self.reassembly_bytes);
+        kcb.set_memory_budget(self.memory_budget);
+        if self.random_isn {
+            let isn = match self.seed {
+                Some(seed) => XorShift::new(seed ^ kcb.conv() as u64).next_u32(),
+                None => rand::random(),
+            };
+            kcb.set_initial_sn(isn);
+        }
+        kcb.set_send_backlog(self.send_backlog);
+        kcb.set_keepalive(self.keepalive.map_or(0, |k| k.interval()));
+        kcb.set_output_error_policy(self.output_error);
+    }
+}
+
+/// the limits left at their default, TOML has no integers that large
+#[cfg(feature = "config-file")]
+fn unlimited(n: &usize) -> bool {
+    *n == usize::MAX
+}
+
+#[cfg(feature = "config-file")]
+impl KcpConfig {
+    /// A config from a TOML document with the fields of `KcpConfig` as
+    /// keys, those left out keep their defaults. Enums are written in
+    /// kebab case, e.g. `congestion = "cubic"` or
+    /// `keepalive = { every = 20000 }`.
+    pub fn from_toml(s: &str) -> io::Result<KcpConfig> {
+        let table = s.parse::<toml::Value>().map_err(invalid)?;
+        table.try_into().map_err(invalid)
+    }
+
+    /// The config as a TOML document `from_toml` reads back, to share it
+    /// between client and server.
+    pub fn to_toml(&self) -> io::Result<String> {
+        // through a `toml::Value`, which puts the tables such as
+        // `keepalive = { every = .. }` after the plain values
+        let value = toml::Value::try_from(self).map_err(invalid)?;
+        toml::to_string(&value).map_err(invalid)
+    }
+
+    /// The config of the binaries: the TOML file at `path`, if any, with
+    /// every field overridden by an environment variable of its name in
+    /// upper case behind `KCP_`, e.g. `KCP_SND_WND=256` or
+    /// `KCP_CONGESTION=cubic`. Variables are read as TOML values, ones that
+    /// are not are taken as strings.
+    pub fn load(path: Option<&Path>) -> io::Result<KcpConfig> {
+        let mut table = match path {
+            Some(path) => {
+                let mut s = String::new();
+                File::open(path)?.read_to_string(&mut s)?;
+                s.parse::<toml::Value>().map_err(invalid)?
+            }
+            None => toml::Value::Table(toml::value::Table::new()),
+        };
+        if let toml::Value::Table(ref mut fields) = table {
+            for (name, value) in env::vars() {
+                if !name.starts_with(ENV_PREFIX) || name.len() == ENV_PREFIX.len() {
+                    continue;
+                }
+                let key = name[ENV_PREFIX.len()..].to_lowercase();
+                let value = match format!("v = {}", value).parse::<toml::Value>() {
+                    Ok(toml::Value::Table(mut parsed)) => parsed.remove("v").unwrap(),
+                    _ => toml::Value::String(value),
+                };
+                fields.insert(key, value);
+            }
+        }
+        table.try_into().map_err(invalid)
+    }
+}
+
+#[cfg(feature = "config-file")]
+const ENV_PREFIX: &str = "KCP_";
+
+#[cfg(feature = "config-file")]
+fn invalid<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+const OFFER_MAGIC: &[u8] = b"KCFG";
+const OFFER_VERSION: u8 = 1;
+const OFFER_LEN: usize = 16;
+const OFFER_STREAM: u8 = 0x01;
+const OFFER_ACK_RANGES: u8 = 0x02;
+
+/// The parameters both ends of a session have to agree on, as seen from
+/// the client. Sent by the client as its first message and answered by the
+/// server with the values it agreed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Offer {
+    pub stream: bool,
+    pub ack_ranges: bool,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+    pub mtu: u16,
+}
+
+impl Offer {
+    pub fn new(config: &KcpConfig) -> Offer {
+        Offer {
+            stream: config.stream,
+            ack_ranges: config.ack_ranges,
+            snd_wnd: cmp::max(config.snd_wnd, 1) as u32,
+            rcv_wnd: cmp::max(config.rcv_wnd, 1) as u32,
+            mtu: cmp::min(config.mtu, u16::MAX as usize) as u16,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; OFFER_LEN];
+        buf[..4].copy_from_slice(OFFER_MAGIC);
+        buf[4] = OFFER_VERSION;
+        if self.stream {
+            buf[5] |= OFFER_STREAM;
+        }
+        if self.ack_ranges {
+            buf[5] |= OFFER_ACK_RANGES;
+        }
+        LittleEndian::write_u32(&mut buf[6..10], self.snd_wnd);
+        LittleEndian::write_u32(&mut buf[10..14], self.rcv_wnd);
+        LittleEndian::write_u16(&mut buf[14..16], self.mtu);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Offer> {
+        if buf.len() != OFFER_LEN || &buf[..4] != OFFER_MAGIC || buf[4] != OFFER_VERSION {
+            return None;
+        }
+        let offer = Offer {
+            stream: buf[5] & OFFER_STREAM != 0,
+            ack_ranges: buf[5] & OFFER_ACK_RANGES != 0,
+            snd_wnd: LittleEndian::read_u32(&buf[6..10]),
+            rcv_wnd: LittleEndian::read_u32(&buf[10..14]),
+            mtu: LittleEndian::read_u16(&buf[14..16]),
+        };
+        if offer.snd_wnd == 0 || offer.rcv_wnd == 0 {
+            return None;
+        }
+        Some(offer)
+    }
+
+    /// the values a server configured with `limits` agrees to
+    pub fn clamp(&self, limits: &KcpConfig) -> Offer {
+        let limit = Offer::new(limits);
+        Offer {
+            // boundaries are kept unless both ends are fine without them
+            stream: self.stream && limit.stream,
+            ack_ranges: self.ack_ranges && limit.ack_ranges,
+            snd_wnd: cmp::min(self.snd_wnd, limit.rcv_wnd),
+            rcv_wnd: cmp::min(self.rcv_wnd, limit.snd_wnd),
+            mtu: cmp::min(self.mtu, limit.mtu),
+        }
+    }
+
+    /// configure the client's `kcb` with the agreed values
+    pub fn apply<W: Write>(&self, kcb: &mut Kcb<W>) {
+        kcb.wndsize(wnd(self.snd_wnd), wnd(self.rcv_wnd));
+        self.apply_common(kcb);
+    }
+
+    /// configure the server's `kcb` with the agreed values and queue them
+    /// as the answer to the client
+    pub fn answer<W: Write>(&self, kcb: &mut Kcb<W>) {
+        kcb.wndsize(wnd(self.rcv_wnd), wnd(self.snd_wnd));
+        kcb.send(&self.encode()).ok();
+        self.apply_common(kcb);
+    }
+
+    fn apply_common<W: Write>(&self, kcb: &mut Kcb<W>) {
+        kcb.setmtu(self.mtu as usize);
+        kcb.set_ack_ranges(self.ack_ranges);
+        // after the offer or answer, which keeps its own segment
+        kcb.set_stream(self.stream);
+    }
+}
+
+fn wnd(wnd: u32) -> i32 {
+    cmp::min(wnd, i32::MAX as u32) as i32
+}
@@ -0,0 +1,129 @@
+//! Allocation of conversation ids. Convs are picked by the side opening a
+//! session, a listener only gets to vet the ones it is offered, so a
+//! deployment partitioning the conv space uses the same allocator on both.
+
+use std::net::SocketAddr;
+
+use rand;
+
+use rng::XorShift;
+
+/// Hands out convs for new sessions.
+pub trait ConvAllocator {
+    /// Pick a conv for a new session with `peer`, `in_use` tells whether a
+    /// conv is already taken for that peer. Returns `None` once the conv
+    /// space of the allocator is exhausted.
+    fn allocate(&mut self, peer: &SocketAddr, in_use: &Fn(u32) -> bool) -> Option<u32>;
+
+    /// whether `conv` is one this allocator could have handed out
+    fn owns(&self, _conv: u32) -> bool {
+        true
+    }
+}
+
+/// Random convs from `first..=last`, retrying on collisions. The default.
+pub struct RandomConv {
+    first: u32,
+    last: u32,
+    // picks from the seed instead of the thread rng once seeded
+    rng: Option<XorShift>,
+}
+
+impl RandomConv {
+    pub fn new() -> RandomConv {
+        RandomConv::with_range(0, u32::MAX)
+    }
+
+    /// only hand out convs from `first` to `last` inclusive
+    pub fn with_range(first: u32, last: u32) -> RandomConv {
+        assert!(first <= last, "empty conv range");
+        RandomConv {
+            first: first,
+            last: last,
+            rng: None,
+        }
+    }
+
+    /// the same sequence of convs for the same `seed`, to reproduce a run
+    pub fn seeded(seed: u64) -> RandomConv {
+        RandomConv::new().with_seed(seed)
+    }
+
+    /// pick convs from `seed` instead of at random
+    pub fn with_seed(mut self, seed: u64) -> RandomConv {
+        self.rng = Some(XorShift::new(seed));
+        self
+    }
+
+    fn random(&mut self) -> u64 {
+        match self.rng {
+            Some(ref mut rng) => rng.next_u64(),
+            None => rand::random(),
+        }
+    }
+}
+
+impl Default for RandomConv {
+    fn default() -> RandomConv {
+        RandomConv::new()
+    }
+}
+
+// give up on a crowded range instead of spinning
+const RANDOM_TRIES: usize = 64;
+
+impl ConvAllocator for RandomConv {
+    fn allocate(&mut self, _peer: &SocketAddr, in_use: &Fn(u32) -> bool) -> Option<u32> {
+        let span = (self.last - self.first) as u64 + 1;
+        for _ in 0..RANDOM_TRIES {
+            let conv = self.first + (self.random() % span) as u32;
+            if !in_use(conv) {
+                return Some(conv);
+            }
+        }
+        None
+    }
+
+    fn owns(&self, conv: u32) -> bool {
+        conv >= self.first && conv <= self.last
+    }
+}
+
+/// Consecutive convs from `first..=last`, wrapping around and skipping the
+/// ones in use.
+pub struct SequentialConv {
+    first: u32,
+    last: u32,
+    next: u32,
+}
+
+impl SequentialConv {
+    pub fn new(first: u32, last: u32) -> SequentialConv {
+        assert!(first <= last, "empty conv range");
+        SequentialConv {
+            first: first,
+            last: last,
+            next: first,
+        }
+    }
+}
+
+impl ConvAllocator for SequentialConv {
+    fn allocate(&mut self, _peer: &SocketAddr, in_use: &Fn(u32) -> bool) -> Option<u32> {
+        let span = (self.last - self.first) as u64 + 1;
+        let mut tried = 0;
+        while tried < span {
+            let conv = self.next;
+            self.next = if conv == self.last { self.first } else { conv + 1 };
+            if !in_use(conv) {
+                return Some(conv);
+            }
+            tried += 1;
+        }
+        None
+    }
+
+    fn owns(&self, conv: u32) -> bool {
+        conv >= self.first && conv <= self.last
+    }
+}
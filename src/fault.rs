@@ -0,0 +1,75 @@
+//! Failures forced on a `Kcb` on purpose, so applications can exercise
+//! their recovery paths against a real control block instead of a mock.
+//! Armed through `Kcb::faults`, only built with the `fault-injection`
+//! feature.
+
+use std::io::{Error, ErrorKind};
+
+/// The faults armed on a control block, all off by default.
+#[derive(Debug, Default)]
+pub struct Faults {
+    drop: u32,
+    corrupt: u32,
+    fail: Option<ErrorKind>,
+    frozen: bool,
+    // datagrams dropped or corrupted so far
+    injected: u64,
+}
+
+impl Faults {
+    /// silently lose the next `n` datagrams, as if the network did
+    pub fn drop_outputs(&mut self, n: u32) {
+        self.drop = n;
+    }
+
+    /// garble the cmd of the first segment in each of the next `n`
+    /// datagrams, so the peer rejects them as malformed
+    pub fn corrupt_headers(&mut self, n: u32) {
+        self.corrupt = n;
+    }
+
+    /// refuse every write to the output sink with `kind` until it is reset
+    /// to `None`, the output error policy decides what happens next
+    pub fn fail_output(&mut self, kind: Option<ErrorKind>) {
+        self.fail = kind;
+    }
+
+    /// stop the clock, `update` keeps the time of the last update before
+    /// the freeze until it is thawed
+    pub fn freeze_clock(&mut self, on: bool) {
+        self.frozen = on;
+    }
+
+    pub fn is_clock_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// datagrams dropped or corrupted so far
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    /// whether any fault would touch the next datagram
+    pub(crate) fn armed(&self) -> bool {
+        self.drop > 0 || self.corrupt > 0 || self.fail.is_some()
+    }
+
+    /// apply the armed faults to a datagram about to be written, `Ok(false)`
+    /// if it is lost instead
+    pub(crate) fn apply(&mut self, datagram: &mut [u8]) -> Result<bool, Error> {
+        if let Some(kind) = self.fail {
+            return Err(Error::new(kind, "injected output failure"));
+        }
+        if self.drop > 0 {
+            self.drop -= 1;
+            self.injected += 1;
+            return Ok(false);
+        }
+        if self.corrupt > 0 && datagram.len() > 4 {
+            self.corrupt -= 1;
+            self.injected += 1;
+            datagram[4] ^= 0xff;
+        }
+        Ok(true)
+    }
+}
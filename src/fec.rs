@@ -0,0 +1,268 @@
+//! Forward error correction of datagrams with Reed-Solomon codes, behind
+//! the `fec` feature. `FecEncoder` wraps the output of a `Kcb` and sends
+//! `parity_shards` parity datagrams after every `data_shards` datagrams,
+//! `FecDecoder` restores up to `parity_shards` datagrams lost from each
+//! such group before they are handed to `Kcb::input`, so a lossy link
+//! needs far fewer retransmissions. Both ends must use the same shards.
+//!
+//! Each datagram gets a header like kcptun's: the seqid of the shard (4
+//! bytes) and its flag (2 bytes), data shards then carry the length of
+//! the datagram plus 2 (2 bytes). One encoder and one decoder serve a
+//! single pair of addresses, they don't look into the datagrams.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Error, ErrorKind, Write};
+
+use bytes::{ByteOrder, LittleEndian};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// bytes put in front of each datagram, the size of a data shard included
+pub const FEC_OVERHEAD: usize = FEC_HEADER + FEC_SIZE;
+
+const FEC_HEADER: usize = 6; // seqid(4) flag(2)
+const FEC_SIZE: usize = 2;
+const FEC_DATA: u16 = 0xf1;
+const FEC_PARITY: u16 = 0xf2;
+// groups a decoder keeps the shards of, older ones are given up on
+const FEC_GROUPS: usize = 64;
+
+/// Adds parity datagrams to what is written to `W`.
+pub struct FecEncoder<W> {
+    output: W,
+    codec: ReedSolomon,
+    data_shards: usize,
+    // seqids wrap at a multiple of the group size
+    wrap: u32,
+    next: u32,
+    // the data shards of the open group, size first
+    shards: Vec<Vec<u8>>,
+    parity: u64,
+}
+
+impl<W: Write> FecEncoder<W> {
+    /// `data_shards` datagrams are followed by `parity_shards` parity
+    /// datagrams, 10 and 3 are kcptun's defaults
+    pub fn new(output: W, data_shards: usize, parity_shards: usize) -> io::Result<FecEncoder<W>> {
+        let codec = codec(data_shards, parity_shards)?;
+        let total = (data_shards + parity_shards) as u32;
+        Ok(FecEncoder {
+            output: output,
+            codec: codec,
+            data_shards: data_shards,
+            wrap: u32::MAX / total * total,
+            next: 0,
+            shards: Vec::with_capacity(data_shards),
+            parity: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// The wrapped output, the open group gets no parity.
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    /// parity datagrams sent so far
+    pub fn parity_sent(&self) -> u64 {
+        self.parity
+    }
+
+    fn seqid(&mut self) -> u32 {
+        let seqid = self.next;
+        self.next = (seqid + 1) % self.wrap;
+        seqid
+    }
+
+    /// send the parity of the full group, a parity datagram that fails is
+    /// as good as lost on the way
+    fn close_group(&mut self) {
+        let len = self.shards.iter().map(|shard| shard.len()).max().unwrap_or(0);
+        for shard in &mut self.shards {
+            shard.resize(len, 0);
+        }
+        let mut parity = vec![vec![0; len]; self.codec.parity_shard_count()];
+        {
+            let mut all: Vec<&mut [u8]> = self.shards.iter_mut().map(|s| &mut s[..]).collect();
+            all.extend(parity.iter_mut().map(|s| &mut s[..]));
+            self.codec.encode(all).expect("shards of equal size");
+        }
+        self.shards.clear();
+        for shard in parity {
+            let mut datagram = vec![0; FEC_HEADER];
+            LittleEndian::write_u32(&mut datagram[..4], self.seqid());
+            LittleEndian::write_u16(&mut datagram[4..6], FEC_PARITY);
+            datagram.extend_from_slice(&shard);
+            if self.output.write(&datagram).is_ok() {
+                self.parity += 1;
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for FecEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() + FEC_SIZE > u16::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "datagram too large for fec"));
+        }
+        let mut datagram = Vec::with_capacity(FEC_OVERHEAD + buf.len());
+        datagram.extend_from_slice(&[0; FEC_OVERHEAD]);
+        LittleEndian::write_u32(&mut datagram[..4], self.next);
+        LittleEndian::write_u16(&mut datagram[4..6], FEC_DATA);
+        LittleEndian::write_u16(&mut datagram[6..8], (buf.len() + FEC_SIZE) as u16);
+        datagram.extend_from_slice(buf);
+        // the shard only counts once it made it out, a retried write
+        // comes back with the same datagram
+        self.output.write(&datagram)?;
+        self.seqid();
+        self.shards.push(datagram.split_off(FEC_HEADER));
+        if self.shards.len() == self.data_shards {
+            self.close_group();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Strips the headers of `FecEncoder` and restores lost datagrams.
+pub struct FecDecoder {
+    codec: ReedSolomon,
+    data_shards: usize,
+    total: u32,
+    groups: HashMap<u32, Group>,
+    // groups by age, the oldest first
+    order: VecDeque<u32>,
+    recovered: u64,
+}
+
+struct Group {
+    shards: Vec<Option<Vec<u8>>>,
+    received: usize,
+    // every data shard is in, or was restored
+    done: bool,
+}
+
+impl FecDecoder {
+    pub fn new(data_shards: usize, parity_shards: usize) -> io::Result<FecDecoder> {
+        Ok(FecDecoder {
+            codec: codec(data_shards, parity_shards)?,
+            data_shards: data_shards,
+            total: (data_shards + parity_shards) as u32,
+            groups: HashMap::new(),
+            order: VecDeque::new(),
+            recovered: 0,
+        })
+    }
+
+    /// datagrams restored so far
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Take a datagram off the wire, `out` gets the datagram it carries,
+    /// if any, then those of its group it makes up for. Every datagram is
+    /// passed on once, duplicates and those restored before they arrived
+    /// are dropped.
+    pub fn decode<F>(&mut self, datagram: &[u8], mut out: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]),
+    {
+        if datagram.len() < FEC_HEADER {
+            return Err(Error::new(ErrorKind::InvalidData, "fec header truncated"));
+        }
+        let seqid = LittleEndian::read_u32(&datagram[..4]);
+        let flag = LittleEndian::read_u16(&datagram[4..6]);
+        let shard = &datagram[FEC_HEADER..];
+        let carried = match flag {
+            FEC_DATA => Some(payload(shard)?),
+            FEC_PARITY => None,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "unknown fec flag")),
+        };
+
+        let id = seqid / self.total;
+        let index = (seqid % self.total) as usize;
+        if !self.groups.contains_key(&id) {
+            if self.order.len() == FEC_GROUPS {
+                let oldest = self.order.pop_front().unwrap();
+                self.groups.remove(&oldest);
+            }
+            self.order.push_back(id);
+            self.groups.insert(
+                id,
+                Group {
+                    shards: vec![None; self.total as usize],
+                    received: 0,
+                    done: false,
+                },
+            );
+        }
+        let group = self.groups.get_mut(&id).unwrap();
+        // a duplicate, or a late one that was restored already
+        if group.done || group.shards[index].is_some() {
+            return Ok(());
+        }
+        if let Some(datagram) = carried {
+            out(datagram);
+        }
+        group.shards[index] = Some(shard.to_vec());
+        group.received += 1;
+        let data = &group.shards[..self.data_shards];
+        if data.iter().all(|shard| shard.is_some()) {
+            group.done = true;
+            return Ok(());
+        }
+        if group.received < self.data_shards {
+            return Ok(());
+        }
+
+        // a parity shard has the size of the largest data shard
+        let len = group.shards[self.data_shards..]
+            .iter()
+            .filter_map(|shard| shard.as_ref().map(|shard| shard.len()))
+            .next()
+            .unwrap();
+        let missing: Vec<usize> = (0..self.data_shards)
+            .filter(|&i| group.shards[i].is_none())
+            .collect();
+        for shard in group.shards.iter_mut().filter_map(|shard| shard.as_mut()) {
+            shard.resize(len, 0);
+        }
+        group.done = true;
+        if self.codec.reconstruct_data(&mut group.shards).is_err() {
+            return Err(Error::new(ErrorKind::InvalidData, "fec group inconsistent"));
+        }
+        for i in missing {
+            if let Ok(datagram) = payload(group.shards[i].as_ref().unwrap()) {
+                self.recovered += 1;
+                out(datagram);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn codec(data_shards: usize, parity_shards: usize) -> io::Result<ReedSolomon> {
+    ReedSolomon::new(data_shards, parity_shards)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("fec shards: {:?}", e)))
+}
+
+/// the datagram in a data shard, without the padding of a restored one
+fn payload(shard: &[u8]) -> io::Result<&[u8]> {
+    if shard.len() < FEC_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "fec size truncated"));
+    }
+    let size = LittleEndian::read_u16(&shard[..FEC_SIZE]) as usize;
+    if size < FEC_SIZE || size > shard.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "fec size out of range"));
+    }
+    Ok(&shard[FEC_SIZE..size])
+}
@@ -0,0 +1,59 @@
+//! gRPC over KCP with tonic, behind the `grpc` feature, on the sessions of
+//! the `tokio` module. A channel is one session, tonic's HTTP/2 multiplexes
+//! its calls, so a lost datagram stalls them only until KCP resent it.
+//!
+//! A server takes the sessions of a listener, bound with `stream: true`,
+//! through `incoming`:
+//!
+//! ```ignore
+//! Server::builder().add_service(svc).serve_with_incoming(grpc::incoming(listener))
+//! ```
+//!
+//! and a client opens its channel with `Connector`, whose sessions are in
+//! stream mode:
+//!
+//! ```ignore
+//! Endpoint::from_shared(uri)?.connect_with_connector(Connector::new())
+//! ```
+//!
+//! Requests carry the address of the peer as a `SocketAddr` extension.
+
+pub extern crate tonic;
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use self::tonic::codegen::futures_core::Stream;
+use self::tonic::transport::server::Connected;
+
+use tokio::{KcpListener, KcpStream};
+
+pub use http::Connector;
+
+/// The sessions `listener` accepts, for `Server::serve_with_incoming`.
+pub fn incoming(listener: KcpListener) -> Incoming {
+    Incoming { listener: listener }
+}
+
+/// Stream of the sessions of a listener, see `incoming`.
+pub struct Incoming {
+    listener: KcpListener,
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<KcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<KcpStream>>> {
+        self.listener.poll_accept(cx).map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }
+}
+
+impl Connected for KcpStream {
+    type ConnectInfo = SocketAddr;
+
+    fn connect_info(&self) -> SocketAddr {
+        self.peer_addr()
+    }
+}
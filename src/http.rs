@@ -0,0 +1,172 @@
+//! HTTP over KCP with hyper, behind the `http` feature, on the sessions of
+//! the `tokio` module. `serve` answers HTTP/1.1 and HTTP/2 on every session
+//! of a listener, `Connector` opens a session for each connection of a
+//! hyper `Client`, `Client::builder().http2_only(true)` talks HTTP/2 with
+//! prior knowledge. Sessions on both ends have to be in stream mode, so
+//! hyper's reads need not line up with the peer's writes: `Connector` sets
+//! it, the listener is bound with `stream: true`.
+
+pub extern crate hyper;
+
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use self::hyper::body::HttpBody;
+use self::hyper::client::connect::{Connected, Connection};
+use self::hyper::server::conn::Http;
+use self::hyper::service::Service;
+use self::hyper::{Body, Request, Response, Uri};
+use tokio1::task::{self, JoinHandle};
+
+use tokio::{KcpListener, KcpStream};
+use KcpConfig;
+
+/// Serve HTTP on every session `listener` hands out, with a service made
+/// by `new_service` for the address of the peer. Whether a connection
+/// speaks HTTP/1.1 or HTTP/2 is told from its first bytes. Connections
+/// are spawned on the runtime, an error on one only ends that connection.
+/// The returned future runs until the listener fails.
+pub fn serve<F, S, B>(listener: KcpListener, new_service: F) -> Serve<F>
+where
+    F: FnMut(SocketAddr) -> S,
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<Box<StdError + Send + Sync>>,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<StdError + Send + Sync>>,
+{
+    Serve {
+        listener: listener,
+        http: Http::new(),
+        new_service: new_service,
+    }
+}
+
+/// Future of `serve`.
+pub struct Serve<F> {
+    listener: KcpListener,
+    http: Http,
+    new_service: F,
+}
+
+impl<F, S, B> Future for Serve<F>
+where
+    F: FnMut(SocketAddr) -> S + Unpin,
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<Box<StdError + Send + Sync>>,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<StdError + Send + Sync>>,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let (stream, addr) = match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok(accepted)) => accepted,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let connection = this.http.serve_connection(stream, (this.new_service)(addr));
+            task::spawn(Detached(Box::pin(connection)));
+        }
+    }
+}
+
+/// a connection spawned on its own, what it ends with only concerns its
+/// peer
+struct Detached<C>(Pin<Box<C>>);
+
+impl<C, E> Future for Detached<C>
+where
+    C: Future<Output = Result<(), E>>,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.0.as_mut().poll(cx).map(|_| ())
+    }
+}
+
+/// Connects hyper's `Client` over KCP: each connection is a session to the
+/// host and port of the uri, port 80 when it has none. Host names are
+/// resolved on the blocking pool of the runtime.
+#[derive(Clone)]
+pub struct Connector {
+    config: KcpConfig,
+}
+
+impl Connector {
+    pub fn new() -> Connector {
+        Connector::with_config(KcpConfig::default())
+    }
+
+    /// sessions tuned by `config`, in stream mode whatever it says
+    pub fn with_config(config: KcpConfig) -> Connector {
+        Connector {
+            config: KcpConfig {
+                stream: true,
+                ..config
+            },
+        }
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = KcpStream;
+    type Error = io::Error;
+    type Future = Connecting;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Connecting {
+        let host = uri.host().map(|host| host.trim_matches(|c| c == '[' || c == ']').to_owned());
+        let port = uri.port_u16().unwrap_or(80);
+        let resolve = task::spawn_blocking(move || {
+            let host = host.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "no host in uri")
+            })?;
+            (&host[..], port).to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "host has no address")
+            })
+        });
+        Connecting {
+            resolve: resolve,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Future of a session opened by `Connector`.
+pub struct Connecting {
+    resolve: JoinHandle<io::Result<SocketAddr>>,
+    config: KcpConfig,
+}
+
+impl Future for Connecting {
+    type Output = io::Result<KcpStream>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<KcpStream>> {
+        let addr = match Pin::new(&mut self.resolve).poll(cx) {
+            Poll::Ready(Ok(addr)) => addr?,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(KcpStream::connect_with_config(&addr, &self.config))
+    }
+}
+
+impl Connection for KcpStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
@@ -0,0 +1,139 @@
+//! Thin wrapper around the reference ikcp.c, linked with the
+//! `ikcp-conformance` feature to run it side by side with `Kcb`.
+
+use std::collections::VecDeque;
+use std::io::{self, Error, ErrorKind};
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::slice;
+
+#[allow(non_camel_case_types)]
+enum ikcpcb {}
+
+extern "C" {
+    fn ikcp_create(conv: u32, user: *mut c_void) -> *mut ikcpcb;
+    fn ikcp_release(kcp: *mut ikcpcb);
+    fn ikcp_setoutput(
+        kcp: *mut ikcpcb,
+        output: extern "C" fn(*const c_char, c_int, *mut ikcpcb, *mut c_void) -> c_int,
+    );
+    fn ikcp_recv(kcp: *mut ikcpcb, buffer: *mut c_char, len: c_int) -> c_int;
+    fn ikcp_send(kcp: *mut ikcpcb, buffer: *const c_char, len: c_int) -> c_int;
+    fn ikcp_update(kcp: *mut ikcpcb, current: u32);
+    fn ikcp_check(kcp: *const ikcpcb, current: u32) -> u32;
+    fn ikcp_input(kcp: *mut ikcpcb, data: *const c_char, size: c_long) -> c_int;
+    fn ikcp_flush(kcp: *mut ikcpcb);
+    fn ikcp_peeksize(kcp: *const ikcpcb) -> c_int;
+    fn ikcp_setmtu(kcp: *mut ikcpcb, mtu: c_int) -> c_int;
+    fn ikcp_wndsize(kcp: *mut ikcpcb, sndwnd: c_int, rcvwnd: c_int) -> c_int;
+    fn ikcp_waitsnd(kcp: *const ikcpcb) -> c_int;
+    fn ikcp_nodelay(kcp: *mut ikcpcb, nodelay: c_int, interval: c_int, resend: c_int, nc: c_int)
+        -> c_int;
+}
+
+extern "C" fn queue_output(
+    buf: *const c_char,
+    len: c_int,
+    _: *mut ikcpcb,
+    user: *mut c_void,
+) -> c_int {
+    let queue = unsafe { &mut *(user as *mut VecDeque<Vec<u8>>) };
+    let buf = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+    queue.push_back(buf.to_vec());
+    0
+}
+
+/// A reference control block, whose output datagrams are queued up to be
+/// taken with `pop_output`.
+pub struct Ikcp {
+    kcp: *mut ikcpcb,
+    // boxed so the pointer handed to ikcp stays put
+    output: Box<VecDeque<Vec<u8>>>,
+}
+
+impl Ikcp {
+    pub fn new(conv: u32) -> Ikcp {
+        let mut output = Box::new(VecDeque::new());
+        let kcp = unsafe {
+            let kcp = ikcp_create(conv, &mut *output as *mut VecDeque<Vec<u8>> as *mut c_void);
+            ikcp_setoutput(kcp, queue_output);
+            kcp
+        };
+        Ikcp {
+            kcp: kcp,
+            output: output,
+        }
+    }
+
+    pub fn pop_output(&mut self) -> Option<Vec<u8>> {
+        self.output.pop_front()
+    }
+
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { ikcp_recv(self.kcp, buf.as_mut_ptr() as *mut c_char, buf.len() as c_int) };
+        if n < 0 {
+            Err(Error::new(ErrorKind::Other, format!("ikcp_recv: {}", n)))
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { ikcp_send(self.kcp, buf.as_ptr() as *const c_char, buf.len() as c_int) };
+        if n < 0 {
+            Err(Error::new(ErrorKind::Other, format!("ikcp_send: {}", n)))
+        } else {
+            Ok(buf.len())
+        }
+    }
+
+    pub fn input(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { ikcp_input(self.kcp, buf.as_ptr() as *const c_char, buf.len() as c_long) };
+        if n < 0 {
+            Err(Error::new(ErrorKind::InvalidData, format!("ikcp_input: {}", n)))
+        } else {
+            Ok(buf.len())
+        }
+    }
+
+    pub fn update(&mut self, current: u32) {
+        unsafe { ikcp_update(self.kcp, current) }
+    }
+
+    pub fn check(&self, current: u32) -> u32 {
+        unsafe { ikcp_check(self.kcp, current) }
+    }
+
+    pub fn flush(&mut self) {
+        unsafe { ikcp_flush(self.kcp) }
+    }
+
+    pub fn peeksize(&self) -> isize {
+        unsafe { ikcp_peeksize(self.kcp) as isize }
+    }
+
+    pub fn setmtu(&mut self, mtu: usize) -> bool {
+        unsafe { ikcp_setmtu(self.kcp, mtu as c_int) == 0 }
+    }
+
+    pub fn wndsize(&mut self, sndwnd: i32, rcvwnd: i32) {
+        unsafe {
+            ikcp_wndsize(self.kcp, sndwnd, rcvwnd);
+        }
+    }
+
+    pub fn waitsnd(&self) -> usize {
+        unsafe { ikcp_waitsnd(self.kcp) as usize }
+    }
+
+    pub fn nodelay(&mut self, nodelay: i32, interval: i32, resend: i32, nc: bool) {
+        unsafe {
+            ikcp_nodelay(self.kcp, nodelay, interval, resend, nc as c_int);
+        }
+    }
+}
+
+impl Drop for Ikcp {
+    fn drop(&mut self) {
+        unsafe { ikcp_release(self.kcp) }
+    }
+}
@@ -1,8 +1,18 @@
 use std::cmp;
 use std::collections::VecDeque;
-use std::io::{self, Cursor, Error, ErrorKind, Read, Write};
+use std::error;
+use std::fmt;
+use std::io::{self, Cursor, Error, ErrorKind, IoSlice, Read, Write};
+use std::mem;
+use std::time::{Duration, Instant};
 
-use bytes::{Buf, BufMut, BytesMut, LittleEndian};
+use bytes::{Buf, BufMut, ByteOrder, Bytes, BytesMut, LittleEndian};
+use smallvec::SmallVec;
+
+use cc::{Classic, CongestionControl, Window};
+#[cfg(feature = "fault-injection")]
+use fault::Faults;
+use trace::{Trace, TraceEvent};
 
 const KCP_RTO_NDL: u32 = 30; // no delay min rto
 const KCP_RTO_MIN: u32 = 100; // normal min rto
@@ -12,19 +22,188 @@ const KCP_CMD_PUSH: u8 = 81; // cmd: push data
 const KCP_CMD_ACK: u8 = 82; // cmd: ack
 const KCP_CMD_WASK: u8 = 83; // cmd: window probe (ask)
 const KCP_CMD_WINS: u8 = 84; // cmd: window size (tell)
+const KCP_CMD_UPUSH: u8 = 85; // cmd: push unordered data
+const KCP_CMD_ACKR: u8 = 86; // cmd: ack a run of consecutive sns
+const KCP_CMD_PART: u8 = 87; // cmd: part of a segment too large for the mtu
+const KCP_CMD_CLOSE: u8 = 88; // cmd: no data after sn, with a close code and reason
+const KCP_CMD_COMPACT: u8 = 89; // cmd: datagram of segments sharing conv, wnd and una
+const KCP_CMD_RESET: u8 = 90; // cmd: conversation aborted, stop sending
+const KCP_FRG_FIRST: u8 = 0x80; // first fragment of an unordered message
+const KCP_EXT_ACKR: u8 = 0x01; // frg of ACK, WASK and WINS: ack ranges understood
+const KCP_EXT_PART: u8 = 0x02; // frg of ACK, WASK and WINS: segment parts understood
+const KCP_EXT_COMPACT: u8 = 0x04; // frg of ACK, WASK and WINS: compact datagrams understood
+// frg of WASK: the sender has yet to learn our sns, ignore its una. of WASK
+// and WINS: the sn is where the sender numbers from
+const KCP_FRG_SYN: u8 = 0x80;
+const KCP_COMPACT_HEADER: usize = 11; // conv, KCP_CMD_COMPACT, wnd and una of a compact datagram
+const KCP_COMPACT_OVERHEAD: usize = 14; // cmd, frg, ts, sn and len of a compact segment
+const KCP_PART_HEADER: usize = 9; // original cmd, total length and offset of a part
+const KCP_PART_MAX: usize = 1 << 16; // largest segment reassembled from parts
+const KCP_PARTIALS: usize = 16; // segments reassembled at the same time
+const KCP_MTU_STEPS: [usize; 3] = [1_400, 1_200, 1_024]; // black hole downshift
+const KCP_BLACKHOLE_XMIT: u32 = 3; // timeouts of a large segment before stepping down
+const KCP_CLOSE_REASON: usize = 123; // longest close reason in bytes
 const KCP_ASK_SEND: u32 = 0b01; // need to send KCP_CMD_WASK
 const KCP_ASK_TELL: u32 = 0b10; // need to send KCP_CMD_WINS
+const KCP_WINS_RESEND: u32 = 4; // window updates sent after the window reopened
 const KCP_WND_SND: u32 = 32;
 const KCP_WND_RCV: u32 = 32;
 const KCP_MTU_DEF: usize = 1_400;
 // const KCP_ACK_FAST: u32 = 3; // never used
 const KCP_INTERVAL: u32 = 100;
 const KCP_OVERHEAD: usize = 24;
-// const KCP_DEADLINK: u32 = 20; // never used
+const KCP_DEADLINK: u32 = 20;
 const KCP_THRESH_INIT: u32 = 2;
-const KCP_THRESH_MIN: u32 = 2;
+const KCP_CWND_RESTART: u32 = 4; // least cwnd an idle sender decays to
+const KCP_HYSTART_LOW: u32 = 16; // cwnd below which hystart keeps slow starting
+const KCP_HYSTART_SAMPLES: u32 = 8; // rtt samples taken at the start of a round
 const KCP_PROBE_INIT: u32 = 7_000; // 7 secs to probe window size
 const KCP_PROBE_LIMIT: u32 = 120_000; // up to 120 secs to probe window
+const KCP_BW_SAMPLES: usize = 10; // delivery rate samples in the bandwidth filter
+const KCP_PRESSURE_STREAK: u32 = 2; // flushes in a row the output sink refused before backing off
+const KCP_BUDGET_MIN: u32 = 4; // least data segments a flush may emit under pressure
+const KCP_BACKLOG: usize = 1024; // segments waiting to be sent before writers are held back
+#[cfg(feature = "bounded-queues")]
+const KCP_QUEUE_LIMIT: usize = 256; // max segments held by each queue
+#[cfg(not(feature = "bounded-queues"))]
+const KCP_QUEUE_LIMIT: usize = ::std::usize::MAX;
+
+/// The fixed 24 bytes in front of every segment on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub conv: u32,
+    pub cmd: u8,
+    pub frg: u8,
+    pub wnd: u16,
+    pub ts: u32,
+    pub sn: u32,
+    pub una: u32,
+    /// payload length
+    pub len: u32,
+}
+
+/// Which way a segment passed, see `Kcb::set_segment_tap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// split off the first segment of `data` into its header and payload,
+/// `None` at the end of the datagram or where zero padding starts
+fn split_segment(data: &[u8]) -> io::Result<Option<(SegmentHeader, &[u8], &[u8])>> {
+    if data.len() < KCP_OVERHEAD || data.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    let header = SegmentHeader {
+        conv: LittleEndian::read_u32(&data[0..4]),
+        cmd: data[4],
+        frg: data[5],
+        wnd: LittleEndian::read_u16(&data[6..8]),
+        ts: LittleEndian::read_u32(&data[8..12]),
+        sn: LittleEndian::read_u32(&data[12..16]),
+        una: LittleEndian::read_u32(&data[16..20]),
+        len: LittleEndian::read_u32(&data[20..24]),
+    };
+    let len = header.len as usize;
+    if data.len() - KCP_OVERHEAD < len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected EOF"));
+    }
+    let (body, rest) = data[KCP_OVERHEAD..].split_at(len);
+    Ok(Some((header, body, rest)))
+}
+
+/// The reset frame answering `datagram` of a conversation nothing is
+/// known of, which its sender honours like a `Kcb::reset` of the peer.
+/// `None` unless the datagram starts with a segment the sender numbered,
+/// its sn is what proves that the reset was not guessed.
+pub fn reset_frame(datagram: &[u8]) -> Option<Vec<u8>> {
+    let header = match split_segment(datagram) {
+        Ok(Some((header, _, _))) => header,
+        _ => return None,
+    };
+    match header.cmd {
+        KCP_CMD_PUSH | KCP_CMD_UPUSH | KCP_CMD_PART | KCP_CMD_CLOSE => {}
+        _ => return None,
+    }
+    let mut seg = Segment::default();
+    seg.conv = header.conv;
+    seg.cmd = KCP_CMD_RESET;
+    seg.ts = header.ts;
+    seg.una = header.sn;
+    let mut frame = Vec::with_capacity(KCP_OVERHEAD);
+    seg.encode(&mut frame);
+    Some(frame)
+}
+
+fn dead_link() -> Error {
+    Error::new(ErrorKind::TimedOut, "link dead")
+}
+
+/// `datagram` with conv, wnd and una written once in front of its
+/// segments, `None` if it holds a single segment or they differ
+fn compact(datagram: &[u8]) -> Option<Vec<u8>> {
+    if datagram.len() < KCP_OVERHEAD {
+        return None;
+    }
+    let (conv, wnd, una) = (&datagram[0..4], &datagram[6..8], &datagram[16..20]);
+    let mut out = Vec::with_capacity(datagram.len());
+    out.extend_from_slice(conv);
+    out.push(KCP_CMD_COMPACT);
+    out.extend_from_slice(wnd);
+    out.extend_from_slice(una);
+    let mut count = 0;
+    let mut rest = datagram;
+    while !rest.is_empty() {
+        if rest.len() < KCP_OVERHEAD {
+            return None;
+        }
+        let len = LittleEndian::read_u32(&rest[20..24]) as usize;
+        if rest.len() - KCP_OVERHEAD < len || &rest[0..4] != conv || &rest[6..8] != wnd ||
+            &rest[16..20] != una
+        {
+            return None;
+        }
+        out.push(rest[4]);
+        out.push(rest[5]);
+        out.extend_from_slice(&rest[8..16]);
+        out.extend_from_slice(&rest[20..KCP_OVERHEAD + len]);
+        rest = &rest[KCP_OVERHEAD + len..];
+        count += 1;
+    }
+    if count < 2 {
+        return None;
+    }
+    Some(out)
+}
+
+/// the segments of a compact datagram with their full headers back
+fn expand(datagram: &[u8]) -> io::Result<Vec<u8>> {
+    if datagram.len() < KCP_COMPACT_HEADER {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid data"));
+    }
+    let (conv, wnd, una) = (&datagram[0..4], &datagram[5..7], &datagram[7..11]);
+    let mut out = Vec::with_capacity(datagram.len() * 2);
+    let mut rest = &datagram[KCP_COMPACT_HEADER..];
+    while !rest.is_empty() && !rest.iter().all(|&b| b == 0) {
+        if rest.len() < KCP_COMPACT_OVERHEAD {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected EOF"));
+        }
+        let len = LittleEndian::read_u32(&rest[10..14]) as usize;
+        if rest.len() - KCP_COMPACT_OVERHEAD < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected EOF"));
+        }
+        out.extend_from_slice(conv);
+        out.push(rest[0]);
+        out.push(rest[1]);
+        out.extend_from_slice(wnd);
+        out.extend_from_slice(&rest[2..10]);
+        out.extend_from_slice(una);
+        out.extend_from_slice(&rest[10..KCP_COMPACT_OVERHEAD + len]);
+        rest = &rest[KCP_COMPACT_OVERHEAD + len..];
+    }
+    Ok(out)
+}
 
 #[derive(Default)]
 struct Segment {
@@ -39,11 +218,15 @@ struct Segment {
     rto: u32,
     fastack: u32,
     xmit: u32,
-    data: Vec<u8>,
+    delivered: bool,
+    // a slice of the datagram it arrived in when given to `input_bytes`
+    data: Bytes,
+    // the header as last sent, empty until the segment is first sent
+    encoded: Vec<u8>,
 }
 
 impl Segment {
-    fn encode(&self, buf: &mut BytesMut) {
+    fn encode<B: BufMut>(&self, buf: &mut B) {
         buf.put_u32::<LittleEndian>(self.conv);
         buf.put::<u8>(self.cmd);
         buf.put::<u8>(self.frg);
@@ -54,6 +237,62 @@ impl Segment {
         buf.put_u32::<LittleEndian>(self.data.len() as u32);
         buf.put_slice(&self.data);
     }
+
+    /// encode for (re)transmission, the first call caches the encoded
+    /// header and later calls only patch the fields that change between
+    /// sends
+    fn encode_cached(&mut self, buf: &mut BytesMut) {
+        self.refresh_encoded();
+        buf.put_slice(&self.encoded);
+        buf.put_slice(&self.data);
+    }
+
+    fn refresh_encoded(&mut self) {
+        if self.encoded.is_empty() {
+            let mut encoded = Vec::with_capacity(KCP_OVERHEAD);
+            encoded.put_u32::<LittleEndian>(self.conv);
+            encoded.put::<u8>(self.cmd);
+            encoded.put::<u8>(self.frg);
+            encoded.put_u16::<LittleEndian>(self.wnd as u16);
+            encoded.put_u32::<LittleEndian>(self.ts);
+            encoded.put_u32::<LittleEndian>(self.sn);
+            encoded.put_u32::<LittleEndian>(self.una);
+            encoded.put_u32::<LittleEndian>(self.data.len() as u32);
+            self.encoded = encoded;
+        } else {
+            LittleEndian::write_u16(&mut self.encoded[6..8], self.wnd as u16);
+            LittleEndian::write_u32(&mut self.encoded[8..12], self.ts);
+            LittleEndian::write_u32(&mut self.encoded[16..20], self.una);
+        }
+    }
+
+    /// encode `len` bytes of the payload from `offset` as a KCP_CMD_PART
+    fn encode_part(&self, buf: &mut BytesMut, offset: usize, len: usize) {
+        let payload = &self.data[..];
+        buf.put_u32::<LittleEndian>(self.conv);
+        buf.put::<u8>(KCP_CMD_PART);
+        buf.put::<u8>(self.frg);
+        buf.put_u16::<LittleEndian>(self.wnd as u16);
+        buf.put_u32::<LittleEndian>(self.ts);
+        buf.put_u32::<LittleEndian>(self.sn);
+        buf.put_u32::<LittleEndian>(self.una);
+        buf.put_u32::<LittleEndian>((KCP_PART_HEADER + len) as u32);
+        buf.put::<u8>(self.cmd);
+        buf.put_u32::<LittleEndian>(payload.len() as u32);
+        buf.put_u32::<LittleEndian>(offset as u32);
+        buf.put_slice(&payload[offset..offset + len]);
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// sn of the last fragment of the message the segment belongs to
+    #[inline]
+    fn chain_end(&self) -> u32 {
+        chain_end(self.cmd, self.sn, self.frg)
+    }
 }
 
 /// KCP control block
@@ -91,8 +330,82 @@ pub struct Kcb<W: Write> {
 
     ts_probe: u32,
     probe_wait: u32,
-
-    // dead_link: u32, // never used
+    // first wait and upper bound of the window probe backoff
+    probe_init: u32,
+    probe_limit: u32,
+
+    parse_mode: ParseMode,
+    // segments skipped by the lenient parser
+    malformed: u64,
+
+    // data segments received again, or beyond the receive window
+    duplicates: u64,
+    out_of_window: u64,
+    // one past the highest sn received, anything below arrived late
+    rcv_top: u32,
+    reordered: u64,
+    reorder_distance: u32,
+
+    // largest message in bytes and fragments the peer may send
+    max_message: usize,
+    max_fragments: u8,
+    // messages and bytes rcv_buf may hold out of order, and the segments
+    // refused or dropped for going beyond
+    reassembly_chains: usize,
+    reassembly_bytes: usize,
+    reassembly_dropped: u64,
+    // messages and payload bytes rcv_buf holds right now, and the last sns
+    // of messages dropped to make room whose stragglers are still to come
+    held_chains: usize,
+    held_bytes: usize,
+    evicted: Vec<u32>,
+    // payload bytes all queues together may hold before writers are held
+    // back and segments ahead of a gap are refused
+    memory_budget: usize,
+    // waitsnd above which the async layer stops taking writes
+    snd_backlog: usize,
+
+    // calls recorded since `record`
+    trace: Option<Trace>,
+
+    // our close frame, sent as sn `close_sn` once snd_buf drained
+    close: Option<CloseFrame>,
+    close_sn: u32,
+    close_xmit: u32,
+    ts_close: u32,
+    close_acked: bool,
+    peer_close: Option<CloseFrame>,
+    // aborted by `reset`, ConnectionAborted, or by the peer,
+    // ConnectionReset
+    reset: Option<ErrorKind>,
+
+    // send a window update after `keepalive` millisec without output
+    keepalive: u32,
+    ts_keepalive: u32,
+    keepalive_sent: u64,
+
+    // window updates still to send after the window reopened, and when
+    // the next one is due
+    wins_left: u32,
+    ts_wins: u32,
+    // advertise a closed window whatever the receive queue holds
+    paused: bool,
+    // most of the receive window ever advertised
+    wnd_clamp: u32,
+
+    // transmissions of a segment after which the link counts as dead,
+    // and whether a segment got there
+    dead_link: u32,
+    dead: bool,
+    // called when a segment needs `alarm_xmit` transmissions or its rto
+    // backs off to `alarm_rto`, 0 for either leaves it out
+    alarm: Option<Box<FnMut(FailoverAlarm) + Send>>,
+    alarm_xmit: u32,
+    alarm_rto: u32,
+    // most transmissions of a single segment so far
+    max_xmit: u32,
+    // data segments emitted so far, retransmissions included
+    segments_sent: u64,
     incr: u32,
 
     snd_queue: VecDeque<Segment>,
@@ -100,7 +413,21 @@ pub struct Kcb<W: Write> {
     snd_buf: VecDeque<Segment>,
     rcv_buf: VecDeque<Segment>,
 
-    acklist: Vec<(u32, u32)>,
+    // pending acks as (sn, ts), at most one per sn
+    acklist: SmallVec<[(u32, u32); 32]>,
+    // delay of messages behind the fastest one seen, 0 delivers them as
+    // they complete, and the least transit time seen (local clock minus
+    // the peer's send time)
+    playout: u32,
+    playout_base: Option<i32>,
+    // how long acks wait for data to ride along, and when the oldest
+    // pending one was queued
+    ack_delay: u32,
+    ts_ack: u32,
+    // flush right from `input` on acks, at most every that many millisec,
+    // and when it may next
+    ack_flush: Option<u32>,
+    ts_ack_flush: u32,
 
     // user: String,
     buffer: BytesMut,
@@ -108,9 +435,456 @@ pub struct Kcb<W: Write> {
     fastresend: u32,
 
     nocwnd: bool,
+    // only grow cwnd while it limits the sender, decay it while idle
+    cwnd_validation: bool,
+    cwnd_limited: bool,
+    ts_last_xmit: u32,
+    // thousandths of a byte the pacing rate lets out, and when it was
+    // last topped up
+    pace_credit: u64,
+    ts_pace: u32,
+    // growth past slow start and backing off on losses
+    cc: Box<CongestionControl + Send>,
+    // hystart rounds: sn ending the current one, and the least rtt seen in
+    // the first samples of the last and the current round
+    slow_start: SlowStart,
+    hs_round_end: u32,
+    hs_last_min: u32,
+    hs_cur_min: u32,
+    hs_samples: u32,
     stream: bool,
+    // segments queued before stream mode was switched on are not merged into
+    stream_barrier: bool,
+
+    skew_valid: bool,
+    skew_offset: i32,
+    skew_base_ts: u32,
+    skew_base_offset: i32,
+    skew_drift: i32,
+
+    epoch: Instant,
+    padding: Vec<usize>,
+
+    delivered: u64,
+    rate_ts: u32,
+    rate_delivered: u64,
+    delivery_rate: u64,
+    bw_samples: VecDeque<u64>,
+    bandwidth: u64,
+
+    // a valid segment has been received from the peer
+    established: bool,
+    // sns start at a set offset, probes carry snd_una in their sn. the sn
+    // the peer numbers from is yet to be learned, once, from its WASK or
+    // WINS flagged KCP_FRG_SYN, nothing is sent or taken until then. WASK
+    // is repeated at ts_isn. tell_isn flags the next WINS, answering a
+    // WASK that asked for our sns
+    initial_sn: bool,
+    learn_isn: bool,
+    ts_isn: u32,
+    tell_isn: bool,
+
+    // KCP_EXT_* bits announced to the peer, and the ones it announced
+    extensions: u8,
+    peer_extensions: u8,
+
+    // step the mtu down when large datagrams vanish but small ones do not
+    downshift: bool,
+    // acked segments that fit the next lower mtu step since a larger one
+    small_acked: u32,
+    downshifts: u32,
+    // the last segment moved to snd_buf is not the end of its message
+    snd_msg_open: bool,
+    partials: VecDeque<Partial>,
+
+    // data segments a flush may emit while the output sink is under pressure
+    flush_budget: u32,
+    pressure_streak: u32,
+    // data segments a flush may emit at all
+    burst_limit: u32,
+    vectored: bool,
+
+    output: Output<W>,
+}
+
+/// A segment the peer had to split into parts after lowering its mtu.
+struct Partial {
+    sn: u32,
+    cmd: u8,
+    frg: u8,
+    data: Vec<u8>,
+    have: Vec<bool>,
+    missing: usize,
+}
+
+/// What made the alarm of `Kcb::set_failover_alarm` go off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverAlarm {
+    /// segment `sn` was sent for the `xmit`th time
+    Retransmits { sn: u32, xmit: u32 },
+    /// the backed off retransmission timeout of segment `sn` reached `rto`
+    /// millisec
+    Rto { sn: u32, rto: u32 },
+}
+
+/// Why a side closed the conversation, sent once everything before it was
+/// acknowledged, see `Kcb::close`. The code and its meaning are up to the
+/// application, 0 is a plain close.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    /// at most 123 bytes of UTF-8
+    pub reason: String,
+}
+
+impl fmt::Display for CloseFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.reason.is_empty() {
+            write!(f, "closed with code {}", self.code)
+        } else {
+            write!(f, "closed with code {}: {}", self.code, self.reason)
+        }
+    }
+}
+
+/// The next message did not fit the buffer given to `Kcb::recv`, carried
+/// by its `InvalidInput` error. The message stays queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortBuffer {
+    /// size of the message in bytes
+    pub needed: usize,
+}
+
+impl fmt::Display for ShortBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "short buffer, {} bytes needed", self.needed)
+    }
+}
+
+impl error::Error for ShortBuffer {
+    fn description(&self) -> &str {
+        "short buffer"
+    }
+}
+
+/// How `Kcb::input` treats malformed segments, see `Kcb::set_parse_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "config-file", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "config-file", serde(rename_all = "kebab-case"))]
+pub enum ParseMode {
+    /// reject the whole datagram, nothing of it is applied
+    Strict,
+    /// skip malformed segments and stop at trailing bytes that are not
+    /// segments of this conversation, such as padding or extra framing
+    Lenient,
+}
+
+/// How slow start ends, see `Kcb::set_slow_start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "config-file", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "config-file", serde(rename_all = "kebab-case"))]
+pub enum SlowStart {
+    /// at ssthresh, which starts out at 2 and is set by losses (default)
+    Standard,
+    /// once the rtt of a round grew by more than an eighth of the last
+    /// one, before queues at the bottleneck overflow
+    HyStart,
+}
+
+/// What to do with a datagram the output sink fails to take, see
+/// `Kcb::set_output_error_policy`. Datagrams refused with `WouldBlock` are
+/// always held back until `Kcb::flush_output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "config-file", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "config-file", serde(rename_all = "kebab-case"))]
+pub enum OutputErrorPolicy {
+    /// drop it and leave recovery to retransmission (default)
+    Drop,
+    /// keep it and send it again ahead of the next flush
+    Retry,
+    /// fail the control block, `send` returns the error from then on
+    Fail,
+}
+
+const OUTPUT_RETRY_LIMIT: usize = 256; // datagrams held back for a retry
+
+/// A sink taking each datagram as the slices it is made of, the header
+/// and payload of every segment in it, see `Kcb::with_segment_output`.
+/// Payloads are handed over from where `send` put them, e.g. for sendmsg
+/// or a ring of a kernel bypass driver, without being copied into one
+/// buffer first.
+pub trait SegmentOutput {
+    /// send `bufs` as a single datagram, returns the bytes sent
+    fn send_segments(&mut self, bufs: &[IoSlice]) -> io::Result<usize>;
+}
+
+/// The `Write` a `Kcb` sees of a `SegmentOutput`, every write is one
+/// datagram.
+pub struct Segments<O>(pub O);
+
+impl<O: SegmentOutput> Write for Segments<O> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send_segments(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.0.send_segments(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The output sink and the datagrams it refused.
+struct Output<W: Write> {
+    sink: W,
+    policy: OutputErrorPolicy,
+    retry: VecDeque<Vec<u8>>,
+    errors: u64,
+    dropped: u64,
+    failed: Option<Error>,
+    // datagrams handed to `send` or `send_vectored`
+    sent: u64,
+    // write datagrams of several segments in the compact form
+    compact: bool,
+    // called with the header of every segment sent, at `current`
+    tap: Option<Box<FnMut(Direction, u32, &SegmentHeader) + Send>>,
+    current: u32,
+    #[cfg(feature = "fault-injection")]
+    faults: Faults,
+}
+
+impl<W: Write> Output<W> {
+    /// write out the datagram in `buffer`, padded to one of `padding`
+    fn send(&mut self, buffer: &mut BytesMut, padding: &[usize], mtu: usize) {
+        self.sent += 1;
+        self.tap(buffer);
+        if self.compact {
+            if let Some(datagram) = compact(buffer) {
+                buffer.clear();
+                buffer.put_slice(&datagram);
+            }
+        }
+        let len = buffer.len();
+        if let Some(&size) = padding.iter().find(|&&size| size >= len) {
+            let size = cmp::min(size, mtu);
+            if size > len {
+                buffer.put_slice(&vec![0; size - len]);
+            }
+        }
+        if self.failed.is_none() {
+            if !self.retry.is_empty() {
+                // keep the order, the sink is still refusing
+                self.hold(buffer);
+            } else if let Err(e) = self.write(buffer) {
+                self.error(buffer, e);
+            }
+        }
+        buffer.clear();
+    }
+
+    /// write out a datagram made of `bufs` in a single `write_vectored`
+    fn send_vectored(&mut self, bufs: &[&[u8]], padding: &[usize], mtu: usize) {
+        if self.compact || self.gathers() {
+            // the headers are rewritten anyway, nothing left to gather
+            let mut buffer = BytesMut::with_capacity(mtu);
+            for buf in bufs {
+                buffer.put_slice(buf);
+            }
+            return self.send(&mut buffer, padding, mtu);
+        }
+        self.sent += 1;
+        for buf in bufs {
+            self.tap(buf);
+        }
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let zeros = match padding.iter().find(|&&size| size >= len) {
+            Some(&size) if cmp::min(size, mtu) > len => vec![0; cmp::min(size, mtu) - len],
+            _ => Vec::new(),
+        };
+        if self.failed.is_some() {
+            return;
+        }
+        let mut slices = bufs.iter().map(|buf| IoSlice::new(buf)).collect::<SmallVec<[_; 16]>>();
+        if !zeros.is_empty() {
+            slices.push(IoSlice::new(&zeros));
+        }
+        let result = if self.retry.is_empty() {
+            self.sink.write_vectored(&slices).and_then(|n| if n < len {
+                Err(Error::new(ErrorKind::WriteZero, "datagram truncated"))
+            } else {
+                Ok(())
+            })
+        } else {
+            Err(Error::new(ErrorKind::WouldBlock, "output held back"))
+        };
+        if let Err(e) = result {
+            let datagram = slices.iter().flat_map(|s| s.iter().cloned()).collect::<Vec<u8>>();
+            if self.retry.is_empty() {
+                self.error(&datagram, e);
+            } else {
+                // keep the order, the sink is still refusing
+                self.hold(&datagram);
+            }
+        }
+    }
+
+    /// write a whole datagram to the sink
+    #[cfg(not(feature = "fault-injection"))]
+    fn write(&mut self, datagram: &mut [u8]) -> io::Result<()> {
+        self.sink.write_all(datagram)
+    }
+
+    /// write a whole datagram to the sink, unless an armed fault gets it
+    #[cfg(feature = "fault-injection")]
+    fn write(&mut self, datagram: &mut [u8]) -> io::Result<()> {
+        if self.faults.apply(datagram)? {
+            self.sink.write_all(datagram)?;
+        }
+        Ok(())
+    }
+
+    /// whether datagrams have to be gathered into one buffer before they
+    /// are written, for the faults to get at them
+    #[cfg(not(feature = "fault-injection"))]
+    fn gathers(&self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn gathers(&self) -> bool {
+        self.faults.armed()
+    }
+
+    /// hand the headers of the segments in `data` to the tap
+    fn tap(&mut self, data: &[u8]) {
+        if let Some(ref mut tap) = self.tap {
+            let mut rest = data;
+            while let Ok(Some((header, _, next))) = split_segment(rest) {
+                tap(Direction::Outgoing, self.current, &header);
+                rest = next;
+            }
+        }
+    }
+
+    fn error(&mut self, datagram: &[u8], e: Error) {
+        self.errors += 1;
+        if e.kind() == ErrorKind::WouldBlock {
+            self.hold(datagram);
+            return;
+        }
+        match self.policy {
+            OutputErrorPolicy::Drop => self.dropped += 1,
+            OutputErrorPolicy::Retry => self.hold(datagram),
+            OutputErrorPolicy::Fail => {
+                self.dropped += 1;
+                self.failed = Some(e);
+            }
+        }
+    }
+
+    fn hold(&mut self, datagram: &[u8]) {
+        if self.retry.len() >= OUTPUT_RETRY_LIMIT {
+            self.retry.pop_front();
+            self.dropped += 1;
+        }
+        self.retry.push_back(datagram.to_vec());
+    }
+
+    /// send the held back datagrams, stops at the first one refused again
+    fn retry(&mut self) {
+        while let Some(mut datagram) = self.retry.pop_front() {
+            if self.write(&mut datagram).is_err() {
+                self.errors += 1;
+                self.retry.push_front(datagram);
+                break;
+            }
+        }
+    }
+}
+
+/// Snapshot of connection statistics, see `Kcb::stats`
+#[derive(Debug, Clone, Default)]
+pub struct KcpStats {
+    /// smoothed round trip time in millisec
+    pub srtt: u32,
+    /// current retransmission timeout in millisec
+    pub rto: u32,
+    /// congestion window in segments
+    pub cwnd: u32,
+    /// number of segments retransmitted after a timeout
+    pub retransmits: u32,
+    /// total payload bytes acknowledged by the remote
+    pub delivered: u64,
+    /// latest delivery rate sample in bytes per second
+    pub delivery_rate: u64,
+    /// estimated bottleneck bandwidth in bytes per second, the highest
+    /// delivery rate among recent samples
+    pub bandwidth: u64,
+    /// datagrams the output sink failed to take, including failed retries
+    pub output_errors: u64,
+    /// datagrams given up on after an output error
+    pub output_dropped: u64,
+    /// data segments a flush may emit while backing off from an output sink
+    /// under pressure, `u32::MAX` when unlimited
+    pub flush_budget: u32,
+    /// current mtu in bytes
+    pub mtu: usize,
+    /// times the mtu was stepped down after a black hole was detected
+    pub mtu_downshifts: u32,
+    /// most transmissions any single segment needed so far
+    pub max_xmit: u32,
+    /// data segments sent, retransmissions included
+    pub segments_sent: u64,
+    /// malformed segments skipped in lenient parsing mode
+    pub malformed: u64,
+    /// data segments dropped because they had been received before
+    pub duplicates: u64,
+    /// data segments dropped for arriving beyond the receive window
+    pub out_of_window: u64,
+    /// data segments that arrived after one with a higher sn
+    pub reordered: u64,
+    /// the most sns a reordered segment arrived behind
+    pub reorder_distance: u32,
+    /// data segments refused or dropped for the reassembly limits or the
+    /// memory budget
+    pub reassembly_dropped: u64,
+    /// payload bytes held by the send and receive queues
+    pub memory: usize,
+}
+
+/// Iterator over the complete messages in the receive queue, created by
+/// `Kcb::drain_messages`. Every message is taken with `recv`, so the
+/// iteration also ends once the conversation was reset, `recv` tells why
+pub struct DrainMessages<'a, W: Write + 'a> {
+    kcb: &'a mut Kcb<W>,
+}
+
+impl<'a, W: Write> Iterator for DrainMessages<'a, W> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let size = match self.kcb.peeksize() {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+        let mut buf = vec![0; size];
+        match self.kcb.recv(&mut buf) {
+            Ok(_) => Some(buf),
+            Err(_) => None,
+        }
+    }
+}
 
-    output: W,
+impl<O: SegmentOutput> Kcb<Segments<O>> {
+    /// A control block handing its datagrams to `output` as slices, with
+    /// `set_vectored` on.
+    pub fn with_segment_output(conv: u32, output: O) -> Kcb<Segments<O>> {
+        let mut kcb = Kcb::new(conv, Segments(output));
+        kcb.set_vectored(true);
+        kcb
+    }
 }
 
 impl<W: Write> Kcb<W> {
@@ -134,10 +908,53 @@ impl<W: Write> Kcb<W> {
             updated: false,
             ts_probe: 0,
             probe_wait: 0,
+            probe_init: KCP_PROBE_INIT,
+            probe_limit: KCP_PROBE_LIMIT,
             incr: 0,
             fastresend: 0,
             nocwnd: false,
+            cwnd_validation: false,
+            cwnd_limited: false,
+            ts_last_xmit: 0,
+            pace_credit: u64::max_value(),
+            ts_pace: 0,
+            cc: Box::new(Classic),
+            slow_start: SlowStart::Standard,
+            hs_round_end: 0,
+            hs_last_min: u32::max_value(),
+            hs_cur_min: u32::max_value(),
+            hs_samples: 0,
             stream: false,
+            stream_barrier: false,
+            skew_valid: false,
+            skew_offset: 0,
+            skew_base_ts: 0,
+            skew_base_offset: 0,
+            skew_drift: 0,
+            epoch: Instant::now(),
+            padding: Vec::new(),
+            delivered: 0,
+            rate_ts: 0,
+            rate_delivered: 0,
+            delivery_rate: 0,
+            bw_samples: VecDeque::with_capacity(KCP_BW_SAMPLES),
+            bandwidth: 0,
+            established: false,
+            initial_sn: false,
+            learn_isn: false,
+            ts_isn: 0,
+            tell_isn: false,
+            extensions: 0,
+            peer_extensions: 0,
+            downshift: false,
+            small_acked: 0,
+            downshifts: 0,
+            snd_msg_open: false,
+            partials: VecDeque::new(),
+            flush_budget: u32::max_value(),
+            burst_limit: u32::max_value(),
+            pressure_streak: 0,
+            vectored: false,
 
             conv: conv,
             snd_wnd: KCP_WND_SND,
@@ -147,78 +964,191 @@ impl<W: Write> Kcb<W> {
             mss: KCP_MTU_DEF - KCP_OVERHEAD,
             // user: user,
             buffer: BytesMut::with_capacity((KCP_MTU_DEF + KCP_OVERHEAD) * 3),
-            snd_queue: VecDeque::new(),
-            rcv_queue: VecDeque::new(),
-            snd_buf: VecDeque::new(),
-            rcv_buf: VecDeque::new(),
-            acklist: Vec::new(),
+            snd_queue: VecDeque::with_capacity(initial_capacity()),
+            rcv_queue: VecDeque::with_capacity(initial_capacity()),
+            snd_buf: VecDeque::with_capacity(initial_capacity()),
+            rcv_buf: VecDeque::with_capacity(initial_capacity()),
+            acklist: SmallVec::with_capacity(initial_capacity()),
+            ack_delay: 0,
+            playout: 0,
+            playout_base: None,
+            ts_ack: 0,
+            ack_flush: None,
+            ts_ack_flush: 0,
             rx_rto: KCP_RTO_DEF,
             rx_minrto: KCP_RTO_MIN,
             interval: KCP_INTERVAL,
             ts_flush: KCP_INTERVAL,
-            ssthresh: KCP_THRESH_INIT, // dead_link: KCP_DEADLINK,
-            output: output,
+            ssthresh: KCP_THRESH_INIT,
+            dead_link: KCP_DEADLINK,
+            dead: false,
+            alarm: None,
+            alarm_xmit: 0,
+            alarm_rto: 0,
+            max_xmit: 0,
+            segments_sent: 0,
+            parse_mode: ParseMode::Strict,
+            malformed: 0,
+            duplicates: 0,
+            out_of_window: 0,
+            rcv_top: 0,
+            reordered: 0,
+            reorder_distance: 0,
+            max_message: usize::MAX,
+            reassembly_chains: usize::MAX,
+            reassembly_bytes: usize::MAX,
+            reassembly_dropped: 0,
+            held_chains: 0,
+            held_bytes: 0,
+            evicted: Vec::new(),
+            memory_budget: usize::MAX,
+            max_fragments: 255,
+            snd_backlog: KCP_BACKLOG,
+            trace: None,
+            close: None,
+            close_sn: 0,
+            close_xmit: 0,
+            ts_close: 0,
+            close_acked: false,
+            peer_close: None,
+            reset: None,
+            keepalive: 0,
+            ts_keepalive: 0,
+            keepalive_sent: 0,
+            wins_left: 0,
+            ts_wins: 0,
+            paused: false,
+            wnd_clamp: u32::MAX,
+            output: Output {
+                sink: output,
+                policy: OutputErrorPolicy::Drop,
+                retry: VecDeque::new(),
+                errors: 0,
+                dropped: 0,
+                failed: None,
+                sent: 0,
+                compact: false,
+                tap: None,
+                current: 0,
+                #[cfg(feature = "fault-injection")]
+                faults: Faults::default(),
+            },
         }
     }
 
-    /// user/upper level recv: returns size, returns Err for EAGAIN
+    /// user/upper level recv: returns the size of the message read into
+    /// `buf`, `WouldBlock` while no complete message is queued, and 0 once
+    /// the peer closed the conversation and everything was read. a message
+    /// larger than `buf` fails with `InvalidInput` carrying `ShortBuffer`.
+    /// in stream mode the segments following the first one are read into
+    /// the same call as long as they fit
     pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.rcv_queue.is_empty() {
-            return Err(Error::new(ErrorKind::Other, "EOF"));
+        if let Some(ref mut trace) = self.trace {
+            trace.events.push(TraceEvent::Recv {
+                ts: self.current,
+                len: buf.len(),
+            });
         }
+        self.check_reset()?;
         let peeksize = match self.peeksize() {
             Ok(x) => x,
-            Err(_) => return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected EOF")),
+            Err(_) if self.rcv_queue.is_empty() && self.peer_close.is_some() => return Ok(0),
+            // what arrived before can still be read
+            Err(_) if self.dead => return Err(dead_link()),
+            Err(_) => return Err(Error::new(ErrorKind::WouldBlock, "no message yet")),
         };
 
         if peeksize > buf.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "short buffer"));
+            return Err(Error::new(ErrorKind::InvalidInput, ShortBuffer { needed: peeksize }));
         }
 
         let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
 
         // merge fragment
         let mut buf = Cursor::new(buf);
-        let mut index: usize = 0;
-        for seg in &self.rcv_queue {
+        self.pop_message(&mut buf)?;
+        assert!(buf.position() as usize == peeksize);
+
+        // move available data from rcv_buf -> rcv_queue
+        self.move_rcv_buf();
+
+        // no boundaries to keep in stream mode, fill the buffer
+        if self.stream {
+            while let Ok(size) = self.peeksize() {
+                if size > buf.get_ref().len() - buf.position() as usize {
+                    break;
+                }
+                self.pop_message(&mut buf)?;
+                self.move_rcv_buf();
+            }
+        }
+
+        // fast recover
+        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
+            self.window_reopened();
+        }
+        Ok(buf.position() as usize)
+    }
+
+    /// move the next message of rcv_queue into `buf`
+    fn pop_message(&mut self, buf: &mut Cursor<&mut [u8]>) -> io::Result<()> {
+        while let Some(seg) = self.rcv_queue.pop_front() {
             buf.write_all(&seg.data)?;
-            index += 1;
             if seg.frg == 0 {
                 break;
             }
         }
-        if index > 0 {
-            let new_rcv_queue = self.rcv_queue.split_off(index);
-            self.rcv_queue = new_rcv_queue;
-        }
-        assert!(buf.position() as usize == peeksize);
+        Ok(())
+    }
 
-        // move available data from rcv_buf -> rcv_queue
-        index = 0;
-        let mut nrcv_que = self.rcv_queue.len();
-        for seg in &self.rcv_buf {
-            if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
-                nrcv_que += 1;
-                self.rcv_nxt += 1;
-                index += 1;
+    /// user/upper level batch recv: returns up to `max` complete messages,
+    /// data is moved from rcv_buf to rcv_queue once after all of them.
+    /// fails like `recv` once the conversation was reset and while no
+    /// complete message is queued, an empty batch is the end of a
+    /// conversation the peer closed
+    pub fn recv_many(&mut self, max: usize) -> io::Result<Vec<Bytes>> {
+        self.check_reset()?;
+        if self.peeksize().is_err() {
+            return if self.rcv_queue.is_empty() && self.peer_close.is_some() {
+                Ok(Vec::new())
+            } else if self.dead {
+                Err(dead_link())
             } else {
-                break;
-            }
+                Err(Error::new(ErrorKind::WouldBlock, "no message yet"))
+            };
         }
+        let mut msgs = Vec::new();
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
 
-        if index > 0 {
-            let new_rcv_buf = self.rcv_buf.split_off(index);
-            self.rcv_queue.append(&mut self.rcv_buf);
-            self.rcv_buf = new_rcv_buf;
+        while msgs.len() < max {
+            let peeksize = match self.peeksize() {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            let mut msg = BytesMut::with_capacity(peeksize);
+            while let Some(seg) = self.rcv_queue.pop_front() {
+                msg.extend_from_slice(&seg.data);
+                if seg.frg == 0 {
+                    break;
+                }
+            }
+            msgs.push(msg.freeze());
         }
 
+        // move available data from rcv_buf -> rcv_queue
+        self.move_rcv_buf();
+
         // fast recover
         if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
-            // ready to send back KCP_CMD_WINS in `flush`
-            // tell remote my window size
-            self.probe |= KCP_ASK_TELL;
+            self.window_reopened();
         }
-        Ok(buf.position() as usize)
+        Ok(msgs)
+    }
+
+    /// yield every complete message currently in the recv queue, stops at
+    /// the first message still waiting for fragments
+    pub fn drain_messages(&mut self) -> DrainMessages<'_, W> {
+        DrainMessages { kcb: self }
     }
 
     /// check the size of next message in the recv queue
@@ -227,6 +1157,9 @@ impl<W: Write> Kcb<W> {
             Some(x) => x,
             None => return Err(-1),
         };
+        if self.playout_wait(seg.ts) > 0 {
+            return Err(-1);
+        }
         if seg.frg == 0 {
             return Ok(seg.data.len());
         }
@@ -243,8 +1176,26 @@ impl<W: Write> Kcb<W> {
         Ok(length)
     }
 
-    /// user/upper level send, returns Err for error
+    /// user/upper level send, returns the bytes queued, Err for error.
+    /// messages are queued whole or not at all, in stream mode as much as
+    /// fits below the send backlog is taken
     pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut trace) = self.trace {
+            trace.events.push(TraceEvent::Send {
+                ts: self.current,
+                data: buf.to_vec(),
+            });
+        }
+        if let Some(ref e) = self.output.failed {
+            return Err(Error::new(e.kind(), format!("output failed: {}", e)));
+        }
+        self.check_reset()?;
+        if self.dead {
+            return Err(dead_link());
+        }
+        if self.close.is_some() {
+            return Err(Error::new(ErrorKind::BrokenPipe, "closed"));
+        }
         let n = buf.len();
         if n == 0 {
             return Err(Error::new(ErrorKind::InvalidInput, "no data available"));
@@ -252,45 +1203,142 @@ impl<W: Write> Kcb<W> {
         let mut buf = Cursor::new(buf);
 
         // append to previous segment in streaming mode (if possible)
-        if self.stream {
+        if self.stream && !self.stream_barrier {
             if let Some(seg) = self.snd_queue.back_mut() {
                 let l = seg.data.len();
                 if l < self.mss as usize {
                     let new_len = cmp::min(l + n, self.mss as usize);
-                    seg.data.resize(new_len, 0);
-                    buf.read_exact(&mut seg.data[l..new_len])?;
+                    let mut data = BytesMut::with_capacity(new_len);
+                    data.extend_from_slice(&seg.data);
+                    data.resize(new_len, 0);
+                    buf.read_exact(&mut data[l..new_len])?;
+                    seg.data = data.freeze();
                     seg.frg = 0;
                     if buf.remaining() == 0 {
-                        return Ok(1);
+                        return Ok(n);
                     }
                 }
             };
         }
 
-        let count = if buf.remaining() <= self.mss as usize {
+        let mut count = if buf.remaining() <= self.mss as usize {
             1
         } else {
             (buf.remaining() + self.mss as usize - 1) / self.mss as usize
         };
+        let room = KCP_QUEUE_LIMIT - self.snd_queue.len();
 
-        if count > 255 {
-            return Err(Error::new(ErrorKind::InvalidInput, "data too long"));
+        if self.stream {
+            // no fragment numbers to run out of, take what fits like
+            // `Write::write` does
+            let room = cmp::min(room, self.snd_backlog.saturating_sub(self.waitsnd()));
+            if room == 0 {
+                if buf.remaining() < n {
+                    return Ok(n - buf.remaining());
+                }
+                return Err(Error::new(ErrorKind::WouldBlock, "send queue full"));
+            }
+            count = cmp::min(count, room);
+        } else {
+            if count > 255 {
+                return Err(Error::new(ErrorKind::InvalidInput, "data too long"));
+            }
+            if count > room {
+                return Err(Error::new(ErrorKind::WouldBlock, "send queue full"));
+            }
         }
         assert!(count > 0);
-        let count = count as u8;
 
         // fragment
         for i in 0..count {
             let size = cmp::min(self.mss as usize, buf.remaining());
             let mut seg = Segment::default();
-            seg.data.resize(size, 0);
-            buf.read_exact(&mut seg.data)?;
-            seg.frg = if !self.stream { count - i - 1 } else { 0 };
+            seg.cmd = KCP_CMD_PUSH;
+            let mut data = vec![0; size];
+            buf.read_exact(&mut data)?;
+            seg.data = Bytes::from(data);
+            seg.frg = if !self.stream { (count - i - 1) as u8 } else { 0 };
             self.snd_queue.push_back(seg);
         }
+        self.stream_barrier = false;
         Ok(n - buf.remaining())
     }
 
+    /// user/upper level batch send: enqueues every message in `msgs` and,
+    /// if `flush` is set, pushes them out with a single `flush` so they are
+    /// packed together. returns the total bytes queued, Err for error.
+    /// in stream mode it stops at the first message only partly taken, or
+    /// once the queue is full after something was taken; the caller has to
+    /// send the rest again, from `total` bytes into the batch
+    pub fn send_many<I: IntoIterator<Item = Bytes>>(
+        &mut self,
+        msgs: I,
+        flush: bool,
+    ) -> io::Result<usize> {
+        let mut total = 0;
+        for msg in msgs {
+            let n = match self.send(&msg) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock && total > 0 => break,
+                Err(e) => return Err(e),
+            };
+            total += n;
+            if n < msg.len() {
+                break;
+            }
+        }
+        if flush {
+            self.flush();
+        }
+        Ok(total)
+    }
+
+    /// user/upper level send of a message that the remote may deliver as
+    /// soon as all of its fragments arrive, without waiting for earlier
+    /// messages. not available in stream mode, returns Err for error
+    pub fn send_unordered(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_reset()?;
+        if self.dead {
+            return Err(dead_link());
+        }
+        if self.stream {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unordered send in stream mode",
+            ));
+        }
+        let n = buf.len();
+        if n == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "no data available"));
+        }
+        let mut buf = Cursor::new(buf);
+
+        let count = (n + self.mss as usize - 1) / self.mss as usize;
+        if count > KCP_FRG_FIRST as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "data too long"));
+        }
+        if count > KCP_QUEUE_LIMIT - self.snd_queue.len() {
+            return Err(Error::new(ErrorKind::WouldBlock, "send queue full"));
+        }
+        let count = count as u8;
+
+        // fragment
+        for i in 0..count {
+            let size = cmp::min(self.mss as usize, buf.remaining());
+            let mut seg = Segment::default();
+            seg.cmd = KCP_CMD_UPUSH;
+            let mut data = vec![0; size];
+            buf.read_exact(&mut data)?;
+            seg.data = Bytes::from(data);
+            seg.frg = count - i - 1;
+            if i == 0 {
+                seg.frg |= KCP_FRG_FIRST;
+            }
+            self.snd_queue.push_back(seg);
+        }
+        Ok(n)
+    }
+
     fn update_ack(&mut self, rtt: u32) {
         if self.rx_srtt == 0 {
             self.rx_srtt = rtt;
@@ -309,34 +1357,124 @@ impl<W: Write> Kcb<W> {
         }
         let rto = self.rx_srtt + cmp::max(self.interval, 4 * self.rx_rttval);
         self.rx_rto = bound(self.rx_minrto, rto, KCP_RTO_MAX);
+        if self.slow_start == SlowStart::HyStart && self.cwnd < self.ssthresh {
+            self.hystart_sample(rtt);
+        }
+        let current = self.current;
+        self.congestion(|cc, window| cc.on_rtt(window, rtt, current));
     }
 
-    #[inline]
-    fn shrink_buf(&mut self) {
-        self.snd_una = match self.snd_buf.front() {
-            Some(seg) => seg.sn,
-            None => self.snd_nxt,
-        };
-    }
-
-    fn parse_ack(&mut self, sn: u32) {
-        if sn < self.snd_una || sn >= self.snd_nxt {
+    /// leave slow start when the least rtt of the first acks of a round,
+    /// a round being everything in flight when it began, grew by more
+    /// than an eighth (4 to 16 millisec) over the last round
+    fn hystart_sample(&mut self, rtt: u32) {
+        if timediff(self.snd_una, self.hs_round_end) >= 0 {
+            self.hs_round_end = self.snd_nxt;
+            self.hs_last_min = self.hs_cur_min;
+            self.hs_cur_min = u32::max_value();
+            self.hs_samples = 0;
+        }
+        if self.hs_samples >= KCP_HYSTART_SAMPLES {
             return;
         }
-        for i in 0..self.snd_buf.len() {
-            if sn == self.snd_buf[i].sn {
-                self.snd_buf.remove(i);
+        self.hs_samples += 1;
+        self.hs_cur_min = cmp::min(self.hs_cur_min, rtt);
+        if self.hs_samples == KCP_HYSTART_SAMPLES && self.cwnd >= KCP_HYSTART_LOW &&
+            self.hs_last_min != u32::max_value()
+        {
+            let eta = bound(4, self.hs_last_min / 8, 16);
+            if self.hs_cur_min >= self.hs_last_min + eta {
+                self.ssthresh = self.cwnd;
+            }
+        }
+    }
+
+    /// millisec until a message sent at `ts` by the peer's clock is due
+    /// for delivery, 0 once it is or without a playout delay
+    fn playout_wait(&self, ts: u32) -> u32 {
+        match self.playout_base {
+            Some(base) if self.playout > 0 => {
+                let due = ts.wrapping_add(base as u32).wrapping_add(self.playout);
+                cmp::max(timediff(due, self.current), 0) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// sample the remote clock from the `ts` of a pushed segment, the peer
+    /// stamped it at send time so half of the smoothed rtt is added back
+    fn update_skew(&mut self, ts: u32) {
+        let transit = timediff(self.current, ts);
+        if self.playout_base.map_or(true, |base| transit < base) {
+            self.playout_base = Some(transit);
+        }
+        let offset = timediff(ts, self.current) + (self.rx_srtt / 2) as i32;
+        if !self.skew_valid {
+            self.skew_valid = true;
+            self.skew_offset = offset;
+            self.skew_base_ts = self.current;
+            self.skew_base_offset = offset;
+            return;
+        }
+        self.skew_offset = ((7 * self.skew_offset as i64 + offset as i64) / 8) as i32;
+
+        let elapsed = timediff(self.current, self.skew_base_ts);
+        if elapsed >= 1000 {
+            let delta = self.skew_offset as i64 - self.skew_base_offset as i64;
+            self.skew_drift = (delta * 1_000_000 / elapsed as i64) as i32;
+        }
+    }
+
+    #[inline]
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for i in 0..self.snd_buf.len() {
+            if sn == self.snd_buf[i].sn {
+                if let Some(seg) = self.snd_buf.remove(i) {
+                    self.delivered += seg.len() as u64;
+                    count_acked(seg.len(), self.mtu, &mut self.small_acked);
+                }
                 break;
-            } else if sn < self.snd_buf[i].sn {
+            } else if timediff(sn, self.snd_buf[i].sn) < 0 {
                 break;
             }
         }
     }
 
+    /// acknowledge every segment from `first` to `last` inclusive
+    fn parse_ack_range(&mut self, first: u32, last: u32) {
+        if timediff(last, self.snd_una) < 0 || timediff(first, self.snd_nxt) >= 0 {
+            return;
+        }
+        let delivered = &mut self.delivered;
+        let small_acked = &mut self.small_acked;
+        let mtu = self.mtu;
+        self.snd_buf.retain(|seg| if timediff(seg.sn, first) >= 0 &&
+            timediff(seg.sn, last) <= 0
+        {
+            *delivered += seg.len() as u64;
+            count_acked(seg.len(), mtu, small_acked);
+            false
+        } else {
+            true
+        });
+    }
+
     fn parse_una(&mut self, una: u32) {
         let mut index: usize = 0;
         for seg in &self.snd_buf {
-            if una > seg.sn {
+            if timediff(una, seg.sn) > 0 {
+                self.delivered += seg.len() as u64;
+                count_acked(seg.len(), self.mtu, &mut self.small_acked);
                 index += 1;
             } else {
                 break;
@@ -348,12 +1486,66 @@ impl<W: Write> Kcb<W> {
         }
     }
 
+    /// hand the congestion window to the controller
+    fn congestion<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut (CongestionControl + Send), &mut Window),
+    {
+        let mut window = Window {
+            cwnd: self.cwnd,
+            ssthresh: self.ssthresh,
+            incr: self.incr,
+            mss: self.mss as u32,
+        };
+        f(&mut *self.cc, &mut window);
+        self.cwnd = window.cwnd;
+        self.ssthresh = window.ssthresh;
+        self.incr = window.incr;
+    }
+
+    /// halve cwnd for every rto without data sent, down to a restart
+    /// window, keeping ssthresh high enough to slow start back quickly
+    fn decay_idle_cwnd(&mut self) {
+        let rto = cmp::max(self.rx_rto, 1);
+        let idle = timediff(self.current, self.ts_last_xmit);
+        if idle <= rto as i32 || self.cwnd <= KCP_CWND_RESTART {
+            return;
+        }
+        self.ssthresh = cmp::max(self.ssthresh, self.cwnd / 4 * 3);
+        let periods = cmp::min(idle as u32 / rto, 31);
+        self.cwnd = cmp::max(self.cwnd >> periods, KCP_CWND_RESTART);
+        self.incr = self.cwnd * self.mss as u32;
+        self.ts_last_xmit = self.current;
+    }
+
+    /// sample the delivery rate once per smoothed rtt (at least one
+    /// interval) and keep the max over recent samples as the estimated
+    /// bottleneck bandwidth
+    fn sample_delivery_rate(&mut self) {
+        let elapsed = timediff(self.current, self.rate_ts);
+        if elapsed < cmp::max(self.rx_srtt, self.interval) as i32 {
+            return;
+        }
+        let rate = (self.delivered - self.rate_delivered) * 1000 / elapsed as u64;
+        self.rate_ts = self.current;
+        self.rate_delivered = self.delivered;
+        self.delivery_rate = rate;
+
+        if self.bw_samples.len() >= KCP_BW_SAMPLES {
+            self.bw_samples.pop_front();
+        }
+        self.bw_samples.push_back(rate);
+        self.bandwidth = self.bw_samples.iter().cloned().max().unwrap_or(0);
+        let current = self.current;
+        self.congestion(|cc, window| cc.on_delivery(window, rate, current));
+    }
+
     fn parse_fastack(&mut self, sn: u32) {
-        if sn < self.snd_una || sn >= self.snd_nxt {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
             return;
         }
         for seg in &mut self.snd_buf {
-            if sn < seg.sn {
+            if timediff(sn, seg.sn) < 0 {
                 break;
             } else if sn != seg.sn {
                 seg.fastack += 1;
@@ -361,12 +1553,124 @@ impl<W: Write> Kcb<W> {
         }
     }
 
-    fn parse_data(&mut self, newseg: Segment) {
+    /// whether rcv_buf may take a segment `sn` of `len` bytes. past the
+    /// reassembly limits the oldest message held out of order is dropped to
+    /// make room, unless the newcomer belongs to it or is older still. the
+    /// segment `rcv_nxt` waits for and ones already held always fit
+    fn reassembly_admits(&mut self, sn: u32, cmd: u8, frg: u8, len: usize) -> bool {
+        if timediff(sn, self.rcv_nxt) <= 0
+            || timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0
+        {
+            return true;
+        }
+        let index = match self.rcv_buf.binary_search_by(|seg| timediff(seg.sn, sn).cmp(&0)) {
+            // a segment held already costs nothing more
+            Ok(_) => return true,
+            Err(index) => index,
+        };
+        if self.memory_budget != usize::MAX && self.memory() + len > self.memory_budget {
+            return false;
+        }
+        let end = chain_end(cmd, sn, frg);
+        if self.evicted.contains(&end) {
+            // only a placeholder is kept
+            return true;
+        }
+        let joins = {
+            let live = |seg: Option<&Segment>| {
+                seg.map_or(false, |seg| !seg.delivered && seg.chain_end() == end)
+            };
+            (index > 0 && live(self.rcv_buf.get(index - 1))) || live(self.rcv_buf.get(index))
+        };
+        loop {
+            let chains = self.held_chains + if joins { 0 } else { 1 };
+            if chains <= self.reassembly_chains && self.held_bytes + len <= self.reassembly_bytes {
+                return true;
+            }
+            match self.oldest_chain() {
+                Some((start, oldest)) if timediff(oldest, end) < 0 => self.evict(start),
+                _ => return false,
+            }
+        }
+    }
+
+    /// index and last sn of the oldest message held out of order that may
+    /// be dropped, neither one waiting for room in rcv_queue nor one with
+    /// fragments there already
+    fn oldest_chain(&self) -> Option<(usize, u32)> {
+        let mut next = self.rcv_nxt;
+        let mut partial = self.rcv_queue.back().map(Segment::chain_end);
+        for (i, seg) in self.rcv_buf.iter().enumerate() {
+            if seg.sn == next {
+                next = next.wrapping_add(1);
+                partial = Some(seg.chain_end());
+            } else if !seg.delivered && partial != Some(seg.chain_end()) {
+                return Some((i, seg.chain_end()));
+            }
+        }
+        None
+    }
+
+    /// drop the message held from `index` of rcv_buf on, leaving
+    /// placeholders so that `rcv_nxt` still advances over it. fragments of
+    /// it arriving later are turned into placeholders too
+    fn evict(&mut self, index: usize) {
+        let end = self.rcv_buf[index].chain_end();
+        for seg in self.rcv_buf.iter_mut().skip(index) {
+            if seg.delivered || seg.chain_end() != end {
+                break;
+            }
+            self.held_bytes -= seg.len();
+            self.reassembly_dropped += 1;
+            seg.data = Bytes::new();
+            seg.delivered = true;
+        }
+        self.held_chains -= 1;
+        self.evicted.push(end);
+    }
+
+    /// whether the segment at `index` of rcv_buf shares its message with
+    /// another one held, which then sits right next to it
+    fn chain_shared(&self, index: usize) -> bool {
+        let end = self.rcv_buf[index].chain_end();
+        let live = |seg: Option<&Segment>| {
+            seg.map_or(false, |seg| !seg.delivered && seg.chain_end() == end)
+        };
+        (index > 0 && live(self.rcv_buf.get(index - 1))) || live(self.rcv_buf.get(index + 1))
+    }
+
+    /// count the segment at `index` of rcv_buf in the held totals
+    fn hold(&mut self, index: usize) {
+        if self.rcv_buf[index].delivered {
+            return;
+        }
+        if !self.chain_shared(index) {
+            self.held_chains += 1;
+        }
+        self.held_bytes += self.rcv_buf[index].len();
+    }
+
+    /// take the segment at `index` of rcv_buf out of the held totals
+    fn release(&mut self, index: usize) {
+        if self.rcv_buf[index].delivered {
+            return;
+        }
+        if !self.chain_shared(index) {
+            self.held_chains -= 1;
+        }
+        self.held_bytes -= self.rcv_buf[index].len();
+    }
+
+    fn parse_data(&mut self, mut newseg: Segment) {
         let sn = newseg.sn;
-        if sn >= self.rcv_nxt + self.rcv_wnd || sn < self.rcv_nxt {
+        if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0 {
             // ikcp_segment_delete(kcp, newseg);
             return;
         }
+        if timediff(sn, self.rcv_nxt) < 0 {
+            self.duplicates += 1;
+            return;
+        }
 
         let mut repeat = false;
         let mut index: usize = self.rcv_buf.len();
@@ -374,77 +1678,279 @@ impl<W: Write> Kcb<W> {
             if sn == seg.sn {
                 repeat = true;
                 break;
-            } else if sn > seg.sn {
+            } else if timediff(sn, seg.sn) > 0 {
                 break;
             }
             index -= 1;
         }
 
         if !repeat {
+            if timediff(sn, self.rcv_top) < 0 {
+                // arrived after a later one
+                self.reordered += 1;
+                let distance = self.rcv_top.wrapping_sub(sn) - 1;
+                self.reorder_distance = cmp::max(self.reorder_distance, distance);
+            } else {
+                self.rcv_top = sn.wrapping_add(1);
+            }
+            let unordered = newseg.cmd == KCP_CMD_UPUSH;
+            if !self.evicted.is_empty() && self.evicted.contains(&newseg.chain_end()) {
+                // the rest of its message was dropped to make room
+                self.reassembly_dropped += 1;
+                newseg.data = Bytes::new();
+                newseg.delivered = true;
+            }
             self.rcv_buf.insert(index, newseg);
+            self.hold(index);
+            if unordered {
+                self.deliver_unordered(index);
+            }
         } else {
             // ikcp_segment_delete(kcp, newseg);
+            self.duplicates += 1;
         }
 
         // move available data from rcv_buf -> rcv_queue
-        index = 0;
+        self.move_rcv_buf();
+    }
+
+    /// queue an ack for `sn`, a duplicate only refreshes the timestamp to
+    /// the latest one. once a window's worth of acks is pending, further
+    /// ones are dropped and left to una and retransmission
+    fn ack_push(&mut self, sn: u32, ts: u32) {
+        if let Some(ack) = self.acklist.iter_mut().rev().find(|ack| ack.0 == sn) {
+            if timediff(ts, ack.1) > 0 {
+                ack.1 = ts;
+            }
+            return;
+        }
+        if self.acklist.is_empty() {
+            self.ts_ack = self.current;
+        }
+        if self.acklist.len() < cmp::min(self.rcv_wnd as usize, KCP_QUEUE_LIMIT) {
+            self.acklist.push((sn, ts));
+        }
+    }
+
+    /// tell the peer the window is open again, right at the next flush and
+    /// a few times more every rto until it sends data
+    fn window_reopened(&mut self) {
+        self.wins_left = KCP_WINS_RESEND;
+        self.ts_wins = self.current;
+    }
+
+    /// move available data from rcv_buf -> rcv_queue, unordered segments
+    /// that have already been delivered are skipped
+    fn move_rcv_buf(&mut self) {
         let mut nrcv_que = self.rcv_queue.len();
-        for seg in &self.rcv_buf {
-            if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
-                nrcv_que += 1;
-                self.rcv_nxt += 1;
-                index += 1;
-            } else {
+        while nrcv_que < self.rcv_wnd as usize {
+            match self.rcv_buf.front() {
+                Some(seg) if seg.sn == self.rcv_nxt => {}
+                _ => break,
+            }
+            self.release(0);
+            let mut seg = self.rcv_buf.pop_front().unwrap();
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            if seg.delivered {
+                continue;
+            }
+            if seg.cmd == KCP_CMD_UPUSH {
+                seg.frg &= !KCP_FRG_FIRST;
+            }
+            self.rcv_queue.push_back(seg);
+            nrcv_que += 1;
+        }
+        if !self.evicted.is_empty() {
+            let rcv_nxt = self.rcv_nxt;
+            self.evicted.retain(|&end| timediff(end, rcv_nxt) >= 0);
+        }
+    }
+
+    /// hand an unordered message to rcv_queue as soon as all of its
+    /// fragments are in rcv_buf, leaving delivered placeholders behind so
+    /// that `rcv_nxt` still advances over them
+    fn deliver_unordered(&mut self, index: usize) {
+        let end = self.rcv_buf[index].chain_end();
+
+        // find the first fragment
+        let mut start = index;
+        loop {
+            let seg = &self.rcv_buf[start];
+            if seg.cmd != KCP_CMD_UPUSH || seg.delivered {
+                return;
+            }
+            if seg.frg & KCP_FRG_FIRST != 0 {
                 break;
             }
+            if start == 0 || self.rcv_buf[start - 1].sn.wrapping_add(1) != seg.sn {
+                return;
+            }
+            start -= 1;
         }
-        if index > 0 {
-            let new_rcv_buf = self.rcv_buf.split_off(index);
-            self.rcv_queue.append(&mut self.rcv_buf);
-            self.rcv_buf = new_rcv_buf;
+        let first = self.rcv_buf[start].sn;
+        if first.wrapping_add((self.rcv_buf[start].frg & !KCP_FRG_FIRST) as u32) != end {
+            return;
+        }
+
+        // make sure every fragment up to the last one has arrived
+        let count = end.wrapping_sub(first) as usize + 1;
+        if start + count > self.rcv_buf.len() {
+            return;
+        }
+        if self.rcv_queue.len() + count > cmp::min(self.rcv_wnd as usize, KCP_QUEUE_LIMIT) {
+            // no room in the window, leave it to the ordered path
+            return;
+        }
+        for (i, seg) in self.rcv_buf.iter().skip(start).take(count).enumerate() {
+            if seg.sn != first.wrapping_add(i as u32) || seg.cmd != KCP_CMD_UPUSH || seg.delivered {
+                return;
+            }
+        }
+
+        self.held_chains -= 1;
+        for seg in self.rcv_buf.iter_mut().skip(start).take(count) {
+            self.held_bytes -= seg.len();
+            let mut msg = Segment::default();
+            msg.conv = seg.conv;
+            msg.cmd = seg.cmd;
+            msg.frg = seg.frg & !KCP_FRG_FIRST;
+            msg.sn = seg.sn;
+            msg.ts = seg.ts;
+            msg.data = mem::replace(&mut seg.data, Bytes::new());
+            seg.delivered = true;
+            self.rcv_queue.push_back(msg);
         }
     }
 
     /// when you received a low level packet (eg. UDP packet), call it
-    pub fn input(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let n = buf.len();
-        let mut buf = Cursor::new(buf);
+    pub fn input(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.input_from(data, None)
+    }
+
+    /// Same as `input`, for a datagram received into a shared buffer: the
+    /// payload of the segment `rcv_nxt` waits for is taken as a slice of
+    /// `data` instead of a copy. Such a slice keeps the whole allocation
+    /// behind `data` alive until it is read, so receive into a buffer
+    /// split off a larger one rather than into one of its own per
+    /// datagram. Segments held out of order are always copied, they may
+    /// wait for a retransmission and would pin the buffer meanwhile.
+    pub fn input_bytes(&mut self, data: &Bytes) -> io::Result<usize> {
+        self.input_from(data, Some(data))
+    }
 
-        if buf.remaining() < KCP_OVERHEAD {
+    /// take in the datagram `data`, slicing the payloads out of `source`
+    /// when it holds the same bytes
+    fn input_from(&mut self, data: &[u8], source: Option<&Bytes>) -> io::Result<usize> {
+        if let Some(ref mut trace) = self.trace {
+            trace.events.push(TraceEvent::Input {
+                ts: self.current,
+                data: data.to_vec(),
+            });
+        }
+        let expanded;
+        let (data, source) = if data.len() > 4 && data[4] == KCP_CMD_COMPACT &&
+            self.extensions & KCP_EXT_COMPACT != 0
+        {
+            // the segments get their headers back, nothing left to slice
+            expanded = expand(data)?;
+            (&expanded[..], None)
+        } else {
+            (data, source)
+        };
+        if data.len() < KCP_OVERHEAD {
             return Err(Error::new(ErrorKind::InvalidData, "invalid data"));
         }
+        let strict = self.parse_mode == ParseMode::Strict;
+        if strict {
+            // nothing of a datagram with a malformed segment is applied
+            let mut rest = data;
+            while let Some((header, body, next)) = split_segment(rest)? {
+                self.check_segment(&header, body)?;
+                rest = next;
+            }
+        }
+
         let old_una = self.snd_una;
         let mut flag = false;
         let mut maxack: u32 = 0;
-        while buf.remaining() >= KCP_OVERHEAD {
-            let conv = buf.get_u32::<LittleEndian>();
-            if conv != self.conv {
-                return Err(Error::new(ErrorKind::InvalidData, "invalid data"));
+        let mut rest = data;
+        loop {
+            let at = data.len() - rest.len() + KCP_OVERHEAD;
+            let (header, body, next) = match split_segment(rest) {
+                Ok(Some(segment)) => segment,
+                Ok(None) => break,
+                // trailing bytes that are not a segment
+                Err(_) if !strict => break,
+                Err(e) => return Err(e),
+            };
+            if header.conv != self.conv && !strict {
+                // extra framing after the segments
+                break;
             }
-
-            let cmd = buf.get_u8();
-            let frg = buf.get_u8();
-            let wnd = buf.get_u16::<LittleEndian>();
-            let ts = buf.get_u32::<LittleEndian>();
-            let sn = buf.get_u32::<LittleEndian>();
-            let una = buf.get_u32::<LittleEndian>();
-            let len = buf.get_u32::<LittleEndian>();
-
-            let len = len as usize;
-            if buf.remaining() < len {
-                return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected EOF"));
+            rest = next;
+            if let Some(ref mut tap) = self.output.tap {
+                tap(Direction::Incoming, self.current, &header);
+            }
+            if let Err(e) = self.check_segment(&header, body) {
+                if strict {
+                    return Err(e);
+                }
+                self.malformed += 1;
+                continue;
+            }
+            let SegmentHeader { cmd, frg, wnd, ts, sn, una, .. } = header;
+            let mut buf = Cursor::new(body);
+
+            if cmd == KCP_CMD_RESET {
+                // only from someone who saw what we sent, a guessed conv
+                // alone does not do
+                if timediff(una, self.snd_una) >= 0 && timediff(una, self.snd_nxt) <= 0 {
+                    self.abort(ErrorKind::ConnectionReset);
+                    return Ok(data.len());
+                }
+                continue;
             }
 
-            if cmd != KCP_CMD_PUSH && cmd != KCP_CMD_ACK && cmd != KCP_CMD_WASK &&
-                cmd != KCP_CMD_WINS
+            if cmd != KCP_CMD_PUSH && cmd != KCP_CMD_UPUSH && cmd != KCP_CMD_PART &&
+                cmd != KCP_CMD_CLOSE
             {
-                return Err(Error::new(ErrorKind::InvalidData, "invalid data"));
+                let frg = frg & !KCP_FRG_SYN;
+                if self.extensions != 0 && frg & !self.peer_extensions != 0 {
+                    // answer in kind, the peer might not hear from us otherwise
+                    self.probe |= KCP_ASK_TELL;
+                }
+                self.peer_extensions |= frg;
+            } else {
+                // the peer is sending again, it has seen the window
+                self.wins_left = 0;
             }
 
+            let probe = cmd == KCP_CMD_WASK || cmd == KCP_CMD_WINS;
+            if self.learn_isn && !probe {
+                // nowhere to place it yet, the peer sends it again
+                continue;
+            }
+            let syn = probe && frg & KCP_FRG_SYN != 0;
+            if syn && cmd == KCP_CMD_WASK && self.initial_sn {
+                self.tell_isn = true;
+            }
+            if syn && self.learn_isn {
+                // the sn of a flagged probe is where the peer numbers from.
+                // taken once, a later probe cannot move rcv_nxt anymore
+                self.rcv_nxt = sn;
+                self.rcv_top = sn;
+                self.learn_isn = false;
+            }
+            self.established = true;
             self.rmt_wnd = wnd as u32;
-            self.parse_una(una);
-            self.shrink_buf();
+            if cmd != KCP_CMD_WASK || frg & KCP_FRG_SYN == 0 {
+                self.parse_una(una);
+                self.shrink_buf();
+            }
             if cmd == KCP_CMD_ACK {
+                if self.close_xmit > 0 && sn == self.close_sn {
+                    self.close_acked = true;
+                }
                 let rtt = timediff(self.current, ts);
                 if rtt >= 0 {
                     self.update_ack(rtt as u32);
@@ -455,26 +1961,92 @@ impl<W: Write> Kcb<W> {
                     flag = true;
                     maxack = sn;
                 } else {
-                    if sn > maxack {
+                    if timediff(sn, maxack) > 0 {
                         maxack = sn;
                     }
                 }
-            } else if cmd == KCP_CMD_PUSH {
-                if sn < self.rcv_nxt + self.rcv_wnd {
-                    self.acklist.push((sn, ts));
-                    if sn >= self.rcv_nxt {
+            } else if cmd == KCP_CMD_ACKR {
+                let count = buf.get_u32::<LittleEndian>();
+                if count > 0 {
+                    let rtt = timediff(self.current, ts);
+                    if rtt >= 0 {
+                        self.update_ack(rtt as u32);
+                    }
+                    let last = sn.wrapping_add(count - 1);
+                    if self.close_xmit > 0 && timediff(self.close_sn, sn) >= 0 &&
+                        timediff(self.close_sn, last) <= 0
+                    {
+                        self.close_acked = true;
+                    }
+                    self.parse_ack_range(sn, last);
+                    self.shrink_buf();
+                    if !flag || timediff(last, maxack) > 0 {
+                        flag = true;
+                        maxack = last;
+                    }
+                }
+            } else if cmd == KCP_CMD_PUSH || cmd == KCP_CMD_UPUSH {
+                self.update_skew(ts);
+                if !self.reassembly_admits(sn, cmd, frg, body.len()) {
+                    self.reassembly_dropped += 1;
+                } else if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 {
+                    self.ack_push(sn, ts);
+                    if timediff(sn, self.rcv_nxt) < 0 {
+                        self.duplicates += 1;
+                    } else {
                         let mut seg = Segment::default();
-                        seg.conv = conv;
+                        seg.conv = self.conv;
                         seg.cmd = cmd;
                         seg.frg = frg;
                         seg.wnd = wnd as u32;
                         seg.ts = ts;
                         seg.sn = sn;
                         seg.una = una;
-                        seg.data.resize(len, 0);
-                        buf.read_exact(&mut seg.data)?;
+                        // only the segment rcv_nxt waits for moves on right
+                        // away, one held out of order gets a copy of its own
+                        // rather than pinning the receive buffer meanwhile
+                        seg.data = match source {
+                            Some(source) if sn == self.rcv_nxt => {
+                                source.slice(at, at + body.len())
+                            }
+                            _ => Bytes::from(body),
+                        };
+                        self.parse_data(seg);
+                    }
+                } else {
+                    self.out_of_window += 1;
+                }
+            } else if cmd == KCP_CMD_PART {
+                let orig = buf.get_u8();
+                let total = buf.get_u32::<LittleEndian>() as usize;
+                let offset = buf.get_u32::<LittleEndian>() as usize;
+                let part = &body[KCP_PART_HEADER..];
+                self.update_skew(ts);
+                if timediff(sn, self.rcv_nxt) < 0 {
+                    self.ack_push(sn, ts);
+                    self.duplicates += 1;
+                } else if !self.reassembly_admits(sn, orig, frg, total) {
+                    self.reassembly_dropped += 1;
+                } else if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 {
+                    if let Some(seg) = self.parse_part(orig, frg, sn, total, offset, part) {
+                        self.ack_push(sn, ts);
                         self.parse_data(seg);
                     }
+                } else {
+                    self.out_of_window += 1;
+                }
+            } else if cmd == KCP_CMD_CLOSE {
+                let code = buf.get_u16::<LittleEndian>();
+                let reason = &body[2..];
+                // only once everything sent before it was received
+                if timediff(sn, self.rcv_nxt) <= 0 {
+                    self.ack_push(sn, ts);
+                    if self.peer_close.is_none() {
+                        self.peer_close = Some(CloseFrame {
+                            code: code,
+                            reason: String::from_utf8_lossy(reason).into_owned(),
+                        });
+                    }
                 }
             } else if cmd == KCP_CMD_WASK {
                 // ready to send back KCP_CMD_WINS in `flush`
@@ -482,28 +2054,22 @@ impl<W: Write> Kcb<W> {
                 self.probe |= KCP_ASK_TELL;
             } else if cmd == KCP_CMD_WINS {
                 // do nothing
-            } else {
-                return Err(Error::new(ErrorKind::InvalidData, "invalid data"));
             }
         }
         if flag {
             self.parse_fastack(maxack);
         }
 
-        if self.snd_una > old_una {
+        if timediff(self.snd_una, old_una) > 0 && (!self.cwnd_validation || self.cwnd_limited) {
             if self.cwnd < self.rmt_wnd {
                 let mss = self.mss as u32;
                 if self.cwnd < self.ssthresh {
                     self.cwnd += 1;
                     self.incr += mss;
                 } else {
-                    if self.incr < mss {
-                        self.incr = mss;
-                    }
-                    self.incr += (mss * mss) / self.incr + (mss / 16);
-                    if (self.cwnd + 1) * mss <= self.incr {
-                        self.cwnd += 1;
-                    }
+                    let acked = self.snd_una.wrapping_sub(old_una);
+                    let (current, srtt) = (self.current, self.rx_srtt);
+                    self.congestion(|cc, window| cc.on_ack(window, acked, current, srtt));
                 }
                 if self.cwnd > self.rmt_wnd {
                     self.cwnd = self.rmt_wnd;
@@ -511,114 +2077,516 @@ impl<W: Write> Kcb<W> {
                 }
             }
         }
-        Ok(n - buf.remaining())
+        if self.updated {
+            self.sample_delivery_rate();
+            self.flush_on_ack(old_una);
+        }
+        Ok(data.len() - rest.len())
     }
 
-    fn wnd_unused(&self) -> u32 {
-        let nrcv_que = self.rcv_queue.len() as u32;
-        if nrcv_que < self.rcv_wnd {
-            return self.rcv_wnd - nrcv_que;
+    /// flush at once when the acks just taken in opened the window for
+    /// queued data or call for a fast resend, see `set_ack_flush`
+    fn flush_on_ack(&mut self, old_una: u32) {
+        let gap = match self.ack_flush {
+            Some(gap) => gap,
+            None => return,
+        };
+        if timediff(self.current, self.ts_ack_flush) < 0 {
+            return;
+        }
+        let opened = timediff(self.snd_una, old_una) > 0 && !self.snd_queue.is_empty();
+        let resend = self.fastresend > 0 &&
+            self.snd_buf.iter().any(|seg| seg.xmit > 0 && seg.fastack >= self.fastresend);
+        if opened || resend {
+            self.ts_ack_flush = self.current.wrapping_add(gap);
+            self.flush_out();
         }
-        0
     }
 
-    /// flush pending data
-    pub fn flush(&mut self) {
-        // `update` haven't been called.
-        if !self.updated {
-            return;
+    /// whether a segment is well formed and acceptable, before anything
+    /// of it is applied
+    fn check_segment(&self, header: &SegmentHeader, body: &[u8]) -> io::Result<()> {
+        let invalid = || Err(Error::new(ErrorKind::InvalidData, "invalid data"));
+        if header.conv != self.conv {
+            return invalid();
         }
-        let current = self.current;
-        let mut lost = false;
-        let mut change = false;
-        let mut seg = Segment::default();
+        let len = body.len();
+        match header.cmd {
+            KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS => {}
+            KCP_CMD_RESET => {
+                if len != 0 {
+                    return invalid();
+                }
+            }
+            KCP_CMD_ACKR => {
+                if len != 4 {
+                    return invalid();
+                }
+            }
+            KCP_CMD_PUSH | KCP_CMD_UPUSH => {
+                if !self.message_fits(header.frg, len) {
+                    return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+                }
+            }
+            KCP_CMD_PART => {
+                if len <= KCP_PART_HEADER {
+                    return invalid();
+                }
+                let orig = body[0];
+                let total = LittleEndian::read_u32(&body[1..5]) as usize;
+                let offset = LittleEndian::read_u32(&body[5..9]) as usize;
+                if (orig != KCP_CMD_PUSH && orig != KCP_CMD_UPUSH) || total > KCP_PART_MAX ||
+                    offset + len - KCP_PART_HEADER > total
+                {
+                    return invalid();
+                }
+                if !self.message_fits(header.frg, total) {
+                    return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+                }
+            }
+            KCP_CMD_CLOSE => {
+                if len < 2 || len > 2 + KCP_CLOSE_REASON {
+                    return invalid();
+                }
+            }
+            _ => return invalid(),
+        }
+        Ok(())
+    }
 
-        seg.conv = self.conv;
-        seg.cmd = KCP_CMD_ACK;
-        seg.wnd = self.wnd_unused();
-        seg.una = self.rcv_nxt;
+    /// whether the message a fragment of `len` bytes belongs to stays within
+    /// the limits. fragments but the last one are full, so `frg` more of
+    /// them make the message at least `frg * len + 1` bytes
+    fn message_fits(&self, frg: u8, len: usize) -> bool {
+        let frg = (frg & !KCP_FRG_FIRST) as usize;
+        let least = if frg == 0 { len } else { frg * len + 1 };
+        frg < self.max_fragments as usize && least <= self.max_message
+    }
 
-        // flush acknowledges
-        for ack in &self.acklist {
-            if self.buffer.remaining_mut() + KCP_OVERHEAD > self.mtu {
-                self.output.write_all(&self.buffer);
-                self.buffer.clear();
+    /// collect a part of segment `sn`, returns the segment once complete
+    fn parse_part(
+        &mut self,
+        cmd: u8,
+        frg: u8,
+        sn: u32,
+        total: usize,
+        offset: usize,
+        part: &[u8],
+    ) -> Option<Segment> {
+        let rcv_nxt = self.rcv_nxt;
+        self.partials.retain(|p| timediff(p.sn, rcv_nxt) >= 0);
+        let i = match self.partials.iter().position(|p| p.sn == sn) {
+            Some(i) => i,
+            None => {
+                if self.partials.len() >= KCP_PARTIALS {
+                    self.partials.pop_front();
+                }
+                self.partials.push_back(Partial {
+                    sn: sn,
+                    cmd: cmd,
+                    frg: frg,
+                    data: vec![0; total],
+                    have: vec![false; total],
+                    missing: total,
+                });
+                self.partials.len() - 1
+            }
+        };
+        {
+            let p = &mut self.partials[i];
+            if p.data.len() != total {
+                return None;
+            }
+            for (j, &b) in part.iter().enumerate() {
+                if !p.have[offset + j] {
+                    p.have[offset + j] = true;
+                    p.data[offset + j] = b;
+                    p.missing -= 1;
+                }
+            }
+            if p.missing > 0 {
+                return None;
             }
-            seg.sn = ack.0;
-            seg.ts = ack.1;
-            seg.encode(&mut self.buffer);
         }
-        self.acklist.clear();
+        let p = self.partials.remove(i).unwrap();
+        let mut seg = Segment::default();
+        seg.conv = self.conv;
+        seg.cmd = p.cmd;
+        seg.frg = p.frg;
+        seg.sn = p.sn;
+        seg.data = Bytes::from(p.data);
+        Some(seg)
+    }
 
-        // probe window size (if remote window size equals zero)
-        if self.rmt_wnd == 0 {
-            if self.probe_wait == 0 {
-                self.probe_wait = KCP_PROBE_INIT;
-                self.ts_probe = self.current + self.probe_wait;
-            } else {
-                if timediff(self.current, self.ts_probe) >= 0 {
-                    if self.probe_wait < KCP_PROBE_INIT {
-                        self.probe_wait = KCP_PROBE_INIT;
-                    }
-                    self.probe_wait += self.probe_wait / 2;
-                    if self.probe_wait > KCP_PROBE_LIMIT {
-                        self.probe_wait = KCP_PROBE_LIMIT;
-                    }
-                    self.ts_probe = self.current + self.probe_wait;
-                    self.probe |= KCP_ASK_SEND;
+    /// flush acknowledges, runs of consecutive sns go out as a single
+    /// KCP_CMD_ACKR carrying the newest timestamp of the run
+    fn flush_ack_ranges(&mut self, seg: &mut Segment) {
+        let base = self.rcv_nxt.wrapping_sub(self.rcv_wnd);
+        self.acklist.sort_by_key(|ack| ack.0.wrapping_sub(base));
+        let mut i = 0;
+        while i < self.acklist.len() {
+            let (first, mut ts) = self.acklist[i];
+            let mut j = i + 1;
+            while j < self.acklist.len() &&
+                self.acklist[j].0 == self.acklist[j - 1].0.wrapping_add(1)
+            {
+                if timediff(self.acklist[j].1, ts) > 0 {
+                    ts = self.acklist[j].1;
                 }
+                j += 1;
             }
-        } else {
-            self.ts_probe = 0;
-            self.probe_wait = 0;
+            if self.buffer.len() + KCP_OVERHEAD + 4 > self.mtu {
+                self.output.send(&mut self.buffer, &self.padding, self.mtu);
+            }
+            seg.sn = first;
+            seg.ts = ts;
+            if j - i > 1 {
+                seg.cmd = KCP_CMD_ACKR;
+                let mut run = [0; 4];
+                LittleEndian::write_u32(&mut run, (j - i) as u32);
+                seg.data = Bytes::from(&run[..]);
+                seg.encode(&mut self.buffer);
+                seg.data = Bytes::new();
+            } else {
+                seg.cmd = KCP_CMD_ACK;
+                seg.encode(&mut self.buffer);
+            }
+            i = j;
         }
+        seg.cmd = KCP_CMD_ACK;
+    }
 
-        // flush window probing commands
-        if (self.probe & KCP_ASK_SEND) != 0 {
-            seg.cmd = KCP_CMD_WASK;
-            if self.buffer.remaining_mut() + KCP_OVERHEAD > self.mtu {
-                self.output.write_all(&self.buffer);
-                self.buffer.clear();
+    /// write out the pending acks with the header fields of `seg`
+    fn flush_acks(&mut self, seg: &mut Segment) {
+        seg.cmd = KCP_CMD_ACK;
+        if self.extensions & self.peer_extensions & KCP_EXT_ACKR != 0 {
+            self.flush_ack_ranges(seg);
+        } else {
+            for ack in &self.acklist {
+                if self.buffer.len() + KCP_OVERHEAD > self.mtu {
+                    self.output.send(&mut self.buffer, &self.padding, self.mtu);
+                }
+                seg.sn = ack.0;
+                seg.ts = ack.1;
+                seg.encode(&mut self.buffer);
             }
-            seg.encode(&mut self.buffer);
         }
+        self.acklist.clear();
+    }
 
-        // flush window probing commands
-        if (self.probe & KCP_ASK_TELL) != 0 {
-            seg.cmd = KCP_CMD_WINS;
-            if self.buffer.remaining_mut() + KCP_OVERHEAD > self.mtu {
-                self.output.write_all(&self.buffer);
-                self.buffer.clear();
+    /// pack the segments of `snd_buf` at `batch` into datagrams behind what
+    /// is left in `buffer`, each handed to the sink without copying
+    fn flush_vectored(&mut self, batch: &[usize]) {
+        let mut bufs = SmallVec::<[&[u8]; 32]>::new();
+        let mut len = self.buffer.len();
+        if len > 0 {
+            bufs.push(&self.buffer);
+        }
+        for &i in batch {
+            let segment = &self.snd_buf[i];
+            let need = KCP_OVERHEAD + segment.data.len();
+            if len + need > self.mtu && len > 0 {
+                self.output.send_vectored(&bufs, &self.padding, self.mtu);
+                bufs.clear();
+                len = 0;
             }
-            seg.encode(&mut self.buffer);
+            // the payload goes out from where `send` put it
+            bufs.push(&segment.encoded);
+            if !segment.data.is_empty() {
+                bufs.push(&segment.data);
+            }
+            len += need;
         }
-        self.probe = 0;
+        self.output.send_vectored(&bufs, &self.padding, self.mtu);
+        drop(bufs);
+        self.buffer.clear();
+    }
 
-        // calculate window size
-        let mut cwnd = cmp::min(self.snd_wnd, self.rmt_wnd);
-        if !self.nocwnd {
-            cwnd = cmp::min(self.cwnd, cwnd);
+    /// step the mtu down after large datagrams went missing while smaller
+    /// ones got through, e.g. behind a tunnel dropping fragments, and split
+    /// what is queued to fit
+    fn downshift_mtu(&mut self) {
+        let mtu = mtu_step_below(self.mtu);
+        if mtu == 0 || !self.setmtu(mtu) {
+            return;
+        }
+        self.small_acked = 0;
+        self.downshifts += 1;
+        self.refragment();
+
+        if self.extensions & self.peer_extensions & KCP_EXT_PART != 0 {
+            // resend what no longer fits as parts right away
+            let mss = self.mss;
+            let current = self.current;
+            for seg in &mut self.snd_buf {
+                if seg.len() > mss {
+                    seg.resendts = current;
+                }
+            }
+        }
+    }
+
+    /// split the messages in snd_queue to the current mss. the rest of a
+    /// message whose first fragments are in flight keeps its fragments,
+    /// what does not fit is sent as parts
+    fn refragment(&mut self) {
+        let mss = self.mss;
+        if self.snd_queue.iter().all(|seg| seg.data.len() <= mss) {
+            return;
+        }
+        let mut queue = mem::replace(&mut self.snd_queue, VecDeque::new());
+        if self.snd_msg_open {
+            while let Some(seg) = queue.pop_front() {
+                let last = seg.frg & !KCP_FRG_FIRST == 0;
+                self.snd_queue.push_back(seg);
+                if last {
+                    break;
+                }
+            }
+        }
+        let mut msg: Vec<Segment> = Vec::new();
+        while let Some(seg) = queue.pop_front() {
+            let last = seg.frg & !KCP_FRG_FIRST == 0;
+            msg.push(seg);
+            if !last && !queue.is_empty() {
+                continue;
+            }
+            let cmd = msg[0].cmd;
+            let mut data = Vec::new();
+            for seg in &msg {
+                data.extend_from_slice(&seg.data);
+            }
+            let count = (data.len() + mss - 1) / mss;
+            let limit = if cmd == KCP_CMD_UPUSH { KCP_FRG_FIRST as usize } else { 255 };
+            if self.stream {
+                for chunk in data.chunks(mss) {
+                    let mut seg = Segment::default();
+                    seg.cmd = cmd;
+                    seg.data = Bytes::from(chunk);
+                    self.snd_queue.push_back(seg);
+                }
+            } else if count <= limit {
+                for (i, chunk) in data.chunks(mss).enumerate() {
+                    let mut seg = Segment::default();
+                    seg.cmd = cmd;
+                    seg.frg = (count - i - 1) as u8;
+                    if cmd == KCP_CMD_UPUSH && i == 0 {
+                        seg.frg |= KCP_FRG_FIRST;
+                    }
+                    seg.data = Bytes::from(chunk);
+                    self.snd_queue.push_back(seg);
+                }
+            } else {
+                // too many fragments, left to be sent as parts
+                self.snd_queue.extend(msg.drain(..));
+            }
+            msg.clear();
+        }
+    }
+
+    /// back off from an output sink that keeps refusing datagrams, e.g. a
+    /// full socket send buffer, so it does not turn into a silent loss the
+    /// congestion control mistakes for the network. the budget grows back
+    /// once datagrams are taken again
+    fn update_flush_budget(&mut self) {
+        if !self.output.retry.is_empty() {
+            self.pressure_streak += 1;
+            if self.pressure_streak >= KCP_PRESSURE_STREAK {
+                let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+                let budget = cmp::min(self.flush_budget, inflight) / 2;
+                self.flush_budget = cmp::max(budget, KCP_BUDGET_MIN);
+            }
+        } else {
+            self.pressure_streak = 0;
+            if self.flush_budget != u32::max_value() {
+                self.flush_budget += self.flush_budget / 4 + 1;
+                if self.flush_budget >= self.snd_wnd {
+                    self.flush_budget = u32::max_value();
+                }
+            }
+        }
+    }
+
+    fn wnd_unused(&self) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        let nrcv_que = self.rcv_queue.len() as u32;
+        let wnd = cmp::min(self.rcv_wnd, self.wnd_clamp);
+        if nrcv_que < wnd {
+            return wnd - nrcv_que;
+        }
+        0
+    }
+
+    /// flush pending data
+    pub fn flush(&mut self) {
+        if let Some(ref mut trace) = self.trace {
+            trace.events.push(TraceEvent::Flush { ts: self.current });
+        }
+        self.flush_out();
+    }
+
+    fn flush_out(&mut self) {
+        // `update` haven't been called.
+        if !self.updated || self.output.failed.is_some() || self.reset.is_some() || self.dead {
+            return;
+        }
+        self.output.retry();
+        self.output.compact = self.extensions & self.peer_extensions & KCP_EXT_COMPACT != 0;
+        self.output.current = self.current;
+        let current = self.current;
+        let mut lost = false;
+        let mut change = false;
+        let mut seg = Segment::default();
+
+        seg.conv = self.conv;
+        seg.cmd = KCP_CMD_ACK;
+        seg.wnd = self.wnd_unused();
+        seg.una = self.rcv_nxt;
+        seg.frg = self.extensions;
+
+        // young acks wait to ride along with data
+        let hold_acks = self.ack_delay > 0 && !self.acklist.is_empty() &&
+            timediff(current, self.ts_ack) < self.ack_delay as i32;
+        if !hold_acks {
+            self.flush_acks(&mut seg);
+        }
+
+        // probe window size (if remote window size equals zero)
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = self.probe_init;
+                self.ts_probe = self.current + self.probe_wait;
+            } else {
+                if timediff(self.current, self.ts_probe) >= 0 {
+                    if self.probe_wait < self.probe_init {
+                        self.probe_wait = self.probe_init;
+                    }
+                    self.probe_wait += self.probe_wait / 2;
+                    if self.probe_wait > self.probe_limit {
+                        self.probe_wait = self.probe_limit;
+                    }
+                    self.ts_probe = self.current + self.probe_wait;
+                    self.probe |= KCP_ASK_SEND;
+                }
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+
+        // close once everything sent was acknowledged, resent every rto
+        // until the peer acknowledges it or the link counts as dead
+        if self.close.is_some() && !self.close_acked && !self.learn_isn &&
+            self.snd_queue.is_empty() && self.snd_buf.is_empty() &&
+            self.close_xmit < self.dead_link &&
+            timediff(current, self.ts_close) >= 0
+        {
+            self.close_xmit += 1;
+            self.close_sn = self.snd_nxt;
+            self.ts_close = current + self.rx_rto;
+            let mut close = Segment::default();
+            close.conv = self.conv;
+            close.cmd = KCP_CMD_CLOSE;
+            close.wnd = seg.wnd;
+            close.ts = current;
+            close.sn = self.close_sn;
+            close.una = self.rcv_nxt;
+            if let Some(ref frame) = self.close {
+                let mut data = BytesMut::with_capacity(2 + frame.reason.len());
+                data.put_u16::<LittleEndian>(frame.code);
+                data.extend_from_slice(frame.reason.as_bytes());
+                close.data = data.freeze();
+            }
+            if self.buffer.len() + KCP_OVERHEAD + close.data.len() > self.mtu {
+                self.output.send(&mut self.buffer, &self.padding, self.mtu);
+            }
+            close.encode(&mut self.buffer);
+        }
+
+        // repeat the update of a reopened window, a lost one would leave
+        // the peer waiting for its next probe
+        if self.wins_left > 0 && timediff(current, self.ts_wins) >= 0 {
+            self.wins_left -= 1;
+            self.ts_wins = current + self.rx_rto;
+            self.probe |= KCP_ASK_TELL;
+        }
+
+        // ask until the peer tells where its sns start
+        if self.learn_isn && timediff(current, self.ts_isn) >= 0 {
+            self.ts_isn = current + self.rx_rto;
+            self.probe |= KCP_ASK_SEND;
+        }
+
+        // keep the path open, e.g. a NAT mapping, while there is nothing to say
+        if self.keepalive > 0 && timediff(current, self.ts_keepalive) >= 0 {
+            self.probe |= KCP_ASK_TELL;
+        }
+
+        // flush window probing commands
+        if self.initial_sn {
+            seg.sn = self.snd_una;
+        }
+        if (self.probe & KCP_ASK_SEND) != 0 {
+            seg.cmd = KCP_CMD_WASK;
+            if self.learn_isn {
+                seg.frg |= KCP_FRG_SYN;
+            }
+            if self.buffer.len() + KCP_OVERHEAD > self.mtu {
+                self.output.send(&mut self.buffer, &self.padding, self.mtu);
+            }
+            seg.encode(&mut self.buffer);
+            seg.frg = self.extensions;
+        }
+
+        // flush window probing commands
+        if (self.probe & KCP_ASK_TELL) != 0 {
+            seg.cmd = KCP_CMD_WINS;
+            if self.tell_isn {
+                seg.frg |= KCP_FRG_SYN;
+                self.tell_isn = false;
+            }
+            if self.buffer.len() + KCP_OVERHEAD > self.mtu {
+                self.output.send(&mut self.buffer, &self.padding, self.mtu);
+            }
+            seg.encode(&mut self.buffer);
+            seg.frg = self.extensions;
+        }
+        self.probe = 0;
+
+        if self.cwnd_validation && self.snd_buf.is_empty() {
+            self.decay_idle_cwnd();
+        }
+
+        // calculate window size
+        let mut cwnd = cmp::min(self.snd_wnd, self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = cmp::min(self.cwnd, cwnd);
         }
 
         // move data from snd_queue to snd_buf
-        while self.snd_nxt < self.snd_una + cwnd {
+        while !self.learn_isn && timediff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
             if let Some(mut newseg) = self.snd_queue.pop_front() {
                 newseg.conv = self.conv;
-                newseg.cmd = KCP_CMD_PUSH;
                 newseg.wnd = seg.wnd;
                 newseg.ts = current;
                 newseg.sn = self.snd_nxt;
-                self.snd_nxt += 1;
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
                 newseg.una = self.rcv_nxt;
                 newseg.resendts = current;
                 newseg.rto = self.rx_rto;
                 newseg.fastack = 0;
                 newseg.xmit = 0;
+                self.snd_msg_open = newseg.frg & !KCP_FRG_FIRST != 0;
                 self.snd_buf.push_back(newseg);
             } else {
                 break;
             }
         }
+        // the window held data back or is in full use
+        self.cwnd_limited = !self.snd_queue.is_empty() || self.snd_nxt.wrapping_sub(self.snd_una) >= cwnd;
+
         // calculate resent
         let resent = if self.fastresend > 0 {
             self.fastresend
@@ -631,8 +2599,40 @@ impl<W: Write> Kcb<W> {
             0
         };
 
+        let parts = self.extensions & self.peer_extensions & KCP_EXT_PART != 0;
+        let small_mtu = if self.downshift { mtu_step_below(self.mtu) } else { 0 };
+        let mut blackhole = false;
+
         // flush data segments
-        for segment in &mut self.snd_buf {
+        let mut alarms = SmallVec::<[FailoverAlarm; 4]>::new();
+        let mut emitted = 0;
+        let budget = cmp::min(self.flush_budget, self.burst_limit);
+        let paced = match (self.nocwnd, self.cc.pacing_rate()) {
+            (false, Some(rate)) => {
+                // credit grows with the time since the last flush, however
+                // often that was, and saves up at most an interval's worth
+                let elapsed = cmp::max(timediff(current, self.ts_pace), 0) as u64;
+                let cap = rate.saturating_mul(self.interval as u64);
+                let cap = cmp::max(cap, self.mss as u64 * 1000);
+                let credit = self.pace_credit.saturating_add(rate.saturating_mul(elapsed));
+                self.pace_credit = cmp::min(credit, cap);
+                true
+            }
+            _ => {
+                // a full interval's worth once pacing starts
+                self.pace_credit = u64::max_value();
+                false
+            }
+        };
+        self.ts_pace = current;
+        let mut batch = SmallVec::<[usize; 32]>::new();
+        for (i, segment) in self.snd_buf.iter_mut().enumerate() {
+            if emitted >= budget {
+                break;
+            }
+            if paced && self.pace_credit < segment.len() as u64 * 1000 {
+                break;
+            }
             let mut needsend = false;
             if segment.xmit == 0 {
                 needsend = true;
@@ -643,13 +2643,26 @@ impl<W: Write> Kcb<W> {
                 needsend = true;
                 segment.xmit += 1;
                 self.xmit += 1;
+                let rto = segment.rto;
                 if self.nodelay == 0 {
                     segment.rto += self.rx_rto;
                 } else {
                     segment.rto += self.rx_rto / 2;
                 }
+                if self.alarm_rto > 0 && rto < self.alarm_rto && segment.rto >= self.alarm_rto {
+                    alarms.push(FailoverAlarm::Rto {
+                        sn: segment.sn,
+                        rto: segment.rto,
+                    });
+                }
                 segment.resendts = current + segment.rto;
                 lost = true;
+                // large datagrams keep vanishing while smaller ones get through
+                if small_mtu > 0 && KCP_OVERHEAD + segment.len() > small_mtu &&
+                    segment.xmit > KCP_BLACKHOLE_XMIT && self.small_acked > 0
+                {
+                    blackhole = true;
+                }
             } else if segment.fastack >= resent {
                 needsend = true;
                 segment.xmit += 1;
@@ -659,50 +2672,94 @@ impl<W: Write> Kcb<W> {
             }
 
             if needsend {
+                if segment.xmit == self.alarm_xmit {
+                    alarms.push(FailoverAlarm::Retransmits {
+                        sn: segment.sn,
+                        xmit: segment.xmit,
+                    });
+                }
+                emitted += 1;
+                if paced {
+                    self.pace_credit -= segment.len() as u64 * 1000;
+                }
+                self.max_xmit = cmp::max(self.max_xmit, segment.xmit);
                 segment.ts = current;
                 segment.wnd = seg.wnd;
                 segment.una = self.rcv_nxt;
 
-                let len = segment.data.len();
+                let len = segment.len();
+                if parts && KCP_OVERHEAD + len > self.mtu {
+                    // left over from a larger mtu, the peer reassembles it
+                    let chunk = self.mtu - KCP_OVERHEAD - KCP_PART_HEADER;
+                    let mut offset = 0;
+                    while offset < len {
+                        let n = cmp::min(chunk, len - offset);
+                        if self.buffer.len() + KCP_OVERHEAD + KCP_PART_HEADER + n > self.mtu {
+                            self.output.send(&mut self.buffer, &self.padding, self.mtu);
+                        }
+                        segment.encode_part(&mut self.buffer, offset, n);
+                        offset += n;
+                    }
+                    continue;
+                }
+
+                if self.vectored {
+                    segment.refresh_encoded();
+                    batch.push(i);
+                    continue;
+                }
+
                 let need = KCP_OVERHEAD + len;
 
-                if self.buffer.remaining_mut() + need > self.mtu {
-                    self.output.write_all(&self.buffer);
-                    self.buffer.clear();
+                if self.buffer.len() + need > self.mtu {
+                    self.output.send(&mut self.buffer, &self.padding, self.mtu);
                 }
-                segment.encode(&mut self.buffer);
+                segment.encode_cached(&mut self.buffer);
+            }
+            if segment.xmit >= self.dead_link {
+                self.dead = true;
+            }
+        }
+
+        // data or probes are going out anyway, take the held acks along
+        if hold_acks && (self.buffer.len() > 0 || !batch.is_empty()) {
+            self.flush_acks(&mut seg);
+        }
 
-                // never used
-                // if segment.xmit >= self.dead_link {
-                //     self.state = -1;
-                // }
+        if !batch.is_empty() {
+            self.flush_vectored(&batch);
+        }
+        self.segments_sent += emitted as u64;
+        if emitted > 0 {
+            self.ts_last_xmit = current;
+        }
+        if let Some(ref mut alarm) = self.alarm {
+            for &a in &alarms {
+                alarm(a);
             }
         }
 
         // flash remain segments
-        if self.buffer.remaining_mut() > 0 {
-            self.output.write_all(&self.buffer);
-            self.buffer.clear();
+        if self.buffer.len() > 0 {
+            self.output.send(&mut self.buffer, &self.padding, self.mtu);
+        }
+        self.update_flush_budget();
+        if blackhole {
+            self.downshift_mtu();
+        }
+        if self.output.sent != self.keepalive_sent {
+            self.keepalive_sent = self.output.sent;
+            self.ts_keepalive = current + self.keepalive;
         }
 
         // update ssthresh
         if change {
-            let inflight = self.snd_nxt - self.snd_una;
-            self.ssthresh = inflight / 2;
-            if self.ssthresh < KCP_THRESH_MIN {
-                self.ssthresh = KCP_THRESH_MIN;
-            }
-            self.cwnd = self.ssthresh + resent;
-            self.incr = self.cwnd * self.mss as u32;
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.congestion(|cc, window| cc.on_fast_resend(window, inflight, resent));
         }
 
         if lost {
-            self.ssthresh = cwnd / 2;
-            if self.ssthresh < KCP_THRESH_MIN {
-                self.ssthresh = KCP_THRESH_MIN;
-            }
-            self.cwnd = 1;
-            self.incr = self.mss as u32;
+            self.congestion(|cc, window| cc.on_timeout(window, cwnd));
         }
 
         if self.cwnd < 1 {
@@ -715,10 +2772,20 @@ impl<W: Write> Kcb<W> {
     /// `check` when to call it again (without `input`/`send` calling).
     /// `current` - current timestamp in millisec.
     pub fn update(&mut self, current: u32) {
+        #[cfg(feature = "fault-injection")]
+        let current = if self.output.faults.is_clock_frozen() {
+            self.current
+        } else {
+            current
+        };
+        if let Some(ref mut trace) = self.trace {
+            trace.events.push(TraceEvent::Update { ts: current });
+        }
         self.current = current;
         if !self.updated {
             self.updated = true;
             self.ts_flush = self.current;
+            self.rate_ts = self.current;
         }
         let mut slap = timediff(self.current, self.ts_flush);
 
@@ -732,7 +2799,69 @@ impl<W: Write> Kcb<W> {
             if timediff(self.current, self.ts_flush) >= 0 {
                 self.ts_flush = self.current + self.interval;
             }
-            self.flush();
+            self.flush_out();
+        }
+    }
+
+    /// the faults armed on this control block, to test how the
+    /// application copes with lost or garbled output, a sink that fails,
+    /// or a clock that stands still
+    #[cfg(feature = "fault-injection")]
+    pub fn faults(&mut self) -> &mut Faults {
+        &mut self.output.faults
+    }
+
+    /// start recording every datagram, clock tick, send and recv into a
+    /// `Trace`, or stop and discard the recording. drain_messages is
+    /// recorded as the recvs it makes, but send_unordered and recv_many
+    /// are not, replays of sessions using them stray from the original.
+    /// the initial sn is recorded when recording starts before anything
+    /// was sent
+    pub fn record(&mut self, on: bool) {
+        if !on {
+            self.trace = None;
+        } else if self.trace.is_none() {
+            let mut trace = Trace::new(self.conv);
+            if self.initial_sn && self.snd_nxt == self.snd_una && self.delivered == 0 {
+                trace.isn = Some(self.snd_nxt);
+            }
+            self.trace = Some(trace);
+        }
+    }
+
+    /// the recording so far, recording goes on into a fresh one
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        let conv = self.conv;
+        self.trace.as_mut().map(|trace| mem::replace(trace, Trace::new(conv)))
+    }
+
+    /// same as `update`, with the current time given as an `Instant`.
+    /// timestamps are counted from the creation of this control block
+    pub fn update_at(&mut self, now: Instant) {
+        let current = self.millis_since_epoch(now);
+        self.update(current);
+    }
+
+    /// same as `update`, with the current time given as the `Duration`
+    /// elapsed since a fixed start of the caller's choosing. don't mix it
+    /// with `update_at`, which counts from the creation of this block
+    pub fn update_with(&mut self, elapsed: Duration) {
+        self.update(millis(elapsed));
+    }
+
+    /// same as `check`, returns the `Instant` at which `update_at` should
+    /// be invoked next
+    pub fn check_at(&self, now: Instant) -> Instant {
+        let current = self.millis_since_epoch(now);
+        now + Duration::from_millis(self.check(current) as u64)
+    }
+
+    #[inline]
+    fn millis_since_epoch(&self, now: Instant) -> u32 {
+        if now > self.epoch {
+            millis(now.duration_since(self.epoch))
+        } else {
+            0
         }
     }
 
@@ -761,6 +2890,10 @@ impl<W: Write> Kcb<W> {
 
         let tm_flush = timediff(ts_flush, current) as u32;
         for seg in &self.snd_buf {
+            if seg.xmit == 0 {
+                // held back by a budget, it goes with the next flush
+                continue;
+            }
             let diff = timediff(seg.resendts, current);
             if diff <= 0 {
                 return 0;
@@ -770,6 +2903,37 @@ impl<W: Write> Kcb<W> {
             }
         }
 
+        if let Some(seg) = self.rcv_queue.front() {
+            let wait = self.playout_wait(seg.ts);
+            if wait > 0 {
+                tm_packet = cmp::min(tm_packet, wait);
+            }
+        }
+
+        if self.learn_isn {
+            let diff = timediff(self.ts_isn, current);
+            if diff <= 0 {
+                return 0;
+            }
+            tm_packet = cmp::min(tm_packet, diff as u32);
+        }
+
+        if self.wins_left > 0 {
+            let diff = timediff(self.ts_wins, current);
+            if diff <= 0 {
+                return 0;
+            }
+            tm_packet = cmp::min(tm_packet, diff as u32);
+        }
+
+        if self.ack_delay > 0 && !self.acklist.is_empty() {
+            let diff = timediff(self.ts_ack.wrapping_add(self.ack_delay), current);
+            if diff <= 0 {
+                return 0;
+            }
+            tm_packet = cmp::min(tm_packet, diff as u32);
+        }
+
         let minimal = cmp::min(cmp::min(tm_packet, tm_flush), self.interval);
 
         minimal
@@ -782,8 +2946,9 @@ impl<W: Write> Kcb<W> {
         }
         self.mtu = mtu;
         self.mss = self.mtu - KCP_OVERHEAD;
-        let additional = (mtu + KCP_OVERHEAD) * 3 - self.buffer.capacity();
-        if additional > 0 {
+        let capacity = (mtu + KCP_OVERHEAD) * 3;
+        if capacity > self.buffer.capacity() {
+            let additional = capacity - self.buffer.len();
             self.buffer.reserve(additional);
         }
         true
@@ -819,20 +2984,778 @@ impl<W: Write> Kcb<W> {
         self.nocwnd = nc;
     }
 
+    /// cwnd validation: grow cwnd only on acks for data sent while the
+    /// window limited the sender, and halve it for every rto the sender
+    /// sat idle, so the first burst after a pause does not go out with a
+    /// window the path no longer supports. off by default
+    pub fn set_cwnd_validation(&mut self, on: bool) {
+        self.cwnd_validation = on;
+    }
+
+    /// the controller growing cwnd past slow start and backing off on
+    /// losses, `cc::Classic` by default, `cc::Ledbat` and `cc::Bbr` steer
+    /// by delay and bandwidth instead. only used with congestion control
+    /// on, see `nodelay`
+    pub fn set_congestion<C: CongestionControl + Send + 'static>(&mut self, cc: C) {
+        self.cc = Box::new(cc);
+    }
+
+    /// how slow start ends. hystart leaves it on a rise of the rtt, so
+    /// ssthresh starts out unbounded instead of at 2 and cwnd grows
+    /// exponentially until the path starts queueing or a loss
+    pub fn set_slow_start(&mut self, slow_start: SlowStart) {
+        self.slow_start = slow_start;
+        self.ssthresh = match slow_start {
+            SlowStart::Standard => KCP_THRESH_INIT,
+            SlowStart::HyStart => u32::max_value(),
+        };
+        self.hs_last_min = u32::max_value();
+        self.hs_cur_min = u32::max_value();
+        self.hs_samples = 0;
+    }
+
+    /// stream mode merges small sends into full segments, message boundaries
+    /// are lost. off by default, messages queued before it is switched on
+    /// keep their boundaries
+    pub fn set_stream(&mut self, on: bool) {
+        if on && !self.stream && !self.snd_queue.is_empty() {
+            self.stream_barrier = true;
+        }
+        self.stream = on;
+    }
+
+    /// lower bound of the retransmission timeout in millisec, overriding the
+    /// one picked by `nodelay`
+    pub fn set_min_rto(&mut self, rto: u32) {
+        self.rx_minrto = bound(1, rto, KCP_RTO_MAX);
+    }
+
+    /// wait `init` millisec before asking a peer with a zero window for an
+    /// update, backing off by half each time up to `limit`. defaults to 7
+    /// and 120 secs, far too long on a LAN
+    pub fn set_probe_timers(&mut self, init: u32, limit: u32) {
+        self.probe_init = cmp::max(init, 1);
+        self.probe_limit = cmp::max(limit, self.probe_init);
+        // a probe already scheduled with the old timers starts over
+        self.probe_wait = 0;
+        self.ts_probe = 0;
+    }
+
+    /// Strict parsing, the default, rejects a datagram with any malformed
+    /// segment as a whole. Lenient parsing skips such segments and ignores
+    /// trailing bytes, to interoperate with encoders appending padding or
+    /// framing of their own. Skipped segments are counted in
+    /// `KcpStats::malformed`.
+    pub fn set_parse_mode(&mut self, mode: ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    /// Refuse messages from the peer larger than `size` bytes or made of
+    /// more than `fragments` fragments, so a peer cannot make us buffer
+    /// more than that for a message `recv` would reject anyway. A fragment
+    /// showing its message exceeds either limit fails `input` with
+    /// `InvalidData` and is not acknowledged, so the message never
+    /// completes. Unlimited by default.
+    pub fn set_max_message(&mut self, size: usize, fragments: u8) {
+        self.max_message = cmp::max(size, 1);
+        self.max_fragments = cmp::max(fragments, 1);
+    }
+
+    /// Number segments from `isn` instead of 0, so an off-path attacker
+    /// has to guess the sns of a session to inject data into it. Where the
+    /// peer numbers from is learned once, from its window probe or the
+    /// answer to ours: nothing is sent and every segment but those is
+    /// dropped until then, and a probe asks for it every rto. Both ends must call it with
+    /// a random `isn` before any data flows. Only the first call counts,
+    /// and not for a direction that has already carried data.
+    pub fn set_initial_sn(&mut self, isn: u32) {
+        if self.initial_sn {
+            return;
+        }
+        if self.snd_buf.is_empty() && self.delivered == 0 {
+            self.snd_una = isn;
+            self.snd_nxt = isn;
+            self.hs_round_end = isn;
+            if let Some(ref mut trace) = self.trace {
+                trace.isn = Some(isn);
+            }
+        }
+        if self.rcv_nxt == 0 && self.rcv_buf.is_empty() && self.rcv_queue.is_empty() {
+            self.learn_isn = true;
+        }
+        self.initial_sn = true;
+    }
+
+    /// Hold at most `chains` messages and `bytes` bytes of segments that
+    /// arrived ahead of a gap, so a peer cannot pin memory with messages it
+    /// never completes. Past them the oldest message held is dropped to
+    /// make room: its fragments were acknowledged already and are lost,
+    /// and the ones still to come are taken and thrown away. A message
+    /// with fragments in the receive queue already, or one only waiting for
+    /// room there, is never dropped; a segment of a message older than
+    /// every one that may be is refused unacknowledged instead, and sent
+    /// again by the peer. The segment filling the gap is always taken.
+    /// Unlimited by default.
+    pub fn set_reassembly_limits(&mut self, chains: usize, bytes: usize) {
+        self.reassembly_chains = cmp::max(chains, 1);
+        self.reassembly_bytes = bytes;
+    }
+
+    /// Bound the payload bytes the connection holds across its send and
+    /// receive queues to about `bytes`. Once the send queues alone take it
+    /// `writable` turns false, holding writers back like the send backlog,
+    /// and above it segments arriving ahead of a gap are refused as by the
+    /// reassembly limits. The segment
+    /// filling the gap is always taken and `send` itself does not check
+    /// it, so the budget can be overshot by a message. Unlimited by
+    /// default.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory_budget = bytes;
+    }
+
+    /// payload bytes held by the send and receive queues
+    pub fn memory(&self) -> usize {
+        self.waitsnd_bytes() + self.waitrcv_bytes()
+    }
+
+    /// jitter buffer: hold every message until `delay` millisec after the
+    /// time it would have arrived on the fastest transit seen, releasing
+    /// them on the sender's clock instead of as the network delivers them.
+    /// messages arriving later than that are delivered right away, in
+    /// order, a resent one counts from its last transmission. meant
+    /// for real-time streams such as audio or positions, 0 (the default)
+    /// delivers messages as soon as they are complete
+    pub fn set_playout_delay(&mut self, delay: u32) {
+        self.playout = delay;
+    }
+
+    pub fn playout_delay(&self) -> u32 {
+        self.playout
+    }
+
+    /// hold acks up to `delay` millisec for data to send along with them,
+    /// so request/response traffic takes one datagram per direction
+    /// instead of an ack-only one followed by the response. acks still go
+    /// out with any data or probe flushed in the meantime. 0, the default,
+    /// acks at the next flush
+    pub fn set_ack_delay(&mut self, delay: u32) {
+        self.ack_delay = delay;
+    }
+
+    /// Emit at most `segments` data segments per flush, the rest goes out
+    /// with the following ones, so a window opening wide does not leave
+    /// in a line rate burst that shallow buffers on the path drop.
+    /// Unlimited by default
+    pub fn set_burst_limit(&mut self, segments: u32) {
+        self.burst_limit = cmp::max(segments, 1);
+    }
+
+    /// `set_burst_limit` down to 0, for drivers sharing out a budget of
+    /// data segments, acks and probes still go out
+    pub(crate) fn limit_burst(&mut self, segments: u32) {
+        self.burst_limit = segments;
+    }
+
+    /// the most data segments a flush emits, see `set_burst_limit`
+    pub fn burst_limit(&self) -> u32 {
+        self.burst_limit
+    }
+
+    /// data segments sent so far, retransmissions included
+    pub fn segments_sent(&self) -> u64 {
+        self.segments_sent
+    }
+
+    /// Flush right from `input` when acks open the window for queued data
+    /// or call for a fast resend, instead of at the next `update`, which
+    /// takes up to an interval off the recovery from a loss. `gap` is the
+    /// least millisec between two such flushes, so a stream of acks does
+    /// not turn into a flush per datagram. `None`, the default, leaves
+    /// flushing to `update`
+    pub fn set_ack_flush(&mut self, gap: Option<u32>) {
+        self.ack_flush = gap;
+    }
+
+    /// send a window update after `interval` millisec without any output,
+    /// keeping NAT mappings and firewall state of an idle session alive.
+    /// 0 turns it off, the default
+    pub fn set_keepalive(&mut self, interval: u32) {
+        self.keepalive = interval;
+        self.ts_keepalive = self.current + interval;
+    }
+
+    /// transmissions of a single segment after which the link counts as
+    /// dead, 20 by default. a LAN service wants far fewer than a satellite
+    /// link, `KcpStats::max_xmit` shows what a path actually needs. once
+    /// dead nothing is sent any more, `send` fails with `TimedOut` and so
+    /// does `recv` after the messages that made it
+    pub fn set_dead_link(&mut self, xmit: u32) {
+        self.dead_link = cmp::max(xmit, 1);
+    }
+
+    pub fn dead_link(&self) -> u32 {
+        self.dead_link
+    }
+
+    /// whether a segment was sent `dead_link` times without being
+    /// acknowledged, see `set_dead_link`
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// call `alarm` when a segment is sent for the `xmit`th time or its
+    /// retransmission timeout backs off to `rto` millisec, 0 leaving either
+    /// out. meant to fail over to another path well before `dead_link`
+    /// gives up on this one. `alarm` runs inside `flush` and `update`, it
+    /// must not call back into the control block
+    pub fn set_failover_alarm<F>(&mut self, xmit: u32, rto: u32, alarm: F)
+    where
+        F: FnMut(FailoverAlarm) + Send + 'static,
+    {
+        self.alarm = Some(Box::new(alarm));
+        self.alarm_xmit = if xmit > 1 { xmit } else { 0 };
+        self.alarm_rto = rto;
+    }
+
+    /// call `tap` with the header of every segment parsed from the input
+    /// and of every segment sent, with the time it passed on the clock of
+    /// `update`. headers of a compact datagram are seen expanded
+    pub fn set_segment_tap<F>(&mut self, tap: F)
+    where
+        F: FnMut(Direction, u32, &SegmentHeader) + Send + 'static,
+    {
+        self.output.tap = Some(Box::new(tap));
+    }
+
+    pub fn clear_segment_tap(&mut self) {
+        self.output.tap = None;
+    }
+
+    pub fn clear_failover_alarm(&mut self) {
+        self.alarm = None;
+    }
+
+    /// acknowledge runs of consecutive segments with a single range segment
+    /// instead of one header each. ranges are only sent once the peer has
+    /// shown it understands them, which it is told with a window update
+    pub fn set_ack_ranges(&mut self, on: bool) {
+        self.set_extension(KCP_EXT_ACKR, on);
+    }
+
+    /// step the mtu down (1400, 1200, 1024) when full size segments keep
+    /// timing out while smaller ones are acknowledged, as behind a tunnel
+    /// silently dropping large datagrams. segments already in flight are
+    /// split into parts if the peer understands them, which it is told
+    /// with a window update. off by default
+    pub fn set_mtu_downshift(&mut self, on: bool) {
+        self.downshift = on;
+        self.set_extension(KCP_EXT_PART, on);
+    }
+
+    /// write conv, window and una once per datagram instead of in front of
+    /// every segment, saving 10 bytes per coalesced segment. only used
+    /// once the peer has shown it reads such datagrams, which it is told
+    /// with a window update. off by default
+    pub fn set_compact_headers(&mut self, on: bool) {
+        self.set_extension(KCP_EXT_COMPACT, on);
+    }
+
+    fn set_extension(&mut self, ext: u8, on: bool) {
+        if on {
+            if self.extensions & ext == 0 {
+                self.probe |= KCP_ASK_TELL;
+            }
+            self.extensions |= ext;
+        } else {
+            self.extensions &= !ext;
+        }
+    }
+
+    /// pad every outgoing datagram with zeros up to the smallest of `sizes`
+    /// it fits in, hiding message lengths from observers. sizes are capped
+    /// at the MTU, pass a single size for constant-size datagrams or an
+    /// empty slice to disable padding (default)
+    pub fn set_padding(&mut self, sizes: &[usize]) {
+        self.padding = sizes.to_vec();
+        self.padding.sort();
+        self.padding.dedup();
+    }
+
+    /// replace the output sink, e.g. with a socket re-created on another
+    /// interface, keeping all protocol state. returns the previous one
+    pub fn set_output(&mut self, output: W) -> W {
+        mem::replace(&mut self.output.sink, output)
+    }
+
+    /// hand each datagram to the output sink with a single `write_vectored`
+    /// of the headers and payloads of its segments instead of copying them
+    /// into one buffer first. the sink has to send all slices as one
+    /// datagram, which the default `Write::write_vectored` does not, see
+    /// `SegmentOutput`. off by default
+    pub fn set_vectored(&mut self, on: bool) {
+        self.vectored = on;
+    }
+
+    /// what to do with datagrams the output sink fails to take
+    pub fn set_output_error_policy(&mut self, policy: OutputErrorPolicy) {
+        self.output.policy = policy;
+    }
+
+    /// whether datagrams refused by the output sink are held back
+    pub fn output_pending(&self) -> bool {
+        !self.output.retry.is_empty()
+    }
+
+    /// send the datagrams held back after the output sink refused them,
+    /// e.g. once a socket that returned `WouldBlock` is writable again
+    pub fn flush_output(&mut self) {
+        self.output.retry();
+    }
+
+    /// the error the control block failed with under
+    /// `OutputErrorPolicy::Fail`
+    pub fn output_error(&self) -> Option<&Error> {
+        self.output.failed.as_ref()
+    }
+
     /// set maximum window size: `sndwnd`=32, `rcvwnd`=32 by default
     pub fn wndsize(&mut self, sndwnd: i32, rcvwnd: i32) {
+        let limit = cmp::min(KCP_QUEUE_LIMIT, i32::max_value() as usize) as i32;
         if sndwnd > 0 {
-            self.snd_wnd = sndwnd as u32;
+            self.snd_wnd = cmp::min(sndwnd, limit) as u32;
         }
         if rcvwnd > 0 {
-            self.rcv_wnd = rcvwnd as u32;
+            self.rcv_wnd = cmp::min(rcvwnd, limit) as u32;
+        }
+    }
+
+    /// the receive window set by `wndsize`, in segments
+    pub fn rcv_wnd(&self) -> u32 {
+        self.rcv_wnd
+    }
+
+    /// snapshot of the connection statistics
+    pub fn stats(&self) -> KcpStats {
+        KcpStats {
+            srtt: self.rx_srtt,
+            rto: self.rx_rto,
+            cwnd: self.cwnd,
+            retransmits: self.xmit,
+            delivered: self.delivered,
+            delivery_rate: self.delivery_rate,
+            bandwidth: self.bandwidth,
+            output_errors: self.output.errors,
+            output_dropped: self.output.dropped,
+            flush_budget: self.flush_budget,
+            mtu: self.mtu,
+            mtu_downshifts: self.downshifts,
+            max_xmit: self.max_xmit,
+            segments_sent: self.segments_sent,
+            malformed: self.malformed,
+            duplicates: self.duplicates,
+            out_of_window: self.out_of_window,
+            reordered: self.reordered,
+            reorder_distance: self.reorder_distance,
+            reassembly_dropped: self.reassembly_dropped,
+            memory: self.memory(),
+        }
+    }
+
+    /// conversation id of this control block
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    /// whether anything has been heard from the peer yet
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Close the conversation with `code` and `reason`, which the peer reads
+    /// with `peer_close` once it received everything sent before. Nothing
+    /// can be sent afterwards, reasons longer than 123 bytes are cut.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.close.is_some() {
+            return;
+        }
+        let mut end = cmp::min(reason.len(), KCP_CLOSE_REASON);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.close = Some(CloseFrame {
+            code: code,
+            reason: reason[..end].to_owned(),
+        });
+        self.ts_close = self.current;
+    }
+
+    /// whether our close frame was acknowledged, or given up on after as
+    /// many attempts as make the link count as dead
+    pub fn is_closed(&self) -> bool {
+        self.close.is_some() && (self.close_acked || self.close_xmit >= self.dead_link)
+    }
+
+    /// the close frame of the peer, once it closed the conversation
+    pub fn peer_close(&self) -> Option<&CloseFrame> {
+        self.peer_close.as_ref()
+    }
+
+    /// Abort the conversation: drop everything queued either way and send
+    /// the peer a reset frame right away, so it stops retransmitting
+    /// instead of waiting for the link to count as dead. Nothing is sent
+    /// or received afterwards, reads and writes fail with
+    /// `ConnectionAborted` here and `ConnectionReset` at the peer.
+    pub fn reset(&mut self) {
+        if self.reset.is_some() {
+            return;
+        }
+        let mut seg = Segment::default();
+        seg.conv = self.conv;
+        seg.cmd = KCP_CMD_RESET;
+        seg.ts = self.current;
+        seg.sn = self.snd_nxt;
+        seg.una = self.rcv_nxt;
+        // whatever a flush left unsent goes first, not after the reset
+        if !self.buffer.is_empty() {
+            self.output.send(&mut self.buffer, &self.padding, self.mtu);
         }
+        seg.encode(&mut self.buffer);
+        self.output.current = self.current;
+        self.output.send(&mut self.buffer, &self.padding, self.mtu);
+        self.abort(ErrorKind::ConnectionAborted);
+    }
+
+    /// whether the conversation was aborted by `reset` on either side
+    pub fn is_reset(&self) -> bool {
+        self.reset.is_some()
+    }
+
+    fn abort(&mut self, kind: ErrorKind) {
+        self.reset = Some(kind);
+        self.snd_queue.clear();
+        self.snd_buf.clear();
+        self.rcv_queue.clear();
+        self.rcv_buf.clear();
+        self.held_chains = 0;
+        self.held_bytes = 0;
+        self.evicted.clear();
+        self.acklist.clear();
+        self.probe = 0;
+        self.wins_left = 0;
+    }
+
+    fn check_reset(&self) -> io::Result<()> {
+        match self.reset {
+            Some(ErrorKind::ConnectionAborted) => {
+                Err(Error::new(ErrorKind::ConnectionAborted, "conversation reset"))
+            }
+            Some(kind) => Err(Error::new(kind, "conversation reset by peer")),
+            None => Ok(()),
+        }
+    }
+
+    /// ask the peer for its window size on the next flush, which also
+    /// tells whether it is reachable at all
+    pub fn ask_window(&mut self) {
+        self.probe |= KCP_ASK_SEND;
     }
 
     /// get how many packet is waiting to be sent
     pub fn waitsnd(&self) -> usize {
         self.snd_buf.len() + self.snd_queue.len()
     }
+
+    /// payload bytes of the segments counted by `waitsnd`
+    pub fn waitsnd_bytes(&self) -> usize {
+        self.snd_buf.iter().chain(&self.snd_queue).map(Segment::len).sum()
+    }
+
+    /// segments sent and not acknowledged yet
+    pub fn inflight(&self) -> usize {
+        self.snd_buf.len()
+    }
+
+    /// complete messages waiting for `recv`, in stream mode every segment
+    /// counts as one
+    pub fn waitrcv(&self) -> usize {
+        self.rcv_queue.iter().filter(|seg| seg.frg == 0).count()
+    }
+
+    /// payload bytes received and not taken by `recv` yet, including
+    /// segments still waiting for a gap before them to be filled
+    pub fn waitrcv_bytes(&self) -> usize {
+        self.rcv_queue
+            .iter()
+            .chain(self.rcv_buf.iter().filter(|seg| !seg.delivered))
+            .map(|seg| seg.data.len())
+            .sum()
+    }
+
+    /// how many segments may wait to be sent before `writable` turns
+    /// false, 1024 by default. in stream mode `send` takes no more than
+    /// fits below it, messages are queued whole regardless, it is up to
+    /// writers such as `KcpStream` to hold back
+    pub fn set_send_backlog(&mut self, segments: usize) {
+        self.snd_backlog = cmp::max(segments, 1);
+    }
+
+    /// advertise a zero window while `on`, holding the peer back until
+    /// the application can take more. segments already in flight are
+    /// still received. the peer learns of it at the next flush, and of
+    /// the window reopening like after a full receive queue
+    pub fn set_paused(&mut self, on: bool) {
+        if on == self.paused {
+            return;
+        }
+        self.paused = on;
+        if on {
+            self.probe |= KCP_ASK_TELL;
+        } else if self.wnd_unused() > 0 {
+            self.window_reopened();
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// advertise at most `wnd` segments of the receive window, slowing
+    /// the peer down without dropping what it has in flight. `u32::MAX`
+    /// lifts it, the default
+    pub fn set_window_clamp(&mut self, wnd: u32) {
+        let reopens = wnd > self.wnd_clamp;
+        self.wnd_clamp = wnd;
+        if reopens && self.wnd_unused() > 0 {
+            self.window_reopened();
+        }
+    }
+
+    /// whether `waitsnd` is below the send backlog, and `waitsnd_bytes`
+    /// below the memory budget. what was received and not read yet does
+    /// not count, two ends writing before they read would hold each other
+    /// up otherwise
+    pub fn writable(&self) -> bool {
+        self.waitsnd() < self.snd_backlog &&
+            (self.memory_budget == usize::MAX || self.waitsnd_bytes() < self.memory_budget)
+    }
+
+    /// consistency checks of the queues and sequence numbers, used by the
+    /// fuzz targets
+    #[doc(hidden)]
+    pub fn verify_invariants(&self) -> Result<(), String> {
+        if timediff(self.snd_nxt, self.snd_una) < 0 {
+            return Err(format!("snd_una {} ahead of snd_nxt {}", self.snd_una, self.snd_nxt));
+        }
+        let mut prev = None;
+        for seg in &self.snd_buf {
+            if timediff(seg.sn, self.snd_una) < 0 || timediff(seg.sn, self.snd_nxt) >= 0 {
+                return Err(format!(
+                    "snd_buf sn {} outside [{}, {})",
+                    seg.sn,
+                    self.snd_una,
+                    self.snd_nxt
+                ));
+            }
+            if prev.map_or(false, |prev| timediff(seg.sn, prev) <= 0) {
+                return Err(format!("snd_buf sn {} out of order", seg.sn));
+            }
+            prev = Some(seg.sn);
+        }
+        let mut prev = None;
+        for seg in &self.rcv_buf {
+            if timediff(seg.sn, self.rcv_nxt) < 0 ||
+                timediff(seg.sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0
+            {
+                return Err(format!(
+                    "rcv_buf sn {} outside receive window at {}",
+                    seg.sn,
+                    self.rcv_nxt
+                ));
+            }
+            if prev.map_or(false, |prev| timediff(seg.sn, prev) <= 0) {
+                return Err(format!("rcv_buf sn {} out of order", seg.sn));
+            }
+            prev = Some(seg.sn);
+        }
+        let live = self.rcv_buf.iter().filter(|seg| !seg.delivered);
+        let bytes: usize = live.clone().map(Segment::len).sum();
+        let mut chains = 0;
+        let mut last = None;
+        for seg in live {
+            if last != Some(seg.chain_end()) {
+                chains += 1;
+                last = Some(seg.chain_end());
+            }
+        }
+        if chains != self.held_chains || bytes != self.held_bytes {
+            return Err(format!(
+                "rcv_buf holds {} messages of {} bytes, counted {} of {}",
+                chains,
+                bytes,
+                self.held_chains,
+                self.held_bytes
+            ));
+        }
+        if self.rcv_queue.iter().any(|seg| seg.delivered) {
+            return Err("delivered placeholder in rcv_queue".to_string());
+        }
+        Ok(())
+    }
+
+    /// estimated offset of the remote clock relative to ours in millisec
+    /// (remote - local), `None` until the first data segment arrives
+    pub fn clock_offset(&self) -> Option<i32> {
+        if self.skew_valid {
+            Some(self.skew_offset)
+        } else {
+            None
+        }
+    }
+
+    /// estimated drift of the remote clock relative to ours in parts per
+    /// million, positive when the remote clock runs faster. `None` until
+    /// at least one second of samples has been collected
+    pub fn clock_drift(&self) -> Option<i32> {
+        if self.skew_valid && timediff(self.current, self.skew_base_ts) >= 1000 {
+            Some(self.skew_drift)
+        } else {
+            None
+        }
+    }
+
+    /// the state of the control block for a bug report: the `Debug` form
+    /// plus a line per segment in flight. payloads are never included,
+    /// only their lengths
+    pub fn dump(&self) -> String {
+        let mut out = format!("{:#?}\n", self);
+        for seg in &self.snd_buf {
+            out.push_str(&format!(
+                "  sn {} frg {} len {} xmit {} rto {} resend in {} fastack {}\n",
+                seg.sn,
+                seg.frg,
+                seg.len(),
+                seg.xmit,
+                seg.rto,
+                timediff(seg.resendts, self.current),
+                seg.fastack,
+            ));
+        }
+        out
+    }
+}
+
+impl<W: Write> fmt::Debug for Kcb<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Kcb")
+            .field("conv", &self.conv)
+            .field("current", &self.current)
+            .field("mss", &self.mss)
+            .field("snd_una", &self.snd_una)
+            .field("snd_nxt", &self.snd_nxt)
+            .field("rcv_nxt", &self.rcv_nxt)
+            .field("cwnd", &self.cwnd)
+            .field("ssthresh", &self.ssthresh)
+            .field("snd_wnd", &self.snd_wnd)
+            .field("rcv_wnd", &self.rcv_wnd)
+            .field("rmt_wnd", &self.rmt_wnd)
+            .field("srtt", &self.rx_srtt)
+            .field("rttval", &self.rx_rttval)
+            .field("rto", &self.rx_rto)
+            .field("minrto", &self.rx_minrto)
+            .field("max_xmit", &self.max_xmit)
+            .field("snd_queue", &SegmentCount(&self.snd_queue))
+            .field("snd_buf", &SnRanges::of(&self.snd_buf))
+            .field("rcv_buf", &SnRanges::of(&self.rcv_buf))
+            .field("rcv_queue", &SnRanges::of(&self.rcv_queue))
+            .field("acklist", &SnRanges(self.acklist.iter().map(|ack| ack.0).collect()))
+            .field("probe", &self.probe)
+            .field("learn_isn", &self.learn_isn)
+            .field("paused", &self.paused)
+            .field("closing", &self.close.is_some())
+            .field("peer_closed", &self.peer_close.is_some())
+            .finish()
+    }
+}
+
+/// segments waiting for an sn, as their count and bytes
+struct SegmentCount<'a>(&'a VecDeque<Segment>);
+
+impl<'a> fmt::Debug for SegmentCount<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: usize = self.0.iter().map(Segment::len).sum();
+        write!(f, "{} segments, {} bytes", self.0.len(), bytes)
+    }
+}
+
+/// the sns of a queue, runs of consecutive ones as `first-last`
+struct SnRanges(Vec<u32>);
+
+impl SnRanges {
+    fn of(segs: &VecDeque<Segment>) -> SnRanges {
+        SnRanges(segs.iter().map(|seg| seg.sn).collect())
+    }
+}
+
+impl fmt::Debug for SnRanges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        let mut sep = "";
+        let mut i = 0;
+        while i < self.0.len() {
+            let first = self.0[i];
+            let mut last = first;
+            while i + 1 < self.0.len() && self.0[i + 1] == last.wrapping_add(1) {
+                last = last.wrapping_add(1);
+                i += 1;
+            }
+            if first == last {
+                write!(f, "{}{}", sep, first)?;
+            } else {
+                write!(f, "{}{}-{}", sep, first, last)?;
+            }
+            sep = ", ";
+            i += 1;
+        }
+        write!(f, "]")
+    }
+}
+
+/// queues are reserved up front with the `bounded-queues` feature, so they
+/// never have to grow after creation
+#[inline]
+fn initial_capacity() -> usize {
+    if cfg!(feature = "bounded-queues") {
+        KCP_QUEUE_LIMIT
+    } else {
+        0
+    }
+}
+
+/// the next mtu below `mtu` to try when large datagrams vanish, 0 if none
+fn mtu_step_below(mtu: usize) -> usize {
+    KCP_MTU_STEPS.iter().cloned().find(|&step| step < mtu).unwrap_or(0)
+}
+
+/// count an acked segment of `len` bytes as evidence for a black hole if it
+/// would fit the next lower mtu step, a larger one clears the evidence
+fn count_acked(len: usize, mtu: usize, small_acked: &mut u32) {
+    if KCP_OVERHEAD + len <= mtu_step_below(mtu) {
+        *small_acked += 1;
+    } else {
+        *small_acked = 0;
+    }
+}
+
+#[inline]
+fn millis(d: Duration) -> u32 {
+    (d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000) as u32
+}
+
+/// sn of the last fragment of a message, counting down from `sn`
+#[inline]
+fn chain_end(cmd: u8, sn: u32, frg: u8) -> u32 {
+    let frg = if cmd == KCP_CMD_UPUSH { frg & !KCP_FRG_FIRST } else { frg };
+    sn.wrapping_add(frg as u32)
 }
 
 #[inline]
@@ -1,34 +1,413 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+#[cfg(unix)]
+use std::env;
 use std::io::{self, Read, Write};
-use std::net::SocketAddr;
+#[cfg(unix)]
+use std::io::IoSlice;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::process;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
 
-use bytes::{Buf, BufMut, ByteOrder, LittleEndian};
-use ctime;
+use bytes::{Buf, BufMut, ByteOrder, Bytes, BytesMut, LittleEndian};
 use futures::stream::Stream;
-use futures::{Poll, Async, Future};
+use futures::future::{self, Either};
+use futures::sync::mpsc as sync_mpsc;
+use futures::sync::oneshot;
+use futures::unsync::mpsc::{self as unsync_mpsc, UnboundedReceiver, UnboundedSender};
+use futures::{task, Poll, Async, Future, IntoFuture};
 use iovec::IoVec;
+#[cfg(unix)]
+use libc::{self, c_int};
 use mio::event::Evented;
 use mio::{self, Ready, Registration, PollOpt, Token, SetReadiness};
 use rand;
-use tokio_core::net::UdpSocket;
-use tokio_core::reactor::{Handle, PollEvented, Timeout};
+use tokio_core::net::{TcpStream, UdpSocket};
+use tokio_core::reactor::{Core, Handle, Interval, PollEvented, Timeout};
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use Kcb;
+use config::{KcpConfig, Keepalive, Offer};
+use conv::{ConvAllocator, RandomConv};
+use sessions::{SessionId, SessionMap};
+use socks;
+use {reset_frame, CloseFrame, FailoverAlarm, Kcb, KcpStats, ShortBuffer};
 
 struct KcpPair {
     k: Rc<RefCell<Kcb<KcpOutput>>>,
     set_readiness: SetReadiness,
-    token: Rc<RefCell<Timeout>>,
+    // `None` when the session is updated by a shared driver
+    token: Option<Rc<RefCell<Timeout>>>,
+    closed: Rc<Cell<bool>>,
+    // stream held back until the authenticator admits the session
+    pending: Option<KcpStream>,
+    // the session parameters were agreed on, or did not have to be
+    negotiated: bool,
+    state: Rc<StateWatch>,
+    // when the last datagram of the peer came in
+    heard: Cell<Instant>,
+    budget: Rc<TickBudget>,
+}
+
+impl KcpPair {
+    /// feed a datagram to the session and wake up its reader
+    fn input(&self, buf: &Bytes) {
+        let mut kcb = self.k.borrow_mut();
+        let now = Instant::now();
+        self.heard.set(now);
+        // acks flushed right away count against the tick
+        self.budget.flush(&mut kcb, |kcb| {
+            kcb.input_bytes(buf);
+            kcb.update_at(now);
+        });
+        if let Some(ref token) = self.token {
+            token.borrow_mut().reset(kcb.check_at(now));
+        }
+        self.state.refresh(&mut kcb);
+
+        self.set_readiness.set_readiness(readiness(&kcb));
+    }
+}
+
+const RECV_POOL_DATAGRAMS: usize = 64; // datagrams received into one buffer of a pool
+const RECV_DATAGRAM_MIN: usize = 1500; // least room for a datagram, an ethernet frame
+
+/// Receives datagrams into consecutive slices of one larger buffer, so
+/// sessions take their payloads without copying them, see
+/// `Kcb::input_bytes`. A fresh buffer is started once the current one is
+/// used up, the old one goes away with the last slice still pointing into
+/// it. Datagrams larger than the room given to `new` are cut short, so
+/// it is sized to the mtu of the sessions it receives for.
+struct RecvPool {
+    buf: BytesMut,
+    // most bytes a single datagram is received into
+    datagram: usize,
+}
+
+impl RecvPool {
+    fn new(mtu: usize) -> RecvPool {
+        RecvPool {
+            buf: BytesMut::new(),
+            datagram: cmp::max(mtu, RECV_DATAGRAM_MIN),
+        }
+    }
+
+    /// receive the next datagram with `recv`
+    fn recv<F>(&mut self, recv: F) -> io::Result<(Bytes, SocketAddr)>
+    where
+        F: FnOnce(&mut [u8]) -> io::Result<(usize, SocketAddr)>,
+    {
+        if self.buf.len() < self.datagram {
+            // zeroed once per pool, not per datagram
+            let size = self.datagram * RECV_POOL_DATAGRAMS;
+            self.buf = BytesMut::with_capacity(size);
+            self.buf.resize(size, 0);
+        }
+        let (n, addr) = recv(&mut self.buf[..self.datagram])?;
+        Ok((self.buf.split_to(n).freeze(), addr))
+    }
+}
+
+/// readiness of a session for its `KcpStream`: always worth a read, data
+/// may have arrived, writable while acks keep the backlog in check or once
+/// the link is dead, so a pending write gets to see the error
+fn readiness(kcb: &Kcb<KcpOutput>) -> mio::Ready {
+    if kcb.writable() || kcb.is_dead() {
+        mio::Ready::readable() | mio::Ready::writable()
+    } else {
+        mio::Ready::readable()
+    }
+}
+
+const MEMORY_CHECK_INTERVAL: u64 = 100; // how often a listener sums up its sessions in millisec
+const DEFAULT_TIME_WAIT: u64 = 10_000; // how long the conv of a closed session is retired in millisec
+const SWEEP_INTERVAL: u64 = 1_000; // how often a listener drops gone sessions from its table in millisec
+
+/// refuse what the sessions here can't do instead of silently going on
+/// without it, forward error correction is up to the `tokio` module
+#[cfg(feature = "fec")]
+fn check_config(config: &KcpConfig) -> io::Result<()> {
+    match config.fec {
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "fec is only supported by the tokio module",
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "fec"))]
+fn check_config(_: &KcpConfig) -> io::Result<()> {
+    Ok(())
+}
+
+/// answer a datagram of a conversation there is no session for with a
+/// reset, so that its sender gives up right away
+fn send_reset(udp: &UdpSocket, datagram: &[u8], addr: &SocketAddr) {
+    if let Some(frame) = reset_frame(datagram) {
+        // like any other datagram it may get lost
+        let _ = udp.send_to(&frame, addr);
+    }
+}
+
+/// clamp the advertised window of a session to a quarter while the
+/// listener is short of memory
+fn squeeze_window(kcb: &mut Kcb<KcpOutput>, on: bool) {
+    let wnd = if on {
+        cmp::max(kcb.rcv_wnd() / 4, 1)
+    } else {
+        u32::MAX
+    };
+    kcb.set_window_clamp(wnd);
+}
+
+/// Session lifecycle events reported by `KcpListener::events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// a new session was admitted and handed out by `accept`
+    Opened { addr: SocketAddr, conv: u32 },
+    /// the accepted `KcpStream` was dropped by the application
+    Closed { addr: SocketAddr, conv: u32 },
+    /// the session was reset by the listener after going idle for the
+    /// idle timeout, or dropped once its link was dead
+    Expired { addr: SocketAddr, conv: u32 },
+    /// the first datagram from a peer was refused, `conv` is 0 when the
+    /// datagram was too short to carry one
+    Rejected { addr: SocketAddr, conv: u32 },
+    /// a new peer at `addr` was refused for opening with the conv of the
+    /// session of `owner`
+    Collision {
+        addr: SocketAddr,
+        conv: u32,
+        owner: SocketAddr,
+    },
+}
+
+/// Totals over the sessions of a listener, see `KcpListener::stats`
+#[derive(Debug, Clone, Default)]
+pub struct ListenerStats {
+    /// sessions open now
+    pub sessions: usize,
+    /// sessions accepted since the listener was bound
+    pub accepted: u64,
+    /// sessions closed, dropped or expired since the listener was bound
+    pub closed: u64,
+    /// new peers refused, see `SessionEvent::Rejected`
+    pub rejected: u64,
+    /// new peers refused for the conv of another session
+    pub collisions: u64,
+    /// sessions accepted per second, averaged since the listener was bound
+    pub accept_rate: f64,
+    /// sessions closed per second, averaged since the listener was bound
+    pub close_rate: f64,
+    /// segments retransmitted after a timeout, over all sessions
+    pub retransmits: u64,
+    /// payload bytes acknowledged by the peers, over all sessions
+    pub delivered: u64,
+    /// sum of the latest delivery rates of the open sessions in bytes per
+    /// second
+    pub goodput: u64,
+    /// payload bytes held by the queues of all sessions
+    pub memory: usize,
+}
+
+/// Lifecycle of a `KcpStream`, see `KcpStream::state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// nothing has been heard from the peer yet
+    Connecting,
+    /// the peer answered
+    Established,
+    /// closed, waiting for the peer to acknowledge what was sent and the
+    /// close frame
+    Closing,
+    /// closed with everything acknowledged, or dropped
+    Closed,
+    /// the socket failed to send, the conversation was reset or the link
+    /// went dead, the session cannot recover
+    Broken,
+}
+
+impl ConnectionState {
+    /// whether the state is `Closed` or `Broken`, never left again
+    pub fn is_final(&self) -> bool {
+        match *self {
+            ConnectionState::Closed | ConnectionState::Broken => true,
+            _ => false,
+        }
+    }
+}
+
+/// Current state of a stream and the subscribers to its changes.
+struct StateWatch {
+    state: Cell<ConnectionState>,
+    watchers: RefCell<Vec<UnboundedSender<ConnectionState>>>,
+    // the session is done with, and until when a dropped stream closing
+    // keeps it going
+    closed: Rc<Cell<bool>>,
+    linger: Cell<Option<Instant>>,
+}
+
+impl StateWatch {
+    fn new(state: ConnectionState, closed: Rc<Cell<bool>>) -> Rc<StateWatch> {
+        Rc::new(StateWatch {
+            state: Cell::new(state),
+            watchers: RefCell::new(Vec::new()),
+            closed: closed,
+            linger: Cell::new(None),
+        })
+    }
+
+    fn get(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// move to `state` and tell the watchers, which are let go once it is
+    /// a final one so their streams end
+    fn set(&self, state: ConnectionState) {
+        if self.state.get() != state {
+            self.state.set(state);
+            let mut watchers = self.watchers.borrow_mut();
+            watchers.retain(|tx| tx.unbounded_send(state).is_ok());
+            if state.is_final() {
+                watchers.clear();
+            }
+        }
+    }
+
+    /// move on to what the control block tells, `Closed` and `Broken` are
+    /// final. a lingering session still short of the close acknowledgement
+    /// when its time is up resets the peer, which would wait for the rest
+    /// forever otherwise
+    fn refresh(&self, kcb: &mut Kcb<KcpOutput>) {
+        let state = match self.get() {
+            state @ ConnectionState::Closed |
+            state @ ConnectionState::Broken => state,
+            _ if kcb.output_error().is_some() || kcb.is_reset() || kcb.is_dead() => {
+                ConnectionState::Broken
+            }
+            ConnectionState::Connecting if kcb.is_established() => ConnectionState::Established,
+            ConnectionState::Closing if kcb.is_closed() => ConnectionState::Closed,
+            state => state,
+        };
+        self.set(state);
+        if let Some(until) = self.linger.get() {
+            let done = match state {
+                ConnectionState::Closed | ConnectionState::Broken => true,
+                _ => Instant::now() >= until,
+            };
+            if done {
+                if state == ConnectionState::Closing {
+                    kcb.reset();
+                }
+                self.linger.set(None);
+                self.closed.set(true);
+                if state != ConnectionState::Broken {
+                    self.set(ConnectionState::Closed);
+                }
+            }
+        }
+    }
+
+    fn watch(&self) -> StateChanges {
+        let (tx, rx) = unsync_mpsc::unbounded();
+        tx.unbounded_send(self.get()).ok();
+        if !self.get().is_final() {
+            self.watchers.borrow_mut().push(tx);
+        }
+        StateChanges { rx: rx }
+    }
+}
+
+/// Stream of the states a `KcpStream` goes through, starting with the
+/// current one, see `KcpStream::watch_state`. Ends after `Closed` or
+/// `Broken`, or once the stream is gone.
+pub struct StateChanges {
+    rx: UnboundedReceiver<ConnectionState>,
+}
+
+impl Stream for StateChanges {
+    type Item = ConnectionState;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<ConnectionState>, io::Error> {
+        self.rx.poll().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "state changes closed")
+        })
+    }
+}
+
+/// Stream of `SessionEvent`s, see `KcpListener::events`
+pub struct SessionEvents {
+    rx: UnboundedReceiver<SessionEvent>,
+}
+
+impl Stream for SessionEvents {
+    type Item = SessionEvent;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<SessionEvent>, io::Error> {
+        self.rx.poll().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "session events closed")
+        })
+    }
 }
 
 pub struct KcpListener {
     udp: Rc<UdpSocket>,
-    connections: HashMap<SocketAddr, KcpPair>,
+    // further sockets of `bind_multi`, sharing the session table
+    extra: Vec<Rc<UdpSocket>>,
+    // socket to read from first, so that a busy one starves none
+    next: usize,
+    pool: RecvPool,
+    connections: SessionMap<SocketAddr, KcpPair>,
+    // the session of every conv, and the newcomers refused for reusing one
+    convs: HashMap<u32, SessionId>,
+    collisions: u64,
+    // since `bound`: sessions accepted, refused and dropped from the
+    // table, and what the dropped ones had retransmitted and delivered
+    bound: Instant,
+    accepted: u64,
+    rejected: u64,
+    closed: u64,
+    closed_retransmits: u64,
+    closed_delivered: u64,
+    // `set_memory_limit`: the limit, what the sessions held at the last
+    // check, and whether their windows are clamped for it
+    memory_limit: usize,
+    memory_used: usize,
+    memory_checked: Instant,
+    squeezed: bool,
+    // recently closed sessions whose datagrams are still dropped, by conv
+    // and peer, and the same in the order they run out
+    tombstones: HashMap<(u32, SocketAddr), Instant>,
+    tombstone_order: VecDeque<(Instant, u32, SocketAddr)>,
+    time_wait: Duration,
+    // `set_idle_timeout` and `set_max_sessions`, and when the table is
+    // next checked for sessions that are gone
+    idle_timeout: Option<Duration>,
+    max_sessions: usize,
+    sweep: Timeout,
+    // shared out among the sessions, see `set_tick_budget`
+    budget: Rc<TickBudget>,
     handle: Handle,
+    events: Option<UnboundedSender<SessionEvent>>,
+    authenticator: Option<Box<Fn(&SocketAddr, u32, &[u8]) -> bool>>,
+    allocator: Box<ConvAllocator>,
+    config: KcpConfig,
+    negotiation: bool,
 }
 
 pub struct Incoming {
@@ -37,101 +416,868 @@ pub struct Incoming {
 
 impl KcpListener {
     pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<KcpListener> {
-        let udp = UdpSocket::bind(addr, handle).unwrap();
-        let listener = KcpListener {
+        KcpListener::bind_with_config(addr, KcpConfig::default(), handle)
+    }
+
+    /// Same as `bind`, with `config` applied to every accepted session
+    /// before its first datagram is processed.
+    pub fn bind_with_config(
+        addr: &SocketAddr,
+        config: KcpConfig,
+        handle: &Handle,
+    ) -> io::Result<KcpListener> {
+        check_config(&config)?;
+        let udp = UdpSocket::bind(addr, handle)?;
+        Ok(KcpListener::from_socket(udp, config, handle))
+    }
+
+    /// One listener on all of `addrs`, for hosts with several uplinks.
+    /// Sessions are accepted from any of the sockets into one table and
+    /// answered from the socket their first datagram came in on, socket
+    /// options and `local_addr` apply to the first one.
+    pub fn bind_multi(addrs: &[SocketAddr], handle: &Handle) -> io::Result<KcpListener> {
+        KcpListener::bind_multi_with_config(addrs, KcpConfig::default(), handle)
+    }
+
+    /// Same as `bind_multi`, with `config` applied to every accepted
+    /// session.
+    pub fn bind_multi_with_config(
+        addrs: &[SocketAddr],
+        config: KcpConfig,
+        handle: &Handle,
+    ) -> io::Result<KcpListener> {
+        check_config(&config)?;
+        let (first, rest) = match addrs.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "no addresses"));
+            }
+        };
+        let udp = UdpSocket::bind(first, handle)?;
+        let mut listener = KcpListener::from_socket(udp, config, handle);
+        for addr in rest {
+            listener.extra.push(Rc::new(UdpSocket::bind(addr, handle)?));
+        }
+        Ok(listener)
+    }
+
+    /// A listener on `socket`, which is already bound, e.g. inherited from
+    /// the parent process.
+    pub fn from_std(
+        socket: net::UdpSocket,
+        config: KcpConfig,
+        handle: &Handle,
+    ) -> io::Result<KcpListener> {
+        check_config(&config)?;
+        let udp = UdpSocket::from_socket(socket, handle)?;
+        Ok(KcpListener::from_socket(udp, config, handle))
+    }
+
+    /// A listener on the UDP sockets passed by systemd socket activation
+    /// (`LISTEN_PID` and `LISTEN_FDS`), or `None` when the process was not
+    /// started that way. Several sockets make one listener as with
+    /// `bind_multi`. The variables are removed from the environment so
+    /// that children do not take the sockets as well.
+    #[cfg(unix)]
+    pub fn from_systemd(config: KcpConfig, handle: &Handle) -> io::Result<Option<KcpListener>> {
+        check_config(&config)?;
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok());
+        if pid != Some(process::id()) {
+            return Ok(None);
+        }
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        let fds = match fds {
+            Some(fds) if fds > 0 => fds,
+            _ => return Ok(None),
+        };
+        let mut sockets = Vec::new();
+        for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds {
+            if socket_type(fd)? != libc::SOCK_DGRAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("inherited fd {} is not a UDP socket", fd),
+                ));
+            }
+            let socket = unsafe { net::UdpSocket::from_raw_fd(fd) };
+            sockets.push(UdpSocket::from_socket(socket, handle)?);
+        }
+        let mut sockets = sockets.into_iter();
+        let mut listener = match sockets.next() {
+            Some(udp) => KcpListener::from_socket(udp, config, handle),
+            None => return Ok(None),
+        };
+        listener.extra.extend(sockets.map(Rc::new));
+        Ok(Some(listener))
+    }
+
+    /// Pass the sockets of the listener over `to` to another process, e.g.
+    /// the upgraded binary this one exec'd, which goes on serving them
+    /// with `take_over` so the port never stops answering. The listener
+    /// is gone from this process afterwards.
+    ///
+    /// The session table goes along, as the conv and peer of every open
+    /// session and of those in their time wait. The sessions themselves
+    /// do not, so drain them before the hand off: the new process keeps
+    /// dropping their datagrams for its time wait, rather than taking
+    /// them for new sessions.
+    #[cfg(unix)]
+    pub fn hand_off(self, to: &UnixStream) -> io::Result<()> {
+        let mut fds = vec![self.udp.as_raw_fd()];
+        fds.extend(self.extra.iter().map(|udp| udp.as_raw_fd()));
+        send_fds(to.as_raw_fd(), &fds)?;
+        let now = Instant::now();
+        let mut table = vec![0; 4];
+        for (addr, kp) in self.connections.iter() {
+            encode_session(&mut table, kp.k.borrow().conv(), addr);
+        }
+        for (&(conv, ref addr), &until) in &self.tombstones {
+            if until > now {
+                encode_session(&mut table, conv, addr);
+            }
+        }
+        let len = table.len() - 4;
+        LittleEndian::write_u32(&mut table[..4], len as u32);
+        let mut to = to;
+        to.write_all(&table)
+    }
+
+    /// A listener on the sockets another process passes over `from` with
+    /// `hand_off`, blocking until they and the session table arrive.
+    #[cfg(unix)]
+    pub fn take_over(
+        from: &UnixStream,
+        config: KcpConfig,
+        handle: &Handle,
+    ) -> io::Result<KcpListener> {
+        check_config(&config)?;
+        let mut sockets = Vec::new();
+        for fd in recv_fds(from.as_raw_fd())? {
+            let socket = unsafe { net::UdpSocket::from_raw_fd(fd) };
+            sockets.push(UdpSocket::from_socket(socket, handle)?);
+        }
+        let mut sockets = sockets.into_iter();
+        let mut listener = match sockets.next() {
+            Some(udp) => KcpListener::from_socket(udp, config, handle),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no sockets handed off"));
+            }
+        };
+        listener.extra.extend(sockets.map(Rc::new));
+        let mut from = from;
+        let mut len = [0; 4];
+        from.read_exact(&mut len)?;
+        let mut table = vec![0; LittleEndian::read_u32(&len) as usize];
+        from.read_exact(&mut table)?;
+        for (conv, addr) in decode_sessions(&table)? {
+            listener.retire(conv, addr);
+        }
+        Ok(listener)
+    }
+
+    fn from_socket(udp: UdpSocket, config: KcpConfig, handle: &Handle) -> KcpListener {
+        KcpListener {
             udp: Rc::new(udp),
-            connections: HashMap::new(),
+            extra: Vec::new(),
+            next: 0,
+            pool: RecvPool::new(config.mtu),
+            connections: SessionMap::new(),
+            convs: HashMap::new(),
+            collisions: 0,
+            bound: Instant::now(),
+            accepted: 0,
+            rejected: 0,
+            closed: 0,
+            closed_retransmits: 0,
+            closed_delivered: 0,
+            memory_limit: usize::MAX,
+            memory_used: 0,
+            memory_checked: Instant::now(),
+            squeezed: false,
+            tombstones: HashMap::new(),
+            tombstone_order: VecDeque::new(),
+            time_wait: Duration::from_millis(DEFAULT_TIME_WAIT),
+            idle_timeout: None,
+            max_sessions: usize::MAX,
+            budget: TickBudget::new(),
+            sweep: Timeout::new(Duration::from_millis(SWEEP_INTERVAL), handle).unwrap(),
             handle: handle.clone(),
+            events: None,
+            authenticator: None,
+            allocator: Box::new(RandomConv::new()),
+            config: config,
+            negotiation: false,
+        }
+    }
+
+    /// Bind `addr` and serve its sessions on `workers` threads, each
+    /// running a reactor of its own, so packet processing of many sessions
+    /// uses more than one core. A receiving thread shards the datagrams
+    /// across the workers by a hash of their conv, every session stays on
+    /// one worker. `serve` is called on that worker with each accepted
+    /// stream, the future it returns is spawned there. `config` applies
+    /// to every session.
+    pub fn spawn_workers<F, R>(
+        addr: &SocketAddr,
+        workers: usize,
+        config: KcpConfig,
+        serve: F,
+    ) -> io::Result<KcpWorkers>
+    where
+        F: Fn(KcpStream, SocketAddr, &Handle) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = (), Error = ()>,
+        R::Future: 'static,
+    {
+        assert!(workers > 0, "no workers");
+        check_config(&config)?;
+        let socket = net::UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        let serve = Arc::new(serve);
+        let mtu = config.mtu;
+        let mut shards = Vec::with_capacity(workers);
+        let mut threads = Vec::with_capacity(workers + 1);
+        for i in 0..workers {
+            let (tx, rx) = sync_mpsc::unbounded::<(Bytes, SocketAddr)>();
+            shards.push(tx);
+            let socket = socket.try_clone()?;
+            let config = config.clone();
+            let serve = serve.clone();
+            let thread = thread::Builder::new().name(format!("kcp-worker-{}", i)).spawn(
+                move || {
+                    let mut core = Core::new().expect("worker reactor");
+                    let handle = core.handle();
+                    // replies go out straight from the worker
+                    let udp = UdpSocket::from_socket(socket, &handle).expect("worker socket");
+                    let listener = KcpListener::from_socket(udp, config, &handle);
+                    let listener = Rc::new(RefCell::new(listener));
+                    let sweeper = listener.clone();
+                    let spawner = handle.clone();
+                    let work = rx.for_each(move |(buf, addr)| {
+                        let mut listener = listener.borrow_mut();
+                        let udp = listener.udp.clone();
+                        if let Some((stream, addr)) = listener.dispatch(&udp, &buf, addr) {
+                            spawner.spawn(serve(stream, addr, &spawner).into_future());
+                        }
+                        Ok(())
+                    });
+                    // sessions go away even while no datagrams come in
+                    let sweep = future::poll_fn(move || {
+                        sweeper.borrow_mut().poll_sweep().map(|()| Async::NotReady)
+                    });
+                    core.run(work.select(sweep.map_err(|_| ()))).ok();
+                },
+            )?;
+            threads.push(thread);
+        }
+        let thread = thread::Builder::new().name("kcp-receiver".to_owned()).spawn(
+            move || {
+                let mut core = Core::new().expect("receiver reactor");
+                let handle = core.handle();
+                let udp = UdpSocket::from_socket(socket, &handle).expect("receiver socket");
+                core.run(ShardReceiver {
+                    udp: udp,
+                    pool: RecvPool::new(mtu),
+                    shards: shards,
+                }).ok();
+            },
+        )?;
+        threads.push(thread);
+        Ok(KcpWorkers {
+            local_addr: local_addr,
+            threads: threads,
+        })
+    }
+
+    /// Only admit sessions whose conv `allocator` owns, e.g. the range of
+    /// one tenant. Clients have to pick their convs with a matching
+    /// allocator, see `KcpConnector::set_conv_allocator`.
+    pub fn set_conv_allocator<A: ConvAllocator + 'static>(&mut self, allocator: A) {
+        self.allocator = Box::new(allocator);
+    }
+
+    /// Require every new session to start with a token message, see
+    /// `KcpStream::connect_with_token`. `f` is called with the peer address,
+    /// conv and token, and the session is only handed out by `accept` if it
+    /// returns true, otherwise the peer is reset.
+    pub fn set_authenticator<F>(&mut self, f: F)
+    where
+        F: Fn(&SocketAddr, u32, &[u8]) -> bool + 'static,
+    {
+        self.authenticator = Some(Box::new(f));
+    }
+
+    /// Expect every new session to open with the parameters proposed by
+    /// `KcpStream::connect_negotiated`. They are clamped to the config of
+    /// the listener, applied and answered with the agreed values. Sessions
+    /// opening with anything else are rejected.
+    pub fn set_negotiation(&mut self, on: bool) {
+        self.negotiation = on;
+    }
+
+    /// Bound the payload bytes held by all sessions together to about
+    /// `bytes`, so that a flood of slow clients cannot grow the process
+    /// until it is killed. Past three quarters of it every session
+    /// advertises a quarter of its receive window, holding the peers back,
+    /// and past all of it new sessions are refused as
+    /// `SessionEvent::Rejected`. The windows open up again once usage is
+    /// below half. Usage is summed up at most every 100ms as datagrams
+    /// arrive. Unlimited by default.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = bytes;
+        // take effect with the next datagram
+        self.memory_checked = self.bound;
+    }
+
+    /// How long the conv and peer of a closed session stay retired. Late
+    /// retransmits of the old session are dropped meanwhile instead of
+    /// opening a new one, which would never complete and could take data
+    /// meant for the old one. A peer reconnecting from the same address
+    /// has to pick another conv until then. 10s by default, zero turns it
+    /// off.
+    pub fn set_time_wait(&mut self, time_wait: Duration) {
+        self.time_wait = time_wait;
+    }
+
+    /// Reset sessions whose peer was not heard from for `timeout`, their
+    /// streams fail with `ConnectionAborted` and `SessionEvent::Expired`
+    /// is emitted. Pick it well above the keepalive of the peers. `None`,
+    /// the default, keeps idle sessions until their link is dead.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Refuse new peers as `SessionEvent::Rejected` while `max` sessions
+    /// are open, they are sent a reset so they give up right away.
+    /// Unlimited by default.
+    pub fn set_max_sessions(&mut self, max: usize) {
+        self.max_sessions = max;
+    }
+
+    /// Let all sessions together emit at most `segments` data segments
+    /// every 10 ms, see `KcpConnector::set_tick_budget`. Sessions take
+    /// their turns as their timers come due, and what they flush in
+    /// between is taken from the same budget. Unlimited by default
+    pub fn set_tick_budget(&mut self, segments: u32) {
+        self.budget.segments.set(segments);
+    }
+
+    /// Payload bytes held by all sessions at the last check of the memory
+    /// limit, see `stats().memory` for an up to date sum.
+    pub fn memory(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Keep the paths of idle sessions accepted from now on open, `None`
+    /// turns it off.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.config.keepalive = keepalive;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// How many new peers were refused for opening with the conv of a
+    /// session another peer holds, see `SessionEvent::Collision`.
+    pub fn collisions(&self) -> u64 {
+        self.collisions
+    }
+
+    /// Snapshot of the totals over all sessions of the listener, the
+    /// stats of each one are kept by its `KcpStream`.
+    pub fn stats(&self) -> ListenerStats {
+        let mut stats = ListenerStats {
+            accepted: self.accepted,
+            rejected: self.rejected,
+            collisions: self.collisions,
+            closed: self.closed,
+            retransmits: self.closed_retransmits,
+            delivered: self.closed_delivered,
+            ..ListenerStats::default()
         };
-        Ok(listener)
+        for (_, kp) in self.connections.iter() {
+            if kp.pending.is_some() {
+                continue;
+            }
+            let kcb = kp.k.borrow().stats();
+            stats.retransmits += kcb.retransmits as u64;
+            stats.delivered += kcb.delivered;
+            stats.memory += kcb.memory;
+            if kp.closed.get() {
+                // not dropped from the table yet
+                stats.closed += 1;
+            } else {
+                stats.sessions += 1;
+                stats.goodput += kcb.delivery_rate;
+            }
+        }
+        let secs = self.bound.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            stats.accept_rate = stats.accepted as f64 / secs;
+            stats.close_rate = stats.closed as f64 / secs;
+        }
+        stats
+    }
+
+    /// Returns a stream of session lifecycle events. Only the stream from
+    /// the latest call receives events, call it before `incoming`.
+    pub fn events(&mut self) -> SessionEvents {
+        let (tx, rx) = unsync_mpsc::unbounded();
+        self.events = Some(tx);
+        SessionEvents { rx: rx }
+    }
+
+    /// agree on the parameters of a pending session, then check its next
+    /// message against the authenticator, returns the stream once the
+    /// session is admitted
+    fn admit(&mut self, id: SessionId) -> Option<(KcpStream, SocketAddr)> {
+        let addr = *self.connections.key(id).unwrap();
+        let kp = self.connections.by_id_mut(id).unwrap();
+        let conv = kp.k.borrow().conv();
+        if !kp.negotiated {
+            let offer = kp.k.borrow_mut().drain_messages().next();
+            match offer.map(|offer| Offer::decode(&offer)) {
+                Some(Some(offer)) => {
+                    offer.clamp(&self.config).answer(&mut kp.k.borrow_mut());
+                    kp.negotiated = true;
+                }
+                Some(None) => return self.reject(id, addr, conv),
+                None => return None,
+            }
+        }
+        let admitted = match self.authenticator {
+            Some(ref f) => {
+                let token = kp.k.borrow_mut().drain_messages().next();
+                match token {
+                    Some(token) => f(&addr, conv, &token),
+                    None => return None,
+                }
+            }
+            None => true,
+        };
+        if admitted {
+            let stream = kp.pending.take().unwrap();
+            self.emit(SessionEvent::Opened {
+                addr: addr,
+                conv: conv,
+            });
+            Some((stream, addr))
+        } else {
+            self.reject(id, addr, conv)
+        }
+    }
+
+    fn reject(
+        &mut self,
+        id: SessionId,
+        addr: SocketAddr,
+        conv: u32,
+    ) -> Option<(KcpStream, SocketAddr)> {
+        // the peer is told instead of retrying
+        if let Some(kp) = self.connections.remove_id(id) {
+            kp.k.borrow_mut().reset();
+        }
+        self.convs.remove(&conv);
+        self.budget.sessions.set(self.connections.len());
+        self.emit(SessionEvent::Rejected {
+            addr: addr,
+            conv: conv,
+        });
+        None
+    }
+
+    /// sum up what the sessions hold when due, and clamp or reopen their
+    /// windows as the memory limit is approached or left
+    fn check_memory(&mut self) {
+        if self.memory_limit == usize::MAX {
+            return;
+        }
+        let now = Instant::now();
+        if now < self.memory_checked + Duration::from_millis(MEMORY_CHECK_INTERVAL) {
+            return;
+        }
+        self.memory_checked = now;
+        self.memory_used = self.connections
+            .iter()
+            .map(|(_, kp)| kp.k.borrow().memory())
+            .sum();
+        let squeeze = if self.squeezed {
+            self.memory_used >= self.memory_limit / 2
+        } else {
+            self.memory_used >= self.memory_limit / 4 * 3
+        };
+        if squeeze != self.squeezed {
+            self.squeezed = squeeze;
+            for (_, kp) in self.connections.iter() {
+                squeeze_window(&mut kp.k.borrow_mut(), squeeze);
+            }
+        }
+    }
+
+    /// drop what `addr` sends on `conv` for the time wait
+    fn retire(&mut self, conv: u32, addr: SocketAddr) {
+        if self.time_wait == Duration::from_millis(0) {
+            return;
+        }
+        let until = Instant::now() + self.time_wait;
+        self.tombstones.insert((conv, addr), until);
+        self.tombstone_order.push_back((until, conv, addr));
+    }
+
+    /// whether `conv` of `addr` belongs to a session closed within the
+    /// time wait, forgetting the ones that ran out
+    fn retired(&mut self, conv: u32, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        while let Some(&(until, conv, addr)) = self.tombstone_order.front() {
+            if until > now {
+                break;
+            }
+            self.tombstone_order.pop_front();
+            // unless retired once more since
+            if self.tombstones.get(&(conv, addr)) == Some(&until) {
+                self.tombstones.remove(&(conv, addr));
+            }
+        }
+        self.tombstones.contains_key(&(conv, addr))
+    }
+
+    fn emit(&mut self, event: SessionEvent) {
+        match event {
+            SessionEvent::Opened { .. } => self.accepted += 1,
+            SessionEvent::Rejected { .. } => self.rejected += 1,
+            SessionEvent::Closed { .. } | SessionEvent::Expired { .. } => self.closed += 1,
+            SessionEvent::Collision { .. } => {}
+        }
+        if let Some(ref tx) = self.events {
+            let _ = tx.unbounded_send(event);
+        }
+    }
+
+    /// sweep the table whenever the sweep timer is due, registering the
+    /// task for the next one
+    fn poll_sweep(&mut self) -> io::Result<()> {
+        while let Async::Ready(()) = self.sweep.poll()? {
+            self.sweep_sessions();
+            let next = Instant::now() + Duration::from_millis(SWEEP_INTERVAL);
+            self.sweep.reset(next);
+        }
+        Ok(())
+    }
+
+    /// drop the sessions that closed, died or were idle for too long from
+    /// the table, so their convs are free for other peers without waiting
+    /// for another datagram from theirs
+    fn sweep_sessions(&mut self) {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        let mut gone = Vec::new();
+        self.connections.retain(|&addr, kp| {
+            if kp.closed.get() {
+                let kcb = kp.k.borrow();
+                gone.push((addr, kcb.conv(), kcb.stats(), false));
+                return false;
+            }
+            let idle = idle_timeout.map_or(false, |timeout| now - kp.heard.get() >= timeout);
+            if !idle && !kp.k.borrow().is_dead() {
+                return true;
+            }
+            let mut kcb = kp.k.borrow_mut();
+            kcb.reset();
+            kp.state.refresh(&mut kcb);
+            // the stream, if still held, fails its next read or write
+            kp.closed.set(true);
+            kp.set_readiness.set_readiness(Ready::readable() | Ready::writable()).ok();
+            gone.push((addr, kcb.conv(), kcb.stats(), true));
+            false
+        });
+        for (addr, conv, stats, expired) in gone {
+            self.release(addr, conv, stats, expired);
+        }
+    }
+
+    /// account for a session dropped from the table, its conv is free for
+    /// other peers from now on
+    fn release(&mut self, addr: SocketAddr, conv: u32, stats: KcpStats, expired: bool) {
+        self.closed_retransmits += stats.retransmits as u64;
+        self.closed_delivered += stats.delivered;
+        self.convs.remove(&conv);
+        self.budget.sessions.set(self.connections.len());
+        self.retire(conv, addr);
+        self.emit(if expired {
+            SessionEvent::Expired {
+                addr: addr,
+                conv: conv,
+            }
+        } else {
+            SessionEvent::Closed {
+                addr: addr,
+                conv: conv,
+            }
+        });
     }
 
     pub fn accept(&mut self) -> io::Result<(KcpStream, SocketAddr)> {
-        let mut buf = vec![0; 1024];
+        self.poll_sweep()?;
         loop {
-            match self.udp.recv_from(&mut buf) {
-                Err(e) => {
-                    return Err(e);
+            let (buf, addr, udp) = self.recv_any()?;
+            if let Some(accepted) = self.dispatch(&udp, &buf, addr) {
+                return Ok(accepted);
+            }
+        }
+    }
+
+    /// receive from whichever socket has a datagram, each one not ready
+    /// registers the task for its readiness
+    fn recv_any(&mut self) -> io::Result<(Bytes, SocketAddr, Rc<UdpSocket>)> {
+        let count = 1 + self.extra.len();
+        for i in 0..count {
+            let k = (self.next + i) % count;
+            let udp = if k == 0 {
+                self.udp.clone()
+            } else {
+                self.extra[k - 1].clone()
+            };
+            match self.pool.recv(|buf| udp.recv_from(buf)) {
+                Ok((buf, addr)) => {
+                    self.next = (k + 1) % count;
+                    return Ok((buf, addr, udp));
                 }
-                Ok((n, addr)) => {
-                    if self.connections.contains_key(&addr) {
-                        if let Some(kp) = self.connections.get(&addr) {
-                            let mut kcb = kp.k.borrow_mut();
-                            kcb.input(&buf[..n]);
-
-                            kcb.update(clock());
-                            let dur = kcb.check(clock());
-                            kp.token.borrow_mut().reset(
-                                Instant::now() +
-                                    Duration::from_millis(dur as u64),
-                            );
-
-                            kp.set_readiness.set_readiness(mio::Ready::readable());
-                        }
-                    } else {
-                        let conv = LittleEndian::read_u32(&buf[..4]);
-                        let mut kcb = Kcb::new(
-                            conv,
-                            KcpOutput {
-                                udp: self.udp.clone(),
-                                peer: addr.clone(),
-                            },
-                        );
-                        kcb.wndsize(128, 128);
-                        kcb.nodelay(0, 10, 0, true);
-                        let kcb = Rc::new(RefCell::new(kcb));
-                        let (registration, set_readiness) = Registration::new2();
-                        let now = Instant::now();
-                        let token = Timeout::new_at(now, &self.handle).unwrap();
-                        let token = Rc::new(RefCell::new(token));
-                        let core = KcpCore {
-                            kcb: kcb.clone(),
-                            registration: registration,
-                            set_readiness: set_readiness.clone(),
-                            token: token.clone(),
-                        };
-                        let interval = KcpInterval {
-                            kcb: kcb.clone(),
-                            token: token.clone(),
-                        };
-                        &self.handle.spawn(
-                            interval.for_each(|_| Ok(())).then(|_| Ok(())),
-                        );
-                        let io = PollEvented::new(core, &self.handle).unwrap();
-                        let stream = KcpStream { io: io };
-                        stream.io.get_ref().kcb.borrow_mut().input(&buf[..n]);
-
-                        let kcbc = kcb.clone();
-                        let mut kcb1 = kcbc.borrow_mut();
-                        kcb1.update(clock());
-                        let dur = kcb1.check(clock());
-                        token.borrow_mut().reset(
-                            Instant::now() +
-                                Duration::from_millis(dur as u64),
-                        );
-
-                        stream.io.get_ref().set_readiness.set_readiness(
-                            mio::Ready::readable(),
-                        );
-
-                        let kp = KcpPair {
-                            k: kcb.clone(),
-                            set_readiness: set_readiness.clone(),
-                            token: token.clone(),
-                        };
-                        self.connections.insert(addr, kp);
-                        return Ok((stream, addr));
-                    }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no datagram"))
+    }
+
+    /// hand a datagram from `addr` received on `udp` to its session, or
+    /// open a new one, returns the stream once a new session is admitted
+    fn dispatch(
+        &mut self,
+        udp: &Rc<UdpSocket>,
+        buf: &Bytes,
+        addr: SocketAddr,
+    ) -> Option<(KcpStream, SocketAddr)> {
+        self.check_memory();
+        // the only lookup by address, the session is reached by its id
+        // from here on
+        if let Some(id) = self.connections.id(&addr) {
+            let (closed, pending) = {
+                let kp = self.connections.by_id(id).unwrap();
+                let closed = kp.closed.get();
+                if !closed {
+                    kp.input(buf);
                 }
+                (closed, kp.pending.is_some())
+            };
+            if closed {
+                let kp = self.connections.remove_id(id).unwrap();
+                let (conv, stats) = {
+                    let kcb = kp.k.borrow();
+                    (kcb.conv(), kcb.stats())
+                };
+                self.release(addr, conv, stats, false);
+                return None;
+            }
+            if pending {
+                self.admit(id)
+            } else {
+                None
+            }
+        } else {
+            if buf.len() < 4 {
+                self.emit(SessionEvent::Rejected {
+                    addr: addr,
+                    conv: 0,
+                });
+                return None;
+            }
+            let conv = LittleEndian::read_u32(&buf[..4]);
+            if self.retired(conv, addr) {
+                // a peer still sending to a session that is gone
+                send_reset(udp, buf, &addr);
+                return None;
+            }
+            if !self.allocator.owns(conv)
+                || self.memory_used >= self.memory_limit
+                || self.connections.len() >= self.max_sessions
+            {
+                send_reset(udp, buf, &addr);
+                self.emit(SessionEvent::Rejected {
+                    addr: addr,
+                    conv: conv,
+                });
+                return None;
+            }
+            // a second client on the conv, or someone guessing it, the
+            // session keeps its peer
+            if let Some(&owner) = self.convs.get(&conv) {
+                let owner = *self.connections.key(owner).unwrap();
+                self.collisions += 1;
+                self.emit(SessionEvent::Collision {
+                    addr: addr,
+                    conv: conv,
+                    owner: owner,
+                });
+                return None;
+            }
+            let mut kcb = Kcb::new(
+                conv,
+                KcpOutput {
+                    link: Link::Udp(udp.clone()),
+                    peer: Rc::new(Cell::new(addr)),
+                    header: Vec::new(),
+                },
+            );
+            self.config.apply(&mut kcb);
+            if self.squeezed {
+                squeeze_window(&mut kcb, true);
+            }
+            #[cfg(unix)]
+            kcb.set_vectored(true);
+            if kcb.input_bytes(buf).is_err() {
+                self.emit(SessionEvent::Rejected {
+                    addr: addr,
+                    conv: conv,
+                });
+                return None;
+            }
+            let kcb = Rc::new(RefCell::new(kcb));
+            let (registration, set_readiness) = Registration::new2();
+            let now = Instant::now();
+            let token = Timeout::new_at(now, &self.handle).unwrap();
+            let token = Rc::new(RefCell::new(token));
+            let closed = Rc::new(Cell::new(false));
+            let state = StateWatch::new(ConnectionState::Established, closed.clone());
+            let core = KcpCore {
+                kcb: kcb.clone(),
+                link: Link::Udp(udp.clone()),
+                registration: registration,
+                set_readiness: set_readiness.clone(),
+                token: Some(token.clone()),
+                closed: closed.clone(),
+                migration: Rc::new(Cell::new(false)),
+                state: state.clone(),
+                linger: Cell::new(Some(Duration::from_millis(DEFAULT_LINGER))),
+                budget: self.budget.clone(),
+            };
+            let interval = KcpInterval {
+                kcb: kcb.clone(),
+                link: Link::Udp(udp.clone()),
+                token: token.clone(),
+                closed: closed.clone(),
+                state: state.clone(),
+                set_readiness: set_readiness.clone(),
+                budget: self.budget.clone(),
+            };
+            &self.handle.spawn(
+                interval.for_each(|_| Ok(())).then(|_| Ok(())),
+            );
+            let io = PollEvented::new(core, &self.handle).unwrap();
+            let stream = KcpStream { io: io };
+
+            let kcbc = kcb.clone();
+            let mut kcb1 = kcbc.borrow_mut();
+            let now = Instant::now();
+            kcb1.update_at(now);
+            token.borrow_mut().reset(kcb1.check_at(now));
+
+            stream.io.get_ref().set_readiness.set_readiness(
+                readiness(&kcb1),
+            );
+
+            let mut kp = KcpPair {
+                k: kcb.clone(),
+                set_readiness: set_readiness.clone(),
+                token: Some(token.clone()),
+                closed: closed,
+                pending: None,
+                negotiated: !self.negotiation,
+                state: state,
+                heard: Cell::new(now),
+                budget: self.budget.clone(),
+            };
+            if self.authenticator.is_some() || self.negotiation {
+                kp.pending = Some(stream);
+                let id = self.connections.insert_new(addr, kp);
+                self.convs.insert(conv, id);
+                self.budget.sessions.set(self.connections.len());
+                drop(kcb1);
+                return self.admit(id);
             }
+            let id = self.connections.insert_new(addr, kp);
+            self.convs.insert(conv, id);
+            self.budget.sessions.set(self.connections.len());
+            self.emit(SessionEvent::Opened {
+                addr: addr,
+                conv: conv,
+            });
+            Some((stream, addr))
         }
     }
 
+    /// Set a raw option on the underlying UDP socket, for the ones without a
+    /// dedicated setter such as `SO_RCVBUF` or `SO_BINDTODEVICE`.
+    #[cfg(unix)]
+    pub fn set_socket_option(&self, level: c_int, name: c_int, value: &[u8]) -> io::Result<()> {
+        setsockopt(self.udp.as_raw_fd(), level, name, value)
+    }
+
+    /// set the IP_TTL option on the underlying socket
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.udp.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.udp.ttl()
+    }
+
+    /// set the SO_BROADCAST option on the underlying socket
+    pub fn set_broadcast(&self, on: bool) -> io::Result<()> {
+        self.udp.set_broadcast(on)
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        self.udp.broadcast()
+    }
+
+    /// set the IP_MULTICAST_TTL option on the underlying socket
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> io::Result<()> {
+        self.udp.set_multicast_ttl_v4(ttl)
+    }
+
+    pub fn multicast_ttl_v4(&self) -> io::Result<u32> {
+        self.udp.multicast_ttl_v4()
+    }
+
+    /// set the IP_MULTICAST_LOOP option on the underlying socket
+    pub fn set_multicast_loop_v4(&self, on: bool) -> io::Result<()> {
+        self.udp.set_multicast_loop_v4(on)
+    }
+
+    pub fn multicast_loop_v4(&self) -> io::Result<bool> {
+        self.udp.multicast_loop_v4()
+    }
+
+    /// get and clear the SO_ERROR option of the underlying socket
+    #[cfg(unix)]
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        take_error(self.udp.as_raw_fd())
+    }
+
     pub fn incoming(self) -> Incoming {
         Incoming { inner: self }
     }
 }
 
+impl Incoming {
+    /// The listener, e.g. to `hand_off` once its sessions are drained.
+    pub fn into_inner(self) -> KcpListener {
+        self.inner
+    }
+}
+
 impl Stream for Incoming {
     type Item = (KcpStream, SocketAddr);
     type Error = io::Error;
@@ -141,58 +1287,978 @@ impl Stream for Incoming {
     }
 }
 
+/// Threads serving the sessions of a listener, see
+/// `KcpListener::spawn_workers`.
+pub struct KcpWorkers {
+    local_addr: SocketAddr,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl KcpWorkers {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Block until every thread exited, which they only do once one of
+    /// the workers panicked.
+    pub fn join(self) -> thread::Result<()> {
+        for thread in self.threads {
+            thread.join()?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives for the workers of a listener, handing each datagram to the
+/// worker its conv hashes to.
+struct ShardReceiver {
+    udp: UdpSocket,
+    pool: RecvPool,
+    shards: Vec<sync_mpsc::UnboundedSender<(Bytes, SocketAddr)>>,
+}
+
+impl Future for ShardReceiver {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let udp = &self.udp;
+            let (buf, addr) = match self.pool.recv(|buf| udp.recv_from(buf)) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                // an ICMP error of an earlier send, e.g. a refused port,
+                // the retransmits of the session find out on their own
+                Err(_) => continue,
+            };
+            let shard = if buf.len() < 4 {
+                0
+            } else {
+                shard_of(LittleEndian::read_u32(&buf[..4]), self.shards.len())
+            };
+            if self.shards[shard].unbounded_send((buf, addr)).is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other, "worker gone"));
+            }
+        }
+    }
+}
+
+/// worker of a conv, spread evenly even for consecutive convs
+fn shard_of(conv: u32, shards: usize) -> usize {
+    (conv.wrapping_mul(0x9e37_79b1) >> 16) as usize % shards
+}
+
+/// Client side endpoint owning a single UDP socket, over which any number
+/// of `KcpStream`s to the same or different servers are multiplexed. One
+/// driver task receives for all of them and runs their `update`s.
+pub struct KcpConnector {
+    udp: Rc<UdpSocket>,
+    sessions: Rc<RefCell<SessionMap<(SocketAddr, u32), KcpPair>>>,
+    handle: Handle,
+    allocator: RefCell<Box<ConvAllocator>>,
+    // where the driver hands sessions opened by peers, `None` drops them
+    accepted: Rc<RefCell<Option<UnboundedSender<(KcpStream, SocketAddr)>>>>,
+    budget: Rc<TickBudget>,
+}
+
+impl KcpConnector {
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<KcpConnector> {
+        let udp = Rc::new(UdpSocket::bind(addr, handle)?);
+        let sessions = Rc::new(RefCell::new(SessionMap::new()));
+        let accepted = Rc::new(RefCell::new(None));
+        let budget = TickBudget::new();
+        let driver = ConnectorDriver {
+            udp: udp.clone(),
+            sessions: sessions.clone(),
+            pool: RecvPool::new(KcpConfig::default().mtu),
+            ticker: Interval::new(Duration::from_millis(CONNECTOR_TICK), handle)?,
+            handle: handle.clone(),
+            accepted: accepted.clone(),
+            budget: budget.clone(),
+            next: 0,
+            tombstones: HashMap::new(),
+            tombstone_order: VecDeque::new(),
+        };
+        handle.spawn(driver.then(|_| Ok(())));
+        Ok(KcpConnector {
+            udp: udp,
+            sessions: sessions,
+            handle: handle.clone(),
+            allocator: RefCell::new(Box::new(RandomConv::new())),
+            accepted: accepted,
+            budget: budget,
+        })
+    }
+
+    /// pick the convs of new sessions with `allocator` instead of at random
+    pub fn set_conv_allocator<A: ConvAllocator + 'static>(&mut self, allocator: A) {
+        self.allocator = RefCell::new(Box::new(allocator));
+    }
+
+    /// Let all sessions together emit at most `segments` data segments per
+    /// tick of the driver. Each gets an even share of what the ones before
+    /// it left over, taking turns at going first, so a bulk transfer
+    /// cannot starve the latency sensitive sessions sharing the socket.
+    /// Every session sends at least one segment a tick, writes and acks
+    /// flushed in between only send what the tick has left. Unlimited by
+    /// default
+    pub fn set_tick_budget(&mut self, segments: u32) {
+        self.budget.segments.set(segments);
+    }
+
+    /// Open a new conversation with `addr` over the shared socket.
+    pub fn connect(&self, addr: &SocketAddr) -> KcpStreamNew {
+        let mut sessions = self.sessions.borrow_mut();
+        let conv = {
+            let in_use = |conv| sessions.contains_key(&(*addr, conv));
+            self.allocator.borrow_mut().allocate(addr, &in_use)
+        };
+        let conv = match conv {
+            Some(conv) => conv,
+            None => {
+                return KcpStreamNew::failed(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "no conv left for this peer",
+                ))
+            }
+        };
+        let (stream, kp) = driven_session(
+            &self.udp,
+            &self.handle,
+            *addr,
+            conv,
+            ConnectionState::Connecting,
+            &self.budget,
+        );
+        sessions.insert((*addr, conv), kp);
+        KcpStreamNew::ready(stream)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+/// a session with `addr` updated by a connector driver
+fn driven_session(
+    udp: &Rc<UdpSocket>,
+    handle: &Handle,
+    addr: SocketAddr,
+    conv: u32,
+    state: ConnectionState,
+    budget: &Rc<TickBudget>,
+) -> (KcpStream, KcpPair) {
+    let mut kcb = Kcb::new(
+        conv,
+        KcpOutput {
+            link: Link::Udp(udp.clone()),
+            peer: Rc::new(Cell::new(addr)),
+            header: Vec::new(),
+        },
+    );
+    KcpConfig::default().apply(&mut kcb);
+    #[cfg(unix)]
+    kcb.set_vectored(true);
+    let kcb = Rc::new(RefCell::new(kcb));
+    let (registration, set_readiness) = Registration::new2();
+    let closed = Rc::new(Cell::new(false));
+    let state = StateWatch::new(state, closed.clone());
+    let core = KcpCore {
+        kcb: kcb.clone(),
+        link: Link::Udp(udp.clone()),
+        registration: registration,
+        set_readiness: set_readiness.clone(),
+        token: None,
+        closed: closed.clone(),
+        migration: Rc::new(Cell::new(false)),
+        state: state.clone(),
+        linger: Cell::new(Some(Duration::from_millis(DEFAULT_LINGER))),
+        budget: budget.clone(),
+    };
+    let io = PollEvented::new(core, handle).unwrap();
+    let kp = KcpPair {
+        k: kcb,
+        set_readiness: set_readiness,
+        token: None,
+        closed: closed,
+        pending: None,
+        negotiated: true,
+        state: state,
+        heard: Cell::new(Instant::now()),
+        budget: budget.clone(),
+    };
+    (KcpStream { io: io }, kp)
+}
+
+/// Peer to peer endpoint: a `KcpConnector` whose socket also takes the
+/// conversations other endpoints open with it, so a node of a mesh
+/// talks to all of its peers over one socket and port. Outgoing and
+/// incoming sessions share the socket and its driver, and are told apart
+/// by peer address and conv. Datagrams for a session closed within the
+/// last 10 s are answered with a reset instead of opening a new one.
+pub struct KcpEndpoint {
+    connector: KcpConnector,
+}
+
+/// Stream of the sessions peers opened with a `KcpEndpoint`, see
+/// `KcpEndpoint::incoming`
+pub struct EndpointIncoming {
+    rx: UnboundedReceiver<(KcpStream, SocketAddr)>,
+}
+
+impl KcpEndpoint {
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<KcpEndpoint> {
+        Ok(KcpEndpoint { connector: KcpConnector::bind(addr, handle)? })
+    }
+
+    /// Open a new conversation with `addr`, see `KcpConnector::connect`.
+    pub fn connect(&self, addr: &SocketAddr) -> KcpStreamNew {
+        self.connector.connect(addr)
+    }
+
+    /// Returns the stream of sessions opened by peers. Only the stream from
+    /// the latest call receives sessions, until the first call datagrams of
+    /// unknown conversations are dropped.
+    pub fn incoming(&mut self) -> EndpointIncoming {
+        let (tx, rx) = unsync_mpsc::unbounded();
+        *self.connector.accepted.borrow_mut() = Some(tx);
+        EndpointIncoming { rx: rx }
+    }
+
+    /// pick the convs of outgoing sessions with `allocator`
+    pub fn set_conv_allocator<A: ConvAllocator + 'static>(&mut self, allocator: A) {
+        self.connector.set_conv_allocator(allocator);
+    }
+
+    /// share out the segments sent per tick, see
+    /// `KcpConnector::set_tick_budget`
+    pub fn set_tick_budget(&mut self, segments: u32) {
+        self.connector.set_tick_budget(segments);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.connector.local_addr()
+    }
+}
+
+impl Stream for EndpointIncoming {
+    type Item = (KcpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(KcpStream, SocketAddr)>, io::Error> {
+        self.rx.poll().map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "endpoint closed")
+        })
+    }
+}
+
+const CONNECTOR_TICK: u64 = 10; // update interval of connector sessions in millisec
+
+struct ConnectorDriver {
+    udp: Rc<UdpSocket>,
+    sessions: Rc<RefCell<SessionMap<(SocketAddr, u32), KcpPair>>>,
+    pool: RecvPool,
+    ticker: Interval,
+    handle: Handle,
+    accepted: Rc<RefCell<Option<UnboundedSender<(KcpStream, SocketAddr)>>>>,
+    // data segments all sessions may emit per tick, and the session the
+    // next tick starts with
+    budget: Rc<TickBudget>,
+    next: usize,
+    // peers and convs of closed sessions for the time wait, so that late
+    // datagrams do not open them once more as sessions of the peer
+    tombstones: HashMap<(SocketAddr, u32), Instant>,
+    tombstone_order: VecDeque<(Instant, SocketAddr, u32)>,
+}
+
+impl ConnectorDriver {
+    /// whether `conv` of `addr` belongs to a session closed within the
+    /// time wait, forgetting the ones that ran out
+    fn retired(&mut self, addr: SocketAddr, conv: u32) -> bool {
+        let now = Instant::now();
+        while let Some(&(until, addr, conv)) = self.tombstone_order.front() {
+            if until > now {
+                break;
+            }
+            self.tombstone_order.pop_front();
+            // unless retired once more since
+            if self.tombstones.get(&(addr, conv)) == Some(&until) {
+                self.tombstones.remove(&(addr, conv));
+            }
+        }
+        self.tombstones.contains_key(&(addr, conv))
+    }
+
+    /// open a session for a datagram of a conversation the peer started
+    fn accept(&self, buf: &Bytes, addr: SocketAddr, conv: u32) {
+        let accepted = self.accepted.borrow();
+        let tx = match *accepted {
+            Some(ref tx) => tx,
+            None => {
+                send_reset(&self.udp, buf, &addr);
+                return;
+            }
+        };
+        let (stream, kp) = driven_session(
+            &self.udp,
+            &self.handle,
+            addr,
+            conv,
+            ConnectionState::Established,
+            &self.budget,
+        );
+        if kp.k.borrow_mut().input_bytes(buf).is_err() {
+            return;
+        }
+        kp.set_readiness.set_readiness(readiness(&kp.k.borrow()));
+        if tx.unbounded_send((stream, addr)).is_ok() {
+            self.sessions.borrow_mut().insert((addr, conv), kp);
+        }
+    }
+}
+
+impl Future for ConnectorDriver {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        while let Async::Ready(Some(())) = self.ticker.poll()? {
+            let mut sessions = self.sessions.borrow_mut();
+            let until = Instant::now() + Duration::from_millis(DEFAULT_TIME_WAIT);
+            let (tombstones, order) = (&mut self.tombstones, &mut self.tombstone_order);
+            sessions.retain(|&(addr, conv), kp| {
+                if !kp.closed.get() {
+                    return true;
+                }
+                tombstones.insert((addr, conv), until);
+                order.push_back((until, addr, conv));
+                false
+            });
+            // the connector is gone and so are all of its streams
+            if sessions.is_empty() && Rc::strong_count(&self.sessions) == 1 {
+                return Ok(Async::Ready(()));
+            }
+            let now = Instant::now();
+            let count = sessions.len();
+            let start = if count > 0 { self.next % count } else { 0 };
+            self.next = start + 1;
+            self.budget.start(now, count);
+            let link = Link::Udp(self.udp.clone());
+            let turn = sessions.iter().skip(start).chain(sessions.iter().take(start));
+            for (_, kp) in turn {
+                {
+                    let mut kcb = kp.k.borrow_mut();
+                    let share = self.budget.turn(now);
+                    self.budget.spend(&mut kcb, share, |kcb| kcb.update_at(now));
+                }
+                flush_held(&kp.k, &link);
+                let mut kcb = kp.k.borrow_mut();
+                kp.state.refresh(&mut kcb);
+                if kcb.playout_delay() > 0 {
+                    kp.set_readiness.set_readiness(readiness(&kcb));
+                }
+            }
+        }
+
+        loop {
+            let udp = &self.udp;
+            let (buf, addr) = try_nb!(self.pool.recv(|buf| udp.recv_from(buf)));
+            if buf.len() < 4 {
+                continue;
+            }
+            let conv = LittleEndian::read_u32(&buf[..4]);
+            let known = match self.sessions.borrow().get(&(addr, conv)) {
+                Some(kp) => {
+                    kp.input(&buf);
+                    true
+                }
+                None => false,
+            };
+            if !known {
+                if self.retired(addr, conv) {
+                    // a peer still sending to a session that is gone
+                    send_reset(&self.udp, &buf, &addr);
+                    continue;
+                }
+                self.accept(&buf, addr, conv);
+            }
+        }
+    }
+}
+
 struct Server {
     socket: Rc<UdpSocket>,
-    buf: Vec<u8>,
-    to_send: Option<(usize, SocketAddr)>,
+    pool: RecvPool,
+    to_send: Option<(Bytes, SocketAddr)>,
     kcb: Rc<RefCell<Kcb<KcpOutput>>>,
     set_readiness: SetReadiness,
 
     token: Rc<RefCell<Timeout>>,
+    closed: Rc<Cell<bool>>,
+    // control connection of the socks5 association the datagrams go
+    // through, it has to stay open as long as the session
+    control: Option<TcpStream>,
+    peer: Rc<Cell<SocketAddr>>,
+    migration: Rc<Cell<bool>>,
+    state: Rc<StateWatch>,
+}
+
+impl Future for Server {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if self.closed.get() {
+                return Ok(Async::Ready(()));
+            }
+            if let Some((buf, from)) = self.to_send.take() {
+                // anyone guessing the conv could corrupt the session, only
+                // the peer is heard unless it is allowed to move. datagrams
+                // through a socks5 proxy always come from the relay
+                let moved = from != self.peer.get();
+                if moved && (!self.migration.get() || self.control.is_some()) {
+                    continue;
+                }
+                let start = if self.control.is_some() {
+                    match socks::header_len(&buf) {
+                        Some(len) => len,
+                        None => continue,
+                    }
+                } else {
+                    0
+                };
+                let mut kcb = self.kcb.borrow_mut();
+                if kcb.input_bytes(&buf.slice_from(start)).is_ok() && moved {
+                    self.peer.set(from);
+                }
+
+                let now = Instant::now();
+                kcb.update_at(now);
+                self.token.borrow_mut().reset(kcb.check_at(now));
+                self.state.refresh(&mut kcb);
+
+                self.set_readiness.set_readiness(readiness(&kcb));
+            }
+
+            let socket = &self.socket;
+            self.to_send = Some(try_nb!(self.pool.recv(|buf| socket.recv_from(buf))));
+        }
+    }
+}
+
+/// Feeds one end of an in-process pair the datagrams of the other end,
+/// see `memory_pair`.
+struct MemoryServer {
+    rx: UnboundedReceiver<Bytes>,
+    session: KcpPair,
+}
+
+impl Future for MemoryServer {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if self.session.closed.get() {
+                return Ok(Async::Ready(()));
+            }
+            match self.rx.poll()? {
+                Async::Ready(Some(buf)) => self.session.input(&buf),
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Two streams of the conversation `conv` wired to each other through
+/// channels on the reactor instead of sockets, each losing `loss` percent
+/// of the datagrams it sends.
+pub(crate) fn memory_pair(conv: u32, loss: u32, handle: &Handle) -> (KcpStream, KcpStream) {
+    let (a_tx, a_rx) = unsync_mpsc::unbounded();
+    let (b_tx, b_rx) = unsync_mpsc::unbounded();
+    (
+        memory_stream(conv, Link::Memory(b_tx, loss), a_rx, handle),
+        memory_stream(conv, Link::Memory(a_tx, loss), b_rx, handle),
+    )
+}
+
+/// one end of a `memory_pair`, sending over `link` and receiving from `rx`
+fn memory_stream(conv: u32, link: Link, rx: UnboundedReceiver<Bytes>, handle: &Handle) -> KcpStream {
+    // nobody to move away from, the peer only fills in the output
+    let peer = Rc::new(Cell::new("127.0.0.1:0".parse().unwrap()));
+    let mut kcb = Kcb::new(
+        conv,
+        KcpOutput {
+            link: link.clone(),
+            peer: peer,
+            header: Vec::new(),
+        },
+    );
+    KcpConfig::default().apply(&mut kcb);
+    let kcb = Rc::new(RefCell::new(kcb));
+    let (registration, set_readiness) = Registration::new2();
+    let token = Timeout::new_at(Instant::now(), handle).unwrap();
+    let token = Rc::new(RefCell::new(token));
+    let closed = Rc::new(Cell::new(false));
+    let state = StateWatch::new(ConnectionState::Connecting, closed.clone());
+    let budget = TickBudget::new();
+    let core = KcpCore {
+        kcb: kcb.clone(),
+        link: link.clone(),
+        registration: registration,
+        set_readiness: set_readiness.clone(),
+        token: Some(token.clone()),
+        closed: closed.clone(),
+        migration: Rc::new(Cell::new(false)),
+        state: state.clone(),
+        linger: Cell::new(Some(Duration::from_millis(DEFAULT_LINGER))),
+        budget: budget.clone(),
+    };
+
+    let interval = KcpInterval {
+        kcb: kcb.clone(),
+        link: link,
+        token: token.clone(),
+        closed: closed.clone(),
+        state: state.clone(),
+        set_readiness: set_readiness.clone(),
+        budget: budget.clone(),
+    };
+    handle.spawn(interval.for_each(|_| Ok(())).then(|_| Ok(())));
+    handle.spawn(MemoryServer {
+        rx: rx,
+        session: KcpPair {
+            k: kcb,
+            set_readiness: set_readiness,
+            token: Some(token),
+            closed: closed,
+            pending: None,
+            negotiated: true,
+            state: state,
+            heard: Cell::new(Instant::now()),
+            budget: budget.clone(),
+        },
+    });
+    KcpStream { io: PollEvented::new(core, handle).unwrap() }
+}
+
+pub struct KcpStreamNew {
+    inner: Option<KcpStream>,
+    race: Option<HappyEyeballs>,
+    pending: Option<Box<Future<Item = KcpStream, Error = io::Error>>>,
+    error: Option<io::Error>,
+}
+
+impl KcpStreamNew {
+    fn ready(stream: KcpStream) -> KcpStreamNew {
+        KcpStreamNew {
+            inner: Some(stream),
+            race: None,
+            pending: None,
+            error: None,
+        }
+    }
+
+    fn race(race: HappyEyeballs) -> KcpStreamNew {
+        KcpStreamNew {
+            inner: None,
+            race: Some(race),
+            pending: None,
+            error: None,
+        }
+    }
+
+    fn failed(e: io::Error) -> KcpStreamNew {
+        KcpStreamNew {
+            inner: None,
+            race: None,
+            pending: None,
+            error: Some(e),
+        }
+    }
+
+    fn pending(f: Box<Future<Item = KcpStream, Error = io::Error>>) -> KcpStreamNew {
+        KcpStreamNew {
+            inner: None,
+            race: None,
+            pending: Some(f),
+            error: None,
+        }
+    }
+}
+
+impl Future for KcpStreamNew {
+    type Item = KcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<KcpStream, io::Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if let Some(ref mut race) = self.race {
+            return race.poll();
+        }
+        if let Some(ref mut pending) = self.pending {
+            return pending.poll();
+        }
+        Ok(Async::Ready(self.inner.take().unwrap()))
+    }
+}
+
+const COPY_CHUNK: usize = 16 * 1024; // bytes copied at a time by send_all_from and recv_all_into
+
+/// Copies a reader into a stream, see `KcpStream::send_all_from`.
+pub struct SendAllFrom<R> {
+    reader: Option<R>,
+    stream: Option<KcpStream>,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    written: u64,
+    // delivered bytes of the session when the copy started
+    delivered: u64,
+    progress: Option<Box<FnMut(u64, u64)>>,
+    reported: (u64, u64),
+}
+
+impl<R> SendAllFrom<R> {
+    /// Call `f` with the bytes written and the bytes the peer
+    /// acknowledged so far whenever either moved.
+    pub fn on_progress<F: FnMut(u64, u64) + 'static>(mut self, f: F) -> SendAllFrom<R> {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    fn report(&mut self) {
+        let acked = {
+            let stream = self.stream.as_ref().unwrap();
+            stream.stats().delivered - self.delivered
+        };
+        let now = (self.written, acked);
+        if now != self.reported {
+            self.reported = now;
+            if let Some(ref mut f) = self.progress {
+                f(now.0, now.1);
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead> Future for SendAllFrom<R> {
+    type Item = (u64, R, KcpStream);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(u64, R, KcpStream), io::Error> {
+        let result = self.copy();
+        self.report();
+        if let Async::NotReady = result? {
+            return Ok(Async::NotReady);
+        }
+        let stream = self.stream.take().unwrap();
+        Ok(Async::Ready((self.written, self.reader.take().unwrap(), stream)))
+    }
+}
+
+impl<R: AsyncRead> SendAllFrom<R> {
+    fn copy(&mut self) -> Poll<(), io::Error> {
+        loop {
+            // the next chunk is read only once the last one was taken, and
+            // writes wait while the send backlog is full
+            if self.pos == self.cap && !self.eof {
+                let n = try_nb!(self.reader.as_mut().unwrap().read(&mut self.buf));
+                if n == 0 {
+                    self.eof = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+            while self.pos < self.cap {
+                let stream = self.stream.as_mut().unwrap();
+                let n = try_nb!(stream.write(&self.buf[self.pos..self.cap]));
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "stream took no data"));
+                }
+                self.pos += n;
+                self.written += n as u64;
+            }
+            if self.eof {
+                try_nb!(self.stream.as_mut().unwrap().flush());
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+/// Copies a stream into a writer, see `KcpStream::recv_all_into`.
+pub struct RecvAllInto<W> {
+    stream: Option<KcpStream>,
+    writer: Option<W>,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    written: u64,
+    progress: Option<Box<FnMut(u64)>>,
+}
+
+impl<W> RecvAllInto<W> {
+    /// Call `f` with the bytes handed to the writer so far whenever more
+    /// were.
+    pub fn on_progress<F: FnMut(u64) + 'static>(mut self, f: F) -> RecvAllInto<W> {
+        self.progress = Some(Box::new(f));
+        self
+    }
+}
+
+impl<W: AsyncWrite> Future for RecvAllInto<W> {
+    type Item = (u64, KcpStream, W);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(u64, KcpStream, W), io::Error> {
+        let written = self.written;
+        let result = self.copy();
+        if self.written != written {
+            if let Some(ref mut f) = self.progress {
+                f(self.written);
+            }
+        }
+        if let Async::NotReady = result? {
+            return Ok(Async::NotReady);
+        }
+        let writer = self.writer.take().unwrap();
+        Ok(Async::Ready((self.written, self.stream.take().unwrap(), writer)))
+    }
+}
+
+impl<W: AsyncWrite> RecvAllInto<W> {
+    fn copy(&mut self) -> Poll<(), io::Error> {
+        loop {
+            // nothing more is read before the writer took the last message
+            if self.pos == self.cap && !self.eof {
+                let n = match self.stream.as_mut().unwrap().read(&mut self.buf) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        // a message larger than the buffer, which grows to it
+                        let needed = e.get_ref()
+                            .and_then(|e| e.downcast_ref::<ShortBuffer>())
+                            .map(|short| short.needed);
+                        match needed {
+                            Some(needed) => {
+                                self.buf.resize(needed, 0);
+                                continue;
+                            }
+                            None => return Err(e),
+                        }
+                    }
+                };
+                if n == 0 {
+                    self.eof = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+            while self.pos < self.cap {
+                let writer = self.writer.as_mut().unwrap();
+                let n = try_nb!(writer.write(&self.buf[self.pos..self.cap]));
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "writer took no data"));
+                }
+                self.pos += n;
+                self.written += n as u64;
+            }
+            if self.eof {
+                // everything the peer sent before closing is out
+                try_nb!(self.writer.as_mut().unwrap().flush());
+                return Ok(Async::Ready(()));
+            }
+        }
+    }
+}
+
+/// Waits for the listener to answer the offer of a session, see
+/// `KcpStream::connect_negotiated`.
+struct Negotiation {
+    stream: Option<KcpStream>,
+    buf: Vec<u8>,
+}
+
+impl Future for Negotiation {
+    type Item = KcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<KcpStream, io::Error> {
+        let n = try_nb!(self.stream.as_mut().unwrap().read(&mut self.buf));
+        let agreed = match Offer::decode(&self.buf[..n]) {
+            Some(agreed) => agreed,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "listener did not answer the offer",
+                ))
+            }
+        };
+        let stream = self.stream.take().unwrap();
+        agreed.apply(&mut stream.io.get_ref().kcb.borrow_mut());
+        Ok(Async::Ready(stream))
+    }
+}
+
+const CONNECT_ATTEMPT_DELAY: u64 = 250; // delay between racing connection attempts in millisec
+const CONNECT_TIMEOUT: u64 = 10000; // bound on resolving and racing the addresses of a peer in millisec
+
+/// Connection attempts to every resolved address, started one after the
+/// other until the first peer answers (RFC 8305).
+struct HappyEyeballs {
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<KcpStream>,
+    timer: Timeout,
+    deadline: Timeout,
+    handle: Handle,
+}
+
+impl Future for HappyEyeballs {
+    type Item = KcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<KcpStream, io::Error> {
+        loop {
+            if let Some(i) = self.attempts.iter().position(|s| s.is_established()) {
+                // dropping the losers closes their sessions
+                return Ok(Async::Ready(self.attempts.swap_remove(i)));
+            }
+            if let Async::Ready(()) = self.deadline.poll()? {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no address answered"));
+            }
+            // get woken up as soon as any of the peers answers
+            for stream in &self.attempts {
+                stream.poll_read();
+            }
+            if let Async::NotReady = self.timer.poll()? {
+                return Ok(Async::NotReady);
+            }
+
+            if let Some(addr) = self.addrs.pop_front() {
+                self.attempts.push(KcpStream::connect_addr(&addr, &self.handle));
+            }
+            // the window probe is not retransmitted by kcp, repeat it
+            for stream in &self.attempts {
+                let core = stream.io.get_ref();
+                core.kcb.borrow_mut().ask_window();
+                core.flush_now();
+            }
+            let next = Instant::now() + Duration::from_millis(CONNECT_ATTEMPT_DELAY);
+            self.timer.reset(next);
+        }
+    }
 }
 
-impl Future for Server {
-    type Item = ();
-    type Error = io::Error;
+/// order addresses alternating between address families, starting with
+/// the family of the first one
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_v6 = addrs.first().map_or(false, |a| a.is_ipv6());
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut out = VecDeque::new();
+    while !first.is_empty() || !second.is_empty() {
+        out.extend(first.pop_front());
+        out.extend(second.pop_front());
+    }
+    out
+}
 
-    fn poll(&mut self) -> Poll<(), io::Error> {
-        loop {
-            if let Some((size, peer)) = self.to_send {
-                let mut kcb = self.kcb.borrow_mut();
-                kcb.input(&self.buf[..size]);
+/// Data segments the sessions sharing a socket may emit per tick between
+/// them. Each session taking its turn gets an even share of what the ones
+/// before it left over, at least one segment, and flushes in between ticks
+/// only send what the tick has left.
+struct TickBudget {
+    segments: Cell<u32>,
+    // when the current tick is over, what is left of it, the sessions
+    // there are and how many of them had their turn
+    end: Cell<Instant>,
+    left: Cell<u32>,
+    sessions: Cell<usize>,
+    served: Cell<usize>,
+}
 
-                kcb.update(clock());
-                let dur = kcb.check(clock());
-                self.token.borrow_mut().reset(
-                    Instant::now() +
-                        Duration::from_millis(dur as u64),
-                );
+impl TickBudget {
+    /// unlimited until `segments` is set
+    fn new() -> Rc<TickBudget> {
+        Rc::new(TickBudget {
+            segments: Cell::new(u32::MAX),
+            end: Cell::new(Instant::now()),
+            left: Cell::new(u32::MAX),
+            sessions: Cell::new(0),
+            served: Cell::new(0),
+        })
+    }
 
-                self.set_readiness.set_readiness(mio::Ready::readable());
-                self.to_send = None;
-            }
+    /// start a tick of `sessions` sessions
+    fn start(&self, now: Instant, sessions: usize) {
+        self.end.set(now + Duration::from_millis(CONNECTOR_TICK));
+        self.left.set(self.segments.get());
+        self.sessions.set(sessions);
+        self.served.set(0);
+    }
 
-            self.to_send = Some(try_nb!(self.socket.recv_from(&mut self.buf)));
+    /// the share of the session taking its turn now
+    fn turn(&self, now: Instant) -> u32 {
+        if now >= self.end.get() {
+            self.start(now, self.sessions.get());
         }
+        let waiting = cmp::max(self.sessions.get().saturating_sub(self.served.get()), 1);
+        self.served.set(self.served.get() + 1);
+        cmp::max(self.left.get() / waiting as u32, 1)
     }
-}
-
-pub struct KcpStreamNew {
-    inner: Option<KcpStream>,
-}
 
-impl Future for KcpStreamNew {
-    type Item = KcpStream;
-    type Error = io::Error;
+    /// run `f` letting `kcb` emit at most `share` data segments, what it
+    /// sent is taken from the tick
+    fn spend<T, F>(&self, kcb: &mut Kcb<KcpOutput>, share: u32, f: F) -> T
+    where
+        F: FnOnce(&mut Kcb<KcpOutput>) -> T,
+    {
+        if self.segments.get() == u32::MAX {
+            return f(kcb);
+        }
+        let limit = kcb.burst_limit();
+        let sent = kcb.segments_sent();
+        kcb.limit_burst(cmp::min(share, limit));
+        let result = f(kcb);
+        kcb.set_burst_limit(limit);
+        let spent = (kcb.segments_sent() - sent) as u32;
+        self.left.set(self.left.get().saturating_sub(spent));
+        result
+    }
 
-    fn poll(&mut self) -> Poll<KcpStream, io::Error> {
-        Ok(Async::Ready(self.inner.take().unwrap()))
+    /// run `f`, which flushes outside of a turn, out of what the tick has
+    /// left
+    fn flush<T, F>(&self, kcb: &mut Kcb<KcpOutput>, f: F) -> T
+    where
+        F: FnOnce(&mut Kcb<KcpOutput>) -> T,
+    {
+        if Instant::now() >= self.end.get() {
+            self.start(Instant::now(), self.sessions.get());
+        }
+        let left = self.left.get();
+        self.spend(kcb, left, f)
     }
 }
 
 struct KcpInterval {
     kcb: Rc<RefCell<Kcb<KcpOutput>>>,
+    link: Link,
     token: Rc<RefCell<Timeout>>,
+    closed: Rc<Cell<bool>>,
+    state: Rc<StateWatch>,
+    set_readiness: SetReadiness,
+    budget: Rc<TickBudget>,
 }
 
 impl Stream for KcpInterval {
@@ -200,14 +2266,23 @@ impl Stream for KcpInterval {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<()>, io::Error> {
+        if self.closed.get() {
+            return Ok(Async::Ready(None));
+        }
+        flush_held(&self.kcb, &self.link);
         let mut token = self.token.borrow_mut();
         match token.poll() {
             Ok(Async::Ready(())) => {
                 let mut kcb = self.kcb.borrow_mut();
-                kcb.update(clock());
-                let dur = kcb.check(clock());
-                let next = Instant::now() + Duration::from_millis(dur as u64);
-                token.reset(next);
+                let now = Instant::now();
+                let share = self.budget.turn(now);
+                self.budget.spend(&mut kcb, share, |kcb| kcb.update_at(now));
+                token.reset(kcb.check_at(now));
+                self.state.refresh(&mut kcb);
+                if kcb.playout_delay() > 0 {
+                    // held messages come due without any input
+                    self.set_readiness.set_readiness(readiness(&kcb));
+                }
                 Ok(Async::Ready(Some(())))
             }
             Ok(Async::NotReady) => Ok(Async::NotReady),
@@ -216,14 +2291,72 @@ impl Stream for KcpInterval {
     }
 }
 
+/// send the datagrams the socket refused with `WouldBlock` once it is
+/// writable again, registers the current task to be woken up until then
+fn flush_held(kcb: &RefCell<Kcb<KcpOutput>>, link: &Link) {
+    let mut kcb = kcb.borrow_mut();
+    if kcb.output_pending() && link.poll_write().is_ready() {
+        kcb.flush_output();
+    }
+}
+
+const DEFAULT_LINGER: u64 = 3_000; // how long a dropped stream keeps closing in millisec
+
 struct KcpCore {
     kcb: Rc<RefCell<Kcb<KcpOutput>>>,
+    // shared with the listener or connector the session belongs to
+    link: Link,
     registration: Registration,
     set_readiness: SetReadiness,
-    token: Rc<RefCell<Timeout>>,
+    token: Option<Rc<RefCell<Timeout>>>,
+    closed: Rc<Cell<bool>>,
+    migration: Rc<Cell<bool>>,
+    state: Rc<StateWatch>,
+    linger: Cell<Option<Duration>>,
+    budget: Rc<TickBudget>,
+}
+
+impl Drop for KcpCore {
+    fn drop(&mut self) {
+        let linger = match self.state.get() {
+            ConnectionState::Established | ConnectionState::Closing => self.linger.get(),
+            // not heard from the peer yet, but written to it
+            ConnectionState::Connecting if self.kcb.borrow().waitsnd() > 0 => self.linger.get(),
+            // nobody to tell
+            _ => None,
+        };
+        if let Some(linger) = linger {
+            // the session goes on without its stream until the peer has
+            // everything and the close, see `KcpStream::set_linger`
+            self.kcb.borrow_mut().close(0, "");
+            self.state.set(ConnectionState::Closing);
+            self.state.linger.set(Some(Instant::now() + linger));
+            self.flush_now();
+            self.state.refresh(&mut self.kcb.borrow_mut());
+            return;
+        }
+        if self.state.get() != ConnectionState::Broken {
+            self.flush_now();
+            self.state.set(ConnectionState::Closed);
+        }
+        self.closed.set(true);
+    }
 }
 
 impl KcpCore {
+    /// flush pending data and acks without waiting for the next tick
+    fn flush_now(&self) {
+        let mut kcb = self.kcb.borrow_mut();
+        let now = Instant::now();
+        self.budget.flush(&mut kcb, |kcb| {
+            kcb.update_at(now);
+            kcb.flush();
+        });
+        if let Some(ref token) = self.token {
+            token.borrow_mut().reset(kcb.check_at(now));
+        }
+    }
+
     pub fn read_bufs(&self, bufs: &mut [&mut IoVec]) -> io::Result<usize> {
         unimplemented!()
     }
@@ -235,29 +2368,39 @@ impl KcpCore {
 
 impl Read for KcpCore {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let result = self.kcb.borrow_mut().recv(buf);
-        match result {
-            Err(e) => Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
-            Ok(n) => Ok(n),
+        let kcb = &mut *self.kcb.borrow_mut();
+        if let Some(e) = kcb.output_error() {
+            return Err(io::Error::new(e.kind(), format!("output failed: {}", e)));
+        }
+        let result = kcb.recv(buf);
+        if result.is_ok() {
+            // what was read no longer counts against the memory budget
+            self.set_readiness.set_readiness(readiness(kcb));
         }
+        result
     }
 }
 
 impl Write for KcpCore {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut kcb = self.kcb.borrow_mut();
+        if !kcb.writable() && !kcb.is_dead() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "send backlog full"));
+        }
         let result = kcb.send(buf);
-        kcb.update(clock());
-        let dur = kcb.check(clock());
-        kcb.flush();
-        self.token.borrow_mut().reset(
-            Instant::now() +
-                Duration::from_millis(dur as u64),
-        );
+        let now = Instant::now();
+        self.budget.flush(&mut kcb, |kcb| {
+            kcb.update_at(now);
+            kcb.flush();
+        });
+        if let Some(ref token) = self.token {
+            token.borrow_mut().reset(kcb.check_at(now));
+        }
         result
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.flush_now();
         Ok(())
     }
 }
@@ -288,40 +2431,155 @@ impl Evented for KcpCore {
     }
 }
 
+/// A KCP session over UDP. In message mode a read returns one message,
+/// and a message larger than the buffer stays queued: the read fails with
+/// `InvalidInput` carrying a `ShortBuffer` with the size it needs, rather
+/// than `WouldBlock` forever, so copy loops with small buffers fail there.
+/// Read such streams with buffers as large as their largest message, or
+/// use stream mode, where reads take what fits.
 pub struct KcpStream {
     io: PollEvented<KcpCore>,
 }
 
 impl KcpStream {
     pub fn connect(addr: &SocketAddr, handle: &Handle) -> KcpStreamNew {
-        let r: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        KcpStreamNew::ready(KcpStream::connect_addr(addr, handle))
+    }
+
+    /// Connect to `addr`, e.g. a `host:port` string. It is resolved on a
+    /// thread of its own, DNS never blocks the reactor. When it resolves to
+    /// several addresses, sessions to all of them are raced happy eyeballs
+    /// style and the first one the peer answers wins. Fails with `TimedOut`
+    /// if the lookup, or the race, takes longer than 10s.
+    pub fn connect_host<A: ToSocketAddrs + Send + 'static>(addr: A, handle: &Handle) -> KcpStreamNew {
+        let deadline = match Timeout::new(Duration::from_millis(CONNECT_TIMEOUT), handle) {
+            Ok(deadline) => deadline,
+            Err(e) => return KcpStreamNew::failed(e),
+        };
+        let (tx, rx) = oneshot::channel();
+        let lookup = thread::Builder::new().name("kcp-resolve".to_owned()).spawn(move || {
+            let addrs = addr.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>());
+            tx.send(addrs).ok();
+        });
+        if let Err(e) = lookup {
+            return KcpStreamNew::failed(e);
+        }
+        let handle = handle.clone();
+        let f = rx.select2(deadline).then(move |r| match r {
+            Ok(Either::A((Ok(addrs), deadline))) => KcpStream::connect_resolved(addrs, deadline, &handle),
+            Ok(Either::A((Err(e), _))) | Err(Either::B((e, _))) => KcpStreamNew::failed(e),
+            Ok(Either::B(_)) => {
+                KcpStreamNew::failed(io::Error::new(io::ErrorKind::TimedOut, "lookup timed out"))
+            }
+            Err(Either::A(_)) => {
+                KcpStreamNew::failed(io::Error::new(io::ErrorKind::Other, "lookup thread panicked"))
+            }
+        });
+        KcpStreamNew::pending(Box::new(f))
+    }
+
+    /// connect to the addresses `addr` resolved to, a race between them
+    /// has to be won before `deadline`
+    fn connect_resolved(addrs: Vec<SocketAddr>, deadline: Timeout, handle: &Handle) -> KcpStreamNew {
+        let addrs = interleave(addrs);
+        match addrs.len() {
+            0 => KcpStreamNew::failed(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )),
+            1 => KcpStreamNew::ready(KcpStream::connect_addr(&addrs[0], handle)),
+            _ => {
+                let timer = match Timeout::new_at(Instant::now(), handle) {
+                    Ok(timer) => timer,
+                    Err(e) => return KcpStreamNew::failed(e),
+                };
+                KcpStreamNew::race(HappyEyeballs {
+                    addrs: addrs,
+                    attempts: Vec::new(),
+                    timer: timer,
+                    deadline: deadline,
+                    handle: handle.clone(),
+                })
+            }
+        }
+    }
+
+    /// Connect to `addr` through the UDP relay of a SOCKS5 proxy, for hosts
+    /// that can only reach the internet via a proxy.
+    pub fn connect_socks5(proxy: &SocketAddr, addr: &SocketAddr, handle: &Handle) -> KcpStreamNew {
+        let addr = *addr;
+        let handle = handle.clone();
+        let f = socks::associate(proxy, &handle).map(move |(control, relay)| {
+            KcpStream::connect_via(&addr, Some((control, relay)), &handle)
+        });
+        KcpStreamNew::pending(Box::new(f))
+    }
+
+    pub(crate) fn connect_addr(addr: &SocketAddr, handle: &Handle) -> KcpStream {
+        KcpStream::connect_via(addr, None, handle)
+    }
+
+    /// connect to `addr`, directly or through an established socks5 UDP
+    /// association given by its control connection and relay address
+    fn connect_via(
+        addr: &SocketAddr,
+        socks: Option<(TcpStream, SocketAddr)>,
+        handle: &Handle,
+    ) -> KcpStream {
+        let (control, peer, header) = match socks {
+            Some((control, relay)) => (Some(control), relay, socks::header(addr)),
+            None => (None, *addr, Vec::new()),
+        };
+        // room for the socks header in front of each datagram
+        let pool = RecvPool::new(KcpConfig::default().mtu + header.len());
+        let r: SocketAddr = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }
+            .parse()
+            .unwrap();
         let udp = UdpSocket::bind(&r, handle).unwrap();
         let udp = Rc::new(udp);
         let conv = rand::random::<u32>();
+        let peer = Rc::new(Cell::new(peer));
         let mut kcb = Kcb::new(
             conv,
             KcpOutput {
-                udp: udp.clone(),
-                peer: addr.clone(),
+                link: Link::Udp(udp.clone()),
+                peer: peer.clone(),
+                header: header,
             },
         );
-        kcb.wndsize(128, 128);
-        kcb.nodelay(0, 10, 0, true);
+        KcpConfig::default().apply(&mut kcb);
+        #[cfg(unix)]
+        kcb.set_vectored(true);
         let kcb = Rc::new(RefCell::new(kcb));
         let (registration, set_readiness) = Registration::new2();
         let now = Instant::now();
         let token = Timeout::new_at(now, handle).unwrap();
         let token = Rc::new(RefCell::new(token));
+        let closed = Rc::new(Cell::new(false));
+        let migration = Rc::new(Cell::new(false));
+        let state = StateWatch::new(ConnectionState::Connecting, closed.clone());
+        let budget = TickBudget::new();
         let core = KcpCore {
             kcb: kcb.clone(),
+            link: Link::Udp(udp.clone()),
             registration: registration,
             set_readiness: set_readiness.clone(),
-            token: token.clone(),
+            token: Some(token.clone()),
+            closed: closed.clone(),
+            migration: migration.clone(),
+            state: state.clone(),
+            linger: Cell::new(Some(Duration::from_millis(DEFAULT_LINGER))),
+            budget: budget.clone(),
         };
 
         let interval = KcpInterval {
             kcb: kcb.clone(),
+            link: Link::Udp(udp.clone()),
             token: token.clone(),
+            closed: closed.clone(),
+            state: state.clone(),
+            set_readiness: set_readiness.clone(),
+            budget: budget.clone(),
         };
         handle.spawn(interval.for_each(|_| Ok(())).then(|_| Ok(())));
         let io = PollEvented::new(core, handle).unwrap();
@@ -329,16 +2587,178 @@ impl KcpStream {
         handle.spawn(
             Server {
                 socket: udp.clone(),
-                buf: vec![0; 1024],
+                pool: pool,
                 to_send: None,
                 kcb: kcb.clone(),
                 set_readiness: set_readiness.clone(),
                 token: token.clone(),
+                closed: closed,
+                control: control,
+                peer: peer,
+                migration: migration,
+                state: state,
             }.then(|_| Ok(())),
         );
-        KcpStreamNew { inner: Some(inner) }
+        inner
+    }
+
+    /// Same as `connect`, but sends `token` as the first message so that a
+    /// listener with an authenticator admits the session.
+    pub fn connect_with_token(addr: &SocketAddr, handle: &Handle, token: &[u8]) -> KcpStreamNew {
+        let stream = KcpStream::connect_addr(addr, handle);
+        stream.io.get_ref().kcb.borrow_mut().send(token).ok();
+        KcpStreamNew::ready(stream)
+    }
+
+    /// Same as `connect`, but proposes the parameters of `config` the
+    /// listener has to agree on, see `KcpListener::set_negotiation`. The
+    /// stream is handed out once the listener answered, configured with
+    /// the agreed values.
+    pub fn connect_negotiated(addr: &SocketAddr, config: KcpConfig, handle: &Handle) -> KcpStreamNew {
+        if let Err(e) = check_config(&config) {
+            return KcpStreamNew::failed(e);
+        }
+        let f = KcpStream::connect(addr, handle).and_then(move |stream| {
+            {
+                let core = stream.io.get_ref();
+                let mut kcb = core.kcb.borrow_mut();
+                // stream mode waits for the answer, the offer keeps its segment
+                KcpConfig { stream: false, ..config.clone() }.apply(&mut kcb);
+                kcb.send(&Offer::new(&config).encode()).ok();
+            }
+            stream.io.get_ref().flush_now();
+            Negotiation {
+                stream: Some(stream),
+                buf: vec![0; 1024],
+            }
+        });
+        KcpStreamNew::pending(Box::new(f))
+    }
+
+    /// How long the session goes on once the stream is dropped, to get
+    /// what was written and a close frame across. Dropping an established
+    /// stream closes it like `close(0, "")`, and the session keeps running
+    /// until the peer acknowledged the close or `linger` is over, when
+    /// the peer gets a reset instead. `None` drops the session after a
+    /// last flush, leaving the peer to find out by itself. 3 secs by
+    /// default.
+    pub fn set_linger(&self, linger: Option<Duration>) {
+        self.io.get_ref().linger.set(linger);
+    }
+
+    pub fn linger(&self) -> Option<Duration> {
+        self.io.get_ref().linger.get()
+    }
+
+    /// conversation id of the session
+    pub fn conv(&self) -> u32 {
+        self.io.get_ref().kcb.borrow().conv()
+    }
+
+    /// whether anything has been heard from the peer yet
+    pub fn is_established(&self) -> bool {
+        self.io.get_ref().kcb.borrow().is_established()
+    }
+
+    /// whether a segment went unacknowledged too often, see
+    /// `Kcb::set_dead_link`. reads and writes fail with `TimedOut` from then
+    pub fn is_dead(&self) -> bool {
+        self.io.get_ref().kcb.borrow().is_dead()
+    }
+
+    /// Close the stream with `code` and `reason` once everything written so
+    /// far was delivered. The peer reads EOF and gets them from
+    /// `close_frame`. `shutdown` closes with code 0 and no reason.
+    pub fn close(&self, code: u16, reason: &str) {
+        let core = self.io.get_ref();
+        match core.state.get() {
+            ConnectionState::Closed | ConnectionState::Broken => return,
+            _ => {}
+        }
+        core.kcb.borrow_mut().close(code, reason);
+        core.state.set(ConnectionState::Closing);
+        core.flush_now();
+        core.state.refresh(&mut core.kcb.borrow_mut());
+    }
+
+    /// Abort the stream at once, dropping whatever is still queued either
+    /// way, see `Kcb::reset`. The peer stops retransmitting as soon as it
+    /// gets the reset and its reads and writes fail with
+    /// `ConnectionReset`, ours with `ConnectionAborted`.
+    pub fn reset(&self) {
+        let core = self.io.get_ref();
+        let mut kcb = core.kcb.borrow_mut();
+        kcb.reset();
+        core.state.refresh(&mut kcb);
+    }
+
+    /// why the peer closed the stream, once it did
+    pub fn close_frame(&self) -> Option<CloseFrame> {
+        self.io.get_ref().kcb.borrow().peer_close().cloned()
     }
 
+    /// where the stream is in its lifecycle
+    pub fn state(&self) -> ConnectionState {
+        self.io.get_ref().state.get()
+    }
+
+    /// Subscribe to the state changes of the stream. The returned stream
+    /// yields the current state first, then every change, and ends with
+    /// `Closed` or `Broken`. A dropped stream gets there once it is done
+    /// lingering.
+    pub fn watch_state(&self) -> StateChanges {
+        self.io.get_ref().state.watch()
+    }
+
+    /// Set a raw option on the underlying UDP socket, see
+    /// `KcpListener::set_socket_option`. Streams accepted by a listener
+    /// share its socket, so the option applies to all of them.
+    #[cfg(unix)]
+    pub fn set_socket_option(&self, level: c_int, name: c_int, value: &[u8]) -> io::Result<()> {
+        setsockopt(self.io.get_ref().link.udp()?.as_raw_fd(), level, name, value)
+    }
+
+    /// set the IP_TTL option on the underlying socket
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.io.get_ref().link.udp()?.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.io.get_ref().link.udp()?.ttl()
+    }
+
+    /// set the SO_BROADCAST option on the underlying socket
+    pub fn set_broadcast(&self, on: bool) -> io::Result<()> {
+        self.io.get_ref().link.udp()?.set_broadcast(on)
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        self.io.get_ref().link.udp()?.broadcast()
+    }
+
+    /// set the IP_MULTICAST_TTL option on the underlying socket
+    pub fn set_multicast_ttl_v4(&self, ttl: u32) -> io::Result<()> {
+        self.io.get_ref().link.udp()?.set_multicast_ttl_v4(ttl)
+    }
+
+    pub fn multicast_ttl_v4(&self) -> io::Result<u32> {
+        self.io.get_ref().link.udp()?.multicast_ttl_v4()
+    }
+
+    /// set the IP_MULTICAST_LOOP option on the underlying socket
+    pub fn set_multicast_loop_v4(&self, on: bool) -> io::Result<()> {
+        self.io.get_ref().link.udp()?.set_multicast_loop_v4(on)
+    }
+
+    pub fn multicast_loop_v4(&self) -> io::Result<bool> {
+        self.io.get_ref().link.udp()?.multicast_loop_v4()
+    }
+
+    /// get and clear the SO_ERROR option of the underlying socket
+    #[cfg(unix)]
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        take_error(self.io.get_ref().link.udp()?.as_raw_fd())
+    }
 
     pub fn poll_read(&self) -> Async<()> {
         self.io.poll_read()
@@ -347,6 +2767,202 @@ impl KcpStream {
     pub fn poll_write(&self) -> Async<()> {
         self.io.poll_write()
     }
+
+    /// push pending data and acks out immediately instead of waiting for
+    /// the next update tick, e.g. right after a burst of writes
+    pub fn flush_now(&self) -> io::Result<()> {
+        self.io.get_ref().flush_now();
+        Ok(())
+    }
+
+    /// pad outgoing datagrams up to one of `sizes`, see `Kcb::set_padding`
+    pub fn set_padding(&self, sizes: &[usize]) {
+        self.io.get_ref().kcb.borrow_mut().set_padding(sizes);
+    }
+
+    /// Accept datagrams of the session from any address and follow the
+    /// peer to the last one that sent a valid segment, e.g. across a NAT
+    /// rebinding. Off by default, datagrams from anywhere but the peer are
+    /// dropped. Only streams from `connect` can migrate, sessions of a
+    /// listener or connector are keyed by their peer address.
+    pub fn set_migration(&self, on: bool) {
+        self.io.get_ref().migration.set(on);
+    }
+
+    /// acknowledge runs of segments as ranges, see `Kcb::set_ack_ranges`
+    pub fn set_ack_ranges(&self, on: bool) {
+        self.io.get_ref().kcb.borrow_mut().set_ack_ranges(on);
+    }
+
+    /// share conv, window and una among the segments of a datagram, see
+    /// `Kcb::set_compact_headers`
+    pub fn set_compact_headers(&self, on: bool) {
+        self.io.get_ref().kcb.borrow_mut().set_compact_headers(on);
+    }
+
+    /// Push back on the sender: the session advertises a closed window
+    /// until `resume_reading`, so the peer stops sending once what is in
+    /// flight arrived. Reads still return what was received.
+    pub fn pause_reading(&self) {
+        let core = self.io.get_ref();
+        core.kcb.borrow_mut().set_paused(true);
+        core.flush_now();
+    }
+
+    /// Open the window again after `pause_reading`.
+    pub fn resume_reading(&self) {
+        let core = self.io.get_ref();
+        core.kcb.borrow_mut().set_paused(false);
+        core.flush_now();
+    }
+
+    /// Call `alarm` when a segment needs `xmit` transmissions or its
+    /// retransmission timeout backs off to `rto` millisec, to move to a
+    /// backup path before the session is given up. See
+    /// `Kcb::set_failover_alarm`, `alarm` must not use this stream.
+    pub fn set_failover_alarm<F>(&self, xmit: u32, rto: u32, alarm: F)
+    where
+        F: FnMut(FailoverAlarm) + Send + 'static,
+    {
+        self.io.get_ref().kcb.borrow_mut().set_failover_alarm(xmit, rto, alarm);
+    }
+
+    /// Deliver messages on the sender's clock, `delay` millisec behind the
+    /// fastest of them, see `Kcb::set_playout_delay`.
+    pub fn set_playout_delay(&self, delay: u32) {
+        self.io.get_ref().kcb.borrow_mut().set_playout_delay(delay);
+    }
+
+    /// let acks wait for a response to carry them, see `Kcb::set_ack_delay`
+    pub fn set_ack_delay(&self, delay: u32) {
+        self.io.get_ref().kcb.borrow_mut().set_ack_delay(delay);
+    }
+
+    /// keep the path of an idle session open, `None` turns it off
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) {
+        let interval = keepalive.map_or(0, |k| k.interval());
+        self.io.get_ref().kcb.borrow_mut().set_keepalive(interval);
+    }
+
+    /// Copy all of `reader` into the stream, resolving to the bytes
+    /// written, the reader and the stream once the source is exhausted.
+    /// The reader is only read as fast as the session takes the data: a
+    /// chunk is read once the last one is queued, and writes wait while
+    /// the send backlog is full, see `set_send_backlog`. Progress is
+    /// reported through `SendAllFrom::on_progress`.
+    pub fn send_all_from<R: AsyncRead>(self, reader: R) -> SendAllFrom<R> {
+        let delivered = self.stats().delivered;
+        SendAllFrom {
+            reader: Some(reader),
+            stream: Some(self),
+            buf: vec![0; COPY_CHUNK].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            eof: false,
+            written: 0,
+            delivered: delivered,
+            progress: None,
+            reported: (0, 0),
+        }
+    }
+
+    /// Copy everything the peer sends into `writer` until it closes the
+    /// session, resolving to the bytes written, the stream and the writer
+    /// once the last of it is flushed. One message is buffered at a time,
+    /// the next one is read once the writer took it, leaving the rest to
+    /// the receive window. Progress is reported through
+    /// `RecvAllInto::on_progress`.
+    pub fn recv_all_into<W: AsyncWrite>(self, writer: W) -> RecvAllInto<W> {
+        RecvAllInto {
+            stream: Some(self),
+            writer: Some(writer),
+            buf: vec![0; COPY_CHUNK],
+            pos: 0,
+            cap: 0,
+            eof: false,
+            written: 0,
+            progress: None,
+        }
+    }
+
+    /// Ask the peer for its window right away. The answer establishes the
+    /// session without any data written, or tells that the peer is still
+    /// there.
+    pub fn ask_window(&self) {
+        let core = self.io.get_ref();
+        core.kcb.borrow_mut().ask_window();
+        core.flush_now();
+    }
+
+    /// Apply `config` to the session, like a listener does to the ones it
+    /// accepts. Best done before any data flowed. Fails with `InvalidInput`
+    /// when `config` asks for forward error correction.
+    pub fn configure(&self, config: &KcpConfig) -> io::Result<()> {
+        check_config(config)?;
+        config.apply(&mut self.io.get_ref().kcb.borrow_mut());
+        Ok(())
+    }
+
+    /// step the mtu down when large datagrams go missing, see
+    /// `Kcb::set_mtu_downshift`
+    pub fn set_mtu_downshift(&self, on: bool) {
+        self.io.get_ref().kcb.borrow_mut().set_mtu_downshift(on);
+    }
+
+    /// Segments waiting to be sent or acknowledged before writes return
+    /// `WouldBlock`, the writing task is woken once acks brought the
+    /// backlog below it again. This bounds the memory a fast writer, such
+    /// as `tokio_io::io::copy` from a faster source, can pin.
+    pub fn set_send_backlog(&self, segments: usize) {
+        self.io.get_ref().kcb.borrow_mut().set_send_backlog(segments);
+    }
+
+    /// Payload bytes the send and receive queues of the session may hold
+    /// together before writes return `WouldBlock`, see
+    /// `Kcb::set_memory_budget`. The current use is in `stats().memory`.
+    pub fn set_memory_budget(&self, bytes: usize) {
+        self.io.get_ref().kcb.borrow_mut().set_memory_budget(bytes);
+    }
+
+    /// snapshot of the connection statistics
+    pub fn stats(&self) -> KcpStats {
+        self.io.get_ref().kcb.borrow().stats()
+    }
+
+    /// segments written and not acknowledged yet, see `Kcb::waitsnd`
+    pub fn waitsnd(&self) -> usize {
+        self.io.get_ref().kcb.borrow().waitsnd()
+    }
+
+    /// payload bytes of the segments counted by `waitsnd`
+    pub fn waitsnd_bytes(&self) -> usize {
+        self.io.get_ref().kcb.borrow().waitsnd_bytes()
+    }
+
+    /// segments sent and not acknowledged yet
+    pub fn inflight(&self) -> usize {
+        self.io.get_ref().kcb.borrow().inflight()
+    }
+
+    /// complete messages waiting to be read
+    pub fn waitrcv(&self) -> usize {
+        self.io.get_ref().kcb.borrow().waitrcv()
+    }
+
+    /// payload bytes received and not read yet
+    pub fn waitrcv_bytes(&self) -> usize {
+        self.io.get_ref().kcb.borrow().waitrcv_bytes()
+    }
+
+    /// estimated offset of the peer's clock relative to ours in millisec
+    pub fn clock_offset(&self) -> Option<i32> {
+        self.io.get_ref().kcb.borrow().clock_offset()
+    }
+
+    /// estimated drift of the peer's clock relative to ours in ppm
+    pub fn clock_drift(&self) -> Option<i32> {
+        self.io.get_ref().kcb.borrow().clock_drift()
+    }
 }
 
 impl Read for KcpStream {
@@ -357,10 +2973,9 @@ impl Read for KcpStream {
 
 impl Write for KcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // TODO
-        self.io.get_ref().set_readiness.set_readiness(
-            mio::Ready::writable(),
-        );
+        // held back by a full backlog until input drains it
+        let ready = readiness(&self.io.get_ref().kcb.borrow());
+        self.io.get_ref().set_readiness.set_readiness(ready);
         self.io.write(buf)
     }
     fn flush(&mut self) -> io::Result<()> {
@@ -437,6 +3052,7 @@ impl<'a> AsyncRead for &'a KcpStream {
 
 impl<'a> AsyncWrite for &'a KcpStream {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close(0, "");
         Ok(().into())
     }
 
@@ -463,21 +3079,376 @@ impl<'a> AsyncWrite for &'a KcpStream {
     }
 }
 
-#[inline]
-fn clock() -> u32 {
-    let timespec = ctime::get_time();
-    let mills = timespec.sec * 1000 + timespec.nsec as i64 / 1000 / 1000;
-    mills as u32
+#[cfg(unix)]
+impl AsRawFd for KcpStream {
+    /// -1 for the in-process streams of `test_util::pair`
+    fn as_raw_fd(&self) -> RawFd {
+        match self.io.get_ref().link {
+            Link::Udp(ref udp) => udp.as_raw_fd(),
+            Link::Memory(..) => -1,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for KcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.udp.as_raw_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for KcpStream {
+    /// `INVALID_SOCKET` for the in-process streams of `test_util::pair`
+    fn as_raw_socket(&self) -> RawSocket {
+        match self.io.get_ref().link {
+            Link::Udp(ref udp) => udp.as_raw_socket(),
+            Link::Memory(..) => !0,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for KcpListener {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.udp.as_raw_socket()
+    }
+}
+
+// the first fd passed by systemd, after stdin, stdout and stderr
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[cfg(unix)]
+fn socket_type(fd: RawFd) -> io::Result<c_int> {
+    let mut kind: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(kind)
+    }
+}
+
+#[cfg(unix)]
+fn setsockopt(fd: RawFd, level: c_int, name: c_int, value: &[u8]) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn take_error(fd: RawFd) -> io::Result<Option<io::Error>> {
+    let mut error: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut error as *mut c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else if error == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(io::Error::from_raw_os_error(error)))
+    }
+}
+
+#[cfg(unix)]
+fn sendmsg(fd: RawFd, bufs: &[IoSlice], peer: &SocketAddr) -> io::Result<usize> {
+    let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+    let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let (name, namelen) = match *peer {
+        SocketAddr::V4(ref a) => {
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            let len = mem::size_of::<libc::sockaddr_in>();
+            (&mut sin as *mut _ as *mut libc::c_void, len)
+        }
+        SocketAddr::V6(ref a) => {
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_scope_id = a.scope_id();
+            let len = mem::size_of::<libc::sockaddr_in6>();
+            (&mut sin6 as *mut _ as *mut libc::c_void, len)
+        }
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = name;
+    msg.msg_namelen = namelen as libc::socklen_t;
+    // IoSlice is ABI compatible with iovec on unix
+    msg.msg_iov = bufs.as_ptr() as *mut libc::iovec;
+    msg.msg_iovlen = bufs.len() as _;
+    let n = unsafe { libc::sendmsg(fd, &msg, 0) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+// most sockets a listener hands off at once
+#[cfg(unix)]
+const MAX_HANDOFF_FDS: usize = 16;
+
+/// pass `fds` over the unix socket `fd`, with their count as the only byte
+/// of data
+#[cfg(unix)]
+fn send_fds(fd: RawFd, fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_HANDOFF_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many sockets"));
+    }
+    let mut count = [fds.len() as u8];
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let data_len = (fds.len() * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(data_len) } as usize];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(data_len) as _;
+        let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+        for (i, &fd) in fds.iter().enumerate() {
+            *data.offset(i as isize) = fd;
+        }
+    }
+    if unsafe { libc::sendmsg(fd, &msg, 0) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// receive the fds passed by `send_fds` over the unix socket `fd`
+#[cfg(unix)]
+fn recv_fds(fd: RawFd) -> io::Result<Vec<RawFd>> {
+    let mut count = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let data_len = (MAX_HANDOFF_FDS * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(data_len) } as usize];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let n = unsafe { libc::recvmsg(fd, &mut msg, RECV_FDS_FLAGS) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "hand off closed"));
+    }
+    let mut fds = Vec::new();
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if !cmsg.is_null()
+            && (*cmsg).cmsg_level == libc::SOL_SOCKET
+            && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+        {
+            let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+            let header = libc::CMSG_LEN(0) as usize;
+            let received = ((*cmsg).cmsg_len as usize - header) / mem::size_of::<RawFd>();
+            for i in 0..received {
+                fds.push(*data.offset(i as isize));
+            }
+        }
+    }
+    if cfg!(not(any(target_os = "linux", target_os = "android", target_os = "freebsd"))) {
+        for &fd in &fds {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+    }
+    if fds.len() != count[0] as usize || msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "sockets missing from hand off"));
+    }
+    Ok(fds)
+}
+
+// received fds are not inherited by processes exec'd later on, where the
+// platform can say so atomically
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+const RECV_FDS_FLAGS: c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android", target_os = "freebsd"))))]
+const RECV_FDS_FLAGS: c_int = 0;
+
+/// append a session of the table passed on by `hand_off`: its conv, the
+/// length of its peer ip, the ip and the port
+#[cfg(unix)]
+fn encode_session(buf: &mut Vec<u8>, conv: u32, addr: &SocketAddr) {
+    let mut field = [0; 4];
+    LittleEndian::write_u32(&mut field, conv);
+    buf.extend_from_slice(&field);
+    match *addr {
+        SocketAddr::V4(ref a) => {
+            buf.push(4);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(ref a) => {
+            buf.push(16);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+    }
+    LittleEndian::write_u16(&mut field, addr.port());
+    buf.extend_from_slice(&field[..2]);
+}
+
+/// the sessions of a table written by `encode_session`
+#[cfg(unix)]
+fn decode_sessions(mut buf: &[u8]) -> io::Result<Vec<(u32, SocketAddr)>> {
+    let mut sessions = Vec::new();
+    while !buf.is_empty() {
+        let ip_len = if buf.len() > 4 { buf[4] as usize } else { 0 };
+        if (ip_len != 4 && ip_len != 16) || buf.len() < 5 + ip_len + 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed session table"));
+        }
+        let conv = LittleEndian::read_u32(&buf[..4]);
+        let ip = if ip_len == 4 {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(&buf[5..9]);
+            net::IpAddr::from(octets)
+        } else {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&buf[5..21]);
+            net::IpAddr::from(octets)
+        };
+        let port = LittleEndian::read_u16(&buf[5 + ip_len..]);
+        sessions.push((conv, SocketAddr::new(ip, port)));
+        buf = &buf[5 + ip_len + 2..];
+    }
+    Ok(sessions)
+}
+
+/// Where the datagrams of a session go: a UDP socket, or the other end of
+/// an in-process pair losing the given percentage of them, see
+/// `test_util::pair`.
+#[derive(Clone)]
+enum Link {
+    Udp(Rc<UdpSocket>),
+    Memory(UnboundedSender<Bytes>, u32),
+}
+
+impl Link {
+    /// the socket of the session, an error for in-process ones
+    fn udp(&self) -> io::Result<&UdpSocket> {
+        match *self {
+            Link::Udp(ref udp) => Ok(udp),
+            Link::Memory(..) => Err(io::Error::new(io::ErrorKind::Other, "in-process session")),
+        }
+    }
+
+    /// whether the socket takes a datagram, registering the current task
+    /// to be woken up if not. outside of a task, e.g. a write or drop of a
+    /// stream before the reactor ran, there is none to register and the
+    /// send is just tried
+    fn poll_write(&self) -> Async<()> {
+        match *self {
+            Link::Udp(_) if !task::is_in_task() => Async::Ready(()),
+            Link::Udp(ref udp) => udp.poll_write(),
+            Link::Memory(..) => Async::Ready(()),
+        }
+    }
 }
 
 pub struct KcpOutput {
-    udp: Rc<UdpSocket>,
-    peer: SocketAddr,
+    link: Link,
+    // moved by the session when migration is enabled
+    peer: Rc<Cell<SocketAddr>>,
+    // socks5 UDP request header put in front of every datagram
+    header: Vec<u8>,
 }
 
 impl Write for KcpOutput {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.udp.send_to(buf, &self.peer)
+        let udp = match self.link {
+            Link::Udp(ref udp) => udp,
+            Link::Memory(ref tx, loss) => {
+                if loss == 0 || rand::random::<u32>() % 100 >= loss {
+                    // the other end being gone is a loss like any other
+                    let _ = tx.unbounded_send(Bytes::from(buf));
+                }
+                return Ok(buf.len());
+            }
+        };
+        // `send_to` registers the current task on WouldBlock
+        #[cfg(unix)]
+        {
+            if !task::is_in_task() {
+                return self.write_vectored(&[IoSlice::new(buf)]);
+            }
+        }
+        if self.header.is_empty() {
+            return udp.send_to(buf, &self.peer.get());
+        }
+        let mut datagram = Vec::with_capacity(self.header.len() + buf.len());
+        datagram.extend_from_slice(&self.header);
+        datagram.extend_from_slice(buf);
+        udp.send_to(&datagram, &self.peer.get())?;
+        Ok(buf.len())
+    }
+
+    /// send `bufs` as a single datagram with sendmsg, the segments are not
+    /// copied into one buffer first
+    #[cfg(unix)]
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let udp = match self.link {
+            Link::Udp(ref udp) => udp.clone(),
+            Link::Memory(..) => {
+                let datagram = bufs.iter().flat_map(|buf| buf.iter().cloned()).collect::<Vec<u8>>();
+                return self.write(&datagram);
+            }
+        };
+        if let Async::NotReady = self.link.poll_write() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+        }
+        let mut iov = Vec::with_capacity(bufs.len() + 1);
+        if !self.header.is_empty() {
+            iov.push(IoSlice::new(&self.header));
+        }
+        iov.extend_from_slice(bufs);
+        let n = sendmsg(udp.as_raw_fd(), &iov, &self.peer.get())?;
+        Ok(n.saturating_sub(self.header.len()))
     }
 
     fn flush(&mut self) -> io::Result<()> {
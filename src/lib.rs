@@ -1,17 +1,75 @@
 extern crate bytes;
 extern crate futures;
 extern crate iovec;
+#[cfg(unix)]
+extern crate libc;
 extern crate mio;
+#[cfg(feature = "otel")]
+extern crate opentelemetry;
 extern crate rand;
+#[cfg(feature = "fec")]
+extern crate reed_solomon_erasure;
+#[cfg(feature = "config-file")]
+extern crate serde;
+#[cfg(feature = "config-file")]
+#[macro_use]
+extern crate serde_derive;
+extern crate smallvec;
 extern crate time;
 extern crate time as ctime;
 #[macro_use]
 extern crate tokio_core;
 extern crate tokio_io;
+#[cfg(feature = "tokio")]
+extern crate tokio1;
+#[cfg(feature = "config-file")]
+extern crate toml;
 
+pub mod cc;
+mod config;
+mod conv;
+#[cfg(feature = "fault-injection")]
+mod fault;
+#[cfg(feature = "fec")]
+pub mod fec;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+pub mod http;
 mod kcb;
 mod kcp;
+mod manager;
+#[cfg(feature = "otel")]
+pub mod otel;
+mod reconnect;
+mod rng;
+pub mod session;
+mod sessions;
+mod socks;
+mod trace;
+pub mod sim;
+pub mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+#[cfg(feature = "ikcp-conformance")]
+#[doc(hidden)]
+pub mod ikcp;
 
-pub use self::kcb::Kcb;
-pub use self::kcp::{KcpStream, KcpStreamNew};
-pub use self::kcp::{KcpListener, Incoming};
+pub use self::config::{Congestion, KcpConfig, Keepalive};
+pub use self::conv::{ConvAllocator, RandomConv, SequentialConv};
+#[cfg(feature = "fault-injection")]
+pub use self::fault::Faults;
+pub use self::kcb::{Kcb, KcpStats, DrainMessages, OutputErrorPolicy, CloseFrame, ShortBuffer};
+pub use self::kcb::{FailoverAlarm, ParseMode, SlowStart, SegmentHeader, Direction, reset_frame};
+pub use self::kcb::{SegmentOutput, Segments};
+pub use self::kcp::{KcpStream, KcpStreamNew, ConnectionState, StateChanges};
+pub use self::kcp::{KcpListener, Incoming, SessionEvent, SessionEvents, KcpWorkers};
+pub use self::kcp::{ListenerStats, RecvAllInto, SendAllFrom};
+pub use self::kcp::{KcpConnector, KcpEndpoint, EndpointIncoming};
+pub use self::manager::SessionManager;
+pub use self::reconnect::ReconnectingKcpStream;
+pub use self::session::KcpSession;
+pub use self::sessions::{SessionId, SessionMap, SharedSessionMap};
+pub use self::trace::{Trace, TraceEvent};
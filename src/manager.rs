@@ -0,0 +1,192 @@
+//! The sessions of one server socket, for runtimes driving `KcpSession`s
+//! by hand. Datagrams are told apart by peer address and conv, so one
+//! peer may run many conversations over the same socket. A session opens
+//! with the first valid datagram of its conv, and goes away once it closed,
+//! or when nothing was heard from the peer for the idle timeout.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::{ByteOrder, LittleEndian};
+use futures::Async;
+
+use kcb::reset_frame;
+use session::{Context, KcpSession};
+use sessions::{SessionId, SessionMap};
+use {Kcb, KcpConfig};
+
+struct Managed<W: Write> {
+    session: KcpSession<W>,
+    // when the peer was last heard from
+    heard: Instant,
+}
+
+/// Sessions by peer address and conv, see the module docs.
+pub struct SessionManager<W: Write> {
+    sessions: SessionMap<(SocketAddr, u32), Managed<W>>,
+    output: Box<FnMut(&SocketAddr) -> W>,
+    config: KcpConfig,
+    idle_timeout: Option<Duration>,
+    max_sessions: usize,
+    // opened by a peer, not accepted yet
+    backlog: VecDeque<SessionId>,
+    expired: u64,
+    rejected: u64,
+}
+
+impl<W: Write> SessionManager<W> {
+    /// New sessions are tuned by `config` and send their datagrams to the
+    /// sink `output` gives for the address of the peer.
+    pub fn new<F>(config: KcpConfig, output: F) -> SessionManager<W>
+    where
+        F: FnMut(&SocketAddr) -> W + 'static,
+    {
+        SessionManager {
+            sessions: SessionMap::new(),
+            output: Box::new(output),
+            config: config,
+            idle_timeout: None,
+            max_sessions: usize::MAX,
+            backlog: VecDeque::new(),
+            expired: 0,
+            rejected: 0,
+        }
+    }
+
+    /// drop sessions whose peer was not heard from for `timeout`, none
+    /// by default. pick it well above the keepalive of the peers
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// refuse new sessions while `max` are open, unlimited by default. a
+    /// refused peer is sent a reset, so it gives up right away
+    pub fn set_max_sessions(&mut self, max: usize) {
+        self.max_sessions = max;
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// sessions dropped for being idle so far
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// new sessions refused for the limit so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Hand a datagram from `addr` to its session, opening one for a conv
+    /// not seen from `addr` yet. Returns the id of the session, new ones
+    /// are also handed out by `accept`.
+    pub fn input(&mut self, cx: &Context, addr: SocketAddr, datagram: &[u8]) -> io::Result<SessionId> {
+        if datagram.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no conv"));
+        }
+        let conv = LittleEndian::read_u32(&datagram[..4]);
+        if let Some(id) = self.sessions.id(&(addr, conv)) {
+            let managed = self.sessions.by_id_mut(id).unwrap();
+            managed.session.input(cx, datagram)?;
+            managed.heard = cx.now();
+            return Ok(id);
+        }
+        if self.sessions.len() >= self.max_sessions {
+            self.rejected += 1;
+            if let Some(frame) = reset_frame(datagram) {
+                let _ = (self.output)(&addr).write(&frame);
+            }
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "session limit reached"));
+        }
+        let mut kcb = Kcb::new(conv, (self.output)(&addr));
+        self.config.apply(&mut kcb);
+        let mut session = KcpSession::new(kcb, cx.now());
+        session.input(cx, datagram)?;
+        let id = self.sessions.insert_new(
+            (addr, conv),
+            Managed {
+                session: session,
+                heard: cx.now(),
+            },
+        );
+        self.backlog.push_back(id);
+        Ok(id)
+    }
+
+    /// the next session opened by a peer
+    pub fn accept(&mut self) -> Option<SessionId> {
+        while let Some(id) = self.backlog.pop_front() {
+            // dropped before it was accepted
+            if self.sessions.by_id(id).is_some() {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// the address of the peer and the conv of session `id`
+    pub fn key(&self, id: SessionId) -> Option<(SocketAddr, u32)> {
+        self.sessions.key(id).cloned()
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<&KcpSession<W>> {
+        self.sessions.by_id(id).map(|managed| &managed.session)
+    }
+
+    pub fn get_mut(&mut self, id: SessionId) -> Option<&mut KcpSession<W>> {
+        self.sessions.by_id_mut(id).map(|managed| &mut managed.session)
+    }
+
+    pub fn remove(&mut self, id: SessionId) -> Option<KcpSession<W>> {
+        self.sessions.remove_id(id).map(|managed| managed.session)
+    }
+
+    /// Run the timers due at the time of `cx`. Sessions idle for too long,
+    /// whose close completed or whose output failed are dropped, returns
+    /// their peers and convs.
+    pub fn update(&mut self, cx: &Context) -> Vec<(SocketAddr, u32)> {
+        let now = cx.now();
+        let idle_timeout = self.idle_timeout;
+        let mut dropped = Vec::new();
+        let mut expired = 0;
+        self.sessions.retain(|key, managed| {
+            if let Some(timeout) = idle_timeout {
+                if now.duration_since(managed.heard) >= timeout {
+                    expired += 1;
+                    dropped.push(*key);
+                    return false;
+                }
+            }
+            match managed.session.poll_timeout(cx) {
+                Ok(Async::NotReady) => true,
+                _ => {
+                    dropped.push(*key);
+                    false
+                }
+            }
+        });
+        self.expired += expired;
+        dropped
+    }
+
+    /// When `update` has work to do next, `None` without sessions.
+    pub fn deadline(&self) -> Option<Instant> {
+        let idle_timeout = self.idle_timeout;
+        self.sessions
+            .iter()
+            .map(|(_, managed)| match idle_timeout {
+                Some(timeout) => cmp::min(managed.session.deadline(), managed.heard + timeout),
+                None => managed.session.deadline(),
+            })
+            .min()
+    }
+}
@@ -0,0 +1,105 @@
+//! OpenTelemetry spans of sessions, behind the `otel` feature. A session
+//! gets a span from `trace_session` until it is closed or broken, with
+//! child spans for the handshake, for retransmissions piling up on a
+//! segment and for the close, all carrying the conv and the peer address
+//! so a KCP hop shows up in the traces of the services tunnelled over it.
+//! Spans go to the global tracer provider, see `opentelemetry::global`.
+
+use std::net::SocketAddr;
+
+use futures::{Future, Stream};
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry::trace::{Span, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use tokio_core::reactor::Handle;
+
+use kcb::FailoverAlarm;
+use kcp::{ConnectionState, KcpStream};
+
+const TRACER: &'static str = "kcp";
+// a segment sent this often or with an rto this long in millisec counts as
+// a retransmit burst
+const BURST_XMIT: u32 = 4;
+const BURST_RTO: u32 = 3_000;
+
+/// Trace the session of `stream` with `peer`, from now until it is closed
+/// or broken. The states of the stream are followed by a task spawned on
+/// `handle`. Retransmit bursts are reported through the failover alarm of
+/// the stream, this replaces an alarm set before, see
+/// `KcpStream::set_failover_alarm`.
+pub fn trace_session(stream: &KcpStream, peer: SocketAddr, handle: &Handle) {
+    let conv = stream.conv();
+    let mut session = global::tracer(TRACER).start("kcp.session");
+    session.set_attributes(attributes(conv, &peer));
+    let context = Context::current_with_span(session);
+
+    let mut handshake = match stream.state() {
+        ConnectionState::Connecting => Some(child("kcp.handshake", &context, conv, &peer)),
+        _ => None,
+    };
+    let mut close = None;
+
+    let alarm_context = context.clone();
+    stream.set_failover_alarm(BURST_XMIT, BURST_RTO, move |alarm| {
+        let mut span = child("kcp.retransmit", &alarm_context, conv, &peer);
+        match alarm {
+            FailoverAlarm::Retransmits { sn, xmit } => {
+                span.set_attribute(KeyValue::new("kcp.sn", sn as i64));
+                span.set_attribute(KeyValue::new("kcp.xmit", xmit as i64));
+            }
+            FailoverAlarm::Rto { sn, rto } => {
+                span.set_attribute(KeyValue::new("kcp.sn", sn as i64));
+                span.set_attribute(KeyValue::new("kcp.rto", rto as i64));
+            }
+        }
+        span.end();
+    });
+
+    let states = stream.watch_state().for_each(move |state| {
+        match state {
+            ConnectionState::Connecting => {}
+            ConnectionState::Established => {
+                if let Some(mut span) = handshake.take() {
+                    span.end();
+                }
+            }
+            ConnectionState::Closing => {
+                if close.is_none() {
+                    close = Some(child("kcp.close", &context, conv, &peer));
+                }
+            }
+            ConnectionState::Closed | ConnectionState::Broken => {
+                if let Some(mut span) = handshake.take() {
+                    span.set_attribute(KeyValue::new("error", true));
+                    span.end();
+                }
+                let mut span = close.take().unwrap_or_else(|| {
+                    child("kcp.close", &context, conv, &peer)
+                });
+                if state == ConnectionState::Broken {
+                    span.set_attribute(KeyValue::new("error", true));
+                    context.span().set_attribute(KeyValue::new("error", true));
+                }
+                span.end();
+                // final, the stream of states ends right after
+                context.span().end();
+            }
+        }
+        Ok(())
+    });
+    handle.spawn(states.then(|_| Ok(())));
+}
+
+fn child(name: &'static str, parent: &Context, conv: u32, peer: &SocketAddr) -> BoxedSpan {
+    let mut span = global::tracer(TRACER).start_with_context(name, parent);
+    span.set_attributes(attributes(conv, peer));
+    span
+}
+
+fn attributes(conv: u32, peer: &SocketAddr) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("kcp.conv", conv as i64),
+        KeyValue::new("net.peer.ip", peer.ip().to_string()),
+        KeyValue::new("net.peer.port", peer.port() as i64),
+    ]
+}
@@ -0,0 +1,239 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use KcpStream;
+
+/// A `KcpStream` that re-establishes its session with exponential backoff
+/// whenever the connection dies, for long-lived tunnel clients.
+///
+/// A session is considered dead when the stream returns an error, or when
+/// sent data stays unacknowledged for longer than the idle timeout. With
+/// replay enabled, messages the peer never acknowledged are written again
+/// on the new session.
+pub struct ReconnectingKcpStream {
+    addr: SocketAddr,
+    handle: Handle,
+    stream: Option<KcpStream>,
+    // pending backoff before the next connect attempt
+    delay: Option<Timeout>,
+    // fires when unacked data has made no progress for `timeout`
+    watchdog: Option<Timeout>,
+    backoff: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    timeout: Duration,
+    replay: bool,
+    // unacked messages with the byte offset at which each one ends
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    // how many of `unacked` went out on the current session
+    replayed: usize,
+    sent: u64,
+    acked: u64,
+    last_progress: Instant,
+}
+
+impl ReconnectingKcpStream {
+    pub fn connect(addr: &SocketAddr, handle: &Handle) -> ReconnectingKcpStream {
+        let mut s = ReconnectingKcpStream {
+            addr: *addr,
+            handle: handle.clone(),
+            stream: None,
+            delay: None,
+            watchdog: None,
+            backoff: Duration::from_millis(100),
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            replay: false,
+            unacked: VecDeque::new(),
+            replayed: 0,
+            sent: 0,
+            acked: 0,
+            last_progress: Instant::now(),
+        };
+        s.open();
+        s
+    }
+
+    /// set the first and the largest delay between reconnect attempts
+    pub fn set_backoff(&mut self, min: Duration, max: Duration) {
+        self.min_backoff = min;
+        self.max_backoff = cmp::max(min, max);
+        self.backoff = min;
+    }
+
+    /// declare the session dead after unacked data made no progress for `timeout`
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// resend messages left unacknowledged by a dead session on the next one
+    pub fn set_replay(&mut self, replay: bool) {
+        self.replay = replay;
+        if !replay {
+            self.unacked.clear();
+        }
+    }
+
+    /// whether a session is currently established
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn open(&mut self) {
+        let stream = KcpStream::connect_addr(&self.addr, &self.handle);
+        self.sent = 0;
+        self.acked = 0;
+        self.replayed = 0;
+        self.last_progress = Instant::now();
+        self.stream = Some(stream);
+    }
+
+    /// write the messages a dead session left unacked on the current one,
+    /// picking up where a session that was not writable yet stopped
+    fn replay(&mut self) -> io::Result<()> {
+        while self.replayed < self.unacked.len() {
+            let result = {
+                let msg = &self.unacked[self.replayed].1;
+                self.stream.as_mut().unwrap().write(msg).map(|_| msg.len() as u64)
+            };
+            match result {
+                Ok(len) => {
+                    self.sent += len;
+                    self.unacked[self.replayed].0 = self.sent;
+                    self.replayed += 1;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Err(would_block()),
+                Err(_) => return self.reset(),
+            }
+        }
+        Ok(())
+    }
+
+    /// tear down the current session and schedule the next attempt
+    fn reset(&mut self) -> io::Result<()> {
+        self.stream = None;
+        self.watchdog = None;
+        let mut delay = Timeout::new(self.backoff, &self.handle)?;
+        // register the task so it is woken up for the next attempt
+        delay.poll()?;
+        self.delay = Some(delay);
+        self.backoff = cmp::min(self.backoff * 2, self.max_backoff);
+        Err(would_block())
+    }
+
+    /// make sure a session is established, or register for the backoff delay
+    fn poll_session(&mut self) -> io::Result<()> {
+        if self.stream.is_some() {
+            return self.replay();
+        }
+        if let Some(ref mut delay) = self.delay {
+            if let Async::NotReady = delay.poll()? {
+                return Err(would_block());
+            }
+        }
+        self.delay = None;
+        self.open();
+        self.replay()
+    }
+
+    fn progress(&mut self) {
+        self.last_progress = Instant::now();
+        self.backoff = self.min_backoff;
+    }
+
+    /// drop acknowledged messages and check the session for liveness
+    fn poll_alive(&mut self) -> io::Result<()> {
+        let delivered = match self.stream {
+            Some(ref stream) => stream.stats().delivered,
+            None => return Ok(()),
+        };
+        if delivered != self.acked {
+            self.acked = delivered;
+            self.progress();
+            // only messages sent on this session count against its acks
+            while self.replayed > 0 && self.unacked.front().is_some_and(|&(end, _)| end <= delivered) {
+                self.unacked.pop_front();
+                self.replayed -= 1;
+            }
+        }
+        if self.acked >= self.sent {
+            self.watchdog = None;
+            return Ok(());
+        }
+        let deadline = self.last_progress + self.timeout;
+        if Instant::now() >= deadline {
+            return self.reset();
+        }
+        let mut watchdog = Timeout::new_at(deadline, &self.handle)?;
+        if let Async::Ready(()) = watchdog.poll()? {
+            return self.reset();
+        }
+        self.watchdog = Some(watchdog);
+        Ok(())
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "reconnecting")
+}
+
+impl Read for ReconnectingKcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.poll_session()?;
+        self.poll_alive()?;
+        let result = self.stream.as_mut().unwrap().read(buf);
+        match result {
+            Ok(n) => {
+                self.progress();
+                Ok(n)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Err(would_block()),
+            Err(_) => self.reset().map(|_| 0),
+        }
+    }
+}
+
+impl Write for ReconnectingKcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll_session()?;
+        let result = self.stream.as_mut().unwrap().write(buf);
+        match result {
+            Ok(n) => {
+                self.sent += n as u64;
+                if self.replay {
+                    self.unacked.push_back((self.sent, buf[..n].to_vec()));
+                    self.replayed = self.unacked.len();
+                }
+                // the data is accepted even if the session turns out dead,
+                // it is replayed on the next one
+                self.poll_alive().ok();
+                Ok(n)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Err(would_block()),
+            Err(_) => self.reset().map(|_| 0),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream {
+            Some(ref mut stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for ReconnectingKcpStream {}
+
+impl AsyncWrite for ReconnectingKcpStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
@@ -0,0 +1,26 @@
+//! The xorshift generator behind everything random that can be seeded, so
+//! runs with the same seed pick the same convs, sns and losses.
+
+/// xorshift64, fast and good enough for picking ids and simulating loss
+#[derive(Debug, Clone)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        // xorshift must not start from zero
+        XorShift { state: seed | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+}
@@ -0,0 +1,165 @@
+//! A KCP session driven by hand, for executors and io runtimes other than
+//! tokio-core. `KcpSession` owns a `Kcb` and never touches a socket or a
+//! timer itself: the runtime feeds it the datagrams that arrive, polls it
+//! for reads, writes and timers with a `Context`, and arms a timer for the
+//! `deadline` it reports.
+
+use std::io::{self, Write};
+use std::time::Instant;
+
+use futures::task::Task;
+use futures::{Async, Poll};
+
+use kcb::Kcb;
+
+/// The clock and the task of one poll. The task, when there is one, is
+/// notified once the call that returned `NotReady` may succeed.
+pub struct Context {
+    now: Instant,
+    task: Option<Task>,
+}
+
+impl Context {
+    /// A context at `now` that wakes no one, for runtimes that poll
+    /// every session after each input anyway.
+    pub fn new(now: Instant) -> Context {
+        Context {
+            now: now,
+            task: None,
+        }
+    }
+
+    /// A context at `now` that wakes `task`.
+    pub fn with_task(now: Instant, task: Task) -> Context {
+        Context {
+            now: now,
+            task: Some(task),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+}
+
+pub struct KcpSession<W: Write> {
+    kcb: Kcb<W>,
+    reader: Option<Task>,
+    writer: Option<Task>,
+    timer: Option<Task>,
+    deadline: Instant,
+}
+
+impl<W: Write> KcpSession<W> {
+    /// Drive `kcb`, whose output sends the datagrams of the session.
+    pub fn new(kcb: Kcb<W>, now: Instant) -> KcpSession<W> {
+        KcpSession {
+            kcb: kcb,
+            reader: None,
+            writer: None,
+            timer: None,
+            deadline: now,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Kcb<W> {
+        &self.kcb
+    }
+
+    pub fn get_mut(&mut self) -> &mut Kcb<W> {
+        &mut self.kcb
+    }
+
+    pub fn into_inner(self) -> Kcb<W> {
+        self.kcb
+    }
+
+    /// When `poll_timeout` has work to do next. Input and writes may move
+    /// it earlier, the timer task is woken when they do.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Process a datagram of the peer, acknowledging it right away, and
+    /// wake whoever waits for the session.
+    pub fn input(&mut self, cx: &Context, datagram: &[u8]) -> io::Result<()> {
+        self.kcb.input(datagram)?;
+        self.kcb.update_at(cx.now);
+        self.kcb.flush();
+        self.reschedule(cx);
+        wake(&mut self.reader);
+        if self.kcb.writable() {
+            wake(&mut self.writer);
+        }
+        Ok(())
+    }
+
+    /// Queue `buf` for sending, `NotReady` while the send backlog is full.
+    pub fn poll_send(&mut self, cx: &Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        self.check_output()?;
+        if !self.kcb.writable() {
+            self.writer = cx.task.clone();
+            return Ok(Async::NotReady);
+        }
+        let n = self.kcb.send(buf)?;
+        self.kcb.update_at(cx.now);
+        self.kcb.flush();
+        self.reschedule(cx);
+        Ok(Async::Ready(n))
+    }
+
+    /// Read the next message into `buf`, `NotReady` until one is complete.
+    /// `Ready(0)` once the peer closed the session.
+    pub fn poll_recv(&mut self, cx: &Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        self.check_output()?;
+        match self.kcb.recv(buf) {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.reader = cx.task.clone();
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Run the timers of the session due at the time of `cx`. `Ready`
+    /// once our close has completed and nothing is left to send,
+    /// otherwise `NotReady` until `deadline`.
+    pub fn poll_timeout(&mut self, cx: &Context) -> Poll<(), io::Error> {
+        self.check_output()?;
+        if cx.now >= self.deadline {
+            self.kcb.update_at(cx.now);
+            // held messages come due without any input
+            if self.kcb.playout_delay() > 0 {
+                wake(&mut self.reader);
+            }
+        }
+        if self.kcb.is_closed() && self.kcb.waitsnd() == 0 {
+            return Ok(Async::Ready(()));
+        }
+        self.deadline = self.kcb.check_at(cx.now);
+        self.timer = cx.task.clone();
+        Ok(Async::NotReady)
+    }
+
+    fn reschedule(&mut self, cx: &Context) {
+        let deadline = self.kcb.check_at(cx.now);
+        if deadline < self.deadline {
+            wake(&mut self.timer);
+        }
+        self.deadline = deadline;
+    }
+
+    fn check_output(&self) -> io::Result<()> {
+        match self.kcb.output_error() {
+            Some(e) => Err(io::Error::new(e.kind(), format!("output failed: {}", e))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn wake(task: &mut Option<Task>) {
+    if let Some(task) = task.take() {
+        task.notify();
+    }
+}
@@ -0,0 +1,323 @@
+//! Session storage of a listener. Sessions are spread over many small
+//! tables instead of one large one: growing a table rehashes every entry
+//! in it, which with 100k+ sessions stalls the reactor for every other
+//! session, while a shard only ever rehashes its own slice.
+//!
+//! `SessionMap` is for sessions driven on one thread, a tokio-core
+//! listener with more than one thread runs one map per worker (see
+//! `KcpListener::spawn_workers`), so neither the map nor its sessions take
+//! locks. The shards only map keys to a `SessionId`, the sessions
+//! themselves sit in a slab. Looking up the id of a datagram's peer
+//! hashes its address twice, once to pick the shard and once within it;
+//! everything after that, and whoever keeps the id around, reaches the
+//! session by indexing. Ids are not reused for another session while the
+//! old one may still be referred to: a slot freed by `remove` is handed
+//! out again with a new generation.
+//!
+//! `SharedSessionMap` is for sessions shared between threads, as those of
+//! the `tokio` module. Every shard has a lock of its own and every session
+//! a mutex, a lookup holds the shard only until it cloned the session out,
+//! so demultiplexing, accepting and ticking sessions in different shards
+//! never wait for each other, and a session busy with a read or write
+//! holds up nobody but its own datagrams.
+
+use std::collections::hash_map::{HashMap, RandomState};
+use std::hash::{BuildHasher, Hash};
+use std::ops::Index;
+use std::slice;
+use std::sync::{Arc, Mutex, RwLock};
+
+const SHARDS: usize = 64;
+
+/// Handle of a session in a `SessionMap`, valid until it is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId {
+    index: u32,
+    generation: u32,
+}
+
+struct Slot<K, V> {
+    generation: u32,
+    entry: Option<(K, V)>,
+}
+
+/// Map sharded by the hash of its keys.
+pub struct SessionMap<K, V> {
+    shards: Vec<HashMap<K, SessionId>>,
+    hasher: RandomState,
+    slots: Vec<Slot<K, V>>,
+    // slots without a session, taken first before the slab grows
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> SessionMap<K, V> {
+    pub fn new() -> SessionMap<K, V> {
+        SessionMap::with_shards(SHARDS)
+    }
+
+    /// `shards` tables, at least one.
+    pub fn with_shards(shards: usize) -> SessionMap<K, V> {
+        assert!(shards > 0, "no shards");
+        SessionMap {
+            shards: (0..shards).map(|_| HashMap::new()).collect(),
+            hasher: RandomState::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn shard(&self, key: &K) -> usize {
+        shard_of(&self.hasher, key, self.shards.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shards[self.shard(key)].contains_key(key)
+    }
+
+    /// The handle of the session of `key`, the only lookup that hashes.
+    pub fn id(&self, key: &K) -> Option<SessionId> {
+        self.shards[self.shard(key)].get(key).cloned()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.id(key).and_then(|id| self.by_id(id))
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        match self.id(key) {
+            Some(id) => self.by_id_mut(id),
+            None => None,
+        }
+    }
+
+    /// The session of `id`, `None` once it was removed.
+    pub fn by_id(&self, id: SessionId) -> Option<&V> {
+        self.entry(id).map(|(_, value)| value)
+    }
+
+    pub fn by_id_mut(&mut self, id: SessionId) -> Option<&mut V> {
+        match self.slots.get_mut(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => {
+                slot.entry.as_mut().map(|(_, value)| value)
+            }
+            _ => None,
+        }
+    }
+
+    /// The key the session of `id` was inserted with.
+    pub fn key(&self, id: SessionId) -> Option<&K> {
+        self.entry(id).map(|(key, _)| key)
+    }
+
+    fn entry(&self, id: SessionId) -> Option<&(K, V)> {
+        match self.slots.get(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.entry.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value `key` had before, if any. A replaced session
+    /// keeps its id.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(id) = self.id(&key) {
+            let slot = &mut self.slots[id.index as usize];
+            return slot.entry.replace((key, value)).map(|(_, old)| old);
+        }
+        self.insert_new(key, value);
+        None
+    }
+
+    /// Same as `insert` for a key not in the map yet, returns the id of
+    /// the new session.
+    pub fn insert_new(&mut self, key: K, value: V) -> SessionId {
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.entry = Some((key.clone(), value));
+                SessionId {
+                    index: index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    entry: Some((key.clone(), value)),
+                });
+                SessionId {
+                    index: (self.slots.len() - 1) as u32,
+                    generation: 0,
+                }
+            }
+        };
+        let shard = self.shard(&key);
+        self.shards[shard].insert(key, id);
+        self.len += 1;
+        id
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        match self.id(key) {
+            Some(id) => self.remove_id(id),
+            None => None,
+        }
+    }
+
+    /// Remove the session of `id`, the id and any copies of it are stale
+    /// from now on.
+    pub fn remove_id(&mut self, id: SessionId) -> Option<V> {
+        let (key, value) = match self.slots.get_mut(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => match slot.entry.take() {
+                Some(entry) => {
+                    slot.generation = slot.generation.wrapping_add(1);
+                    entry
+                }
+                None => return None,
+            },
+            _ => return None,
+        };
+        let shard = self.shard(&key);
+        self.shards[shard].remove(&key);
+        self.free.push(id.index);
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Remove every session for which `f` returns false.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        let mut dropped = Vec::new();
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if let Some((ref key, ref mut value)) = slot.entry {
+                if !f(key, value) {
+                    dropped.push(SessionId {
+                        index: index as u32,
+                        generation: slot.generation,
+                    });
+                }
+            }
+        }
+        for id in dropped {
+            self.remove_id(id);
+        }
+    }
+
+    /// every session, in slab order
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            slots: self.slots.iter(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Default for SessionMap<K, V> {
+    fn default() -> SessionMap<K, V> {
+        SessionMap::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Index<&K> for SessionMap<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("no session for key")
+    }
+}
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    slots: slice::Iter<'a, Slot<K, V>>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        for slot in &mut self.slots {
+            if let Some((ref key, ref value)) = slot.entry {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+fn shard_of<K: Hash>(hasher: &RandomState, key: &K, shards: usize) -> usize {
+    hasher.hash_one(key) as usize % shards
+}
+
+/// Map sharded by the hash of its keys, each shard behind a lock of its
+/// own and each session behind a mutex.
+pub struct SharedSessionMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, Arc<Mutex<V>>>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> SharedSessionMap<K, V> {
+    pub fn new() -> SharedSessionMap<K, V> {
+        SharedSessionMap::with_shards(SHARDS)
+    }
+
+    /// `shards` tables, at least one.
+    pub fn with_shards(shards: usize) -> SharedSessionMap<K, V> {
+        assert!(shards > 0, "no shards");
+        SharedSessionMap {
+            shards: (0..shards).map(|_| RwLock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, Arc<Mutex<V>>>> {
+        &self.shards[shard_of(&self.hasher, key, self.shards.len())]
+    }
+
+    /// Counts the shards one after the other, sessions coming and going
+    /// meanwhile may or may not be counted.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().unwrap().is_empty())
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    /// The session of `key`, its shard is unlocked again on return.
+    pub fn get(&self, key: &K) -> Option<Arc<Mutex<V>>> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    /// Returns the session `key` had before, if any.
+    pub fn insert(&self, key: K, session: Arc<Mutex<V>>) -> Option<Arc<Mutex<V>>> {
+        self.shard(&key).write().unwrap().insert(key, session)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<Arc<Mutex<V>>> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Remove every session for which `f` returns false. One shard is
+    /// locked at a time, each of its sessions while `f` looks at it.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&self, mut f: F) {
+        for shard in &self.shards {
+            shard.write().unwrap().retain(|key, session| f(key, &mut session.lock().unwrap()));
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Default for SharedSessionMap<K, V> {
+    fn default() -> SharedSessionMap<K, V> {
+        SharedSessionMap::new()
+    }
+}
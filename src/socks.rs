@@ -0,0 +1,126 @@
+//! Client side of SOCKS5 UDP ASSOCIATE (RFC 1928), used to carry the
+//! datagrams of a session through a proxy.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::{BigEndian, ByteOrder};
+use futures::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const REP_SUCCEEDED: u8 = 0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Set up an UDP association with `proxy`. Resolves to the control
+/// connection, which has to stay open for as long as the association is
+/// in use, and the relay address the datagrams have to be sent to.
+pub fn associate(
+    proxy: &SocketAddr,
+    handle: &Handle,
+) -> Box<Future<Item = (TcpStream, SocketAddr), Error = io::Error>> {
+    let proxy = *proxy;
+    let f = TcpStream::connect(&proxy, handle)
+        .and_then(|tcp| write_all(tcp, [SOCKS_VERSION, 1, METHOD_NO_AUTH]))
+        .and_then(|(tcp, _)| read_exact(tcp, [0u8; 2]))
+        .and_then(move |(tcp, reply)| {
+            if reply[0] != SOCKS_VERSION || reply[1] != METHOD_NO_AUTH {
+                return Err(invalid("socks5 proxy requires authentication"));
+            }
+            // our own address is not known before the first datagram
+            let any = if proxy.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let mut req = vec![SOCKS_VERSION, CMD_UDP_ASSOCIATE, 0];
+            encode_addr(&mut req, &any.parse().unwrap());
+            Ok((tcp, req))
+        })
+        .and_then(|(tcp, req)| write_all(tcp, req))
+        .and_then(|(tcp, _)| read_exact(tcp, [0u8; 4]))
+        .and_then(|(tcp, reply)| {
+            if reply[0] != SOCKS_VERSION {
+                return Err(invalid("not a socks5 proxy"));
+            }
+            if reply[1] != REP_SUCCEEDED {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("socks5 udp associate failed with {}", reply[1]),
+                ));
+            }
+            match reply[3] {
+                ATYP_IPV4 => Ok((tcp, reply[3], vec![0; 4 + 2])),
+                ATYP_IPV6 => Ok((tcp, reply[3], vec![0; 16 + 2])),
+                _ => Err(invalid("unsupported socks5 relay address")),
+            }
+        })
+        .and_then(|(tcp, atyp, buf)| {
+            read_exact(tcp, buf).map(move |(tcp, buf)| (tcp, atyp, buf))
+        })
+        .map(move |(tcp, atyp, buf)| {
+            let relay = decode_addr(atyp, &buf);
+            // an unspecified relay address stands for the proxy itself
+            if relay.ip().is_unspecified() {
+                (tcp, SocketAddr::new(proxy.ip(), relay.port()))
+            } else {
+                (tcp, relay)
+            }
+        });
+    Box::new(f)
+}
+
+/// the header to put in front of every datagram sent to `dst`
+pub fn header(dst: &SocketAddr) -> Vec<u8> {
+    let mut buf = vec![0, 0, 0];
+    encode_addr(&mut buf, dst);
+    buf
+}
+
+/// length of the header of a datagram received from the relay, `None` if
+/// it is malformed or a fragment, which are not supported
+pub fn header_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let len = match buf[3] {
+        ATYP_IPV4 => 4 + 4 + 2,
+        ATYP_IPV6 => 4 + 16 + 2,
+        ATYP_DOMAIN if buf.len() > 4 => 4 + 1 + buf[4] as usize + 2,
+        _ => return None,
+    };
+    if buf.len() < len { None } else { Some(len) }
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match *addr {
+        SocketAddr::V4(ref a) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+        SocketAddr::V6(ref a) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&a.ip().octets());
+        }
+    }
+    let mut port = [0; 2];
+    BigEndian::write_u16(&mut port, addr.port());
+    buf.extend_from_slice(&port);
+}
+
+fn decode_addr(atyp: u8, buf: &[u8]) -> SocketAddr {
+    if atyp == ATYP_IPV4 {
+        let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+        SocketAddr::new(ip.into(), BigEndian::read_u16(&buf[4..]))
+    } else {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&buf[..16]);
+        SocketAddr::new(Ipv6Addr::from(octets).into(), BigEndian::read_u16(&buf[16..]))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,27 @@
+//! Connected `KcpStream`s for testing protocols built on KCP. The two ends
+//! of a `pair` hand their datagrams to each other through channels on the
+//! reactor instead of sockets, dropping a share of them on the way, so
+//! nothing is bound and no port can clash. Socket options such as
+//! `set_ttl` fail on these streams.
+
+use std::io;
+
+use futures::future;
+use futures::Future;
+use rand;
+use tokio_core::reactor::Handle;
+
+use kcp::{memory_pair, KcpStream};
+
+/// Two streams of one session, connected in-process.
+pub fn pair(handle: &Handle) -> Box<Future<Item = (KcpStream, KcpStream), Error = io::Error>> {
+    pair_with_loss(0, handle)
+}
+
+/// Same as `pair`, losing `loss` percent of the datagrams either way.
+pub fn pair_with_loss(
+    loss: u32,
+    handle: &Handle,
+) -> Box<Future<Item = (KcpStream, KcpStream), Error = io::Error>> {
+    Box::new(future::ok(memory_pair(rand::random(), loss, handle)))
+}
@@ -0,0 +1,271 @@
+//! TLS over KCP with rustls, behind the `tls` feature. TLS wants a byte
+//! stream, so the session is wrapped in a `ByteStream` first, which keeps
+//! whatever part of a message did not fit the buffer of a read for the
+//! next one. Both ends should run in stream mode, so records are not cut
+//! into messages of their own, see `KcpConfig::stream`.
+//!
+//! Records are moved between the rustls session and the `ByteStream` here
+//! rather than by tokio-rustls, whose releases on tokio-io 0.1 are tied to
+//! a rustls too old to build with current toolchains.
+
+pub extern crate rustls;
+pub extern crate webpki;
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll};
+use tokio_io::{AsyncRead, AsyncWrite};
+use self::rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
+use self::webpki::DNSNameRef;
+
+use kcb::ShortBuffer;
+use kcp::KcpStream;
+
+/// TLS client session over KCP.
+pub type TlsClientStream = TlsStream<ClientSession>;
+
+/// TLS server session over KCP.
+pub type TlsServerStream = TlsStream<ServerSession>;
+
+/// A `KcpStream` read as a stream of bytes, reads into a buffer of any
+/// size succeed without `ShortBuffer` errors.
+pub struct ByteStream {
+    stream: KcpStream,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl ByteStream {
+    pub fn new(stream: KcpStream) -> ByteStream {
+        ByteStream {
+            stream: stream,
+            buf: Vec::new(),
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &KcpStream {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut KcpStream {
+        &mut self.stream
+    }
+
+    /// The wrapped stream, bytes kept from the last message are lost.
+    pub fn into_inner(self) -> KcpStream {
+        self.stream
+    }
+}
+
+impl Read for ByteStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.len {
+            let e = match self.stream.read(buf) {
+                Err(e) => e,
+                r => return r,
+            };
+            let needed = match e.get_ref().and_then(|e| e.downcast_ref::<ShortBuffer>()) {
+                Some(short) => short.needed,
+                None => return Err(e),
+            };
+            // the message does not fit, keep it and hand it out in pieces
+            if self.buf.len() < needed {
+                self.buf.resize(needed, 0);
+            }
+            self.len = self.stream.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        let n = cmp::min(buf.len(), self.len - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for ByteStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for ByteStream {}
+
+impl AsyncWrite for ByteStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+/// A TLS session over a `ByteStream`, handed out by `connect` and
+/// `accept` once the handshake is done.
+///
+/// A write returns once its plaintext is taken by the session, records it
+/// could not send yet go out with the next write, `flush` or `shutdown`.
+pub struct TlsStream<S> {
+    io: ByteStream,
+    session: S,
+    eof: bool,
+    closing: bool,
+}
+
+impl<S: Session> TlsStream<S> {
+    fn new(stream: KcpStream, session: S) -> TlsStream<S> {
+        TlsStream {
+            io: ByteStream::new(stream),
+            session: session,
+            eof: false,
+            closing: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> (&ByteStream, &S) {
+        (&self.io, &self.session)
+    }
+
+    pub fn get_mut(&mut self) -> (&mut ByteStream, &mut S) {
+        (&mut self.io, &mut self.session)
+    }
+
+    pub fn into_inner(self) -> (ByteStream, S) {
+        (self.io, self.session)
+    }
+
+    /// take the next records off the stream, 0 at its end
+    fn read_io(&mut self) -> io::Result<usize> {
+        let n = self.session.read_tls(&mut self.io)?;
+        if let Err(e) = self.session.process_new_packets() {
+            // the alert telling the peer why is sent on a best effort basis
+            let _ = self.write_io();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(n)
+    }
+
+    /// send every record queued by the session
+    fn write_io(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            if self.session.write_tls(&mut self.io)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Session> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.read(buf) {
+                Ok(0) if !buf.is_empty() && !self.eof => {}
+                // the peer sent close_notify
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                r => return r,
+            }
+            if self.read_io()? == 0 {
+                self.eof = true;
+            }
+            // answers to what was read, e.g. key updates, are not urgent
+            match self.write_io() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                r => r?,
+            }
+        }
+    }
+}
+
+impl<S: Session> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // earlier records first, nothing more is taken until they are sent
+        self.write_io()?;
+        let n = self.session.write(buf)?;
+        match self.write_io() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            r => r?,
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        self.write_io()?;
+        self.io.flush()
+    }
+}
+
+impl<S: Session> AsyncRead for TlsStream<S> {}
+
+impl<S: Session> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.closing {
+            self.session.send_close_notify();
+            self.closing = true;
+        }
+        try_nb!(self.write_io());
+        self.io.shutdown()
+    }
+}
+
+/// Future of a TLS handshake, resolving to the stream once it is done.
+pub struct Handshake<S> {
+    stream: Option<TlsStream<S>>,
+}
+
+impl<S: Session> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream<S>, io::Error> {
+        {
+            let stream = self.stream.as_mut().expect("poll a Handshake after it is done");
+            loop {
+                try_nb!(stream.write_io());
+                if !stream.session.is_handshaking() {
+                    break;
+                }
+                if try_nb!(stream.read_io()) == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "session closed during the tls handshake",
+                    ));
+                }
+            }
+        }
+        Ok(Async::Ready(self.stream.take().unwrap()))
+    }
+}
+
+/// Run the client side of a TLS handshake over `stream`, checking the
+/// server certificate against `domain`.
+pub fn connect(
+    stream: KcpStream,
+    config: Arc<ClientConfig>,
+    domain: &str,
+) -> io::Result<Handshake<ClientSession>> {
+    let domain = DNSNameRef::try_from_ascii_str(domain).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "invalid dns name")
+    })?;
+    let session = ClientSession::new(&config, domain);
+    Ok(Handshake {
+        stream: Some(TlsStream::new(stream, session)),
+    })
+}
+
+/// Run the server side of a TLS handshake over `stream`, typically one
+/// handed out by `KcpListener::incoming`.
+pub fn accept(stream: KcpStream, config: Arc<ServerConfig>) -> Handshake<ServerSession> {
+    Handshake {
+        stream: Some(TlsStream::new(stream, ServerSession::new(&config))),
+    }
+}
@@ -0,0 +1,632 @@
+//! `KcpStream` and `KcpListener` for tokio 1.x runtimes, behind the `tokio`
+//! feature. Each UDP socket is driven by a task of its own, spawned on the
+//! runtime the stream or listener is created in, which hands datagrams to
+//! their sessions and calls `update` on the sessions whose `check` came
+//! due, in the order of their deadlines. Like the
+//! tokio-core `KcpStream`, one read returns at most one message. With
+//! `KcpConfig::fec` set the datagrams go through forward error correction,
+//! see `fec`.
+
+use std::cmp::Reverse;
+#[cfg(feature = "fec")]
+use std::collections::HashMap;
+use std::collections::{BinaryHeap, VecDeque};
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::{self, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use bytes::{ByteOrder, LittleEndian};
+use rand;
+use tokio1;
+use tokio1::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio1::net::UdpSocket;
+use tokio1::time::{self, Sleep};
+
+#[cfg(feature = "fec")]
+use fec::{FecDecoder, FecEncoder, FEC_OVERHEAD};
+use {reset_frame, Kcb, KcpConfig, SharedSessionMap};
+
+// how long the driver of a listener without sessions sleeps, datagrams
+// wake it up earlier
+const IDLE_TICK: u64 = 60_000;
+
+/// A KCP session over a tokio 1.x UDP socket.
+pub struct KcpStream {
+    session: Arc<Mutex<Session>>,
+    socket: Arc<Socket>,
+    peer: SocketAddr,
+}
+
+/// Accepts KCP sessions on a tokio 1.x UDP socket, a session is opened by
+/// the first valid datagram of a conv from an address.
+pub struct KcpListener {
+    socket: Arc<Socket>,
+}
+
+/// Future of the next session of a `KcpListener`, see `KcpListener::accept`.
+pub struct Accept<'a> {
+    listener: &'a KcpListener,
+}
+
+struct Session {
+    kcb: Kcb<SessionOutput>,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    // the stream is gone, the session stays until its close went through
+    dropped: bool,
+    // when the driver updates it next, earlier entries for the session on
+    // the timers are stale
+    due: Instant,
+}
+
+impl Session {
+    fn wake(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.dropped && (self.kcb.is_closed() || self.kcb.is_dead() || self.kcb.is_reset())
+    }
+}
+
+struct Socket {
+    udp: Arc<UdpSocket>,
+    // outside of `inner`, so datagrams reach their sessions without it
+    sessions: SharedSessionMap<(SocketAddr, u32), Session>,
+    // the deadlines of the sessions, earliest first
+    timers: Mutex<BinaryHeap<Reverse<(Instant, (SocketAddr, u32))>>>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // sessions opened by peers and not accepted yet, `None` unless the
+    // socket belongs to a listener which is still around
+    backlog: Option<VecDeque<(KcpStream, SocketAddr)>>,
+    acceptor: Option<Waker>,
+    driver: Option<Waker>,
+    config: KcpConfig,
+    // peers past it are sent a reset instead of getting a session
+    max_sessions: usize,
+    // by the address of the peer, all of its sessions share one seqid space
+    #[cfg(feature = "fec")]
+    fec: HashMap<SocketAddr, FecPeer>,
+}
+
+/// forward error correction with one peer, the encoder is in the output of
+/// each of its sessions
+#[cfg(feature = "fec")]
+struct FecPeer {
+    encoder: Arc<Mutex<FecEncoder<UdpOutput>>>,
+    decoder: FecDecoder,
+}
+
+#[cfg(feature = "fec")]
+impl FecPeer {
+    fn new(udp: &Arc<UdpSocket>, peer: SocketAddr, (data, parity): (usize, usize)) -> FecPeer {
+        let udp = UdpOutput {
+            udp: udp.clone(),
+            peer: peer,
+        };
+        // the shards were checked when the socket was created
+        FecPeer {
+            encoder: Arc::new(Mutex::new(FecEncoder::new(udp, data, parity).unwrap())),
+            decoder: FecDecoder::new(data, parity).unwrap(),
+        }
+    }
+}
+
+impl Socket {
+    fn new(udp: net::UdpSocket, config: &KcpConfig, listening: bool) -> io::Result<Arc<Socket>> {
+        #[cfg(feature = "fec")]
+        {
+            if let Some((data, parity)) = config.fec {
+                FecDecoder::new(data, parity)?;
+            }
+        }
+        udp.set_nonblocking(true)?;
+        Ok(Arc::new(Socket {
+            udp: Arc::new(UdpSocket::from_std(udp)?),
+            sessions: SharedSessionMap::new(),
+            timers: Mutex::new(BinaryHeap::new()),
+            inner: Mutex::new(Inner {
+                backlog: if listening { Some(VecDeque::new()) } else { None },
+                acceptor: None,
+                driver: None,
+                config: config.clone(),
+                max_sessions: usize::MAX,
+                #[cfg(feature = "fec")]
+                fec: HashMap::new(),
+            }),
+        }))
+    }
+
+    fn lock(&self) -> MutexGuard<Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// have the driver look at the sessions again, after a write or close
+    /// may have moved their next update
+    fn wake_driver(&self) {
+        if let Some(waker) = self.lock().driver.take() {
+            waker.wake();
+        }
+    }
+
+    /// register a new session, due for an update right away
+    fn add(&self, key: (SocketAddr, u32), session: Arc<Mutex<Session>>) {
+        let due = session.lock().unwrap().due;
+        self.sessions.insert(key, session);
+        self.timers.lock().unwrap().push(Reverse((due, key)));
+    }
+
+    /// bring the next update of `session` forward when input, a write or
+    /// a close made it due earlier
+    fn schedule(&self, key: (SocketAddr, u32), session: &mut Session) {
+        let next = session.kcb.check_at(Instant::now());
+        if next < session.due {
+            session.due = next;
+            self.timers.lock().unwrap().push(Reverse((next, key)));
+        }
+    }
+
+    /// the session whose deadline passed first, if any
+    fn next_due(&self, now: Instant) -> Option<(Instant, (SocketAddr, u32))> {
+        let mut timers = self.timers.lock().unwrap();
+        match timers.peek() {
+            Some(&Reverse((due, _))) if due <= now => timers.pop().map(|Reverse(timer)| timer),
+            _ => None,
+        }
+    }
+
+    /// update the sessions that came due, drop those that are done,
+    /// returns when to update next or `None` once nothing is left to drive
+    fn tick(&self, waker: &Waker) -> Option<Instant> {
+        let now = Instant::now();
+        while let Some((due, key)) = self.next_due(now) {
+            let session = match self.sessions.get(&key) {
+                Some(session) => session,
+                None => continue,
+            };
+            let mut session = session.lock().unwrap();
+            if session.due != due {
+                continue;
+            }
+            session.kcb.update_at(now);
+            session.wake();
+            if session.is_done() {
+                drop(session);
+                self.sessions.remove(&key);
+                continue;
+            }
+            // at least a millisec on, so this tick ends
+            session.due = session.kcb.check_at(now).max(now + Duration::from_millis(1));
+            self.timers.lock().unwrap().push(Reverse((session.due, key)));
+        }
+        let next = match self.timers.lock().unwrap().peek() {
+            Some(&Reverse((due, _))) => due,
+            None => now + Duration::from_millis(IDLE_TICK),
+        };
+        let mut inner = self.lock();
+        // peers without sessions hold the only reference to their encoder
+        #[cfg(feature = "fec")]
+        inner.fec.retain(|_, peer| Arc::strong_count(&peer.encoder) > 1);
+        if self.sessions.is_empty() && inner.backlog.is_none() {
+            return None;
+        }
+        inner.driver = Some(waker.clone());
+        Some(next.min(now + Duration::from_millis(IDLE_TICK)))
+    }
+}
+
+/// take a datagram from `addr` off the wire, restoring what forward error
+/// correction can before it goes to the sessions
+#[cfg(feature = "fec")]
+fn receive(socket: &Arc<Socket>, buf: &[u8], addr: SocketAddr) {
+    let mut datagrams = Vec::new();
+    {
+        let mut inner = socket.lock();
+        let shards = match inner.config.fec {
+            Some(shards) => shards,
+            None => {
+                drop(inner);
+                return dispatch(socket, buf, addr);
+            }
+        };
+        let udp = &socket.udp;
+        let peer = inner.fec.entry(addr).or_insert_with(|| FecPeer::new(udp, addr, shards));
+        let _ = peer.decoder.decode(buf, |datagram| datagrams.push(datagram.to_vec()));
+    }
+    for datagram in datagrams {
+        dispatch(socket, &datagram, addr);
+    }
+}
+
+#[cfg(not(feature = "fec"))]
+fn receive(socket: &Arc<Socket>, buf: &[u8], addr: SocketAddr) {
+    dispatch(socket, buf, addr)
+}
+
+/// hand a datagram from `addr` to its session, or open a new one when the
+/// socket belongs to a listener
+fn dispatch(socket: &Arc<Socket>, buf: &[u8], addr: SocketAddr) {
+    if buf.len() < 4 {
+        return;
+    }
+    let conv = LittleEndian::read_u32(&buf[..4]);
+    if let Some(session) = socket.sessions.get(&(addr, conv)) {
+        let mut session = session.lock().unwrap();
+        if session.kcb.input(buf).is_ok() {
+            session.wake();
+            socket.schedule((addr, conv), &mut session);
+        }
+        return;
+    }
+    let mut inner = socket.lock();
+    if inner.backlog.is_none() {
+        return;
+    }
+    if socket.sessions.len() >= inner.max_sessions {
+        // the peer gives up right away instead of retrying
+        if let Some(frame) = reset_frame(buf) {
+            let _ = socket.udp.try_send_to(&frame, addr);
+        }
+        return;
+    }
+    let mut kcb = match control_block(socket, &mut inner, conv, addr) {
+        Ok(kcb) => kcb,
+        Err(_) => return,
+    };
+    if kcb.input(buf).is_err() {
+        return;
+    }
+    let (stream, session) = open(socket, kcb, addr);
+    socket.add((addr, conv), session);
+    inner.backlog.as_mut().unwrap().push_back((stream, addr));
+    if let Some(waker) = inner.acceptor.take() {
+        waker.wake();
+    }
+}
+
+fn control_block(
+    socket: &Socket,
+    inner: &mut Inner,
+    conv: u32,
+    peer: SocketAddr,
+) -> io::Result<Kcb<SessionOutput>> {
+    let udp = UdpOutput {
+        udp: socket.udp.clone(),
+        peer: peer,
+    };
+    #[cfg(feature = "fec")]
+    let output = match inner.config.fec {
+        Some(shards) => {
+            let fec = inner
+                .fec
+                .entry(peer)
+                .or_insert_with(|| FecPeer::new(&socket.udp, peer, shards));
+            SessionOutput::Fec(fec.encoder.clone())
+        }
+        None => SessionOutput::Plain(udp),
+    };
+    #[cfg(not(feature = "fec"))]
+    let output = SessionOutput::Plain(udp);
+    let config = &inner.config;
+    let mut kcb = Kcb::new(conv, output);
+    config.apply(&mut kcb);
+    #[cfg(feature = "fec")]
+    {
+        // room for the fec header in every datagram
+        if config.fec.is_some() {
+            kcb.setmtu(config.mtu.saturating_sub(FEC_OVERHEAD));
+        }
+    }
+    kcb.update_at(Instant::now());
+    Ok(kcb)
+}
+
+/// the stream of a session on `socket`, the session is not registered yet
+fn open(socket: &Arc<Socket>, kcb: Kcb<SessionOutput>, peer: SocketAddr) -> (KcpStream, Arc<Mutex<Session>>) {
+    let session = Arc::new(Mutex::new(Session {
+        kcb: kcb,
+        reader: None,
+        writer: None,
+        dropped: false,
+        due: Instant::now(),
+    }));
+    let stream = KcpStream {
+        session: session.clone(),
+        socket: socket.clone(),
+        peer: peer,
+    };
+    (stream, session)
+}
+
+/// The task behind a socket, it ends once the listener and every session
+/// on the socket are gone.
+struct Driver {
+    socket: Arc<Socket>,
+    timer: Pin<Box<Sleep>>,
+    buf: Vec<u8>,
+}
+
+impl Driver {
+    fn spawn(socket: Arc<Socket>) {
+        tokio1::spawn(Driver {
+            socket: socket,
+            timer: Box::pin(time::sleep(Duration::from_millis(0))),
+            buf: vec![0; 65536],
+        });
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        loop {
+            loop {
+                let mut buf = ReadBuf::new(&mut this.buf);
+                match this.socket.udp.poll_recv_from(cx, &mut buf) {
+                    Poll::Ready(Ok(addr)) => receive(&this.socket, buf.filled(), addr),
+                    // an ICMP error of an earlier send, the retransmits
+                    // of the session find out on their own
+                    Poll::Ready(Err(_)) => {}
+                    Poll::Pending => break,
+                }
+            }
+            let next = match this.socket.tick(cx.waker()) {
+                Some(next) => next,
+                None => return Poll::Ready(()),
+            };
+            this.timer.as_mut().reset(time::Instant::from_std(next));
+            if this.timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl KcpStream {
+    /// Connect to `addr` from an ephemeral port. Must be called within a
+    /// tokio 1.x runtime, which the session is driven on.
+    pub fn connect(addr: &SocketAddr) -> io::Result<KcpStream> {
+        KcpStream::connect_with_config(addr, &KcpConfig::default())
+    }
+
+    pub fn connect_with_config(addr: &SocketAddr, config: &KcpConfig) -> io::Result<KcpStream> {
+        let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }
+            .parse()
+            .unwrap();
+        let socket = Socket::new(net::UdpSocket::bind(&local)?, config, false)?;
+        let conv = rand::random::<u32>();
+        let kcb = control_block(&socket, &mut socket.lock(), conv, *addr)?;
+        let (stream, session) = open(&socket, kcb, *addr);
+        socket.add((*addr, conv), session);
+        Driver::spawn(socket);
+        Ok(stream)
+    }
+
+    /// conversation id of the session
+    pub fn conv(&self) -> u32 {
+        self.session.lock().unwrap().kcb.conv()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.udp.local_addr()
+    }
+
+    /// whether anything has been heard from the peer yet
+    pub fn is_established(&self) -> bool {
+        self.session.lock().unwrap().kcb.is_established()
+    }
+
+    /// whether a segment went unacknowledged too often, see
+    /// `Kcb::set_dead_link`. reads and writes fail with `TimedOut` from then
+    pub fn is_dead(&self) -> bool {
+        self.session.lock().unwrap().kcb.is_dead()
+    }
+
+    /// Close the stream with `code` and `reason` once everything written so
+    /// far was delivered, see `Kcb::close`. `shutdown` closes with code 0
+    /// and no reason.
+    pub fn close(&self, code: u16, reason: &str) {
+        {
+            let mut session = self.session.lock().unwrap();
+            session.kcb.close(code, reason);
+            session.kcb.update_at(Instant::now());
+            session.kcb.flush();
+            self.socket.schedule((self.peer, session.kcb.conv()), &mut session);
+        }
+        self.socket.wake_driver();
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let mut session = self.session.lock().unwrap();
+        if let Some(e) = session.kcb.output_error() {
+            return Poll::Ready(Err(io::Error::new(e.kind(), format!("output failed: {}", e))));
+        }
+        match session.kcb.recv(buf.initialize_unfilled()) {
+            Ok(n) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                session.reader = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = {
+            let mut session = self.session.lock().unwrap();
+            if !session.kcb.writable() && !session.kcb.is_dead() {
+                session.writer = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let result = session.kcb.send(buf);
+            session.kcb.update_at(Instant::now());
+            session.kcb.flush();
+            self.socket.schedule((self.peer, session.kcb.conv()), &mut session);
+            result
+        };
+        self.socket.wake_driver();
+        Poll::Ready(result)
+    }
+
+    /// push out what is queued as far as the windows allow, without
+    /// waiting for the next update
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        {
+            let mut session = self.session.lock().unwrap();
+            session.kcb.update_at(Instant::now());
+            session.kcb.flush();
+            if let Some(e) = session.kcb.output_error() {
+                return Poll::Ready(Err(io::Error::new(e.kind(), format!("output failed: {}", e))));
+            }
+            self.socket.schedule((self.peer, session.kcb.conv()), &mut session);
+        }
+        self.socket.wake_driver();
+        Poll::Ready(Ok(()))
+    }
+
+    /// close the stream and wait until the peer has everything, or the
+    /// link died trying
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.close(0, "");
+        let mut session = self.session.lock().unwrap();
+        let kcb = &session.kcb;
+        if kcb.is_closed() || kcb.is_dead() || kcb.is_reset() {
+            return Poll::Ready(Ok(()));
+        }
+        session.writer = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for KcpStream {
+    fn drop(&mut self) {
+        {
+            let mut session = self.session.lock().unwrap();
+            session.dropped = true;
+            session.kcb.close(0, "");
+            self.socket.schedule((self.peer, session.kcb.conv()), &mut session);
+        }
+        self.socket.wake_driver();
+    }
+}
+
+impl KcpListener {
+    /// Listen on `addr`. Must be called within a tokio 1.x runtime, which
+    /// the sessions are driven on.
+    pub fn bind(addr: &SocketAddr) -> io::Result<KcpListener> {
+        KcpListener::bind_with_config(addr, &KcpConfig::default())
+    }
+
+    /// same as `bind`, with `config` applied to every accepted session
+    pub fn bind_with_config(addr: &SocketAddr, config: &KcpConfig) -> io::Result<KcpListener> {
+        let socket = Socket::new(net::UdpSocket::bind(addr)?, config, true)?;
+        Driver::spawn(socket.clone());
+        Ok(KcpListener { socket: socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.udp.local_addr()
+    }
+
+    /// Send new peers a reset instead of a session while `max` sessions
+    /// are open, accepted or not. Unlimited by default.
+    pub fn set_max_sessions(&self, max: usize) {
+        self.socket.lock().max_sessions = max;
+    }
+
+    /// the next session opened by a peer
+    pub fn poll_accept(&self, cx: &mut Context) -> Poll<io::Result<(KcpStream, SocketAddr)>> {
+        let mut inner = self.socket.lock();
+        match inner.backlog.as_mut().and_then(|backlog| backlog.pop_front()) {
+            Some(accepted) => Poll::Ready(Ok(accepted)),
+            None => {
+                inner.acceptor = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Future of the next session opened by a peer.
+    pub fn accept(&self) -> Accept {
+        Accept { listener: self }
+    }
+}
+
+impl<'a> Future for Accept<'a> {
+    type Output = io::Result<(KcpStream, SocketAddr)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.listener.poll_accept(cx)
+    }
+}
+
+impl Drop for KcpListener {
+    fn drop(&mut self) {
+        // the streams lock the socket themselves when dropped
+        let backlog = self.socket.lock().backlog.take();
+        drop(backlog);
+        self.socket.wake_driver();
+    }
+}
+
+/// what a session writes its datagrams to
+enum SessionOutput {
+    Plain(UdpOutput),
+    // shared by the sessions with the same peer
+    #[cfg(feature = "fec")]
+    Fec(Arc<Mutex<FecEncoder<UdpOutput>>>),
+}
+
+impl Write for SessionOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            SessionOutput::Plain(ref mut output) => output.write(buf),
+            #[cfg(feature = "fec")]
+            SessionOutput::Fec(ref output) => output.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct UdpOutput {
+    udp: Arc<UdpSocket>,
+    peer: SocketAddr,
+}
+
+impl Write for UdpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.udp.try_send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
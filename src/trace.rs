@@ -0,0 +1,163 @@
+//! Recording of what drives a `Kcb`, to replay a session locally. A
+//! recording holds every datagram the session was fed, every clock tick
+//! and every send and receive of the application, with the time of the
+//! block at that moment. Replayed into a block with the same conv and
+//! configuration, it walks through exactly the same states, so a bug
+//! report can ship the recording instead of steps to reproduce. The
+//! conv and initial sn travel with the recording, so random ones replay
+//! too as long as recording starts before the first send.
+
+use std::io::{self, Read, Write};
+
+use bytes::{ByteOrder, LittleEndian};
+
+use kcb::Kcb;
+
+const TRACE_MAGIC: &[u8] = b"KCPT";
+const TRACE_VERSION: u8 = 2;
+const TRACE_INPUT: u8 = 1;
+const TRACE_UPDATE: u8 = 2;
+const TRACE_FLUSH: u8 = 3;
+const TRACE_SEND: u8 = 4;
+const TRACE_RECV: u8 = 5;
+
+/// One call into the recorded block, `ts` is its clock in millisec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// `Kcb::input` of a datagram
+    Input { ts: u32, data: Vec<u8> },
+    /// `Kcb::update`, `ts` being the time passed in
+    Update { ts: u32 },
+    /// `Kcb::flush`
+    Flush { ts: u32 },
+    /// `Kcb::send` of a message
+    Send { ts: u32, data: Vec<u8> },
+    /// `Kcb::recv` into a buffer of `len` bytes
+    Recv { ts: u32, len: usize },
+}
+
+/// Events recorded by `Kcb::record`, in the order they happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub conv: u32,
+    /// the sn the block numbered from, see `Kcb::set_initial_sn`
+    pub isn: Option<u32>,
+    pub events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new(conv: u32) -> Trace {
+        Trace {
+            conv: conv,
+            isn: None,
+            events: Vec::new(),
+        }
+    }
+
+    /// Feed the recording to `kcb`, which has to be created with `conv`
+    /// and configured like the recorded block was, short of an initial sn
+    /// of its own: the recorded one is given to it first. What the calls
+    /// returned is not checked, the block ends up where the recorded one
+    /// was.
+    pub fn replay<W: Write>(&self, kcb: &mut Kcb<W>) -> io::Result<()> {
+        if kcb.conv() != self.conv {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "conv mismatch"));
+        }
+        if let Some(isn) = self.isn {
+            kcb.set_initial_sn(isn);
+        }
+        for event in &self.events {
+            match *event {
+                TraceEvent::Input { ref data, .. } => {
+                    kcb.input(data).ok();
+                }
+                TraceEvent::Update { ts } => kcb.update(ts),
+                TraceEvent::Flush { .. } => kcb.flush(),
+                TraceEvent::Send { ref data, .. } => {
+                    kcb.send(data).ok();
+                }
+                TraceEvent::Recv { len, .. } => {
+                    kcb.recv(&mut vec![0; len]).ok();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the recording in its portable binary form: a header with the
+    /// conv and initial sn, then per event its kind, time and payload
+    /// length and payload.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut header = [0; 14];
+        header[..4].copy_from_slice(TRACE_MAGIC);
+        header[4] = TRACE_VERSION;
+        LittleEndian::write_u32(&mut header[5..9], self.conv);
+        if let Some(isn) = self.isn {
+            header[9] = 1;
+            LittleEndian::write_u32(&mut header[10..], isn);
+        }
+        w.write_all(&header)?;
+        for event in &self.events {
+            let (kind, ts, len, data): (u8, u32, usize, &[u8]) = match *event {
+                TraceEvent::Input { ts, ref data } => (TRACE_INPUT, ts, data.len(), data),
+                TraceEvent::Update { ts } => (TRACE_UPDATE, ts, 0, &[]),
+                TraceEvent::Flush { ts } => (TRACE_FLUSH, ts, 0, &[]),
+                TraceEvent::Send { ts, ref data } => (TRACE_SEND, ts, data.len(), data),
+                TraceEvent::Recv { ts, len } => (TRACE_RECV, ts, len, &[]),
+            };
+            let mut buf = [0; 9];
+            buf[0] = kind;
+            LittleEndian::write_u32(&mut buf[1..5], ts);
+            LittleEndian::write_u32(&mut buf[5..], len as u32);
+            w.write_all(&buf)?;
+            w.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// Read a recording written by `write_to`, or by a version before
+    /// initial sns were recorded.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Trace> {
+        let mut header = [0; 9];
+        r.read_exact(&mut header)?;
+        if &header[..4] != TRACE_MAGIC || header[4] == 0 || header[4] > TRACE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a kcp trace"));
+        }
+        let mut trace = Trace::new(LittleEndian::read_u32(&header[5..]));
+        if header[4] >= 2 {
+            let mut isn = [0; 5];
+            r.read_exact(&mut isn)?;
+            if isn[0] != 0 {
+                trace.isn = Some(LittleEndian::read_u32(&isn[1..]));
+            }
+        }
+        loop {
+            let mut buf = [0; 9];
+            match r.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let ts = LittleEndian::read_u32(&buf[1..5]);
+            let len = LittleEndian::read_u32(&buf[5..]) as usize;
+            let mut data = || -> io::Result<Vec<u8>> {
+                let mut data = Vec::new();
+                r.by_ref().take(len as u64).read_to_end(&mut data)?;
+                if data.len() != len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated trace"));
+                }
+                Ok(data)
+            };
+            let event = match buf[0] {
+                TRACE_INPUT => TraceEvent::Input { ts: ts, data: data()? },
+                TRACE_UPDATE => TraceEvent::Update { ts: ts },
+                TRACE_FLUSH => TraceEvent::Flush { ts: ts },
+                TRACE_SEND => TraceEvent::Send { ts: ts, data: data()? },
+                TRACE_RECV => TraceEvent::Recv { ts: ts, len: len },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown trace event")),
+            };
+            trace.events.push(event);
+        }
+        Ok(trace)
+    }
+}
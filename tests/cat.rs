@@ -0,0 +1,60 @@
+#![cfg(feature = "config-file")]
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use kcp::KcpStream;
+use tokio_core::reactor::Core;
+use tokio_io::io::{read_to_end, shutdown, write_all};
+
+/// an address nobody listens on, for the binary to bind
+fn free_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// wait until someone holds `addr`
+fn wait_bound(addr: &SocketAddr) {
+    for _ in 0..100 {
+        if UdpSocket::bind(addr).is_err() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("nobody bound {}", addr);
+}
+
+#[test]
+fn listens_and_pipes_both_ways() {
+    let addr = free_addr();
+    let mut cat = Command::new(env!("CARGO_BIN_EXE_kcp-cat"))
+        .args(["-l", &addr.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    cat.stdin.take().unwrap().write_all(b"from cat").unwrap();
+    wait_bound(&addr);
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let session = KcpStream::connect(&addr, &handle)
+        .and_then(|stream| write_all(stream, *b"from test"))
+        .and_then(|(stream, _)| shutdown(stream))
+        .and_then(|stream| read_to_end(stream, Vec::new()));
+    let (_, received) = core.run(session).unwrap();
+    assert_eq!(received, b"from cat");
+
+    // the input closed, kcp-cat is done once the test end closed too
+    let output = cat.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"from test");
+}
@@ -0,0 +1,162 @@
+extern crate kcp;
+
+use kcp::cc::{Bbr, Classic, CongestionControl, Cubic, Ledbat, Window};
+use kcp::sim::Simulation;
+use kcp::{Congestion, KcpConfig, KcpStats};
+
+fn window(cwnd: u32) -> Window {
+    Window {
+        cwnd: cwnd,
+        ssthresh: 2,
+        incr: cwnd * 1376,
+        mss: 1376,
+    }
+}
+
+#[test]
+fn classic() {
+    let mut cc = Classic;
+    let mut w = window(10);
+    for _ in 0..100 {
+        cc.on_ack(&mut w, 1, 0, 20);
+    }
+    // about a segment per window of acks
+    assert!(w.cwnd >= 18 && w.cwnd <= 22, "cwnd {}", w.cwnd);
+
+    cc.on_fast_resend(&mut w, 16, 2);
+    assert_eq!((w.ssthresh, w.cwnd), (8, 10));
+    cc.on_timeout(&mut w, 10);
+    assert_eq!((w.ssthresh, w.cwnd), (5, 1));
+}
+
+#[test]
+fn cubic() {
+    let mut cc = Cubic::new();
+    let mut w = window(100);
+    cc.on_fast_resend(&mut w, 100, 2);
+    assert_eq!((w.ssthresh, w.cwnd), (70, 70));
+
+    // a full window acked every 200ms rtt, long enough for the cubic
+    // function to grow faster than reno would
+    let mut now = 0;
+    let mut at = |w: &mut Window, until: u32| {
+        while now < until {
+            let acked = w.cwnd;
+            cc.on_ack(w, acked, now, 200);
+            now += 200;
+        }
+        w.cwnd
+    };
+    // quickly most of the way back, then flat around the old window for
+    // a while (k = 4.2 secs) before probing beyond it
+    let early = at(&mut w, 1_000);
+    assert!(early > 85 && early < 100, "cwnd {}", early);
+    let plateau = at(&mut w, 4_000);
+    assert!(plateau >= 98 && plateau <= 102, "cwnd {}", plateau);
+    let beyond = at(&mut w, 8_000);
+    assert!(beyond > 110, "cwnd {}", beyond);
+}
+
+#[test]
+fn ledbat() {
+    let mut cc = Ledbat::with_target(50);
+    let mut w = window(20);
+    w.ssthresh = 100;
+    cc.on_rtt(&mut w, 40, 0);
+    // below the target the window grows
+    cc.on_rtt(&mut w, 50, 10);
+    for _ in 0..40 {
+        cc.on_ack(&mut w, 1, 10, 50);
+    }
+    assert!(w.cwnd > 20, "cwnd {}", w.cwnd);
+
+    // a queue of 100ms shrinks it before anything is lost, and ends
+    // slow start
+    cc.on_rtt(&mut w, 140, 20);
+    assert_eq!(cc.queueing_delay(), 100);
+    let before = w.cwnd;
+    for _ in 0..40 {
+        cc.on_ack(&mut w, 1, 20, 140);
+    }
+    assert!(w.cwnd < before, "cwnd {}", w.cwnd);
+    assert_eq!(w.ssthresh, w.cwnd);
+}
+
+#[test]
+fn bbr() {
+    let mut cc = Bbr::new();
+    let mut w = window(10);
+    w.ssthresh = u32::max_value();
+    cc.on_rtt(&mut w, 100, 0);
+    // startup while the bandwidth keeps growing
+    for (i, &rate) in [100_000u64, 200_000, 400_000].iter().enumerate() {
+        cc.on_delivery(&mut w, rate, i as u32 * 100);
+    }
+    assert!(!cc.filled());
+    assert_eq!(cc.pacing_rate(), None);
+    w.set_cwnd(200);
+    for i in 3..6 {
+        cc.on_delivery(&mut w, 400_000, i * 100);
+    }
+    assert!(cc.filled());
+    // two bdps of 400kB/s * 100ms
+    assert_eq!(w.cwnd, 2 * 40_000 / 1376);
+    assert_eq!(w.ssthresh, w.cwnd);
+    assert!(cc.pacing_rate().is_some());
+
+    // losses leave the window alone
+    let cwnd = w.cwnd;
+    cc.on_fast_resend(&mut w, cwnd, 2);
+    assert_eq!(w.cwnd, cwnd);
+    cc.on_timeout(&mut w, cwnd);
+    assert_eq!(w.cwnd, cwnd / 2);
+}
+
+#[test]
+fn cubic_transfer() {
+    transfer(Congestion::Cubic);
+}
+
+#[test]
+fn ledbat_transfer() {
+    transfer(Congestion::Ledbat);
+}
+
+#[test]
+fn bbr_transfer() {
+    // paced by the bandwidth it measured along the way
+    let stats = transfer(Congestion::Bbr);
+    assert!(stats.bandwidth > 0);
+}
+
+/// 500 messages from alice to bob over a lossy link, returns alice's stats
+fn transfer(congestion: Congestion) -> KcpStats {
+    let config = KcpConfig {
+        nc: false,
+        congestion: congestion,
+        ..KcpConfig::default()
+    };
+    let sim = Simulation::with_seed(5, 20, 40, 7);
+    let (mut alice, mut bob) = sim.pair(0x11223344);
+    config.apply(&mut alice);
+    config.apply(&mut bob);
+
+    let mut buf = [0; 1000];
+    let (mut queued, mut received) = (0u32, 0);
+    while received < 500 && sim.now() < 60_000 {
+        // a window's worth at a time, so the controller paces the transfer
+        // and not whatever cap the queues have
+        while queued < 500 && alice.waitsnd() < config.snd_wnd as usize {
+            alice.send(&[queued as u8; 1000]).unwrap();
+            queued += 1;
+        }
+        sim.step(&mut alice, &mut bob, 10);
+        while let Ok(n) = bob.recv(&mut buf) {
+            assert_eq!(n, 1000);
+            assert_eq!(buf[0], received as u8);
+            received += 1;
+        }
+    }
+    assert_eq!(received, 500);
+    alice.stats()
+}
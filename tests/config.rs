@@ -0,0 +1,73 @@
+#![cfg(feature = "config-file")]
+
+extern crate kcp;
+
+use kcp::{Congestion, KcpConfig, Keepalive, SlowStart};
+
+#[test]
+fn from_toml() {
+    let config = KcpConfig::from_toml(
+        r#"
+        snd_wnd = 256
+        nodelay = 1
+        congestion = "cubic"
+        slow_start = "hy-start"
+        keepalive = { every = 20000 }
+        "#,
+    ).unwrap();
+    assert_eq!(config.snd_wnd, 256);
+    assert_eq!(config.nodelay, 1);
+    assert_eq!(config.congestion, Congestion::Cubic);
+    assert_eq!(config.slow_start, SlowStart::HyStart);
+    assert_eq!(config.keepalive, Some(Keepalive::Every(20_000)));
+    // the rest keeps its defaults
+    assert_eq!(
+        config,
+        KcpConfig {
+            snd_wnd: 256,
+            nodelay: 1,
+            congestion: Congestion::Cubic,
+            slow_start: SlowStart::HyStart,
+            keepalive: Some(Keepalive::Every(20_000)),
+            ..KcpConfig::default()
+        }
+    );
+
+    assert!(KcpConfig::from_toml("snd_wnd = \"many\"").is_err());
+}
+
+#[test]
+fn toml_round_trip() {
+    let config = KcpConfig {
+        stream: true,
+        min_rto: Some(30),
+        max_message: 1 << 20,
+        keepalive: Some(Keepalive::Mobile),
+        ..KcpConfig::preset("fast").unwrap()
+    };
+    let toml = config.to_toml().unwrap();
+    assert_eq!(KcpConfig::from_toml(&toml).unwrap(), config);
+}
+
+#[test]
+fn keepalive_every_round_trip() {
+    let config = KcpConfig {
+        keepalive: Some(Keepalive::Every(20_000)),
+        ..KcpConfig::default()
+    };
+    let toml = config.to_toml().unwrap();
+    assert_eq!(KcpConfig::from_toml(&toml).unwrap(), config);
+}
+
+#[test]
+fn seed_stays_out_of_toml() {
+    let config = KcpConfig {
+        random_isn: true,
+        seed: Some(u64::max_value()),
+        ..KcpConfig::default()
+    };
+    let toml = config.to_toml().unwrap();
+    assert!(!toml.contains("seed"));
+    assert_eq!(KcpConfig::from_toml(&toml).unwrap().seed, None);
+    assert_eq!(KcpConfig::from_toml("seed = 7").unwrap().seed, None);
+}
@@ -0,0 +1,235 @@
+//! Differential tests running `Kcb` and the reference ikcp.c against the
+//! same traces, asserting they put identical segments on the wire. Both
+//! implementations are fed the datagrams `Kcb` produced.
+//!
+//! Intentional differences are folded out of the reference output before
+//! comparing: `Kcb` sends one ack per sn and flush.
+//!
+//!     IKCP_DIR=/path/to/kcp cargo test --features ikcp-conformance
+#![cfg(feature = "ikcp-conformance")]
+
+extern crate bytes;
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use bytes::{ByteOrder, LittleEndian};
+use kcp::Kcb;
+use kcp::ikcp::Ikcp;
+
+#[derive(Clone)]
+struct Pipe {
+    packets: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe { packets: Rc::new(RefCell::new(VecDeque::new())) }
+    }
+
+    fn pop(&self) -> Option<Vec<u8>> {
+        self.packets.borrow_mut().pop_front()
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets.borrow_mut().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const OVERHEAD: usize = 24;
+const CMD_ACK: u8 = 82;
+
+/// split datagrams into their segments
+fn segments(datagrams: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut segs = Vec::new();
+    for pkt in datagrams {
+        let mut rest = &pkt[..];
+        while rest.len() >= OVERHEAD {
+            let len = LittleEndian::read_u32(&rest[20..24]) as usize;
+            segs.push(rest[..OVERHEAD + len].to_vec());
+            rest = &rest[OVERHEAD + len..];
+        }
+    }
+    segs
+}
+
+/// keep only the first ack of every sn, carrying the latest timestamp
+fn dedup_acks(segs: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut out: Vec<Vec<u8>> = Vec::new();
+    for seg in segs {
+        if seg[4] == CMD_ACK {
+            let sn = &seg[12..16];
+            if let Some(ack) = out.iter_mut().find(|s| s[4] == CMD_ACK && &s[12..16] == sn) {
+                let ts = LittleEndian::read_u32(&seg[8..12]);
+                if (ts.wrapping_sub(LittleEndian::read_u32(&ack[8..12])) as i32) > 0 {
+                    ack[8..12].copy_from_slice(&seg[8..12]);
+                }
+                continue;
+            }
+        }
+        out.push(seg);
+    }
+    out
+}
+
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+}
+
+/// one end of the conversation in both implementations
+struct Peer {
+    rust: Kcb<Pipe>,
+    out: Pipe,
+    c: Ikcp,
+}
+
+impl Peer {
+    fn new(conv: u32, nodelay: i32, interval: i32, resend: i32, nc: bool) -> Peer {
+        let out = Pipe::new();
+        let mut rust = Kcb::new(conv, out.clone());
+        let mut c = Ikcp::new(conv);
+        rust.wndsize(128, 128);
+        c.wndsize(128, 128);
+        rust.nodelay(nodelay, interval, resend, nc);
+        c.nodelay(nodelay, interval, resend, nc);
+        Peer {
+            rust: rust,
+            out: out,
+            c: c,
+        }
+    }
+
+    fn send(&mut self, msg: &[u8]) {
+        let r = self.rust.send(msg).is_ok();
+        let c = self.c.send(msg).is_ok();
+        assert_eq!(r, c, "send of {} bytes", msg.len());
+    }
+
+    fn update(&mut self, now: u32) {
+        self.rust.update(now);
+        self.c.update(now);
+    }
+
+    fn input(&mut self, pkt: &[u8]) {
+        let r = self.rust.input(pkt).is_ok();
+        let c = self.c.input(pkt).is_ok();
+        assert_eq!(r, c, "input of {} bytes", pkt.len());
+    }
+
+    /// datagrams emitted by `Kcb`, whose segments have to match the ones
+    /// of the reference
+    fn output(&mut self, now: u32) -> Vec<Vec<u8>> {
+        let mut rust = Vec::new();
+        while let Some(pkt) = self.out.pop() {
+            rust.push(pkt);
+        }
+        let mut c = Vec::new();
+        while let Some(pkt) = self.c.pop_output() {
+            c.push(pkt);
+        }
+        assert_eq!(segments(&rust), dedup_acks(segments(&c)), "output diverged at {}ms", now);
+        rust
+    }
+
+    fn recv(&mut self, now: u32) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        let mut buf = vec![0; 1 << 16];
+        let mut cbuf = vec![0; 1 << 16];
+        loop {
+            match (self.rust.recv(&mut buf), self.c.recv(&mut cbuf)) {
+                (Ok(r), Ok(c)) => {
+                    assert_eq!(&buf[..r], &cbuf[..c], "recv diverged at {}ms", now);
+                    msgs.push(buf[..r].to_vec());
+                }
+                (Err(_), Err(_)) => return msgs,
+                (r, c) => panic!("recv diverged at {}ms: {:?} vs {:?}", now, r, c),
+            }
+        }
+    }
+}
+
+/// run both implementations through a lossy, delayed exchange for
+/// `duration` millisec, bob echoing everything back to alice
+fn run(nodelay: i32, interval: i32, resend: i32, nc: bool, loss: u32, duration: u32) {
+    let mut rng = Random(0x9e37_79b9_7f4a_7c15);
+    let mut alice = Peer::new(0x11223344, nodelay, interval, resend, nc);
+    let mut bob = Peer::new(0x11223344, nodelay, interval, resend, nc);
+    let mut a2b: VecDeque<(u32, Vec<u8>)> = VecDeque::new();
+    let mut b2a: VecDeque<(u32, Vec<u8>)> = VecDeque::new();
+    let mut sent = 0;
+    let mut echoed = 0;
+
+    for now in 1..duration {
+        if rng.next() % 20 == 0 {
+            let len = 1 + rng.next() as usize % 4000;
+            let msg = (0..len).map(|i| (i + sent) as u8).collect::<Vec<_>>();
+            alice.send(&msg);
+            sent += 1;
+        }
+
+        alice.update(now);
+        bob.update(now);
+
+        for pkt in alice.output(now) {
+            if rng.next() % 100 >= loss {
+                a2b.push_back((now + 20 + rng.next() % 40, pkt));
+            }
+        }
+        for pkt in bob.output(now) {
+            if rng.next() % 100 >= loss {
+                b2a.push_back((now + 20 + rng.next() % 40, pkt));
+            }
+        }
+        while a2b.front().map_or(false, |&(ts, _)| ts <= now) {
+            let (_, pkt) = a2b.pop_front().unwrap();
+            bob.input(&pkt);
+        }
+        while b2a.front().map_or(false, |&(ts, _)| ts <= now) {
+            let (_, pkt) = b2a.pop_front().unwrap();
+            alice.input(&pkt);
+        }
+
+        for msg in bob.recv(now) {
+            bob.send(&msg);
+        }
+        echoed += alice.recv(now).len();
+    }
+    assert!(echoed > 0, "nothing made it through");
+}
+
+#[test]
+fn conformance_default() {
+    run(0, 10, 0, false, 10, 60_000);
+}
+
+#[test]
+fn conformance_normal() {
+    run(0, 10, 0, true, 10, 60_000);
+}
+
+#[test]
+fn conformance_fast() {
+    run(1, 10, 2, true, 10, 60_000);
+}
+
+#[test]
+fn conformance_heavy_loss() {
+    run(1, 10, 2, true, 40, 60_000);
+}
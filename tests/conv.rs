@@ -0,0 +1,30 @@
+extern crate kcp;
+
+use kcp::{ConvAllocator, RandomConv, SequentialConv};
+
+#[test]
+fn sequential_conv() {
+    let peer = "127.0.0.1:4000".parse().unwrap();
+    let mut convs = SequentialConv::new(10, 13);
+    let in_use = |conv| conv == 11;
+    assert_eq!(convs.allocate(&peer, &in_use), Some(10));
+    assert_eq!(convs.allocate(&peer, &in_use), Some(12));
+    assert_eq!(convs.allocate(&peer, &in_use), Some(13));
+    assert_eq!(convs.allocate(&peer, &in_use), Some(10));
+    assert!(convs.owns(13));
+    assert!(!convs.owns(14));
+
+    let full = |_| true;
+    assert_eq!(convs.allocate(&peer, &full), None);
+}
+
+#[test]
+fn random_conv_range() {
+    let peer = "127.0.0.1:4000".parse().unwrap();
+    let mut convs = RandomConv::with_range(100, 199);
+    for _ in 0..1000 {
+        let conv = convs.allocate(&peer, &|_| false).unwrap();
+        assert!(conv >= 100 && conv <= 199);
+    }
+    assert!(!convs.owns(99));
+}
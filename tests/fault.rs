@@ -0,0 +1,98 @@
+#![cfg(feature = "fault-injection")]
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use kcp::{Kcb, OutputErrorPolicy};
+
+#[derive(Clone, Default)]
+struct Pipe {
+    packets: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Pipe {
+    fn pop(&self) -> Option<Vec<u8>> {
+        self.packets.borrow_mut().pop_front()
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets.borrow_mut().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn drop_and_corrupt() {
+    let a2b = Pipe::default();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::default());
+    alice.nodelay(1, 10, 0, true);
+    alice.update(0);
+
+    alice.faults().drop_outputs(1);
+    alice.send(b"lost").unwrap();
+    alice.flush();
+    assert!(a2b.pop().is_none());
+
+    alice.faults().corrupt_headers(1);
+    alice.send(b"garbled").unwrap();
+    alice.flush();
+    let garbled = a2b.pop().unwrap();
+    assert_eq!(bob.input(&garbled).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(alice.faults().injected(), 2);
+
+    // both come through once retransmitted
+    alice.update(1000);
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    let mut buf = [0; 16];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"lost");
+    assert_eq!(bob.recv(&mut buf).unwrap(), 7);
+    assert_eq!(&buf[..7], b"garbled");
+}
+
+#[test]
+fn fail_output() {
+    let pipe = Pipe::default();
+    let mut kcb = Kcb::new(0x11223344, pipe.clone());
+    kcb.set_output_error_policy(OutputErrorPolicy::Fail);
+    kcb.update(0);
+
+    kcb.faults().fail_output(Some(io::ErrorKind::ConnectionRefused));
+    kcb.send(b"hello").unwrap();
+    kcb.flush();
+    assert!(pipe.pop().is_none());
+    assert_eq!(kcb.output_error().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+    assert!(kcb.send(b"again").is_err());
+}
+
+#[test]
+fn freeze_clock() {
+    let pipe = Pipe::default();
+    let mut kcb = Kcb::new(0x11223344, pipe.clone());
+    kcb.nodelay(1, 10, 0, true);
+    kcb.update(0);
+    kcb.send(b"hello").unwrap();
+    kcb.update(10);
+    assert!(pipe.pop().is_some());
+
+    // no retransmit while the clock stands still
+    kcb.faults().freeze_clock(true);
+    kcb.update(5000);
+    assert!(pipe.pop().is_none());
+
+    kcb.faults().freeze_clock(false);
+    kcb.update(5000);
+    assert!(pipe.pop().is_some());
+}
@@ -0,0 +1,137 @@
+#![cfg(feature = "fec")]
+extern crate kcp;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use kcp::fec::{FecDecoder, FecEncoder};
+use kcp::{Kcb, KcpConfig, KcpListener};
+use tokio_core::reactor::Core;
+
+#[derive(Clone, Default)]
+struct Pipe {
+    packets: Rc<RefCell<VecDeque<Vec<u8>>>>,
+}
+
+impl Pipe {
+    fn drain(&self) -> Vec<Vec<u8>> {
+        self.packets.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets.borrow_mut().push_back(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn restore() {
+    let pipe = Pipe::default();
+    let mut encoder = FecEncoder::new(pipe.clone(), 4, 2).unwrap();
+    let sent: Vec<Vec<u8>> = (0..4).map(|i| vec![i as u8; 10 + i * 7]).collect();
+    for datagram in &sent {
+        encoder.write(datagram).unwrap();
+    }
+    assert_eq!(encoder.parity_sent(), 2);
+    let wire = pipe.drain();
+    assert_eq!(wire.len(), 6);
+
+    // two of the data shards are lost, the parity makes up for them
+    let mut decoder = FecDecoder::new(4, 2).unwrap();
+    let mut received = Vec::new();
+    for (i, datagram) in wire.iter().enumerate() {
+        if i == 1 || i == 2 {
+            continue;
+        }
+        decoder.decode(datagram, |d| received.push(d.to_vec())).unwrap();
+    }
+    assert_eq!(decoder.recovered(), 2);
+    received.sort();
+    assert_eq!(received, sent);
+
+    assert!(decoder.decode(&[0; 3], |_| {}).is_err());
+    assert!(FecDecoder::new(0, 2).is_err());
+}
+
+#[test]
+fn unequal_shards_once_each() {
+    let pipe = Pipe::default();
+    let mut encoder = FecEncoder::new(pipe.clone(), 3, 2).unwrap();
+    let sent = vec![vec![1; 40], vec![2; 5], vec![3; 17]];
+    for datagram in &sent {
+        encoder.write(datagram).unwrap();
+    }
+    let wire = pipe.drain();
+    assert_eq!(wire.len(), 5);
+
+    let mut decoder = FecDecoder::new(3, 2).unwrap();
+    let mut received = Vec::new();
+    // the short shard is lost and restored padded to the longest one
+    for &i in &[0, 0, 2, 3, 4] {
+        decoder.decode(&wire[i], |d| received.push(d.to_vec())).unwrap();
+    }
+    assert_eq!(decoder.recovered(), 1);
+    // turning up late once restored
+    decoder.decode(&wire[1], |d| received.push(d.to_vec())).unwrap();
+    received.sort();
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn no_retransmit() {
+    let pipe = Pipe::default();
+    let mut alice = Kcb::new(0x11223344, FecEncoder::new(pipe.clone(), 4, 2).unwrap());
+    let mut bob = Kcb::new(0x11223344, Pipe::default());
+    alice.nodelay(1, 10, 0, true);
+    alice.update(0);
+    bob.update(0);
+
+    // a segment per datagram, 8 of them in two groups
+    for i in 0..8 {
+        alice.send(&[i; 1000]).unwrap();
+    }
+    alice.flush();
+    let wire = pipe.drain();
+    assert_eq!(wire.len(), 12);
+
+    let mut decoder = FecDecoder::new(4, 2).unwrap();
+    for (i, datagram) in wire.iter().enumerate() {
+        if i % 6 == 0 || i == 9 {
+            continue;
+        }
+        decoder
+            .decode(datagram, |d| {
+                bob.input(d).unwrap();
+            })
+            .unwrap();
+    }
+    assert_eq!(decoder.recovered(), 3);
+    let mut buf = [0; 1000];
+    for i in 0..8 {
+        assert_eq!(bob.recv(&mut buf).unwrap(), 1000);
+        assert_eq!(buf[0], i);
+    }
+}
+
+#[test]
+fn tokio_core_refuses_fec() {
+    let core = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let config = KcpConfig {
+        fec: Some((4, 2)),
+        ..KcpConfig::default()
+    };
+    // the sessions there would send without it
+    let e = KcpListener::bind_with_config(&addr, config, &core.handle()).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
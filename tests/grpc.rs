@@ -0,0 +1,41 @@
+#![cfg(feature = "grpc")]
+extern crate kcp;
+extern crate tokio1;
+extern crate tonic_health;
+
+use std::net::SocketAddr;
+
+use kcp::grpc::{self, tonic, Connector};
+use kcp::tokio::KcpListener;
+use kcp::KcpConfig;
+use tokio1::runtime::Builder;
+use tonic::transport::{Endpoint, Server};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::HealthCheckRequest;
+
+#[test]
+fn health_check() {
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+    let _guard = rt.enter();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let config = KcpConfig {
+        stream: true,
+        ..KcpConfig::default()
+    };
+    let listener = KcpListener::bind_with_config(&addr, &config).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_, health) = tonic_health::server::health_reporter();
+    let server = Server::builder().add_service(health).serve_with_incoming(grpc::incoming(listener));
+    rt.spawn(server);
+
+    let endpoint = Endpoint::from_shared(format!("http://{}", addr)).unwrap();
+    let channel = rt.block_on(endpoint.connect_with_connector(Connector::new())).unwrap();
+    let mut client = HealthClient::new(channel);
+    // both calls over the one session
+    for _ in 0..2 {
+        let request = HealthCheckRequest { service: String::new() };
+        let response = rt.block_on(client.check(request)).unwrap();
+        assert_eq!(response.into_inner().status, ServingStatus::Serving as i32);
+    }
+}
@@ -0,0 +1,62 @@
+#![cfg(feature = "http")]
+extern crate kcp;
+extern crate tokio1;
+
+use std::net::SocketAddr;
+
+use kcp::http::{self, hyper, Connector};
+use kcp::tokio::KcpListener;
+use kcp::KcpConfig;
+use tokio1::runtime::{Builder, Runtime};
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+/// a server answering with the path and version of the request
+fn server(rt: &Runtime) -> SocketAddr {
+    let _guard = rt.enter();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let config = KcpConfig {
+        stream: true,
+        ..KcpConfig::default()
+    };
+    let listener = KcpListener::bind_with_config(&addr, &config).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = http::serve(listener, |_| {
+        hyper::service::service_fn(|req: hyper::Request<hyper::Body>| {
+            let body = format!("{:?} {}", req.version(), req.uri().path());
+            std::future::ready(Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(body))))
+        })
+    });
+    rt.spawn(server);
+    addr
+}
+
+fn get(rt: &Runtime, client: &hyper::Client<Connector>, uri: hyper::Uri) -> String {
+    let response = rt.block_on(client.get(uri)).unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    let body = rt.block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[test]
+fn http1() {
+    let rt = runtime();
+    let addr = server(&rt);
+    let client = hyper::Client::builder().build(Connector::new());
+    let uri = format!("http://{}/kcp", addr).parse().unwrap();
+    assert_eq!(get(&rt, &client, uri), "HTTP/1.1 /kcp");
+}
+
+#[test]
+fn http2() {
+    let rt = runtime();
+    let addr = server(&rt);
+    let client = hyper::Client::builder().http2_only(true).build(Connector::new());
+    // both requests over the one session
+    for path in &["/one", "/two"] {
+        let uri = format!("http://{}{}", addr, path).parse().unwrap();
+        assert_eq!(get(&rt, &client, uri), format!("HTTP/2.0 {}", path));
+    }
+}
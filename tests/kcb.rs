@@ -1,132 +1,2085 @@
 extern crate bytes;
+extern crate futures;
 extern crate kcp;
-extern crate rand;
-extern crate time as ctime;
 
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::io::{self, Read, Write};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IoSlice, Write};
 use std::iter::Iterator;
 use std::rc::Rc;
-use std::thread;
-use std::time;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bytes::{ByteOrder, LittleEndian};
-use kcp::Kcb;
+use bytes::{ByteOrder, Bytes, LittleEndian};
+use futures::Async;
+use kcp::{Direction, FailoverAlarm, Kcb, KcpConfig, KcpSession, Keepalive, OutputErrorPolicy, ParseMode, ShortBuffer,
+          SegmentOutput, SlowStart, Trace, reset_frame};
+use kcp::cc::{CongestionControl, Window};
+use kcp::session::Context;
+use kcp::sim::Simulation;
 
-#[inline]
-fn clock() -> u32 {
-    let timespec = ctime::get_time();
-    let mills = timespec.sec * 1000 + timespec.nsec as i64 / 1000 / 1000;
-    mills as u32
+#[derive(Clone)]
+struct Pipe {
+    packets: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    error: Rc<Cell<Option<io::ErrorKind>>>,
 }
 
-#[derive(Default)]
-struct DelayPacket {
-    data: Vec<u8>,
-    ts: u32,
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            packets: Rc::new(RefCell::new(VecDeque::new())),
+            error: Rc::new(Cell::new(None)),
+        }
+    }
+
+    fn pop(&self) -> Option<Vec<u8>> {
+        self.packets.borrow_mut().pop_front()
+    }
+
+    /// refuse every write with `error` until it is reset to `None`
+    fn fail(&self, error: Option<io::ErrorKind>) {
+        self.error.set(error);
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(kind) = self.error.get() {
+            return Err(io::Error::new(kind, "pipe down"));
+        }
+        if !buf.is_empty() {
+            self.packets.borrow_mut().push_back(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    /// all of `bufs` as a single packet
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let packet = bufs.iter().flat_map(|buf| buf.iter().cloned()).collect::<Vec<u8>>();
+        self.write(&packet)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn packing() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    alice.nodelay(1, 10, 0, true);
+
+    // small segments fill a datagram up to the mtu
+    for _ in 0..3 {
+        alice.send(&[0; 100]).unwrap();
+    }
+    alice.update(100);
+    assert_eq!(pipe.pop().unwrap().len(), 3 * (24 + 100));
+    assert!(pipe.pop().is_none());
+}
+
+#[test]
+fn unordered_delivery() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+
+    // flushed apart, together they would share one datagram
+    alice.send(b"first").unwrap();
+    alice.update(100);
+    alice.send_unordered(b"second").unwrap();
+    alice.update(200);
+
+    // lose the ordered message, the unordered one must still get through
+    pipe.pop().unwrap();
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    bob.update(100);
+
+    let mut buf = [0; 16];
+    let n = bob.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"second");
+    assert!(bob.recv(&mut buf).is_err());
+}
+
+#[test]
+fn unordered_delivery_window() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    bob.wndsize(32, 4);
+
+    // two messages bob leaves unread, then a lost one ahead of three
+    // unordered ones with room for only two of them
+    alice.send(b"a").unwrap();
+    alice.send(b"b").unwrap();
+    alice.update(100);
+    alice.send(b"lost").unwrap();
+    alice.update(200);
+    for _ in 0..3 {
+        alice.send_unordered(b"u").unwrap();
+    }
+    alice.update(300);
+
+    bob.input(&pipe.pop().unwrap()).unwrap();
+    pipe.pop().unwrap();
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+
+    let mut buf = [0; 16];
+    let mut read = 0;
+    while bob.recv(&mut buf).is_ok() {
+        read += 1;
+    }
+    assert_eq!(read, 4);
+}
+
+#[test]
+fn clock_skew() {
+    let to_bob = Pipe::new();
+    let to_alice = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, to_bob.clone());
+    let mut bob = Kcb::new(0x11223344, to_alice.clone());
+    alice.nodelay(1, 10, 0, true);
+    assert_eq!(bob.clock_offset(), None);
+
+    // alice's clock is 5 s ahead and gains 1% on bob's
+    for t in (100..5000).step_by(100) {
+        alice.send(b"tick").unwrap();
+        alice.update(5000 + t * 101 / 100);
+        bob.update(t);
+        while let Some(pkt) = to_bob.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        bob.update(t);
+        while let Some(pkt) = to_alice.pop() {
+            alice.input(&pkt).unwrap();
+        }
+        if t == 100 {
+            assert_eq!(bob.clock_offset(), Some(5001));
+            assert_eq!(bob.clock_drift(), None);
+        }
+    }
+    let offset = bob.clock_offset().unwrap();
+    assert!(offset > 5001 && offset <= 5049, "offset {}", offset);
+    // the smoothed offset trails the gain a little
+    let drift = bob.clock_drift().unwrap();
+    assert!(drift > 5_000 && drift <= 10_000, "drift {}", drift);
+}
+
+#[test]
+fn instant_clock() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let start = Instant::now();
+    alice.update_at(start);
+    alice.send(b"hi").unwrap();
+    let next = alice.check_at(start);
+    assert!(next > start && next <= start + Duration::from_millis(100));
+
+    // timestamps count from the creation of the block
+    alice.update_at(next);
+    let ts = LittleEndian::read_u32(&pipe.pop().unwrap()[8..12]);
+    assert!(ts >= 100 && ts < 200, "ts {}", ts);
+
+    // or from wherever the caller starts counting
+    let pipe = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, pipe.clone());
+    bob.update_with(Duration::from_millis(250));
+    bob.send(b"hi").unwrap();
+    bob.update_with(Duration::from_millis(350));
+    assert_eq!(LittleEndian::read_u32(&pipe.pop().unwrap()[8..12]), 350);
+}
+
+/// the push segment of `datagram` as (ts, una, payload)
+fn push_segment(datagram: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    let mut rest = datagram;
+    while rest.len() >= 24 {
+        let len = LittleEndian::read_u32(&rest[20..24]) as usize;
+        if rest[4] == 81 {
+            let ts = LittleEndian::read_u32(&rest[8..12]);
+            let una = LittleEndian::read_u32(&rest[16..20]);
+            return Some((ts, una, rest[24..24 + len].to_vec()));
+        }
+        rest = &rest[24 + len..];
+    }
+    None
+}
+
+#[test]
+fn retransmit_refreshes_header() {
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    for kcb in [&mut alice, &mut bob].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+    }
+    alice.send(b"hello").unwrap();
+    alice.update(100);
+    let first = push_segment(&a2b.pop().unwrap()).unwrap();
+    assert_eq!(first, (100, 0, b"hello".to_vec()));
+
+    // lost, and meanwhile alice took a message of bob
+    bob.send(b"x").unwrap();
+    bob.update(100);
+    alice.input(&b2a.pop().unwrap()).unwrap();
+    let mut resent = None;
+    for now in 11..100 {
+        alice.update(now * 10);
+        if let Some(pkt) = a2b.pop() {
+            resent = push_segment(&pkt).map(|segment| (now * 10, segment));
+            if resent.is_some() {
+                break;
+            }
+        }
+    }
+    // the cached segment goes out with the time and una of the resend
+    let (now, (ts, una, data)) = resent.unwrap();
+    assert_eq!((ts, una, &data[..]), (now, 1, &b"hello"[..]));
+}
+
+#[test]
+fn drain_messages() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    alice.send(b"one").unwrap();
+    alice.send(b"two").unwrap();
+    alice.send(b"three").unwrap();
+    alice.update(100);
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+
+    let msgs = bob.drain_messages().collect::<Vec<_>>();
+    assert_eq!(msgs, vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+    assert_eq!(bob.drain_messages().count(), 0);
+}
+
+#[test]
+fn recv_many() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    for msg in &[&b"one"[..], b"two", b"three"] {
+        alice.send(msg).unwrap();
+    }
+    alice.update(100);
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+
+    let msgs = bob.recv_many(2).unwrap();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(&msgs[0][..], b"one");
+    assert_eq!(&msgs[1][..], b"two");
+    let msgs = bob.recv_many(16).unwrap();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(&msgs[0][..], b"three");
+    assert_eq!(bob.recv_many(16).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn send_many() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    alice.update(100);
+
+    let msgs = vec![Bytes::from_static(b"one"), Bytes::from_static(b"two")];
+    assert_eq!(alice.send_many(msgs, false).unwrap(), 6);
+    assert!(pipe.pop().is_none());
+    let msgs = vec![Bytes::from_static(b"three")];
+    assert_eq!(alice.send_many(msgs, true).unwrap(), 5);
+    // everything queued went out packed into one datagram
+    bob.input(&pipe.pop().unwrap()).unwrap();
+    assert!(pipe.pop().is_none());
+    let msgs = bob.recv_many(16).unwrap();
+    assert_eq!(msgs.len(), 3);
+    assert_eq!(&msgs[2][..], b"three");
+}
+
+#[test]
+fn send_many_partial() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    alice.set_stream(true);
+    alice.set_send_backlog(2);
+
+    // the second message is cut short, nothing after it is taken
+    let msgs = vec![
+        Bytes::from(vec![1; 1376]),
+        Bytes::from(vec![2; 2000]),
+        Bytes::from_static(b"three"),
+    ];
+    assert_eq!(alice.send_many(msgs, false).unwrap(), 2 * 1376);
+    assert_eq!(alice.waitsnd_bytes(), 2 * 1376);
+
+    // a full queue after the first message ends the batch too
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    bob.set_stream(true);
+    bob.set_send_backlog(1);
+    let msgs = vec![Bytes::from(vec![1; 1376]), Bytes::from_static(b"two")];
+    assert_eq!(bob.send_many(msgs, false).unwrap(), 1376);
+    let err = bob.send_many(vec![Bytes::from_static(b"two")], false).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn queue_sizes() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    alice.send(&[1; 2000]).unwrap();
+    alice.send(b"tail").unwrap();
+    assert_eq!(alice.waitsnd(), 3);
+    assert_eq!(alice.inflight(), 0);
+    assert_eq!(alice.waitsnd_bytes(), 2004);
+    alice.update(100);
+    assert_eq!(alice.inflight(), 3);
+    assert_eq!(alice.waitsnd_bytes(), 2004);
+
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    assert_eq!(bob.waitrcv(), 2);
+    assert_eq!(bob.waitrcv_bytes(), 2004);
+    let mut buf = [0; 2000];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 2000);
+    assert_eq!(bob.waitrcv(), 1);
+    assert_eq!(bob.waitrcv_bytes(), 4);
+}
+
+#[test]
+fn memory_budget() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    alice.set_memory_budget(2500);
+    bob.set_memory_budget(1500);
+
+    alice.send(&[1; 1000]).unwrap();
+    alice.send(&[2; 1000]).unwrap();
+    assert!(alice.writable());
+    alice.send(&[3; 1000]).unwrap();
+    assert!(!alice.writable());
+    assert_eq!(alice.memory(), 3000);
+    assert_eq!(alice.stats().memory, 3000);
+
+    alice.update(100);
+    let first = pipe.pop().unwrap();
+    // ahead of the gap, the second message fits and the third does not
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    assert_eq!(bob.waitrcv_bytes(), 1000);
+    assert_eq!(bob.stats().reassembly_dropped, 1);
+    // the segment filling the gap is taken over budget
+    bob.input(&first).unwrap();
+    assert_eq!(bob.memory(), 2000);
+    // unread data does not hold back bob's own writes
+    assert!(bob.writable());
+    let mut buf = [0; 1000];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 1000);
+    assert_eq!(buf[0], 1);
+}
+
+#[test]
+fn session_polling() {
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let start = Instant::now();
+    let mut alice = KcpSession::new(Kcb::new(0x11223344, a2b.clone()), start);
+    let mut bob = KcpSession::new(Kcb::new(0x11223344, b2a.clone()), start);
+    alice.get_mut().nodelay(1, 10, 0, true);
+    alice.get_mut().set_send_backlog(2);
+
+    let cx = Context::new(start);
+    assert_eq!(alice.poll_send(&cx, b"one").unwrap(), Async::Ready(3));
+    assert_eq!(alice.poll_send(&cx, b"two").unwrap(), Async::Ready(3));
+    assert!(alice.poll_send(&cx, b"three").unwrap().is_not_ready());
+    let mut buf = [0; 16];
+    assert!(bob.poll_recv(&cx, &mut buf).unwrap().is_not_ready());
+
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&cx, &pkt).unwrap();
+    }
+    assert_eq!(bob.poll_recv(&cx, &mut buf).unwrap(), Async::Ready(3));
+    assert_eq!(&buf[..3], b"one");
+    assert_eq!(bob.poll_recv(&cx, &mut buf).unwrap(), Async::Ready(3));
+    while let Some(pkt) = b2a.pop() {
+        alice.input(&cx, &pkt).unwrap();
+    }
+    assert_eq!(alice.get_ref().waitsnd(), 0);
+    assert_eq!(alice.poll_send(&cx, b"three").unwrap(), Async::Ready(5));
+
+    // nothing is due before the deadline
+    assert!(alice.poll_timeout(&cx).unwrap().is_not_ready());
+    assert!(alice.deadline() > start);
+}
+
+#[test]
+fn state_dump() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    for _ in 0..5 {
+        alice.send(b"secret").unwrap();
+    }
+    alice.update(100);
+    // lose sn 2
+    let pkt = pipe.pop().unwrap();
+    let mut off = 0;
+    while off < pkt.len() {
+        let len = LittleEndian::read_u32(&pkt[off + 20..]) as usize;
+        if LittleEndian::read_u32(&pkt[off + 12..]) != 2 {
+            bob.input(&pkt[off..off + 24 + len]).unwrap();
+        }
+        off += 24 + len;
+    }
+
+    let dump = bob.dump();
+    assert!(dump.contains("rcv_buf: [3-4]"), "{}", dump);
+    assert!(dump.contains("rcv_queue: [0-1]"), "{}", dump);
+    assert!(dump.contains("acklist: [0-1, 3-4]"), "{}", dump);
+    let dump = alice.dump();
+    assert!(dump.contains("snd_buf: [0-4]"), "{}", dump);
+    assert!(dump.contains("sn 4 frg 0 len 6 xmit 1"), "{}", dump);
+    assert!(!dump.contains("secret"));
+}
+
+#[test]
+fn segment_tap() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = sent.clone();
+    alice.set_segment_tap(move |dir, ts, header| log.lock().unwrap().push((dir, ts, *header)));
+    let log = received.clone();
+    bob.set_segment_tap(move |dir, ts, header| log.lock().unwrap().push((dir, ts, *header)));
+
+    alice.send(b"one").unwrap();
+    alice.send(b"three").unwrap();
+    alice.update(100);
+    bob.update(150);
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].0, Direction::Outgoing);
+    assert_eq!(sent[0].1, 100);
+    assert_eq!((sent[0].2.sn, sent[0].2.len), (0, 3));
+    assert_eq!((sent[1].2.sn, sent[1].2.len), (1, 5));
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[1].0, Direction::Incoming);
+    assert_eq!(received[1].1, 150);
+    assert_eq!(received[1].2, sent[1].2);
+}
+
+#[test]
+fn duplicate_acks() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let acks = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, acks.clone());
+    alice.nodelay(1, 10, 0, true);
+
+    alice.send(b"hello").unwrap();
+    alice.update(100);
+    let pkt = pipe.pop().unwrap();
+    bob.input(&pkt).unwrap();
+    bob.input(&pkt).unwrap();
+    bob.update(100);
+
+    // a single 24 byte ack for both copies
+    assert_eq!(acks.pop().unwrap().len(), 24);
+    assert!(acks.pop().is_none());
+}
+
+#[test]
+fn ack_ranges() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    for kcb in [&mut alice, &mut bob].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+        kcb.wndsize(128, 128);
+        kcb.set_ack_ranges(true);
+    }
+
+    // both ends announce the extension with a window update
+    for now in [0, 10].iter() {
+        alice.update(*now);
+        bob.update(*now);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+    }
+
+    for _ in 0..32 {
+        alice.send(&[0; 1000]).unwrap();
+    }
+    alice.update(20);
+    a2b.pop().unwrap();
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    bob.update(20);
+
+    // sn 1 to 31 acked by a single range segment
+    let pkt = b2a.pop().unwrap();
+    assert!(b2a.pop().is_none());
+    assert_eq!(pkt.len(), 28);
+    assert_eq!(pkt[4], 86);
+    alice.input(&pkt).unwrap();
+    assert_eq!(alice.waitsnd(), 1);
 }
 
-struct LatencySimulator {
-    tx: u32,
-    current: u32,
-    lost_rate: u32,
-    rtt_min: u32,
-    rtt_max: u32,
-    nmax: u32,
-    delay_tunnel: VecDeque<DelayPacket>,
-    rng: Random,
+#[test]
+fn compact_headers() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    for kcb in [&mut alice, &mut bob].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+        kcb.set_compact_headers(true);
+    }
+
+    // both ends announce the extension with a window update
+    for now in [0, 10].iter() {
+        alice.update(*now);
+        bob.update(*now);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+    }
+
+    for i in 0..8u8 {
+        alice.send(&[i; 16]).unwrap();
+    }
+    alice.update(20);
+
+    // 8 segments behind an 11 byte header, 14 bytes each instead of 24
+    let pkt = a2b.pop().unwrap();
+    assert!(a2b.pop().is_none());
+    assert_eq!(pkt[4], 89);
+    assert_eq!(pkt.len(), 11 + 8 * (14 + 16));
+    bob.input(&pkt).unwrap();
+    let mut buf = [0; 16];
+    for i in 0..8u8 {
+        assert_eq!(bob.recv(&mut buf).unwrap(), 16);
+        assert_eq!(buf, [i; 16]);
+    }
+
+    // the acks come back compacted as well
+    bob.update(20);
+    let pkt = b2a.pop().unwrap();
+    assert_eq!(pkt[4], 89);
+    alice.input(&pkt).unwrap();
+    assert_eq!(alice.waitsnd(), 0);
+}
+
+#[test]
+fn ack_delay() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    bob.set_ack_delay(20);
+    alice.update(0);
+    bob.update(0);
+
+    alice.send(b"request").unwrap();
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    bob.flush();
+    assert!(b2a.pop().is_none());
+    assert_eq!(bob.check(0), 10);
+
+    // the response carries the ack in the same datagram
+    let mut buf = [0; 16];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 7);
+    bob.send(b"response").unwrap();
+    bob.update(10);
+    let pkt = b2a.pop().unwrap();
+    assert!(b2a.pop().is_none());
+    assert_eq!(pkt.len(), 24 + 24 + 8);
+    alice.input(&pkt).unwrap();
+    assert_eq!(alice.waitsnd(), 0);
+
+    // without a response the ack goes out once the delay is up
+    alice.update(10);
+    a2b.pop().unwrap();
+    alice.send(b"again").unwrap();
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    bob.update(20);
+    assert!(b2a.pop().is_none());
+    bob.update(30);
+    assert_eq!(b2a.pop().unwrap().len(), 24);
+}
+
+#[test]
+fn burst_limit() {
+    let pipe = Pipe::new();
+    let mut kcb = Kcb::new(0x11223344, pipe.clone());
+    kcb.nodelay(1, 10, 0, true);
+    kcb.set_burst_limit(4);
+    kcb.update(0);
+    for i in 0..10u8 {
+        kcb.send(&[i; 1000]).unwrap();
+    }
+
+    let mut sent = Vec::new();
+    for tick in 1..4 {
+        kcb.update(tick * 10);
+        let mut n = 0;
+        while let Some(pkt) = pipe.pop() {
+            n += pkt.len() / (24 + 1000);
+        }
+        sent.push(n);
+        // the rest waits for the next tick instead of being due at once
+        assert_eq!(kcb.check(tick * 10), 10);
+    }
+    assert_eq!(sent, vec![4, 4, 2]);
+    assert_eq!(kcb.segments_sent(), 10);
+}
+
+#[test]
+fn ack_flush() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    alice.wndsize(1, 128);
+    alice.set_ack_flush(Some(5));
+    alice.update(0);
+    bob.update(0);
+
+    for msg in [b"one", b"two", b"six"].iter() {
+        alice.send(*msg).unwrap();
+    }
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    bob.flush();
+
+    // the ack lets the next message out without waiting for an update
+    alice.input(&b2a.pop().unwrap()).unwrap();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    bob.flush();
+    // but not twice within the gap
+    alice.input(&b2a.pop().unwrap()).unwrap();
+    assert!(a2b.pop().is_none());
+    alice.update(10);
+    bob.input(&a2b.pop().unwrap()).unwrap();
+
+    let mut buf = [0; 16];
+    for msg in [b"one", b"two", b"six"].iter() {
+        assert_eq!(bob.recv(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], *msg);
+    }
+}
+
+#[test]
+fn config_stream_mode() {
+    let config = KcpConfig {
+        stream: true,
+        ..KcpConfig::default()
+    };
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    config.apply(&mut alice);
+    config.apply(&mut bob);
+
+    alice.send(b"ab").unwrap();
+    alice.send(b"cd").unwrap();
+    alice.update(0);
+
+    // both writes merged into a single segment
+    let pkt = pipe.pop().unwrap();
+    assert!(pipe.pop().is_none());
+    assert_eq!(pkt.len(), 24 + 4);
+    bob.input(&pkt).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"abcd");
+}
+
+#[test]
+fn stream_mode_keeps_queued_messages() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    alice.send(b"ab").unwrap();
+    alice.set_stream(true);
+    alice.send(b"cd").unwrap();
+    alice.send(b"ef").unwrap();
+    alice.update(0);
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+
+    let mut buf = [0; 16];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"ab");
+    assert_eq!(bob.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"cdef");
+}
+
+#[test]
+fn stream_read_coalescing() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    alice.set_stream(true);
+    bob.set_stream(true);
+
+    // flushed one at a time, so each is a segment of its own
+    let mut now = 0;
+    for msg in &[&b"one"[..], b"two", b"three"] {
+        alice.send(msg).unwrap();
+        now += 10;
+        alice.update(now);
+    }
+    while let Some(pkt) = pipe.pop() {
+        bob.input(&pkt).unwrap();
+    }
+
+    let mut buf = [0; 8];
+    let n = bob.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"onetwo");
+    let n = bob.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"three");
+}
+
+#[test]
+fn stream_partial_send() {
+    let mut alice = Kcb::new(0x11223344, Pipe::new());
+    alice.set_stream(true);
+
+    // merged into the last segment, still counted in bytes
+    assert_eq!(alice.send(b"ab").unwrap(), 2);
+    assert_eq!(alice.send(b"cd").unwrap(), 2);
+    let big = vec![0x5a; 1024 * 1024];
+    if cfg!(feature = "bounded-queues") {
+        // the queue holds 256 segments of 1376 bytes
+        let mut alice = Kcb::new(0x11223344, Pipe::new());
+        alice.set_stream(true);
+        assert_eq!(alice.send(&big).unwrap(), 256 * 1376);
+        let err = alice.send(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(alice.waitsnd(), 256);
+    } else {
+        // no 255 fragments limit without message boundaries
+        assert_eq!(alice.send(&big).unwrap(), big.len());
+    }
+}
+
+#[test]
+fn stream_send_backlog() {
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.set_stream(true);
+    alice.nodelay(1, 10, 0, true);
+    alice.set_send_backlog(4);
+
+    // four full segments are taken, the rest is left to the caller
+    let data = vec![0x5a; 10 * 1376];
+    assert_eq!(alice.send(&data).unwrap(), 4 * 1376);
+    let err = alice.send(&data).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // and taken once the backlog drained
+    alice.update(0);
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    bob.update(0);
+    while let Some(pkt) = b2a.pop() {
+        alice.input(&pkt).unwrap();
+    }
+    assert_eq!(alice.waitsnd(), 0);
+    assert_eq!(alice.send(&data[4 * 1376..]).unwrap(), 4 * 1376);
+}
+
+#[cfg(feature = "bounded-queues")]
+#[test]
+fn bounded_queues() {
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.wndsize(1024, 1024);
+    bob.wndsize(1024, 1024);
+    for i in 0..256 {
+        alice.send(&[i as u8]).unwrap();
+    }
+    let err = alice.send(b"x").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // what was queued still gets across
+    let mut received = Vec::new();
+    for t in 1..50 {
+        alice.update(t * 10);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        bob.update(t * 10);
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+        received.extend(bob.recv_many(1024).unwrap_or_default());
+    }
+    assert_eq!(received.len(), 256);
+    assert_eq!(alice.waitsnd(), 0);
+    assert_eq!(alice.send(b"x").unwrap(), 1);
+}
+
+#[test]
+fn send_backlog() {
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.set_send_backlog(2);
+
+    assert!(alice.writable());
+    alice.send(b"one").unwrap();
+    alice.send(b"two").unwrap();
+    assert!(!alice.writable());
+    // not enforced by send itself
+    alice.send(b"three").unwrap();
+
+    alice.update(0);
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    bob.update(0);
+    while let Some(pkt) = b2a.pop() {
+        alice.input(&pkt).unwrap();
+    }
+    assert_eq!(alice.waitsnd(), 0);
+    assert!(alice.writable());
+}
+
+#[test]
+fn swap_output() {
+    let old = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, old.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    alice.send(b"hello").unwrap();
+    alice.update(0);
+    assert!(old.pop().is_some());
+
+    // the lost segment is retransmitted through the new sink
+    let new = Pipe::new();
+    alice.set_output(new.clone());
+    alice.update(1000);
+    assert!(old.pop().is_none());
+    bob.input(&new.pop().unwrap()).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 5);
+}
+
+#[test]
+fn output_error_policy() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.set_output_error_policy(OutputErrorPolicy::Retry);
+
+    pipe.fail(Some(io::ErrorKind::Other));
+    alice.send(b"hello").unwrap();
+    alice.update(0);
+    assert!(pipe.pop().is_none());
+    assert_eq!(alice.stats().output_errors, 1);
+
+    // the held back datagram goes out first once the sink recovers
+    pipe.fail(None);
+    alice.update(10);
+    assert_eq!(pipe.pop().unwrap().len(), 24 + 5);
+    assert_eq!(alice.stats().output_dropped, 0);
+
+    alice.set_output_error_policy(OutputErrorPolicy::Fail);
+    pipe.fail(Some(io::ErrorKind::Other));
+    alice.send(b"world").unwrap();
+    alice.update(20);
+    assert!(alice.output_error().is_some());
+    assert!(alice.send(b"again").is_err());
+}
+
+#[test]
+fn would_block_output() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    alice.nodelay(1, 10, 0, true);
+
+    // a whole flush is held back, not dropped
+    pipe.fail(Some(io::ErrorKind::WouldBlock));
+    for _ in 0..4 {
+        alice.send(&[0; 1000]).unwrap();
+    }
+    alice.update(0);
+    assert!(pipe.pop().is_none());
+    assert!(alice.output_pending());
+
+    pipe.fail(None);
+    alice.flush_output();
+    assert!(!alice.output_pending());
+    assert_eq!(pipe.packets.borrow().len(), 4);
+    assert_eq!(alice.stats().output_dropped, 0);
+}
+
+#[test]
+fn send_pressure_backoff() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.wndsize(128, 128);
+
+    // learn the larger window of the peer
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    bob.nodelay(1, 10, 0, true);
+    bob.wndsize(128, 128);
+    bob.send(b"hi").unwrap();
+    bob.update(0);
+    alice.input(&b2a.pop().unwrap()).unwrap();
+
+    pipe.fail(Some(io::ErrorKind::WouldBlock));
+    for _ in 0..64 {
+        alice.send(&[0; 1000]).unwrap();
+    }
+    alice.update(0);
+    alice.update(10);
+    assert_eq!(alice.stats().flush_budget, 32);
+
+    // the held back datagrams go out, but only half as many new ones
+    pipe.fail(None);
+    for _ in 0..64 {
+        alice.send(&[0; 1000]).unwrap();
+    }
+    alice.update(20);
+    assert_eq!(pipe.packets.borrow().len(), 64 + 32);
+    assert!(alice.stats().flush_budget > 32);
+}
+
+#[test]
+fn vectored_output() {
+    let copied = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, copied.clone());
+    let vectored = Pipe::new();
+    let mut carol = Kcb::new(0x11223344, vectored.clone());
+    carol.set_vectored(true);
+
+    for kcb in [&mut alice, &mut carol].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+        kcb.set_padding(&[1400]);
+        for i in 0..10 {
+            kcb.send(&[i; 600]).unwrap();
+        }
+        kcb.update(0);
+    }
+
+    // same datagrams whether or not the segments are copied first
+    assert_eq!(vectored.packets.borrow().len(), 5);
+    assert_eq!(*copied.packets.borrow(), *vectored.packets.borrow());
+}
+
+/// datagrams as the slices they were handed over in
+#[derive(Clone, Default)]
+struct Slices {
+    datagrams: Rc<RefCell<Vec<Vec<Vec<u8>>>>>,
+}
+
+impl SegmentOutput for Slices {
+    fn send_segments(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.datagrams
+            .borrow_mut()
+            .push(bufs.iter().map(|buf| buf.to_vec()).collect());
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+}
+
+#[test]
+fn segment_output() {
+    let copied = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, copied.clone());
+    let slices = Slices::default();
+    let mut carol = Kcb::with_segment_output(0x11223344, slices.clone());
+    alice.nodelay(1, 10, 0, true);
+    carol.nodelay(1, 10, 0, true);
+    for i in 0..2 {
+        alice.send(&[i; 600]).unwrap();
+        carol.send(&[i; 600]).unwrap();
+    }
+    alice.update(0);
+    carol.update(0);
+    // and once more when retransmitted
+    alice.update(1000);
+    carol.update(1000);
+
+    let datagrams = slices.datagrams.borrow();
+    assert_eq!(datagrams.len(), 2);
+    for (datagram, packet) in datagrams.iter().zip(copied.packets.borrow().iter()) {
+        // header and payload of each segment
+        let lens: Vec<usize> = datagram.iter().map(|buf| buf.len()).collect();
+        assert_eq!(lens, [24, 600, 24, 600]);
+        assert_eq!(datagram.concat(), *packet);
+    }
 }
 
-impl LatencySimulator {
-    fn new(lost_rate: u32, rtt_min: u32, rtt_max: u32, nmax: u32) -> LatencySimulator {
-        LatencySimulator {
-            tx: 0,
-            current: clock(),
-            lost_rate: lost_rate / 2,
-            rtt_min: rtt_min / 2,
-            rtt_max: rtt_max / 2,
-            nmax: nmax,
-            delay_tunnel: VecDeque::new(),
-            rng: Random::new(100),
+#[test]
+fn mtu_downshift() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    for kcb in [&mut alice, &mut bob].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+        kcb.wndsize(128, 128);
+        kcb.set_mtu_downshift(true);
+    }
+
+    let msgs = (0..20u8).map(|i| vec![i; if i % 4 == 0 { 3000 } else { 500 }]).collect::<Vec<_>>();
+    for msg in &msgs {
+        alice.send(msg).unwrap();
+    }
+
+    // a path silently dropping datagrams larger than 1200 bytes
+    let mut received = Vec::new();
+    let mut buf = [0; 4096];
+    let mut now = 0;
+    while received.len() < msgs.len() {
+        alice.update(now);
+        bob.update(now);
+        while let Some(pkt) = a2b.pop() {
+            if pkt.len() <= 1200 {
+                bob.input(&pkt).unwrap();
+            }
+        }
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
         }
+        while let Ok(n) = bob.recv(&mut buf) {
+            received.push(buf[..n].to_vec());
+        }
+        now += 10;
+        assert!(now < 60_000, "transfer stalled");
     }
+    assert_eq!(received, msgs);
+    assert_eq!(alice.stats().mtu, 1200);
+    assert_eq!(alice.stats().mtu_downshifts, 1);
 }
 
-impl Write for LatencySimulator {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.tx += 1;
-        if self.rng.uniform() < self.lost_rate {
-            return Err(io::Error::new(io::ErrorKind::Other, "lost"));
+#[test]
+fn probe_timers() {
+    // window update from a peer with a full receive window
+    let mut wins = vec![0; 24];
+    LittleEndian::write_u32(&mut wins[..4], 0x11223344);
+    wins[4] = 84;
+
+    let slow = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, slow.clone());
+    let fast = Pipe::new();
+    let mut carol = Kcb::new(0x11223344, fast.clone());
+    carol.set_probe_timers(20, 100);
+
+    for kcb in [&mut alice, &mut carol].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+        kcb.input(&wins).unwrap();
+        for now in 0..10 {
+            kcb.update(now * 10);
         }
-        if self.delay_tunnel.len() >= self.nmax as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("exceeded nmax: {}", self.delay_tunnel.len()),
-            ));
+    }
+
+    // only carol asks for the window within 100ms
+    let asked = |pipe: &Pipe| {
+        let mut n = 0;
+        while let Some(pkt) = pipe.pop() {
+            n += pkt.chunks(24).filter(|seg| seg[4] == 83).count();
         }
+        n
+    };
+    assert_eq!(asked(&slow), 0);
+    assert!(asked(&fast) >= 2);
+}
+
+#[test]
+fn window_reopened() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    bob.wndsize(32, 4);
+    alice.update(0);
+    bob.update(0);
+
+    for _ in 0..4 {
+        alice.send(&[0; 100]).unwrap();
+    }
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    bob.update(10);
+    b2a.pop().unwrap();
 
-        self.current = clock();
-        let mut delay = self.rtt_min;
-        if self.rtt_max > self.rtt_min {
-            delay += rand::random::<u32>() % (self.rtt_max - self.rtt_min);
+    // the reader catches up, the update about it is lost
+    let mut buf = [0; 100];
+    bob.recv(&mut buf).unwrap();
+    let updates = |pipe: &Pipe| {
+        let mut n = 0;
+        while let Some(pkt) = pipe.pop() {
+            n += pkt.chunks(24).filter(|seg| seg[4] == 84).count();
         }
-        let pkt = DelayPacket {
-            ts: self.current + delay,
-            data: buf.to_vec(),
-        };
-        self.delay_tunnel.push_back(pkt);
+        n
+    };
+    bob.update(20);
+    assert_eq!(updates(&b2a), 1);
 
-        Ok(buf.len())
+    // and repeated until the sender goes on
+    let mut now = 30;
+    while b2a.packets.borrow().is_empty() {
+        bob.update(now);
+        now += 10;
+    }
+    assert!(now < 1000);
+    let pkt = b2a.pop().unwrap();
+    assert_eq!(pkt[4], 84);
+    alice.input(&pkt).unwrap();
+    alice.send(&[1; 100]).unwrap();
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    for t in 0..100 {
+        bob.update(now + t * 10);
     }
+    assert_eq!(updates(&b2a), 0);
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+#[test]
+fn pause_receiving() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    alice.update(0);
+    bob.update(0);
+
+    bob.set_paused(true);
+    assert!(bob.paused());
+    bob.flush();
+    alice.input(&b2a.pop().unwrap()).unwrap();
+
+    // nothing leaves alice while bob's window is closed
+    alice.send(b"held").unwrap();
+    alice.flush();
+    assert!(a2b.pop().is_none());
+
+    bob.set_paused(false);
+    bob.flush();
+    alice.input(&b2a.pop().unwrap()).unwrap();
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 4);
+}
+
+#[test]
+fn window_clamp() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    alice.update(0);
+    bob.update(0);
+
+    bob.set_window_clamp(2);
+    bob.send(b"hi").unwrap();
+    bob.flush();
+    alice.input(&b2a.pop().unwrap()).unwrap();
+    for _ in 0..5 {
+        alice.send(b"data").unwrap();
+    }
+    alice.flush();
+    assert_eq!(alice.inflight(), 2);
+
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    bob.set_window_clamp(u32::max_value());
+    bob.flush();
+    while let Some(pkt) = b2a.pop() {
+        alice.input(&pkt).unwrap();
     }
+    alice.flush();
+    assert_eq!(alice.inflight(), 3);
 }
 
-impl Read for LatencySimulator {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let len: usize;
-        if let Some(pkt) = self.delay_tunnel.front() {
-            self.current = clock();
-            if self.current < pkt.ts {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("current({}) < ts({})", self.current, pkt.ts),
-                ));
+#[test]
+fn cwnd_validation() {
+    // one small message per round trip never fills the window
+    let trickle = |validation: bool| {
+        let a2b = Pipe::new();
+        let mut alice = Kcb::new(0x11223344, a2b.clone());
+        let b2a = Pipe::new();
+        let mut bob = Kcb::new(0x11223344, b2a.clone());
+        alice.nodelay(0, 10, 0, false);
+        alice.set_cwnd_validation(validation);
+        bob.nodelay(0, 10, 0, false);
+        for now in 0..200 {
+            alice.send(b"ping").unwrap();
+            alice.update(now * 10);
+            while let Some(pkt) = a2b.pop() {
+                bob.input(&pkt).unwrap();
             }
-            len = pkt.data.len();
-            if len > buf.len() {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("buf_size({}) < pkt_size({})", buf.len(), len),
-                ));
+            bob.update(now * 10);
+            while let Some(pkt) = b2a.pop() {
+                alice.input(&pkt).unwrap();
             }
-            let buf = &mut buf[..len];
-            buf.copy_from_slice(&pkt.data[..]);
-        } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "empty"));
         }
+        alice
+    };
+    assert!(trickle(false).stats().cwnd >= 8);
+    let mut alice = trickle(true);
+    assert!(alice.stats().cwnd <= 2);
 
-        self.delay_tunnel.pop_front();
-        Ok(len)
+    // a window grown by a bulk transfer decays while idle
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice = Kcb::new(0x11223344, a2b.clone());
+    alice.nodelay(0, 10, 0, false);
+    alice.set_cwnd_validation(true);
+    bob.nodelay(0, 10, 0, false);
+    let mut now = 0;
+    while now < 500 || alice.waitsnd() > 0 {
+        if now < 500 {
+            for _ in 0..8 {
+                alice.send(&[0; 1000]).unwrap();
+            }
+        }
+        alice.update(now);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        bob.update(now);
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+        let mut buf = [0; 1000];
+        while bob.recv(&mut buf).is_ok() {}
+        now += 10;
     }
+    let busy = alice.stats().cwnd;
+    assert!(busy > 8);
+    alice.update(10_000);
+    assert_eq!(alice.stats().cwnd, 4);
 }
 
-struct Output {
-    ls: Rc<RefCell<LatencySimulator>>,
+#[test]
+fn hystart() {
+    // a bottleneck taking a datagram per millisec in front of a 20ms rtt,
+    // with a queue that never overflows
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(0, 10, 0, false);
+    alice.wndsize(1024, 1024);
+    alice.set_slow_start(SlowStart::HyStart);
+    bob.nodelay(1, 10, 0, false);
+    bob.wndsize(1024, 1024);
+
+    let mut queue = VecDeque::new();
+    let mut acks = VecDeque::new();
+    let mut departure = 0;
+    let mut queued = 0;
+    for now in 0..3000 {
+        // topped up as it drains, bounded queues hold fewer than 5000
+        while queued < 5000 && alice.send(&[0; 1000]).is_ok() {
+            queued += 1;
+        }
+        alice.update(now);
+        while let Some(pkt) = a2b.pop() {
+            departure = cmp::max(departure, now) + 1;
+            queue.push_back((departure + 10, pkt));
+        }
+        while queue.front().map_or(false, |&(at, _)| at <= now) {
+            bob.input(&queue.pop_front().unwrap().1).unwrap();
+        }
+        bob.update(now);
+        while let Some(pkt) = b2a.pop() {
+            acks.push_back((now + 10, pkt));
+        }
+        while acks.front().map_or(false, |&(at, _)| at <= now) {
+            alice.input(&acks.pop_front().unwrap().1).unwrap();
+        }
+        let mut buf = [0; 1000];
+        while bob.recv(&mut buf).is_ok() {}
+    }
+
+    // slow start ended as the queue built up, before the rtt outgrew the
+    // rto and timeouts knocked cwnd down
+    let stats = alice.stats();
+    assert!(stats.cwnd >= 16 && stats.cwnd < 128, "cwnd {}", stats.cwnd);
+    assert_eq!(stats.retransmits, 0);
 }
 
-impl Write for Output {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut ls = self.ls.borrow_mut();
-        ls.write(buf)
+#[test]
+fn failover_alarm() {
+    let lost = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, lost.clone());
+    alice.nodelay(1, 10, 0, true);
+    let alarms = Arc::new(Mutex::new(Vec::new()));
+    let sink = alarms.clone();
+    alice.set_failover_alarm(3, 500, move |alarm| sink.lock().unwrap().push(alarm));
+
+    alice.send(&[0; 100]).unwrap();
+    for now in 0..200 {
+        alice.update(now * 10);
     }
+    let alarms = alarms.lock().unwrap();
+    assert_eq!(alarms[0], FailoverAlarm::Retransmits { sn: 0, xmit: 3 });
+    match alarms[1] {
+        FailoverAlarm::Rto { sn: 0, rto } => assert!(rto >= 500),
+        ref other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(alarms.len(), 2);
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+#[test]
+fn playout_delay() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    bob.set_playout_delay(50);
+
+    // a message every 20ms, through 10 to 40ms of jitter
+    let jitter = [10, 40, 25, 10, 35, 15, 40, 20];
+    let mut wire = VecDeque::new();
+    let mut released = Vec::new();
+    for now in 0..400 {
+        alice.update(now);
+        bob.update(now);
+        if now % 20 == 0 && now / 20 < jitter.len() as u32 {
+            alice.send(&[(now / 20) as u8]).unwrap();
+            alice.flush();
+        }
+        while let Some(pkt) = a2b.pop() {
+            let i = wire.len() % jitter.len();
+            wire.push_back((now + jitter[i], pkt));
+        }
+        let mut arrived: Vec<_> = wire.iter().filter(|&&(at, _)| at == now).cloned().collect();
+        for (_, pkt) in arrived.drain(..) {
+            bob.input(&pkt).unwrap();
+        }
+        let mut buf = [0; 4];
+        while let Ok(_) = bob.recv(&mut buf) {
+            released.push((buf[0], now));
+        }
+    }
+
+    // on the sender's clock, 50ms behind the fastest transit
+    let expected: Vec<_> = (0..8).map(|i| (i as u8, i * 20 + 10 + 50)).collect();
+    assert_eq!(released, expected);
+}
+
+#[test]
+fn max_xmit() {
+    let lost = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, lost.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.set_dead_link(5);
+    assert_eq!(alice.dead_link(), 5);
+
+    alice.send(&[0; 100]).unwrap();
+    alice.send(&[1; 100]).unwrap();
+    for now in 0..100 {
+        alice.update(now * 10);
+    }
+    // every transmission went nowhere
+    let sent = lost.packets.borrow().len() as u32;
+    assert_eq!(alice.stats().max_xmit, sent);
+    assert!(sent > 3);
+}
+
+#[test]
+fn keepalive() {
+    let pipe = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, pipe.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.update(0);
+    alice.set_keepalive(Keepalive::Every(1000).interval());
+
+    // an idle session sends a window update every second
+    for now in 1..100 {
+        alice.update(now * 10);
+    }
+    assert!(pipe.pop().is_none());
+    alice.update(1000);
+    let pkt = pipe.pop().unwrap();
+    assert_eq!((pkt.len(), pkt[4]), (24, 84));
+    for now in 101..200 {
+        alice.update(now * 10);
+    }
+    assert!(pipe.pop().is_none());
+    alice.update(2000);
+    assert!(pipe.pop().is_some());
+}
+
+#[test]
+fn close_frame() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    for kcb in [&mut alice, &mut bob].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+    }
+
+    alice.send(b"last words").unwrap();
+    alice.close(4000, "server restarting");
+    assert!(alice.send(b"more").is_err());
+
+    // the first push is lost, the close waits for it to be acked
+    alice.update(0);
+    alice.update(10);
+    a2b.pop().unwrap();
+    let mut buf = [0; 64];
+    for now in 2..50 {
+        alice.update(now * 10);
+        bob.update(now * 10);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+    }
+    assert_eq!(bob.recv(&mut buf).unwrap(), 10);
+    assert_eq!(&buf[..10], b"last words");
+    assert_eq!(bob.recv(&mut buf).unwrap(), 0);
+    let frame = bob.peer_close().unwrap();
+    assert_eq!((frame.code, &frame.reason[..]), (4000, "server restarting"));
+    assert!(alice.is_closed());
+    assert!(!bob.is_closed());
+}
+
+#[test]
+fn reset() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    for kcb in [&mut alice, &mut bob].iter_mut() {
+        kcb.nodelay(1, 10, 0, true);
+        kcb.update(0);
+    }
+
+    alice.send(b"never acked").unwrap();
+    alice.flush();
+    let lost = a2b.pop().unwrap();
+    // a reset that does not match what alice sent is ignored
+    let mut forged = reset_frame(&lost).unwrap();
+    forged[16] ^= 0xff;
+    alice.input(&forged).unwrap();
+    assert!(!alice.is_reset());
+
+    bob.reset();
+    assert!(bob.is_reset());
+    assert!(b2a.pop().is_some());
+    assert_eq!(bob.send(b"x").unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+
+    // a listener without the session answers the retransmit
+    alice.input(&reset_frame(&lost).unwrap()).unwrap();
+    assert!(alice.is_reset());
+    assert_eq!(alice.waitsnd(), 0);
+    let mut buf = [0; 16];
+    assert_eq!(alice.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    assert_eq!(alice.recv_many(16).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    alice.update(1000);
+    assert!(a2b.pop().is_none());
+}
+
+#[test]
+fn dead_link() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    alice.set_dead_link(3);
+    alice.update(0);
+
+    alice.send(b"delivered").unwrap();
+    alice.flush();
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    // bob's acks never make it back
+    let mut now = 0;
+    while !alice.is_dead() {
+        assert!(now < 60_000);
+        now += 10;
+        alice.update(now);
+    }
+    while a2b.pop().is_some() {}
+    assert_eq!(alice.send(b"x").unwrap_err().kind(), io::ErrorKind::TimedOut);
+    let mut buf = [0; 16];
+    assert_eq!(alice.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    alice.update(now + 10_000);
+    assert!(a2b.pop().is_none());
+}
+
+#[test]
+fn input_bytes() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    alice.update(0);
+    bob.update(0);
+
+    alice.send(b"first").unwrap();
+    alice.send(b"second").unwrap();
+    alice.send(b"third").unwrap();
+    alice.flush();
+    let datagram = Bytes::from(a2b.pop().unwrap());
+    assert_eq!(bob.input_bytes(&datagram).unwrap(), datagram.len());
+
+    let mut buf = [0; 16];
+    for msg in [&b"first"[..], b"second", b"third"].iter() {
+        let n = bob.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], *msg);
+    }
+}
+
+#[test]
+fn input_bytes_out_of_order() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    alice.update(0);
+    bob.update(0);
+
+    alice.send(&[1; 100]).unwrap();
+    alice.flush();
+    alice.send(&[2; 100]).unwrap();
+    alice.flush();
+    let first = Bytes::from(a2b.pop().unwrap());
+    let second = Bytes::from(a2b.pop().unwrap());
+
+    // held in rcv_buf, so it must not keep the datagram alive
+    bob.input_bytes(&second).unwrap();
+    assert!(second.try_mut().is_ok());
+    // delivered right away, a slice is fine
+    bob.input_bytes(&first).unwrap();
+    assert!(first.try_mut().is_err());
+
+    let mut buf = [0; 128];
+    for &byte in [1, 2].iter() {
+        let n = bob.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &[byte; 100][..]);
+    }
+}
+
+#[test]
+fn recv_errors() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+
+    let mut buf = [0; 2000];
+    let e = bob.recv(&mut buf).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+    // the first fragment alone is not a message yet
+    alice.send(&[7; 1500]).unwrap();
+    alice.update(0);
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    let e = bob.recv(&mut buf).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+
+    bob.input(&a2b.pop().unwrap()).unwrap();
+    let e = bob.recv(&mut buf[..1000]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let short = e.get_ref().and_then(|e| e.downcast_ref::<ShortBuffer>()).unwrap();
+    assert_eq!(short.needed, 1500);
+    assert_eq!(bob.recv(&mut buf).unwrap(), 1500);
+}
+
+#[test]
+fn max_message() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.set_max_message(3000, 4);
+
+    // 3 fragments, at least 2753 bytes judging from the first one
+    alice.send(&[1; 2800]).unwrap();
+    // 4 fragments, the first one shows at least 4129 bytes
+    alice.send(&[2; 4200]).unwrap();
+    // few bytes in 7 fragments, the first 3 show more than 4
+    alice.setmtu(100);
+    alice.send(&[3; 500]).unwrap();
+    alice.update(0);
+
+    let mut refused = 0;
+    while let Some(pkt) = a2b.pop() {
+        if bob.input(&pkt).is_err() {
+            refused += 1;
+        }
+    }
+    // neither message can complete without the fragments refused
+    assert_eq!(refused, 1 + 3);
+    let mut buf = [0; 4096];
+    assert_eq!(bob.recv(&mut buf).unwrap(), 2800);
+    assert!(bob.recv(&mut buf).is_err());
+}
+
+#[test]
+fn reassembly_limits() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    bob.set_reassembly_limits(3, usize::MAX);
+
+    for i in 0..10u8 {
+        alice.send(&[i; 100]).unwrap();
+    }
+    alice.update(0);
+    // every message a segment of its own, the first one lost
+    let mut pkts = Vec::new();
+    while let Some(pkt) = a2b.pop() {
+        pkts.push(pkt);
+    }
+    for pkt in &pkts {
+        let mut off = 0;
+        while off < pkt.len() {
+            let len = LittleEndian::read_u32(&pkt[off + 20..]) as usize;
+            if LittleEndian::read_u32(&pkt[off + 12..]) != 0 {
+                bob.input(&pkt[off..off + 24 + len]).unwrap();
+                bob.verify_invariants().unwrap();
+            }
+            off += 24 + len;
+        }
+    }
+    // the oldest give way, 7..9 held and everything acknowledged
+    assert_eq!(bob.stats().reassembly_dropped, 6);
+    assert_eq!(bob.waitrcv_bytes(), 300);
+    bob.update(0);
+    while let Some(pkt) = b2a.pop() {
+        alice.input(&pkt).unwrap();
+    }
+    assert_eq!(alice.waitsnd(), 1);
+
+    let mut buf = [0; 100];
+    let mut received = Vec::new();
+    let mut now = 0;
+    while received.len() < 4 && now < 5000 {
+        now += 10;
+        alice.update(now);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        bob.update(now);
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+        while let Ok(n) = bob.recv(&mut buf) {
+            assert_eq!(n, 100);
+            received.push(buf[0]);
+        }
+    }
+    assert_eq!(received, [0, 7, 8, 9]);
+    bob.verify_invariants().unwrap();
+}
+
+#[test]
+fn reassembly_drops_whole_messages() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    bob.set_reassembly_limits(2, usize::MAX);
+
+    alice.send(&[0; 100]).unwrap();
+    alice.send(&[1; 3000]).unwrap();
+    alice.send(&[2; 100]).unwrap();
+    alice.send(&[3; 100]).unwrap();
+    alice.update(0);
+    let mut segs = HashMap::new();
+    while let Some(pkt) = a2b.pop() {
+        let mut off = 0;
+        while off < pkt.len() {
+            let len = LittleEndian::read_u32(&pkt[off + 20..]) as usize;
+            let sn = LittleEndian::read_u32(&pkt[off + 12..]);
+            segs.insert(sn, pkt[off..off + 24 + len].to_vec());
+            off += 24 + len;
+        }
+    }
+    // 1..3 are the fragments of the long message, 0 and 2 go late
+    for sn in &[1, 3, 4] {
+        bob.input(&segs[sn]).unwrap();
+    }
+    assert_eq!(bob.stats().reassembly_dropped, 0);
+    bob.input(&segs[&5]).unwrap();
+    bob.verify_invariants().unwrap();
+    assert_eq!(bob.stats().reassembly_dropped, 2);
+    assert_eq!(bob.waitrcv_bytes(), 200);
+    // what is left of the dropped message is thrown away
+    bob.input(&segs[&2]).unwrap();
+    bob.verify_invariants().unwrap();
+    assert_eq!(bob.stats().reassembly_dropped, 3);
+    assert_eq!(bob.waitrcv_bytes(), 200);
+
+    bob.input(&segs[&0]).unwrap();
+    bob.verify_invariants().unwrap();
+    let mut buf = [0; 4096];
+    for &i in &[0, 2, 3] {
+        assert_eq!(bob.recv(&mut buf).unwrap(), 100);
+        assert_eq!(buf[0], i);
+    }
+    assert!(bob.recv(&mut buf).is_err());
+}
+
+/// sends at a fixed rate with the window wide open
+struct FixedRate(u64);
+
+impl CongestionControl for FixedRate {
+    fn on_ack(&mut self, window: &mut Window, _acked: u32, _now: u32, _srtt: u32) {
+        window.set_cwnd(128);
+    }
+
+    fn on_fast_resend(&mut self, _window: &mut Window, _inflight: u32, _resent: u32) {}
+
+    fn on_timeout(&mut self, _window: &mut Window, _cwnd: u32) {}
+
+    fn pacing_rate(&self) -> Option<u64> {
+        Some(self.0)
+    }
+}
+
+#[test]
+fn pacing_over_time() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let b2a = Pipe::new();
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, false);
+    bob.nodelay(1, 10, 0, false);
+    alice.wndsize(128, 128);
+    bob.wndsize(128, 128);
+    // a segment every 20 millisec
+    alice.set_congestion(FixedRate(50 * 1376));
+
+    for _ in 0..200 {
+        alice.send(&[0; 1376]).unwrap();
+    }
+    let mut buf = [0; 1376];
+    for now in 0..1000 {
+        alice.update(now);
+        // flushes in between, as writes and acks trigger them, add nothing
+        alice.flush();
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        bob.update(now);
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+        while bob.recv(&mut buf).is_ok() {}
+    }
+    let sent = alice.stats().segments_sent;
+    assert!(sent >= 45 && sent <= 51, "{} segments in a second", sent);
+}
+
+#[test]
+fn parse_modes() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    alice.nodelay(1, 10, 0, true);
+    alice.send(b"one").unwrap();
+    alice.send(b"two").unwrap();
+    alice.update(0);
+    let pkt = a2b.pop().unwrap();
+
+    // the second push claims a cmd nobody knows, and a trailer follows
+    let mut mangled = pkt.clone();
+    mangled[27 + 4] = 99;
+    mangled.extend_from_slice(&[0xfe; 30]);
+
+    let mut strict = Kcb::new(0x11223344, Pipe::new());
+    assert!(strict.input(&mangled).is_err());
+    let mut buf = [0; 16];
+    assert!(strict.recv(&mut buf).is_err());
+
+    let mut lenient = Kcb::new(0x11223344, Pipe::new());
+    lenient.set_parse_mode(ParseMode::Lenient);
+    assert_eq!(lenient.input(&mangled).unwrap(), pkt.len());
+    assert_eq!(lenient.recv(&mut buf).unwrap(), 3);
+    assert_eq!(&buf[..3], b"one");
+    assert_eq!(lenient.stats().malformed, 1);
+}
+
+#[test]
+fn receive_counters() {
+    let a2b = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, Pipe::new());
+    alice.nodelay(1, 10, 0, true);
+    bob.wndsize(32, 4);
+    for i in 0..6 {
+        alice.send(&[i; 1000]).unwrap();
+    }
+    alice.update(0);
+    let pkts = (0..6).map(|_| a2b.pop().unwrap()).collect::<Vec<_>>();
+
+    // sn 5 beyond the window, 3 ahead of 0 to 2, then 0 and 2 twice
+    for &i in [5, 3, 0, 2, 1, 0, 2].iter() {
+        bob.input(&pkts[i]).unwrap();
+    }
+    let stats = bob.stats();
+    assert_eq!(stats.duplicates, 2);
+    assert_eq!(stats.out_of_window, 1);
+    assert_eq!(stats.reordered, 3);
+    assert_eq!(stats.reorder_distance, 3);
+}
+
+#[test]
+fn lossy_bulk_transfer() {
+    let sim = Simulation::new(20, 20, 80);
+    let (mut alice, mut bob) = sim.pair(0x11223344);
+    alice.wndsize(128, 128);
+    bob.wndsize(128, 128);
+    alice.nodelay(1, 10, 2, true);
+    bob.nodelay(1, 10, 2, true);
+
+    let msgs = (0..500u32).map(|i| vec![i as u8; 1 + i as usize * 7 % 3000]).collect::<Vec<_>>();
+    let mut pending = msgs.iter();
+    let mut received = Vec::new();
+    let mut buf = [0; 4096];
+    while received.len() < msgs.len() {
+        while alice.waitsnd() < 128 {
+            match pending.next() {
+                Some(msg) => alice.send(msg).unwrap(),
+                None => break,
+            };
+        }
+        sim.step(&mut alice, &mut bob, 1);
+        while let Ok(n) = bob.recv(&mut buf) {
+            received.push(buf[..n].to_vec());
+        }
+        assert!(sim.now() < 600_000, "transfer stalled");
+    }
+    assert_eq!(received, msgs);
+}
+
+#[test]
+fn delivery_stats() {
+    // 100 ms round trips, nothing lost
+    let sim = Simulation::new(0, 50, 50);
+    let (mut alice, mut bob) = sim.pair(0x11223344);
+    alice.wndsize(128, 128);
+    bob.wndsize(128, 128);
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+
+    // 1000 bytes every 10 ms, 100 KB/s for two seconds
+    let mut buf = [0; 1000];
+    for t in 0..200 {
+        alice.send(&[t as u8; 1000]).unwrap();
+        sim.step(&mut alice, &mut bob, 10);
+        while bob.recv(&mut buf).is_ok() {}
+    }
+    let rate = alice.stats().delivery_rate;
+    assert!(rate > 90_000 && rate < 110_000, "delivery rate {}", rate);
+    for _ in 0..20 {
+        sim.step(&mut alice, &mut bob, 10);
+    }
+    let stats = alice.stats();
+    assert!(stats.srtt >= 100 && stats.srtt < 130, "srtt {}", stats.srtt);
+    assert_eq!(stats.retransmits, 0);
+    assert_eq!(stats.delivered, 200 * 1000);
+    // the bandwidth keeps the best of the recent samples
+    assert!(stats.bandwidth >= rate);
+}
+
+#[test]
+fn initial_sn() {
+    let sim = Simulation::new(10, 20, 60);
+    let (mut alice, mut bob) = sim.pair(0x11223344);
+    alice.nodelay(1, 10, 2, true);
+    bob.nodelay(1, 10, 2, true);
+    // both directions wrap around
+    alice.set_initial_sn(0xffff_ff00);
+    bob.set_initial_sn(0xffff_fff0);
+
+    for i in 0..50u32 {
+        bob.send(&[i as u8; 500]).unwrap();
+    }
+    let (mut to_bob, mut to_alice) = (0, 0);
+    let mut queued = 0u32;
+    let mut buf = [0; 500];
+    while (to_bob < 500 || to_alice < 50) && sim.now() < 60_000 {
+        while queued < 500 && alice.send(&[queued as u8; 500]).is_ok() {
+            queued += 1;
+        }
+        sim.step(&mut alice, &mut bob, 10);
+        while let Ok(n) = bob.recv(&mut buf) {
+            assert_eq!((n, buf[0]), (500, to_bob as u8));
+            to_bob += 1;
+        }
+        while let Ok(n) = alice.recv(&mut buf) {
+            assert_eq!((n, buf[0]), (500, to_alice as u8));
+            to_alice += 1;
+        }
+        alice.verify_invariants().unwrap();
+        bob.verify_invariants().unwrap();
     }
+    assert_eq!((to_bob, to_alice), (500, 50));
+}
+
+#[test]
+fn initial_sn_learned_once() {
+    let (a2b, b2a) = (Pipe::new(), Pipe::new());
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 0, true);
+    bob.nodelay(1, 10, 0, true);
+    alice.set_initial_sn(1000);
+    bob.set_initial_sn(5000);
+    for now in 0..5 {
+        alice.update(now * 10);
+        bob.update(now * 10);
+        while let Some(pkt) = a2b.pop() {
+            bob.input(&pkt).unwrap();
+        }
+        while let Some(pkt) = b2a.pop() {
+            alice.input(&pkt).unwrap();
+        }
+    }
+
+    // a probe flagged like the first one, but numbered elsewhere
+    let mut forged = vec![0; 24];
+    LittleEndian::write_u32(&mut forged[0..4], 0x11223344);
+    forged[4] = 83;
+    forged[5] = 0x80;
+    LittleEndian::write_u16(&mut forged[6..8], 128);
+    LittleEndian::write_u32(&mut forged[12..16], 9999);
+    bob.input(&forged).unwrap();
+
+    alice.send(b"hello").unwrap();
+    alice.update(100);
+    while let Some(pkt) = a2b.pop() {
+        bob.input(&pkt).unwrap();
+    }
+    let mut buf = [0; 16];
+    let n = bob.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+}
+
+/// a lossy transfer between a seeded pair, with the trace and stats of
+/// bob's side
+fn seeded_run(seed: u64) -> (u32, Trace, String) {
+    let sim = Simulation::with_seed(10, 20, 60, seed);
+    let config = KcpConfig { random_isn: true, ..KcpConfig::preset("fast").unwrap() };
+    let (mut alice, mut bob) = sim.seeded_pair(&config);
+    bob.record(true);
+    for i in 0..100u32 {
+        alice.send(&[i as u8; 700]).unwrap();
+    }
+    let mut received = 0;
+    let mut buf = [0; 700];
+    while received < 100 {
+        sim.step(&mut alice, &mut bob, 10);
+        while bob.recv(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert!(sim.now() < 60_000, "transfer stalled");
+    }
+    (alice.conv(), bob.take_trace().unwrap(), format!("{:?}", bob.stats()))
+}
+
+#[test]
+fn seeded_pair() {
+    let (conv, trace, stats) = seeded_run(7);
+    assert_eq!(seeded_run(7), (conv, trace, stats));
+    assert!(seeded_run(8).0 != conv);
+}
+
+#[test]
+fn replay_random_isn() {
+    let (conv, trace, stats) = seeded_run(9);
+    assert_eq!(trace.conv, conv);
+    assert!(trace.isn.is_some());
+    let mut file = Vec::new();
+    trace.write_to(&mut file).unwrap();
+    let trace = Trace::read_from(&mut &file[..]).unwrap();
+
+    // a fresh block on the recorded conv and sn goes through the same
+    let mut carol = Kcb::new(trace.conv, Pipe::new());
+    KcpConfig::preset("fast").unwrap().apply(&mut carol);
+    trace.replay(&mut carol).unwrap();
+    assert_eq!(format!("{:?}", carol.stats()), stats);
 }
 
 #[test]
@@ -139,13 +2092,10 @@ fn kcb_tests() {
 }
 
 fn test(mode: &str) -> String {
-    let alice_to_bob = Rc::new(RefCell::new(LatencySimulator::new(10, 60, 125, 1000)));
-    let bob_to_alice = Rc::new(RefCell::new(LatencySimulator::new(10, 60, 125, 1000)));
-
-    let mut alice = Kcb::new(0x11223344, Output { ls: alice_to_bob.clone() });
-    let mut bob = Kcb::new(0x11223344, Output { ls: bob_to_alice.clone() });
+    let sim = Simulation::new(5, 30, 62);
+    let (mut alice, mut bob) = sim.pair(0x11223344);
 
-    let mut current = clock();
+    let mut current = sim.now();
     let mut slap = current + 20;
     let mut index: u32 = 0;
     let mut next: u32 = 0;
@@ -173,44 +2123,24 @@ fn test(mode: &str) -> String {
     };
 
     let mut buffer: [u8; 2000] = [0; 2000];
-    let mut ts1 = clock();
+    let mut ts1 = sim.now();
 
     'outer: loop {
-        thread::sleep(time::Duration::from_millis(1));
-        current = clock();
-
-        alice.update(clock());
-        bob.update(clock());
+        sim.step(&mut alice, &mut bob, 1);
+        current = sim.now();
 
         while current >= slap {
             let mut p: usize = 0;
             LittleEndian::write_u32(&mut buffer[p..p + 4], index);
             p += 4;
-            index += 1;
             LittleEndian::write_u32(&mut buffer[p..p + 4], current);
             p += 4;
-            alice.send(&buffer[..p]).unwrap();
-            slap += 20;
-        }
-
-        let mut a2b = alice_to_bob.borrow_mut();
-        loop {
-            match a2b.read(&mut buffer[..]) {
-                Ok(hr) => {
-                    bob.input(&buffer[..hr]).ok();
-                }
-                Err(_) => break,
-            };
-        }
-
-        let mut b2a = bob_to_alice.borrow_mut();
-        loop {
-            match b2a.read(&mut buffer[..]) {
-                Ok(hr) => {
-                    alice.input(&buffer[..hr]).ok();
-                }
-                Err(_) => break,
+            if alice.send(&buffer[..p]).is_err() {
+                // the queue is full with bounded queues, retry on the next step
+                break;
             }
+            index += 1;
+            slap += 20;
         }
 
         loop {
@@ -252,38 +2182,56 @@ fn test(mode: &str) -> String {
         }
     }
 
-    ts1 = clock() - ts1;
+    ts1 = sim.now() - ts1;
     format!("{} mode result ({}ms):\n", mode, ts1) +
         &format!("avgrtt={} maxrtt={}", sumrtt / count, maxrtt)
 }
 
-struct Random {
-    size: usize,
-    seeds: Vec<u32>,
-}
+#[test]
+fn record_replay() {
+    let a2b = Pipe::new();
+    let b2a = Pipe::new();
+    let mut alice = Kcb::new(0x11223344, a2b.clone());
+    let mut bob = Kcb::new(0x11223344, b2a.clone());
+    alice.nodelay(1, 10, 2, true);
+    bob.nodelay(1, 10, 2, true);
+    bob.record(true);
 
-impl Random {
-    fn new(n: usize) -> Random {
-        Random {
-            size: 0,
-            seeds: vec![0; n],
+    let mut sent = Vec::new();
+    let mut buf = [0; 2000];
+    for i in 0..20 {
+        alice.send(&[i as u8; 1500]).unwrap();
+        alice.update(i * 10);
+        // every third datagram is lost
+        let mut n = 0;
+        while let Some(pkt) = a2b.pop() {
+            if n % 3 != 2 {
+                bob.input(&pkt).ok();
+            }
+            n += 1;
+        }
+        bob.update(i * 10);
+        while bob.recv(&mut buf).is_ok() {}
+        while let Some(pkt) = b2a.pop() {
+            sent.push(pkt.clone());
+            alice.input(&pkt).ok();
         }
     }
 
-    fn uniform(&mut self) -> u32 {
-        if self.seeds.len() == 0 {
-            return 0;
-        }
-        if self.size == 0 {
-            for (i, e) in self.seeds.iter_mut().enumerate() {
-                *e = i as u32;
-            }
-            self.size = self.seeds.len();
-        }
-        let i = rand::random::<usize>() % self.size;
-        let x = self.seeds[i];
-        self.size -= 1;
-        self.seeds[i] = self.seeds[self.size];
-        x
+    let mut file = Vec::new();
+    bob.take_trace().unwrap().write_to(&mut file).unwrap();
+    let trace = Trace::read_from(&mut &file[..]).unwrap();
+
+    // the replay sends exactly what bob sent
+    let out = Pipe::new();
+    let mut carol = Kcb::new(0x11223344, out.clone());
+    carol.nodelay(1, 10, 2, true);
+    trace.replay(&mut carol).unwrap();
+    let mut replayed = Vec::new();
+    while let Some(pkt) = out.pop() {
+        replayed.push(pkt);
     }
+    assert!(!sent.is_empty());
+    assert_eq!(replayed, sent);
+    assert_eq!(carol.stats().delivered, bob.stats().delivered);
 }
@@ -0,0 +1,415 @@
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Future, Stream};
+use kcp::{ConnectionState, KcpConfig, KcpConnector, KcpListener, KcpStream, RandomConv,
+          SequentialConv, SessionEvent, SessionEvents};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_io::io::{read, read_exact, write_all};
+
+fn local() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+/// a connector opening its sessions on `conv`
+fn connector_on(conv: u32, handle: &Handle) -> KcpConnector {
+    let mut connector = KcpConnector::bind(&local(), handle).unwrap();
+    connector.set_conv_allocator(RandomConv::with_range(conv, conv));
+    connector
+}
+
+/// accept the first session of `listener` and serve the rest in the
+/// background, returns the accepted stream and the events from then on
+fn accept_first(
+    core: &mut Core,
+    mut listener: KcpListener,
+    client: Box<Future<Item = KcpStream, Error = io::Error>>,
+) -> (KcpStream, KcpStream, SessionEvents) {
+    let events = listener.events();
+    let handle = core.handle();
+    let accept = listener.incoming().into_future().map_err(|(e, _)| e).map(move |(accepted, rest)| {
+        handle.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+        accepted.unwrap().0
+    });
+    let client = client.and_then(|stream| write_all(stream, *b"hi")).map(|(stream, _)| stream);
+    let (client, server) = core.run(client.join(accept)).unwrap();
+    let (server, _) = core.run(read_exact(server, [0; 2])).unwrap();
+    (client, server, events)
+}
+
+fn next_event(core: &mut Core, events: SessionEvents) -> (SessionEvent, SessionEvents) {
+    let (event, events) = core.run(events.into_future()).map_err(|(e, _)| e).unwrap();
+    (event.unwrap(), events)
+}
+
+/// run `listener` for `ms`, returns the sessions it accepted meanwhile
+fn serve_for(core: &mut Core, listener: &mut KcpListener, ms: u64) -> Vec<KcpStream> {
+    let handle = core.handle();
+    let mut accepted = Vec::new();
+    {
+        let serve = future::poll_fn(|| loop {
+            match listener.accept() {
+                Ok((stream, _)) => accepted.push(stream),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        });
+        let timeout = Timeout::new(Duration::from_millis(ms), &handle).unwrap();
+        core.run(serve.select(timeout).map(|_| ()).map_err(|(e, _)| e)).unwrap();
+    }
+    accepted
+}
+
+fn sleep(core: &mut Core, ms: u64) {
+    let handle = core.handle();
+    core.run(Timeout::new(Duration::from_millis(ms), &handle).unwrap()).unwrap();
+}
+
+#[test]
+fn idle_sessions_expire() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    listener.set_idle_timeout(Some(Duration::from_millis(300)));
+    let addr = listener.local_addr().unwrap();
+
+    // the client goes quiet once its message is acknowledged
+    let connect = KcpStream::connect(&addr, &handle);
+    let (client, server, events) = accept_first(&mut core, listener, Box::new(connect));
+    let (opened, events) = next_event(&mut core, events);
+    let peer = opened_addr(&opened);
+    let (expired, _) = next_event(&mut core, events);
+    assert_eq!(expired, SessionEvent::Expired { addr: peer, conv: client.conv() });
+
+    let e = core.run(read(server, [0; 2])).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+    // and the peer is told
+    sleep(&mut core, 100);
+    assert_eq!(client.state(), ConnectionState::Broken);
+}
+
+fn opened_addr(event: &SessionEvent) -> SocketAddr {
+    match *event {
+        SessionEvent::Opened { addr, .. } => addr,
+        ref other => panic!("not opened: {:?}", other),
+    }
+}
+
+#[test]
+fn dead_sessions_are_dropped() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut config = KcpConfig::default();
+    config.dead_link = 2;
+    config.min_rto = Some(50);
+    let listener = KcpListener::bind_with_config(&local(), config, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let connector = connector_on(7, &handle);
+    let client_addr = connector.local_addr().unwrap();
+    let connect = connector.connect(&addr);
+    let (client, server, events) = accept_first(&mut core, listener, Box::new(connect));
+    let (_, events) = next_event(&mut core, events);
+
+    // nobody acknowledges what the server sends from now on
+    client.set_linger(None);
+    drop((client, connector));
+    let (server, _) = core.run(write_all(server, *b"anyone?")).unwrap();
+    let (expired, _) = next_event(&mut core, events);
+    assert_eq!(expired, SessionEvent::Expired { addr: client_addr, conv: 7 });
+    assert_eq!(server.state(), ConnectionState::Broken);
+}
+
+#[test]
+fn closed_sessions_free_their_conv() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = KcpListener::bind(&local(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let first = connector_on(7, &handle);
+    let connect = first.connect(&addr);
+    let (client, server, events) = accept_first(&mut core, listener, Box::new(connect));
+    let (_, events) = next_event(&mut core, events);
+
+    // gone from the table without another datagram of the peer
+    server.set_linger(None);
+    drop(server);
+    let (closed, events) = next_event(&mut core, events);
+    assert_eq!(closed, SessionEvent::Closed { addr: first.local_addr().unwrap(), conv: 7 });
+
+    // so another peer may open a session on the same conv
+    let second = connector_on(7, &handle);
+    let other = core.run(second.connect(&addr).and_then(|s| write_all(s, *b"hi"))).unwrap();
+    let (opened, _) = next_event(&mut core, events);
+    assert_eq!(opened, SessionEvent::Opened { addr: second.local_addr().unwrap(), conv: 7 });
+    drop((client, other));
+}
+
+#[test]
+fn conv_collisions_are_refused() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = KcpListener::bind(&local(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let first = connector_on(7, &handle);
+    let connect = first.connect(&addr);
+    let (client, _server, events) = accept_first(&mut core, listener, Box::new(connect));
+    let (_, events) = next_event(&mut core, events);
+
+    let second = connector_on(7, &handle);
+    let other = core.run(second.connect(&addr).and_then(|s| write_all(s, *b"hi"))).unwrap();
+    let (collision, _) = next_event(&mut core, events);
+    assert_eq!(
+        collision,
+        SessionEvent::Collision {
+            addr: second.local_addr().unwrap(),
+            conv: 7,
+            owner: first.local_addr().unwrap(),
+        }
+    );
+    drop((client, other));
+}
+
+#[test]
+fn sessions_past_the_limit_are_refused() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    listener.set_max_sessions(1);
+    let addr = listener.local_addr().unwrap();
+
+    let connect = KcpStream::connect(&addr, &handle);
+    let (client, _server, events) = accept_first(&mut core, listener, Box::new(connect));
+    let (_, events) = next_event(&mut core, events);
+
+    let (other, _) = core.run(KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"hi")))
+        .unwrap();
+    let (rejected, _) = next_event(&mut core, events);
+    match rejected {
+        SessionEvent::Rejected { conv, .. } => assert_eq!(conv, other.conv()),
+        other => panic!("not rejected: {:?}", other),
+    }
+    // the refused peer is reset instead of retrying
+    sleep(&mut core, 100);
+    assert_eq!(other.state(), ConnectionState::Broken);
+    drop(client);
+}
+
+#[test]
+fn tick_budget_paces_sessions() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    // about 100 segments a second
+    listener.set_tick_budget(1);
+    let addr = listener.local_addr().unwrap();
+
+    let connect = KcpStream::connect(&addr, &handle);
+    let (client, server, _) = accept_first(&mut core, listener, Box::new(connect));
+    let start = Instant::now();
+    let send = write_all(server, vec![0; 20 * 1024]);
+    let recv = read_exact(client, vec![0; 20 * 1024]);
+    core.run(send.join(recv)).unwrap();
+    // the writes flushing right away draw on the budget as well
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn multi_listeners_answer_from_the_socket_asked() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let free = || UdpSocket::bind(local()).unwrap().local_addr().unwrap();
+    let addrs = [free(), free()];
+    let mut listener = KcpListener::bind_multi(&addrs, &handle).unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addrs[0]);
+
+    let mut clients = Vec::new();
+    for addr in &addrs {
+        let (client, _) = core.run(KcpStream::connect(addr, &handle).and_then(|s| write_all(s, *b"hi")))
+            .unwrap();
+        clients.push(client);
+    }
+    let servers = serve_for(&mut core, &mut listener, 200);
+    assert_eq!(servers.len(), 2);
+    for server in servers {
+        core.run(write_all(server, *b"ok")).unwrap();
+    }
+    // connected streams only take datagrams from the address they asked
+    for client in clients {
+        let (_, reply) = core.run(read_exact(client, [0; 2])).unwrap();
+        assert_eq!(&reply, b"ok");
+    }
+}
+
+#[test]
+fn listener_from_std_socket() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let socket = UdpSocket::bind(local()).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let listener = KcpListener::from_std(socket, KcpConfig::default(), &handle).unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+
+    let connect = KcpStream::connect(&addr, &handle);
+    let (client, server, _) = accept_first(&mut core, listener, Box::new(connect));
+    core.run(write_all(server, *b"ok")).unwrap();
+    let (_, reply) = core.run(read_exact(client, [0; 2])).unwrap();
+    assert_eq!(&reply, b"ok");
+}
+
+#[test]
+fn stats_count_sessions_over_their_life() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (client, _) = core.run(KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"hi")))
+        .unwrap();
+    let server = serve_for(&mut core, &mut listener, 200).pop().unwrap();
+    let stats = listener.stats();
+    assert_eq!((stats.sessions, stats.accepted, stats.closed), (1, 1, 0));
+    assert!(stats.accept_rate > 0.0);
+
+    let (server, _) = core.run(write_all(server, *b"hello")).unwrap();
+    core.run(read_exact(client, [0; 5])).unwrap();
+    // the ack comes in through the listener
+    serve_for(&mut core, &mut listener, 100);
+    assert_eq!(listener.stats().delivered, 5);
+
+    // what a closed session delivered stays in the totals
+    server.set_linger(None);
+    drop(server);
+    serve_for(&mut core, &mut listener, 1200);
+    let stats = listener.stats();
+    assert_eq!((stats.sessions, stats.accepted, stats.closed), (0, 1, 1));
+    assert_eq!(stats.delivered, 5);
+}
+
+#[test]
+fn sessions_need_a_valid_token() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    listener.set_authenticator(|_, _, token| token == b"secret");
+    let events = listener.events();
+    let addr = listener.local_addr().unwrap();
+
+    let connect = |token: &[u8]| {
+        KcpStream::connect_with_token(&addr, &handle, token).and_then(|s| write_all(s, *b"hi"))
+    };
+    let (intruder, _) = core.run(connect(b"guess")).unwrap();
+    let (client, _) = core.run(connect(b"secret")).unwrap();
+    let mut servers = serve_for(&mut core, &mut listener, 200);
+    assert_eq!(servers.len(), 1);
+    // the token is not part of what the session reads
+    let (_, hi) = core.run(read_exact(servers.pop().unwrap(), [0; 2])).unwrap();
+    assert_eq!(&hi, b"hi");
+
+    let events: Vec<_> = core.run(events.take(2).collect()).unwrap();
+    let convs: Vec<_> = events
+        .iter()
+        .map(|event| match *event {
+            SessionEvent::Rejected { conv, .. } => (false, conv),
+            SessionEvent::Opened { conv, .. } => (true, conv),
+            ref other => panic!("unexpected {:?}", other),
+        })
+        .collect();
+    assert_eq!(convs, vec![(false, intruder.conv()), (true, client.conv())]);
+    // and the refused peer is reset
+    sleep(&mut core, 100);
+    assert_eq!(intruder.state(), ConnectionState::Broken);
+}
+
+#[test]
+fn listeners_only_admit_convs_their_allocator_owns() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    listener.set_conv_allocator(SequentialConv::new(100, 199));
+    let events = listener.events();
+    let addr = listener.local_addr().unwrap();
+
+    let mut tenant = KcpConnector::bind(&local(), &handle).unwrap();
+    tenant.set_conv_allocator(SequentialConv::new(150, 150));
+    let stranger = connector_on(7, &handle);
+    let (client, _) = core.run(tenant.connect(&addr).and_then(|s| write_all(s, *b"hi"))).unwrap();
+    assert_eq!(client.conv(), 150);
+    let (other, _) = core.run(stranger.connect(&addr).and_then(|s| write_all(s, *b"hi"))).unwrap();
+    let servers = serve_for(&mut core, &mut listener, 200);
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].conv(), 150);
+
+    let events: Vec<_> = core.run(events.take(2).collect()).unwrap();
+    let convs: Vec<_> = events
+        .iter()
+        .map(|event| match *event {
+            SessionEvent::Rejected { conv, .. } => (false, conv),
+            SessionEvent::Opened { conv, .. } => (true, conv),
+            ref other => panic!("unexpected {:?}", other),
+        })
+        .collect();
+    assert_eq!(convs, vec![(true, 150), (false, other.conv())]);
+
+    // the tenant's range is used up for this listener
+    let err = core.run(tenant.connect(&addr)).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+}
+
+#[test]
+fn bind_errors_are_returned() {
+    let core = Core::new().unwrap();
+    let handle = core.handle();
+    let taken = UdpSocket::bind(local()).unwrap();
+    let addr = taken.local_addr().unwrap();
+    let err = KcpListener::bind_with_config(&addr, KcpConfig::default(), &handle).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+}
+
+/// a push of `data` on `conv` as the first segment of a session
+fn first_push(conv: u32, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&conv.to_le_bytes());
+    buf.extend_from_slice(&[81, 0]);
+    buf.extend_from_slice(&128u16.to_le_bytes());
+    buf.extend_from_slice(&[0; 12]); // ts, sn and una
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[test]
+fn closed_sessions_are_retired_for_the_time_wait() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let mut listener = KcpListener::bind(&local(), &handle).unwrap();
+    listener.set_time_wait(Duration::from_millis(300));
+    let addr = listener.local_addr().unwrap();
+    let peer = UdpSocket::bind(local()).unwrap();
+
+    peer.send_to(&first_push(7, b"hi"), addr).unwrap();
+    let accepted = serve_for(&mut core, &mut listener, 100);
+    assert_eq!(accepted.len(), 1);
+    accepted[0].set_linger(None);
+    drop(accepted);
+    serve_for(&mut core, &mut listener, 50);
+
+    // late retransmits of the closed session open nothing
+    for _ in 0..2 {
+        peer.send_to(&first_push(7, b"hi"), addr).unwrap();
+        assert!(serve_for(&mut core, &mut listener, 100).is_empty());
+    }
+
+    // until the time wait is over
+    sleep(&mut core, 300);
+    peer.send_to(&first_push(7, b"hi"), addr).unwrap();
+    assert_eq!(serve_for(&mut core, &mut listener, 100).len(), 1);
+}
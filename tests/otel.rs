@@ -0,0 +1,123 @@
+#![cfg(feature = "otel")]
+extern crate futures;
+extern crate kcp;
+extern crate opentelemetry;
+extern crate tokio_core;
+
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::Stream;
+use kcp::otel::trace_session;
+use kcp::{test_util, ConnectionState};
+use opentelemetry::trace::{Span, SpanBuilder, SpanContext, Status, Tracer, TracerProvider};
+use opentelemetry::{global, Context, InstrumentationScope, KeyValue};
+use tokio_core::reactor::{Core, Timeout};
+
+/// name, attributes and whether it ended, of every span started
+#[derive(Clone, Default)]
+struct Spans(Arc<Mutex<Vec<(String, Vec<KeyValue>, bool)>>>);
+
+impl Spans {
+    fn ended(&self, name: &str) -> usize {
+        let spans = self.0.lock().unwrap();
+        spans.iter().filter(|span| span.0 == name && span.2).count()
+    }
+
+    fn attribute(&self, name: &str, key: &str) -> Option<String> {
+        let spans = self.0.lock().unwrap();
+        let span = spans.iter().find(|span| span.0 == name)?;
+        span.1.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string())
+    }
+}
+
+struct RecordedSpan {
+    spans: Spans,
+    index: usize,
+    context: SpanContext,
+}
+
+impl Span for RecordedSpan {
+    fn add_event_with_timestamp<T>(&mut self, _name: T, _timestamp: SystemTime, _attributes: Vec<KeyValue>)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+    }
+
+    fn span_context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    fn is_recording(&self) -> bool {
+        true
+    }
+
+    fn set_attribute(&mut self, attribute: KeyValue) {
+        self.spans.0.lock().unwrap()[self.index].1.push(attribute);
+    }
+
+    fn set_status(&mut self, _status: Status) {}
+
+    fn update_name<T>(&mut self, _new_name: T)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+    }
+
+    fn add_link(&mut self, _span_context: SpanContext, _attributes: Vec<KeyValue>) {}
+
+    fn end_with_timestamp(&mut self, _timestamp: SystemTime) {
+        self.spans.0.lock().unwrap()[self.index].2 = true;
+    }
+}
+
+#[derive(Clone)]
+struct Recorder(Spans);
+
+impl Tracer for Recorder {
+    type Span = RecordedSpan;
+
+    fn build_with_context(&self, builder: SpanBuilder, _parent_cx: &Context) -> RecordedSpan {
+        let mut spans = (self.0).0.lock().unwrap();
+        spans.push((builder.name.into_owned(), builder.attributes.unwrap_or_default(), false));
+        RecordedSpan {
+            spans: self.0.clone(),
+            index: spans.len() - 1,
+            context: SpanContext::empty_context(),
+        }
+    }
+}
+
+impl TracerProvider for Recorder {
+    type Tracer = Recorder;
+
+    fn tracer_with_scope(&self, _scope: InstrumentationScope) -> Recorder {
+        self.clone()
+    }
+}
+
+#[test]
+fn session_span_ends_when_broken() {
+    let spans = Spans::default();
+    global::set_tracer_provider(Recorder(spans.clone()));
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (client, server) = core.run(test_util::pair(&handle)).unwrap();
+    let peer = "127.0.0.1:4000".parse().unwrap();
+    trace_session(&server, peer, &handle);
+
+    // the server stream stays around, its span ends with the reset anyway
+    let changes = server.watch_state();
+    client.reset();
+    let states = core.run(changes.collect()).unwrap();
+    assert_eq!(states.last(), Some(&ConnectionState::Broken));
+    core.run(Timeout::new(Duration::from_millis(10), &handle).unwrap()).unwrap();
+    assert_eq!(spans.ended("kcp.session"), 1);
+    assert_eq!(spans.ended("kcp.handshake"), 1);
+    assert_eq!(spans.ended("kcp.close"), 1);
+    assert_eq!(spans.attribute("kcp.session", "net.peer.port"), Some("4000".to_string()));
+    assert_eq!(spans.attribute("kcp.close", "error"), Some("true".to_string()));
+    drop((client, server));
+}
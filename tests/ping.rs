@@ -0,0 +1,133 @@
+#![cfg(feature = "config-file")]
+use std::net::{SocketAddr, UdpSocket};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const PROBE: usize = 200;
+
+/// an address nobody listens on, for the binary to bind
+fn free_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// wait until someone holds `addr`
+fn wait_bound(addr: &SocketAddr) {
+    for _ in 0..100 {
+        if UdpSocket::bind(addr).is_err() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("nobody bound {}", addr);
+}
+
+/// kcp-ping echoing on `addr`
+fn echo_on(addr: &SocketAddr) -> Child {
+    let echo = Command::new(env!("CARGO_BIN_EXE_kcp-ping"))
+        .args(["-l", &addr.to_string()])
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_bound(addr);
+    echo
+}
+
+/// a relay to `server` dropping every `nth` datagram sent to it that is
+/// large enough to carry a probe of `PROBE` bytes, so there is always data
+/// to retransmit, returns the address to ping and the flag stopping it
+fn lossy_relay(server: SocketAddr, nth: usize) -> (SocketAddr, Arc<AtomicBool>) {
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    relay.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    let addr = relay.local_addr().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    thread::spawn(move || {
+        let mut client = None;
+        let mut buf = [0; 64 * 1024];
+        let mut to_server = 0;
+        while !stopped.load(Ordering::SeqCst) {
+            let (n, from) = match relay.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(_) => continue,
+            };
+            if from == server {
+                if let Some(client) = client {
+                    let _ = relay.send_to(&buf[..n], client);
+                }
+                continue;
+            }
+            client = Some(from);
+            if n >= PROBE {
+                to_server += 1;
+            }
+            if n < PROBE || to_server % nth != 0 {
+                let _ = relay.send_to(&buf[..n], server);
+            }
+        }
+    });
+    (addr, stop)
+}
+
+fn ping(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_kcp-ping"))
+        .args(args)
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// the probes sent and answered, the loss and the retransmits of a report
+fn summary(report: &str) -> (u32, u32, String, u32) {
+    let line = report.lines().find(|line| line.contains("probes sent")).unwrap();
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    (
+        words[0].parse().unwrap(),
+        words[3].trim_end_matches(',').parse().unwrap(),
+        words[5].to_owned(),
+        words[7].parse().unwrap(),
+    )
+}
+
+#[test]
+fn reports_every_profile_over_a_lossy_link() {
+    let server = free_addr();
+    let mut echo = echo_on(&server);
+    let (relay, stop) = lossy_relay(server, 4);
+
+    let (size, to) = (PROBE.to_string(), relay.to_string());
+    let modes = "normal,fast";
+    let out = ping(&["--mode", modes, "-c", "20", "-i", "10", "-s", &size, "-w", "3000", &to]);
+    stop.store(true, Ordering::SeqCst);
+    echo.kill().unwrap();
+    let _ = echo.wait();
+
+    let reports = out.split("--- ").skip(1).collect::<Vec<_>>();
+    assert_eq!(reports.len(), 2, "{}", out);
+    for (report, mode) in reports.iter().zip(&["normal", "fast"]) {
+        assert!(report.starts_with(&format!("{} ping statistics, mode {} ---", relay, mode)));
+        // every probe gets through, it just takes retransmits
+        let (sent, answered, lost, retransmits) = summary(report);
+        assert_eq!((sent, answered, &lost[..]), (20, 20, "0.0%"), "{}", report);
+        assert!(retransmits > 0, "{}", report);
+        assert!(report.contains("rtt min/avg/p50/p90/p99/max = "), "{}", report);
+        assert!(report.contains("srtt "), "{}", report);
+    }
+}
+
+#[test]
+fn unanswered_probes_are_lost() {
+    // takes the probes and never answers
+    let hole = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = hole.local_addr().unwrap();
+
+    let out = ping(&["-c", "5", "-i", "10", "-w", "200", &addr.to_string()]);
+    let (sent, answered, lost, _) = summary(&out);
+    assert_eq!((sent, answered, &lost[..]), (5, 0, "100.0%"));
+    assert!(!out.contains("rtt min"));
+    drop(hole);
+}
@@ -0,0 +1,164 @@
+extern crate kcp;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kcp::session::Context;
+use kcp::{Kcb, KcpConfig, SessionManager, SessionMap, SharedSessionMap};
+
+#[derive(Clone, Default)]
+struct Pipe {
+    packets: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl Pipe {
+    fn drain(&self) -> Vec<Vec<u8>> {
+        self.packets.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.packets.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn session_map() {
+    let mut map = SessionMap::with_shards(4);
+    let peers: Vec<SocketAddr> = (0..100)
+        .map(|port| format!("127.0.0.1:{}", 4000 + port).parse().unwrap())
+        .collect();
+    for (i, peer) in peers.iter().enumerate() {
+        assert_eq!(map.insert(*peer, i), None);
+    }
+    assert_eq!(map.len(), 100);
+    assert_eq!(map.insert(peers[7], 70), Some(7));
+    assert_eq!(map.len(), 100);
+    assert_eq!(map[&peers[7]], 70);
+
+    *map.get_mut(&peers[8]).unwrap() = 80;
+    assert_eq!(map.remove(&peers[8]), Some(80));
+    assert_eq!(map.remove(&peers[8]), None);
+    assert!(!map.contains_key(&peers[8]));
+    assert_eq!(map.len(), 99);
+
+    let mut seen: Vec<usize> = map.iter().map(|(_, i)| *i).collect();
+    seen.sort();
+    assert_eq!(seen.len(), 99);
+    assert_eq!(seen[98], 99);
+}
+
+#[test]
+fn session_ids() {
+    let mut map = SessionMap::with_shards(4);
+    let a: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let b: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    let id = map.insert_new(a, "a");
+    assert_eq!(map.id(&a), Some(id));
+    assert_eq!(map.key(id), Some(&a));
+    *map.by_id_mut(id).unwrap() = "A";
+    assert_eq!(map.insert(a, "a2"), Some("A"));
+    assert_eq!(map.id(&a), Some(id));
+
+    assert_eq!(map.remove_id(id), Some("a2"));
+    assert_eq!(map.by_id(id), None);
+    // the slot is taken again, the stale id does not reach the newcomer
+    let id_b = map.insert_new(b, "b");
+    assert_ne!(id_b, id);
+    assert_eq!(map.by_id(id), None);
+    assert_eq!(map.remove_id(id), None);
+    assert_eq!(map[&b], "b");
+
+    map.insert(a, "a");
+    map.retain(|_, v| *v != "b");
+    assert_eq!(map.len(), 1);
+    assert!(map.get(&b).is_none());
+}
+
+#[test]
+fn shared_session_map() {
+    let map = Arc::new(SharedSessionMap::with_shards(4));
+    let a: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let b: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    map.insert(a, Arc::new(Mutex::new(1)));
+    map.insert(b, Arc::new(Mutex::new(2)));
+    assert_eq!(map.len(), 2);
+
+    // a session held by one thread does not keep another from the rest
+    let held = map.get(&a).unwrap();
+    let guard = held.lock().unwrap();
+    let other = map.clone();
+    thread::spawn(move || {
+        *other.get(&b).unwrap().lock().unwrap() = 20;
+        other.insert("127.0.0.1:4002".parse().unwrap(), Arc::new(Mutex::new(3)));
+    }).join()
+        .unwrap();
+    drop(guard);
+    assert_eq!(*map.get(&b).unwrap().lock().unwrap(), 20);
+
+    map.retain(|_, n| *n != 20);
+    assert!(!map.contains_key(&b));
+    assert_eq!(map.len(), 2);
+    assert!(map.remove(&a).is_some());
+    assert!(map.get(&a).is_none());
+}
+
+#[test]
+fn session_manager() {
+    let wire = Pipe::default();
+    let out = wire.clone();
+    let mut manager = SessionManager::new(KcpConfig::default(), move |_: &SocketAddr| out.clone());
+    manager.set_max_sessions(2);
+    manager.set_idle_timeout(Some(Duration::from_secs(30)));
+    let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let now = Instant::now();
+    let cx = Context::new(now);
+
+    // three conversations of the same peer, room for two
+    let mut first = Vec::new();
+    for conv in 1..4 {
+        let pipe = Pipe::default();
+        let mut kcb = Kcb::new(conv, pipe.clone());
+        kcb.send(b"hello").unwrap();
+        kcb.update(0);
+        kcb.flush();
+        first.push(pipe.drain().remove(0));
+    }
+    let a = manager.input(&cx, peer, &first[0]).unwrap();
+    let b = manager.input(&cx, peer, &first[1]).unwrap();
+    assert_ne!(a, b);
+    assert_eq!(manager.input(&cx, peer, &first[0]).unwrap(), a);
+    assert_eq!(manager.accept(), Some(a));
+    assert_eq!(manager.accept(), Some(b));
+    assert_eq!(manager.accept(), None);
+    assert_eq!(manager.key(b), Some((peer, 2)));
+    let mut buf = [0; 8];
+    assert_eq!(manager.get_mut(a).unwrap().get_mut().recv(&mut buf).unwrap(), 5);
+
+    // the third is refused with a reset
+    wire.drain();
+    let e = manager.input(&cx, peer, &first[2]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    assert_eq!(manager.rejected(), 1);
+    assert_eq!(wire.drain().len(), 1);
+
+    assert!(manager.deadline().unwrap() <= now + Duration::from_secs(30));
+    manager.update(&Context::new(now + Duration::from_secs(10)));
+    assert_eq!(manager.len(), 2);
+    let dropped = manager.update(&Context::new(now + Duration::from_secs(31)));
+    assert_eq!(dropped.len(), 2);
+    assert_eq!(manager.expired(), 2);
+    assert!(manager.is_empty());
+    assert!(manager.get(a).is_none());
+}
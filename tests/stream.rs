@@ -0,0 +1,557 @@
+extern crate bytes;
+extern crate futures;
+extern crate kcp;
+#[cfg(unix)]
+extern crate libc;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{self, Cursor, Read, Write};
+#[cfg(unix)]
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+#[cfg(unix)]
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+use bytes::{BigEndian, ByteOrder, LittleEndian};
+use futures::{future, Async, Future, Stream};
+use kcp::{test_util, ConnectionState, KcpConfig, KcpConnector, KcpEndpoint, KcpListener,
+          KcpStream, ReconnectingKcpStream, ShortBuffer};
+use tokio_core::reactor::{Core, Timeout};
+use tokio_io::io::{read, read_exact, write_all};
+
+#[test]
+fn send_all_from_listener() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let data = (0..256 * 1024).map(|i| (i / 3) as u8).collect::<Vec<u8>>();
+
+    // full sized datagrams either way, none may be cut short
+    let expected = data.clone();
+    let send = KcpStream::connect(&addr, &handle)
+        .and_then(|client| client.send_all_from(Cursor::new(data)));
+    let spawner = handle.clone();
+    let recv = listener
+        .incoming()
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(accepted, incoming)| {
+            spawner.spawn(incoming.for_each(|_| Ok(())).map_err(|_| ()));
+            let (server, _) = accepted.unwrap();
+            read_exact(server, vec![0; 256 * 1024])
+        });
+    let (_, (_, received)) = core.run(send.join(recv)).unwrap();
+    assert!(received == expected);
+}
+
+#[test]
+fn short_reads_name_the_message_size() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (client, mut server) = core.run(test_util::pair(&handle)).unwrap();
+    let (_client, _) = core.run(write_all(client, [7; 100])).unwrap();
+
+    let mut read_into = |buf: &mut [u8]| {
+        core.run(future::poll_fn(|| match server.read(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            result => Ok::<_, ()>(Async::Ready(result)),
+        })).unwrap()
+    };
+    let e = read_into(&mut [0; 10]).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let short = e.get_ref().and_then(|e| e.downcast_ref::<ShortBuffer>()).unwrap();
+    assert_eq!(short.needed, 100);
+    // the message is still there for a buffer it fits
+    assert_eq!(read_into(&mut [0; 100]).unwrap(), 100);
+}
+
+#[test]
+fn linger_expiry_resets_peer() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (client, server) = core.run(test_util::pair(&handle)).unwrap();
+
+    // more than the window of a peer that never reads, the close can't
+    // get across before the linger is over
+    let (mut client, _) = core.run(write_all(client, [0; 1000])).unwrap();
+    client.set_linger(Some(Duration::from_millis(200)));
+    core.run(future::lazy(move || {
+        for _ in 1..200 {
+            client.write(&[0; 1000]).ok();
+        }
+        Ok::<(), ()>(())
+    })).unwrap();
+
+    core.run(Timeout::new(Duration::from_secs(1), &handle).unwrap()).unwrap();
+    let e = core.run(read(server, [0; 1000])).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn negotiation_keeps_message_mode() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut listener = KcpListener::bind_with_config(&local, KcpConfig::default(), &handle).unwrap();
+    listener.set_negotiation(true);
+    let addr = listener.local_addr().unwrap();
+
+    // the listener keeps message boundaries, a client asking for stream
+    // mode has to keep them too
+    let config = KcpConfig {
+        stream: true,
+        ..KcpConfig::default()
+    };
+    let client = KcpStream::connect_negotiated(&addr, config, &handle);
+    let spawner = handle.clone();
+    let accept = listener
+        .incoming()
+        .into_future()
+        .map_err(|(e, _)| e)
+        .map(move |(accepted, incoming)| {
+            spawner.spawn(incoming.for_each(|_| Ok(())).map_err(|_| ()));
+            accepted.unwrap().0
+        });
+    let (client, server) = core.run(client.join(accept)).unwrap();
+
+    let write = write_all(client, b"ab").and_then(|(client, _)| write_all(client, b"cd"));
+    let (_client, _) = core.run(write).unwrap();
+    core.run(Timeout::new(Duration::from_millis(200), &handle).unwrap()).unwrap();
+    let (_, buf, n) = core.run(read(server, [0; 16])).unwrap();
+    assert_eq!(&buf[..n], b"ab");
+}
+
+#[test]
+fn state_changes_end_on_final_state() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let (client, server) = core.run(test_util::pair(&handle)).unwrap();
+
+    // both streams stay around, the changes end with the reset all the same
+    let client_changes = client.watch_state();
+    let server_changes = server.watch_state();
+    client.reset();
+    let states = core.run(client_changes.collect()).unwrap();
+    assert_eq!(states, [ConnectionState::Connecting, ConnectionState::Broken]);
+    let states = core.run(server_changes.collect()).unwrap();
+    assert_eq!(states, [ConnectionState::Connecting, ConnectionState::Broken]);
+
+    // watching a stream that is done with yields where it ended up
+    let states = core.run(server.watch_state().collect()).unwrap();
+    assert_eq!(states, [ConnectionState::Broken]);
+    drop((client, server));
+}
+
+#[test]
+fn workers_echo_every_session() {
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let workers = KcpListener::spawn_workers(&local, 3, KcpConfig::default(), |stream, _, _| {
+        read_exact(stream, [0; 5])
+            .and_then(|(stream, buf)| write_all(stream, buf))
+            .map(|_| ())
+            .map_err(|e| panic!("worker: {}", e))
+    }).unwrap();
+    let addr = workers.local_addr();
+
+    // eight sessions, sharded by their conv over the three workers
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let clients = (0..8u8).map(|i| {
+        KcpStream::connect(&addr, &handle)
+            .and_then(move |stream| write_all(stream, [i; 5]))
+            .and_then(|(stream, _)| read_exact(stream, [0; 5]))
+            .map(move |(_, buf)| (i, buf))
+    }).collect::<Vec<_>>();
+    for (i, buf) in core.run(future::join_all(clients)).unwrap() {
+        assert_eq!(buf, [i; 5]);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn hand_off_carries_sockets_and_sessions() {
+    use futures::future::Either;
+    use std::os::unix::net::UnixStream;
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // a session still open at the hand off
+    let (old, _) = core.run(KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"old")))
+        .unwrap();
+    let (accepted, incoming) = core.run(listener.incoming().into_future()).map_err(|(e, _)| e).unwrap();
+    let (server, _) = accepted.unwrap();
+
+    let (from, to) = UnixStream::pair().unwrap();
+    incoming.into_inner().hand_off(&from).unwrap();
+    let listener = KcpListener::take_over(&to, KcpConfig::default(), &handle).unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+
+    // what the old session sends on is dropped, not taken for a new one
+    handle.spawn(write_all(old, *b"again").map(|_| ()).map_err(|_| ()));
+    let wait = Timeout::new(Duration::from_millis(200), &handle).unwrap();
+    let incoming = match core.run(listener.incoming().into_future().select2(wait)) {
+        Ok(Either::B((_, pending))) => pending.into_inner().unwrap(),
+        _ => panic!("the old session was accepted"),
+    };
+
+    let new = KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"new"));
+    let spawner = handle.clone();
+    let accept = incoming.into_future().map_err(|(e, _)| e).and_then(move |(accepted, rest)| {
+        spawner.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+        read_exact(accepted.unwrap().0, [0; 3])
+    });
+    let (_, (_, buf)) = core.run(new.join(accept)).unwrap();
+    assert_eq!(&buf, b"new");
+    drop(server);
+}
+
+#[test]
+fn connect_resolves_host_names() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // localhost may resolve to ::1 as well, where nobody answers
+    let client = KcpStream::connect_host(format!("localhost:{}", port), &handle)
+        .and_then(|stream| write_all(stream, *b"ping"))
+        .and_then(|(stream, _)| read_exact(stream, [0; 4]));
+    let spawner = handle.clone();
+    let server = listener.incoming().into_future().map_err(|(e, _)| e).and_then(move |(accepted, rest)| {
+        spawner.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+        read_exact(accepted.unwrap().0, [0; 4]).and_then(|(stream, buf)| write_all(stream, buf))
+    });
+    let ((_, buf), _) = core.run(client.join(server)).unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[test]
+fn flush_now_sends_held_acks() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    // acks of the server wait a second for its next tick
+    let config = KcpConfig {
+        interval: 1000,
+        ..KcpConfig::default()
+    };
+    let listener = KcpListener::bind_with_config(&local, config, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let spawner = handle.clone();
+    let accept = listener.incoming().into_future().map_err(|(e, _)| e).map(move |(accepted, rest)| {
+        spawner.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+        accepted.unwrap().0
+    });
+    let connect = KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"hi"));
+    let ((client, _), server) = core.run(connect.join(accept)).unwrap();
+    let (server, _) = core.run(read_exact(server, [0; 2])).unwrap();
+    core.run(Timeout::new(Duration::from_millis(50), &handle).unwrap()).unwrap();
+
+    let (client, _) = core.run(write_all(client, *b"again")).unwrap();
+    let (server, _) = core.run(read_exact(server, [0; 5])).unwrap();
+    assert_eq!(client.waitsnd(), 1);
+    server.flush_now().unwrap();
+    core.run(Timeout::new(Duration::from_millis(50), &handle).unwrap()).unwrap();
+    assert_eq!(client.waitsnd(), 0);
+}
+
+#[test]
+fn flush_outside_task() {
+    let core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // fresh sockets, the reactor never ran to report them writable
+    for _ in 0..20 {
+        let connector = KcpConnector::bind(&local, &handle).unwrap();
+        let stream = match connector.connect(&addr).poll() {
+            Ok(Async::Ready(stream)) => stream,
+            _ => panic!("connect is not immediate"),
+        };
+        stream.ask_window();
+        stream.flush_now().unwrap();
+        stream.close(0, "");
+        drop(stream);
+    }
+}
+
+#[test]
+fn connector_sessions_share_a_socket() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut server = KcpEndpoint::bind(&local, &handle).unwrap();
+    let incoming = server.incoming();
+    let connector = KcpConnector::bind(&local, &handle).unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let one = connector.connect(&addr).and_then(|s| write_all(s, *b"one"));
+    let two = connector.connect(&addr).and_then(|s| write_all(s, *b"two"));
+    let accept = incoming.take(2).collect();
+    let (((one, _), (two, _)), accepted) = core.run(one.join(two).join(accept)).unwrap();
+    assert_ne!(one.conv(), two.conv());
+    // told apart by conv though they come from one address
+    for (stream, peer) in accepted {
+        assert_eq!(peer, connector.local_addr().unwrap());
+        let (stream, msg) = core.run(read_exact(stream, [0; 3])).unwrap();
+        let reply = if &msg == b"one" { *b"1st" } else { *b"2nd" };
+        core.run(write_all(stream, reply)).unwrap();
+    }
+    let (_, reply) = core.run(read_exact(one, [0; 3])).unwrap();
+    assert_eq!(&reply, b"1st");
+    let (_, reply) = core.run(read_exact(two, [0; 3])).unwrap();
+    assert_eq!(&reply, b"2nd");
+}
+
+#[test]
+fn reconnect_replays_unacked_messages() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    // a peer that never answers
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut stream = ReconnectingKcpStream::connect(&silent.local_addr().unwrap(), &handle);
+    stream.set_timeout(Duration::from_millis(200));
+    stream.set_backoff(Duration::from_millis(50), Duration::from_millis(400));
+    stream.set_replay(true);
+
+    let (stream, _) = core.run(write_all(stream, *b"hello")).unwrap();
+    handle.spawn(read(stream, [0; 16]).then(|_| -> Result<(), ()> {
+        panic!("read from a silent peer")
+    }));
+
+    // the message goes out again on every new session
+    silent.set_nonblocking(true).unwrap();
+    let mut convs = Vec::new();
+    let mut buf = [0; 1500];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while convs.len() < 2 && Instant::now() < deadline {
+        core.run(Timeout::new(Duration::from_millis(50), &handle).unwrap()).unwrap();
+        while let Ok(n) = silent.recv(&mut buf) {
+            let conv = LittleEndian::read_u32(&buf[..4]);
+            if buf[..n].windows(5).any(|w| w == b"hello") && !convs.contains(&conv) {
+                convs.push(conv);
+            }
+        }
+    }
+    assert!(convs.len() >= 2, "sessions {:?}", convs);
+}
+
+/// a SOCKS5 proxy with a UDP relay for a single association, returns
+/// the address of the proxy and of its relay
+fn socks5_proxy() -> (SocketAddr, SocketAddr) {
+    let control = TcpListener::bind("127.0.0.1:0").unwrap();
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (proxy, relay_addr) = (control.local_addr().unwrap(), relay.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut tcp, _) = control.accept().unwrap();
+        let mut greeting = [0; 3];
+        tcp.read_exact(&mut greeting).unwrap();
+        tcp.write_all(&[5, 0]).unwrap();
+        // UDP ASSOCIATE with an IPv4 address
+        let mut request = [0; 10];
+        tcp.read_exact(&mut request).unwrap();
+        assert_eq!(&request[..4], &[5, 3, 0, 1]);
+        let mut reply = vec![5, 0, 0, 1, 127, 0, 0, 1, 0, 0];
+        BigEndian::write_u16(&mut reply[8..], relay_addr.port());
+        tcp.write_all(&reply).unwrap();
+
+        relay.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut client = None;
+        let mut buf = [0; 2048];
+        while let Ok((n, from)) = relay.recv_from(&mut buf) {
+            // the first datagram comes from the client
+            if client.is_none() || Some(from) == client {
+                client = Some(from);
+                let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+                let dst = SocketAddr::new(ip.into(), BigEndian::read_u16(&buf[8..10]));
+                relay.send_to(&buf[10..n], dst).unwrap();
+            } else {
+                let mut datagram = vec![0, 0, 0, 1];
+                datagram.extend_from_slice(&[127, 0, 0, 1, 0, 0]);
+                BigEndian::write_u16(&mut datagram[8..], from.port());
+                datagram.extend_from_slice(&buf[..n]);
+                relay.send_to(&datagram, client.unwrap()).unwrap();
+            }
+        }
+        drop(tcp);
+    });
+    (proxy, relay_addr)
+}
+
+#[test]
+fn connect_through_socks5() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (proxy, relay) = socks5_proxy();
+
+    let client = KcpStream::connect_socks5(&proxy, &addr, &handle).and_then(|s| write_all(s, *b"hi"));
+    let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+    let ((client, _), (accepted, _)) = core.run(client.join(accept)).unwrap();
+    let (server, peer) = accepted.unwrap();
+    // the server only ever sees the relay
+    assert_eq!(peer, relay);
+    let (server, _) = core.run(read_exact(server, [0; 2])).unwrap();
+    core.run(write_all(server, *b"ok")).unwrap();
+    let (_, reply) = core.run(read_exact(client, [0; 2])).unwrap();
+    assert_eq!(&reply, b"ok");
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_socket_options() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let stream = core.run(KcpStream::connect(&listener.local_addr().unwrap(), &handle)).unwrap();
+
+    let rcvbuf = |fd| {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ptr = &mut value as *mut libc::c_int as *mut libc::c_void;
+        let ret = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, ptr, &mut len) };
+        assert_eq!(ret, 0);
+        value
+    };
+    let size: libc::c_int = 256 * 1024;
+    let value = unsafe {
+        slice::from_raw_parts(&size as *const libc::c_int as *const u8, mem::size_of::<libc::c_int>())
+    };
+    listener.set_socket_option(libc::SOL_SOCKET, libc::SO_RCVBUF, value).unwrap();
+    stream.set_socket_option(libc::SOL_SOCKET, libc::SO_RCVBUF, value).unwrap();
+    // linux doubles what it is asked for
+    assert!(rcvbuf(listener.as_raw_fd()) >= size);
+    assert!(rcvbuf(stream.as_raw_fd()) >= size);
+    assert_ne!(listener.as_raw_fd(), stream.as_raw_fd());
+
+    // in-process streams have no socket to set options on
+    let (memory, _) = core.run(test_util::pair(&handle)).unwrap();
+    assert_eq!(memory.as_raw_fd(), -1);
+    assert!(memory.set_socket_option(libc::SOL_SOCKET, libc::SO_RCVBUF, value).is_err());
+}
+
+#[test]
+fn ip_socket_options() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let stream = core.run(KcpStream::connect(&listener.local_addr().unwrap(), &handle)).unwrap();
+
+    listener.set_ttl(42).unwrap();
+    stream.set_ttl(43).unwrap();
+    assert_eq!((listener.ttl().unwrap(), stream.ttl().unwrap()), (42, 43));
+    listener.set_broadcast(true).unwrap();
+    assert!(listener.broadcast().unwrap() && !stream.broadcast().unwrap());
+    stream.set_multicast_ttl_v4(5).unwrap();
+    stream.set_multicast_loop_v4(false).unwrap();
+    assert_eq!(stream.multicast_ttl_v4().unwrap(), 5);
+    assert!(!stream.multicast_loop_v4().unwrap() && listener.multicast_loop_v4().unwrap());
+    #[cfg(unix)]
+    {
+        assert!(listener.take_error().unwrap().is_none());
+        assert!(stream.take_error().unwrap().is_none());
+    }
+
+    // in-process streams have no socket
+    let (memory, _) = core.run(test_util::pair(&handle)).unwrap();
+    assert!(memory.set_ttl(42).is_err());
+}
+
+/// a push of `data` on `conv` with sn `sn`, as the peer would send it
+fn push(conv: u32, sn: u32, una: u32, data: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0; 24];
+    LittleEndian::write_u32(&mut datagram[0..4], conv);
+    datagram[4] = 81;
+    LittleEndian::write_u16(&mut datagram[6..8], 128);
+    LittleEndian::write_u32(&mut datagram[12..16], sn);
+    LittleEndian::write_u32(&mut datagram[16..20], una);
+    LittleEndian::write_u32(&mut datagram[20..24], data.len() as u32);
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+#[test]
+fn streams_only_hear_their_peer() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&local, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"hi"));
+    let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+    let ((client, _), (accepted, incoming)) = core.run(client.join(accept)).unwrap();
+    handle.spawn(incoming.for_each(|_| Ok(())).map_err(|_| ()));
+    let (server, client_addr) = accepted.unwrap();
+    let (server, _) = core.run(write_all(server, *b"ok")).unwrap();
+    let (client, _) = core.run(read_exact(client, [0; 2])).unwrap();
+
+    // someone who knows the conv sends the next message of the server
+    let intruder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    intruder.send_to(&push(client.conv(), 1, 1, b"evil"), client_addr).unwrap();
+    core.run(write_all(server, *b"fine")).unwrap();
+    let (client, msg) = core.run(read_exact(client, [0; 4])).unwrap();
+    assert_eq!(&msg, b"fine");
+
+    // unless the stream may follow its peer to a new address
+    client.set_migration(true);
+    intruder.send_to(&push(client.conv(), 2, 1, b"moved"), client_addr).unwrap();
+    let (client, msg) = core.run(read_exact(client, [0; 5])).unwrap();
+    assert_eq!(&msg, b"moved");
+    // and answers it there
+    core.run(write_all(client, *b"!")).unwrap();
+    intruder.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0; 1500];
+    let (n, _) = intruder.recv_from(&mut buf).unwrap();
+    assert!(buf[..n].ends_with(b"!"));
+}
+
+#[test]
+fn endpoint_refuses_closed_sessions() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut a = KcpEndpoint::bind(&local, &handle).unwrap();
+    let mut b = KcpEndpoint::bind(&local, &handle).unwrap();
+    let a_incoming = a.incoming();
+    let b_incoming = b.incoming();
+
+    let client = a.connect(&b.local_addr().unwrap())
+        .and_then(|stream| write_all(stream, *b"hi"));
+    let server = b_incoming.into_future().map_err(|(e, _)| e).and_then(|(accepted, _)| {
+        read_exact(accepted.unwrap().0, [0; 2])
+    });
+    let ((client, _), (server, _)) = core.run(client.join(server)).unwrap();
+
+    // gone without a word, what b still sends comes in late
+    client.set_linger(None);
+    drop(client);
+    core.run(Timeout::new(Duration::from_millis(100), &handle).unwrap()).unwrap();
+    let (server, _) = core.run(write_all(server, *b"late")).unwrap();
+
+    let wait = Timeout::new(Duration::from_millis(500), &handle).unwrap();
+    match core.run(a_incoming.into_future().select2(wait)) {
+        Ok(future::Either::B(_)) => {}
+        _ => panic!("late datagrams opened a session"),
+    }
+    // and b is told the session is gone
+    assert_eq!(server.state(), ConnectionState::Broken);
+}
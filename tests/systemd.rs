@@ -0,0 +1,53 @@
+#![cfg(unix)]
+extern crate futures;
+extern crate kcp;
+extern crate libc;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::env;
+use std::net::UdpSocket;
+use std::os::unix::io::IntoRawFd;
+use std::process;
+
+use futures::{Future, Stream};
+use kcp::{KcpConfig, KcpListener, KcpStream};
+use tokio_core::reactor::Core;
+use tokio_io::io::{read_exact, write_all};
+
+// the first fd systemd passes
+const LISTEN_FDS_START: i32 = 3;
+
+// one test in this file, it owns the environment and fd 3 of the process
+#[test]
+fn listener_from_systemd() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let fd = socket.into_raw_fd();
+    if fd != LISTEN_FDS_START {
+        assert_eq!(unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_GETFD) }, -1, "fd 3 is taken");
+        assert_eq!(unsafe { libc::dup2(fd, LISTEN_FDS_START) }, LISTEN_FDS_START);
+        unsafe { libc::close(fd) };
+    }
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    // sockets meant for another process are left alone
+    env::set_var("LISTEN_PID", (process::id() + 1).to_string());
+    env::set_var("LISTEN_FDS", "1");
+    assert!(KcpListener::from_systemd(KcpConfig::default(), &handle).unwrap().is_none());
+    assert!(env::var("LISTEN_FDS").is_ok());
+
+    env::set_var("LISTEN_PID", process::id().to_string());
+    let listener = KcpListener::from_systemd(KcpConfig::default(), &handle).unwrap().unwrap();
+    assert_eq!(listener.local_addr().unwrap(), addr);
+    // children do not take the sockets as well
+    assert!(env::var("LISTEN_PID").is_err() && env::var("LISTEN_FDS").is_err());
+
+    let client = KcpStream::connect(&addr, &handle).and_then(|s| write_all(s, *b"hi"));
+    let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+    let ((client, _), (accepted, _)) = core.run(client.join(accept)).unwrap();
+    core.run(write_all(accepted.unwrap().0, *b"ok")).unwrap();
+    let (_, reply) = core.run(read_exact(client, [0; 2])).unwrap();
+    assert_eq!(&reply, b"ok");
+}
@@ -0,0 +1,75 @@
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::Cell;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::Future;
+use kcp::test_util;
+use tokio_core::reactor::Core;
+use tokio_io::io::{read_exact, write_all};
+
+#[test]
+fn lossy_pair() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<u8>>();
+
+    let expected = data.clone();
+    let transfer = test_util::pair_with_loss(10, &handle)
+        .and_then(move |(client, server)| {
+            write_all(client, data).join(read_exact(server, vec![0; 64 * 1024]))
+        })
+        .map(|(_, (_, received))| received);
+    let received = core.run(transfer).unwrap();
+    assert!(received == expected);
+}
+
+#[test]
+fn send_all_from() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let data = (0..256 * 1024).map(|i| (i / 7) as u8).collect::<Vec<u8>>();
+
+    let written = Rc::new(Cell::new(0));
+    let progress = written.clone();
+    let expected = data.clone();
+    let transfer = test_util::pair(&handle)
+        .and_then(move |(client, server)| {
+            client.set_send_backlog(64);
+            let send = client
+                .send_all_from(Cursor::new(data))
+                .on_progress(move |written, _| progress.set(written));
+            send.join(read_exact(server, vec![0; 256 * 1024]))
+        })
+        .map(|((n, _, _), (_, received))| (n, received));
+    let (n, received) = core.run(transfer).unwrap();
+    assert_eq!(n, 256 * 1024);
+    assert_eq!(written.get(), 256 * 1024);
+    assert!(received == expected);
+}
+
+#[test]
+fn recv_all_into() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let data = (0..256 * 1024).map(|i| (i / 5) as u8).collect::<Vec<u8>>();
+
+    let expected = data.clone();
+    let transfer = test_util::pair_with_loss(5, &handle).and_then(move |(client, server)| {
+        // dropping the client once everything is queued closes the session,
+        // which lingers until the lossy link got it all across. sent in
+        // chunks, a single message this long would outgrow the window
+        client.set_linger(Some(Duration::from_secs(60)));
+        let send = client.send_all_from(Cursor::new(data)).map(|_| ());
+        let recv = server.recv_all_into(Cursor::new(Vec::new()));
+        send.join(recv)
+    });
+    let (_, (n, _, sink)) = core.run(transfer).unwrap();
+    assert_eq!(n, 256 * 1024);
+    assert!(sink.into_inner() == expected);
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "tls")]
+extern crate futures;
+extern crate kcp;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{future, Future, Stream};
+use kcp::tls::{self, rustls};
+use kcp::{KcpListener, KcpStream};
+use tokio_core::reactor::Core;
+use tokio_io::io::{read_exact, write_all};
+
+// localhost.pem is issued to "localhost" by ca.pem
+fn server_config() -> Arc<rustls::ServerConfig> {
+    let certs = rustls::internal::pemfile::certs(&mut &include_bytes!("data/localhost.pem")[..])
+        .unwrap();
+    let mut keys =
+        rustls::internal::pemfile::rsa_private_keys(&mut &include_bytes!("data/localhost.key")[..])
+            .unwrap();
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config.set_single_cert(certs, keys.remove(0)).unwrap();
+    Arc::new(config)
+}
+
+fn client_config() -> Arc<rustls::ClientConfig> {
+    let mut config = rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_pem_file(&mut &include_bytes!("data/ca.pem")[..])
+        .unwrap();
+    Arc::new(config)
+}
+
+#[test]
+fn handshake() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // echo a single line back over TLS
+    let handle2 = handle.clone();
+    let server = listener
+        .incoming()
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(accepted, rest)| {
+            // the listener feeds the session only while it is polled
+            handle2.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+            let (stream, _) = accepted.unwrap();
+            tls::accept(stream, server_config())
+        })
+        .and_then(|stream| read_exact(stream, [0; 5]))
+        .and_then(|(stream, buf)| write_all(stream, buf));
+    handle.spawn(server.map(|_| ()).map_err(|e| panic!("server: {}", e)));
+
+    let client = KcpStream::connect(&addr, &handle)
+        .and_then(|stream| {
+            future::result(tls::connect(stream, client_config(), "localhost")).flatten()
+        })
+        .and_then(|stream| write_all(stream, b"hello"))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]));
+    let (_, buf) = core.run(client).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn wrong_name_fails_handshake() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&addr, &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle2 = handle.clone();
+    let server = listener
+        .incoming()
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(accepted, rest)| {
+            handle2.spawn(rest.for_each(|_| Ok(())).map_err(|_| ()));
+            let (stream, _) = accepted.unwrap();
+            tls::accept(stream, server_config())
+        });
+    handle.spawn(server.map(|_| ()).map_err(|_| ()));
+
+    // localhost.pem is not valid for this name
+    let client = KcpStream::connect(&addr, &handle).and_then(|stream| {
+        future::result(tls::connect(stream, client_config(), "example.com")).flatten()
+    });
+    assert!(core.run(client).is_err());
+}
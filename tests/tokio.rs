@@ -0,0 +1,280 @@
+#![cfg(feature = "tokio")]
+extern crate kcp;
+extern crate tokio1;
+
+use std::future::poll_fn;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "fec")]
+use std::{cell::RefCell, io::Write, rc::Rc};
+#[cfg(feature = "fec")]
+use std::time::Instant;
+
+#[cfg(feature = "fec")]
+use kcp::fec::FecEncoder;
+#[cfg(feature = "fec")]
+use kcp::Kcb;
+use kcp::KcpConfig;
+use kcp::tokio::{KcpListener, KcpStream};
+use tokio1::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio1::runtime::{Builder, Runtime};
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+fn write(rt: &Runtime, stream: &mut KcpStream, msg: &[u8]) {
+    let n = rt.block_on(poll_fn(|cx| Pin::new(&mut *stream).poll_write(cx, msg)));
+    assert_eq!(n.unwrap(), msg.len());
+}
+
+fn read(rt: &Runtime, stream: &mut KcpStream) -> Vec<u8> {
+    try_read(rt, stream).unwrap()
+}
+
+fn try_read(rt: &Runtime, stream: &mut KcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = [0; 64];
+    let n = rt.block_on(poll_fn(|cx| {
+        let mut buf = ReadBuf::new(&mut buf);
+        match Pin::new(&mut *stream).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok::<_, io::Error>(buf.filled().len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }))?;
+    Ok(buf[..n].to_vec())
+}
+
+/// sessions updating every 10 ms and resending early, so lost datagrams
+/// are made up for quickly
+fn fast() -> KcpConfig {
+    KcpConfig {
+        nodelay: 1,
+        interval: 10,
+        resend: 2,
+        nc: true,
+        ..KcpConfig::default()
+    }
+}
+
+/// a relay in front of `server` dropping every third datagram, both ways,
+/// returns the address to connect to
+fn lossy_relay(server: SocketAddr) -> SocketAddr {
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = relay.local_addr().unwrap();
+    relay.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    thread::spawn(move || {
+        let mut client = None;
+        let mut buf = [0; 1500];
+        let mut count = 0;
+        while let Ok((n, from)) = relay.recv_from(&mut buf) {
+            count += 1;
+            if count % 3 == 0 {
+                continue;
+            }
+            let to = if from == server {
+                match client {
+                    Some(client) => client,
+                    None => continue,
+                }
+            } else {
+                client = Some(from);
+                server
+            };
+            let _ = relay.send_to(&buf[..n], to);
+        }
+    });
+    addr
+}
+
+#[test]
+fn echo() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind(&addr).unwrap();
+    let mut client = KcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+
+    write(&rt, &mut client, b"ping");
+    let (mut server, peer) = rt.block_on(listener.accept()).unwrap();
+    assert_eq!(peer.port(), client.local_addr().unwrap().port());
+    assert_eq!(server.conv(), client.conv());
+    assert_eq!(read(&rt, &mut server), b"ping");
+
+    write(&rt, &mut server, b"pong");
+    assert_eq!(read(&rt, &mut client), b"pong");
+    assert!(client.is_established());
+
+    rt.block_on(poll_fn(|cx| Pin::new(&mut client).poll_shutdown(cx))).unwrap();
+    assert!(read(&rt, &mut server).is_empty());
+}
+
+#[test]
+fn retransmits_under_loss() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind_with_config(&addr, &fast()).unwrap();
+    let relay = lossy_relay(listener.local_addr().unwrap());
+    let mut client = KcpStream::connect_with_config(&relay, &fast()).unwrap();
+
+    for i in 0..30u8 {
+        write(&rt, &mut client, &[i; 16]);
+    }
+    let (mut server, _) = rt.block_on(listener.accept()).unwrap();
+    for i in 0..30u8 {
+        assert_eq!(read(&rt, &mut server), [i; 16]);
+    }
+    write(&rt, &mut server, b"done");
+    assert_eq!(read(&rt, &mut client), b"done");
+}
+
+#[test]
+fn dead_link_times_out() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    // a peer that never answers
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = KcpConfig {
+        dead_link: 3,
+        ..fast()
+    };
+    let mut client = KcpStream::connect_with_config(&silent.local_addr().unwrap(), &config).unwrap();
+
+    write(&rt, &mut client, b"anyone?");
+    let err = try_read(&rt, &mut client).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(client.is_dead());
+}
+
+#[test]
+fn sessions_past_the_limit_are_reset() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind_with_config(&addr, &fast()).unwrap();
+    listener.set_max_sessions(1);
+    let addr = listener.local_addr().unwrap();
+
+    let mut first = KcpStream::connect_with_config(&addr, &fast()).unwrap();
+    write(&rt, &mut first, b"one");
+    let (mut server, _) = rt.block_on(listener.accept()).unwrap();
+    assert_eq!(read(&rt, &mut server), b"one");
+
+    let mut second = KcpStream::connect_with_config(&addr, &fast()).unwrap();
+    write(&rt, &mut second, b"two");
+    let err = try_read(&rt, &mut second).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+    // the first session goes on
+    write(&rt, &mut server, b"still here");
+    assert_eq!(read(&rt, &mut first), b"still here");
+}
+
+#[cfg(feature = "fec")]
+#[test]
+fn fec() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let config = KcpConfig {
+        fec: Some((4, 2)),
+        ..KcpConfig::default()
+    };
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind_with_config(&addr, &config).unwrap();
+    let mut client = KcpStream::connect_with_config(&listener.local_addr().unwrap(), &config).unwrap();
+
+    write(&rt, &mut client, b"ping");
+    let (mut server, _) = rt.block_on(listener.accept()).unwrap();
+    assert_eq!(read(&rt, &mut server), b"ping");
+    write(&rt, &mut server, b"pong");
+    assert_eq!(read(&rt, &mut client), b"pong");
+
+    let bad = KcpConfig {
+        fec: Some((0, 2)),
+        ..KcpConfig::default()
+    };
+    assert!(KcpStream::connect_with_config(&addr, &bad).is_err());
+}
+
+/// datagrams of several sessions through one encoder, like a peer with one
+/// socket for all of them
+#[cfg(feature = "fec")]
+#[derive(Clone)]
+struct SharedFec(Rc<RefCell<FecEncoder<Peer>>>);
+
+#[cfg(feature = "fec")]
+impl Write for SharedFec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fec")]
+struct Peer(UdpSocket, SocketAddr);
+
+#[cfg(feature = "fec")]
+impl Write for Peer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send_to(buf, self.1)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fec")]
+#[test]
+fn fec_sessions_of_a_peer_share_seqids() {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let config = KcpConfig {
+        fec: Some((4, 2)),
+        ..KcpConfig::default()
+    };
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = KcpListener::bind_with_config(&addr, &config).unwrap();
+    let udp = UdpSocket::bind(&addr).unwrap();
+    udp.set_nonblocking(true).unwrap();
+    let output = Peer(udp.try_clone().unwrap(), listener.local_addr().unwrap());
+    let encoder = SharedFec(Rc::new(RefCell::new(FecEncoder::new(output, 4, 2).unwrap())));
+
+    let mut servers = Vec::new();
+    for conv in 1..3 {
+        let mut kcb = Kcb::new(conv, encoder.clone());
+        config.apply(&mut kcb);
+        kcb.send(b"hi").unwrap();
+        kcb.update_at(Instant::now());
+        let (mut server, _) = rt.block_on(listener.accept()).unwrap();
+        assert_eq!(read(&rt, &mut server), b"hi");
+        write(&rt, &mut server, b"hi");
+        servers.push(server);
+    }
+
+    // one decoder takes what the listener sends the peer, a seqid used
+    // twice would have the second shard dropped
+    let wait = Instant::now() + Duration::from_millis(200);
+    let mut seqids = Vec::new();
+    let mut buf = [0; 1500];
+    while Instant::now() < wait {
+        rt.block_on(tokio1::time::sleep(Duration::from_millis(10)));
+        while let Ok(n) = udp.recv(&mut buf) {
+            assert!(n > 6);
+            seqids.push(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]));
+        }
+    }
+    let count = seqids.len();
+    seqids.sort();
+    seqids.dedup();
+    assert!(count >= 2);
+    assert_eq!(seqids.len(), count);
+}
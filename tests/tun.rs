@@ -0,0 +1,102 @@
+#![cfg(feature = "config-file")]
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// addresses nobody listens on, for the binaries to bind
+fn free_udp() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn free_tcp() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn tun(args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_kcp-tun"))
+        .args(args)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// connect to `addr` once someone listens there
+fn connect(addr: &SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(tcp) = TcpStream::connect(addr) {
+            return tcp;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("nobody listens on {}", addr);
+}
+
+/// a TCP server echoing the first connection
+fn echo_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        let mut buf = [0; 1024];
+        loop {
+            match tcp.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => tcp.write_all(&buf[..n]).unwrap(),
+            }
+        }
+    });
+    addr
+}
+
+fn get(addr: &SocketAddr) -> String {
+    let mut tcp = connect(addr);
+    tcp.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    tcp.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn status_lists_the_tunneled_sessions() {
+    let target = echo_target();
+    let server = free_udp();
+    let (from, status) = (free_tcp(), free_tcp());
+    let server_end = tun(&["-l", &server.to_string(), &target.to_string()]);
+    let (status_arg, from_arg) = (status.to_string(), from.to_string());
+    let client_end = tun(&["--status", &status_arg, &from_arg, &server.to_string()]);
+
+    // no session before the first connection
+    let response = get(&status);
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: application/json\r\n"), "{}", response);
+    assert!(response.contains("{\"sessions\":[],\"config\":{"), "{}", response);
+    assert!(response.contains("\"stream\":true}}"), "{}", response);
+
+    let mut tcp = connect(&from);
+    tcp.write_all(b"through the tunnel").unwrap();
+    let mut echoed = [0; 18];
+    tcp.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b"through the tunnel");
+
+    let response = get(&status);
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    let first = format!("{{\"sessions\":[{{\"peer\":\"{}\",\"conv\":", server);
+    assert!(body.starts_with(&first), "{}", body);
+    assert!(body.contains("\"state\":\"Established\""), "{}", body);
+    let fields = [
+        "srtt_ms", "rto_ms", "cwnd", "retransmits", "delivered_bytes", "bandwidth", "waitsnd",
+    ];
+    for field in &fields {
+        assert!(body.contains(&format!("\"{}\":", field)), "{}", body);
+    }
+    // one session only
+    assert_eq!(body.matches("\"peer\"").count(), 1, "{}", body);
+
+    drop(tcp);
+    for end in &mut [server_end, client_end] {
+        end.kill().unwrap();
+        let _ = end.wait();
+    }
+}